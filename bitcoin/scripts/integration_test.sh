@@ -3,7 +3,7 @@
 # bitcoind -regtest -server &>/dev/null &
 # pid=$!
 
-docker run -d --name bitcoind --network host --entrypoint bitcoind ruimarinho/bitcoin-core:0.20 -regtest -rpcuser=rpcuser -rpcpassword=rpcpassword
+docker run -d --name bitcoind --network host --entrypoint bitcoind ruimarinho/bitcoin-core:22 -regtest -rpcuser=rpcuser -rpcpassword=rpcpassword -blockfilterindex=1 -peerblockfilters=1
 sleep 1
 
 function finish {
@@ -19,5 +19,6 @@ cd "${DIR}/../"
 export BITCOIN_RPC_URL="http://localhost:18443"
 export BITCOIN_RPC_USER="rpcuser"
 export BITCOIN_RPC_PASS="rpcpassword"
+export BITCOIN_P2P_URL="p2p://localhost:18444"
 
-cargo test --test '*' --features uses-bitcoind -- --test-threads=1 --nocapture
\ No newline at end of file
+cargo test --test '*' --features uses-bitcoind,light-client -- --test-threads=1 --nocapture
\ No newline at end of file
@@ -3,11 +3,15 @@
 use crate::{BitcoinCoreApi, BitcoinCoreBuilder, Error};
 use bitcoincore_rpc::{bitcoin::Network, Auth};
 use clap::Parser;
-use std::{sync::Arc, time::Duration};
+use std::{num::NonZeroU32, sync::Arc, time::Duration};
 
 #[cfg(feature = "light-client")]
 use {
-    crate::{error::KeyLoadingError, BitcoinLight, PrivateKey},
+    crate::{
+        error::KeyLoadingError,
+        light::{CoinSelectionAlgorithm, KeyStoreFile},
+        new_external_signer, BitcoinCompactFilter, BitcoinLight, PrivateKey, SatPerVbyte,
+    },
     std::path::PathBuf,
 };
 
@@ -18,6 +22,16 @@ fn get_private_key_from_file(file_path: &PathBuf) -> Result<PrivateKey, KeyLoadi
     Ok(PrivateKey::from_wif(wif.trim())?)
 }
 
+#[cfg(feature = "light-client")]
+fn get_passphrase_from_file(file_path: &PathBuf) -> Result<Vec<u8>, KeyLoadingError> {
+    let mut data = std::fs::read(file_path)?;
+    // ignore trailing newlines
+    while matches!(data.last(), Some(b'\n' | b'\r')) {
+        data.pop();
+    }
+    Ok(data)
+}
+
 #[derive(Parser, Debug, Clone, Default)]
 pub struct BitcoinOpts {
     #[clap(long, env = "BITCOIN_RPC_URL")]
@@ -37,9 +51,57 @@ pub struct BitcoinOpts {
     pub bitcoin_connection_timeout_ms: u64,
 
     /// Url of the electrs server. If unset, a default fallback
-    /// is used depending on the detected network.
+    /// is used depending on the detected network. Use a tcp:// or
+    /// ssl:// url to connect to an Electrum server instead. Pass a
+    /// comma-separated list to fail over between multiple servers.
+    #[clap(long, value_delimiter = ',')]
+    pub electrs_url: Vec<String>,
+
+    /// Number of electrs servers that must agree on block hashes,
+    /// block headers and merkle proofs.
+    #[clap(long, default_value = "1")]
+    pub electrs_quorum: usize,
+
+    /// Number of transactions and block headers received from electrs that
+    /// are cached, to reduce the number of requests. Set to 0 to disable.
+    #[clap(long, default_value = "10000")]
+    pub electrs_cache_size: usize,
+
+    /// Maximum number of requests per second sent to each Esplora server.
+    /// Requests are not limited if unset.
+    #[clap(long)]
+    pub electrs_rate_limit: Option<NonZeroU32>,
+
+    /// Url of an external signer, either http(s):// or unix:///path/to/socket.
+    /// If set, payments are sent to the signer as PSBTs instead of being
+    /// signed by the bitcoin wallet.
     #[clap(long)]
-    pub electrs_url: Option<String>,
+    pub bitcoin_signer_url: Option<String>,
+
+    /// Address of the `-zmqpubhashblock` endpoint of bitcoin-core, e.g.
+    /// tcp://127.0.0.1:28332. If set, new blocks are awaited via zmq
+    /// instead of polling the rpc.
+    #[clap(long, env = "BITCOIN_ZMQ_BLOCK_URL")]
+    #[cfg_attr(feature = "light-client", clap(conflicts_with = "light"))]
+    pub bitcoin_zmq_block_url: Option<String>,
+
+    /// Create new bitcoin-core wallets as descriptor wallets, which import keys
+    /// with `importdescriptors` instead of the deprecated `importprivkey`.
+    /// Existing wallets are loaded as they are.
+    #[clap(long)]
+    #[cfg_attr(feature = "light-client", clap(conflicts_with = "light"))]
+    pub bitcoin_descriptor_wallet: bool,
+
+    /// Url of a bitcoin node started with -peerblockfilters, e.g.
+    /// p2p://127.0.0.1:8333?birthday=800000. If set, the light client syncs
+    /// headers and compact block filters from the node instead of using
+    /// electrs, and only scans blocks from the `birthday` height.
+    #[cfg_attr(
+        feature = "light-client",
+        clap(long, requires = "light", conflicts_with = "electrs_url")
+    )]
+    #[cfg(feature = "light-client")]
+    pub bitcoin_p2p_url: Option<String>,
 
     /// Experimental: Run in light client mode
     #[cfg_attr(feature = "light-client", clap(long, requires_all(["bitcoin_wif"])))]
@@ -55,6 +117,46 @@ pub struct BitcoinOpts {
     ))]
     #[cfg(feature = "light-client")]
     pub bitcoin_wif: Option<PathBuf>,
+
+    /// File in which the light client persists its (encrypted) deposit keys.
+    /// If unset, deposit keys are only kept in memory.
+    #[cfg_attr(feature = "light-client", clap(long, requires = "light", value_parser))]
+    #[cfg(feature = "light-client")]
+    pub light_key_store: Option<PathBuf>,
+
+    /// Passphrase used to encrypt the light client key store.
+    #[cfg_attr(
+        feature = "light-client",
+        clap(
+            long,
+            env = "LIGHT_KEY_STORE_PASSPHRASE",
+            hide_env_values = true,
+            requires = "light_key_store",
+            conflicts_with = "light_key_store_key_file"
+        )
+    )]
+    #[cfg(feature = "light-client")]
+    pub light_key_store_passphrase: Option<String>,
+
+    /// File containing the key used to encrypt the light client key store.
+    #[cfg_attr(feature = "light-client", clap(long, requires = "light_key_store", value_parser))]
+    #[cfg(feature = "light-client")]
+    pub light_key_store_key_file: Option<PathBuf>,
+
+    /// Coin selection strategy used by the light client, one of `lowest-waste`,
+    /// `branch-and-bound`, `knapsack` or `single-random-draw`.
+    #[cfg_attr(
+        feature = "light-client",
+        clap(long, requires = "light", default_value = "lowest-waste")
+    )]
+    #[cfg(feature = "light-client")]
+    pub light_coin_selection: CoinSelectionAlgorithm,
+
+    /// Fee rate in sat/vByte at which the light client expects to spend its coins
+    /// in the long term. Coin selection spends fewer inputs while fees are above it.
+    #[cfg_attr(feature = "light-client", clap(long, requires = "light", default_value = "10"))]
+    #[cfg(feature = "light-client")]
+    pub light_long_term_fee_rate: u64,
 }
 
 impl BitcoinOpts {
@@ -69,15 +171,69 @@ impl BitcoinOpts {
         BitcoinCoreBuilder::new(self.bitcoin_rpc_url.clone().expect("Url not set"))
             .set_auth(self.new_auth())
             .set_wallet_name(wallet_name)
-            .set_electrs_url(self.electrs_url.clone())
+            .set_electrs_urls(self.electrs_url.clone())
+            .set_electrs_quorum(self.electrs_quorum)
+            .set_electrs_cache_size(self.electrs_cache_size)
+            .set_electrs_rate_limit(self.electrs_rate_limit)
+            .set_signer_url(self.bitcoin_signer_url.clone())
+            .set_zmq_block_url(self.bitcoin_zmq_block_url.clone())
+            .set_descriptor_wallet(self.bitcoin_descriptor_wallet)
     }
 
     #[cfg(feature = "light-client")]
-    fn new_light_client(&self) -> Result<BitcoinLight, Error> {
-        Ok(BitcoinLight::new(
-            self.electrs_url.clone(),
+    fn new_key_store_file(&self) -> Result<Option<KeyStoreFile>, Error> {
+        let path = match self.light_key_store {
+            Some(ref path) => path.clone(),
+            None => return Ok(None),
+        };
+        let passphrase = match (&self.light_key_store_passphrase, &self.light_key_store_key_file) {
+            (Some(passphrase), _) => passphrase.as_bytes().to_vec(),
+            (None, Some(key_file)) => get_passphrase_from_file(key_file)?,
+            (None, None) => return Err(Error::MissingKeyStorePassphrase),
+        };
+        Ok(Some(KeyStoreFile::open(path, &passphrase)?))
+    }
+
+    #[cfg(feature = "light-client")]
+    fn new_compact_filter_client(&self, peer_url: &str) -> Result<BitcoinCompactFilter, Error> {
+        Ok(BitcoinCompactFilter::new(
+            peer_url,
             get_private_key_from_file(self.bitcoin_wif.as_ref().expect("Private key not set"))?,
-        )?)
+            self.new_key_store_file()?,
+            self.light_coin_selection,
+        )?
+        .set_external_signer(
+            self.bitcoin_signer_url
+                .as_deref()
+                .map(new_external_signer)
+                .transpose()?,
+        )
+        .set_long_term_fee_rate(SatPerVbyte(self.light_long_term_fee_rate)))
+    }
+
+    #[cfg(feature = "light-client")]
+    fn new_light_client(&self) -> Result<Arc<dyn BitcoinCoreApi + Send + Sync>, Error> {
+        if let Some(ref peer_url) = self.bitcoin_p2p_url {
+            return Ok(Arc::new(self.new_compact_filter_client(peer_url)?));
+        }
+        Ok(Arc::new(
+            BitcoinLight::new(
+                self.electrs_url.clone(),
+                self.electrs_quorum,
+                self.electrs_cache_size,
+                self.electrs_rate_limit,
+                get_private_key_from_file(self.bitcoin_wif.as_ref().expect("Private key not set"))?,
+                self.new_key_store_file()?,
+                self.light_coin_selection,
+            )?
+            .set_external_signer(
+                self.bitcoin_signer_url
+                    .as_deref()
+                    .map(new_external_signer)
+                    .transpose()?,
+            )
+            .set_long_term_fee_rate(SatPerVbyte(self.light_long_term_fee_rate)),
+        ))
     }
 
     pub async fn new_client(
@@ -87,7 +243,7 @@ impl BitcoinOpts {
         cfg_if::cfg_if! {
             if #[cfg(feature = "light-client")] {
                 Ok(if self.light {
-                    Arc::new(self.new_light_client()?)
+                    self.new_light_client()?
                 } else {
                     let bitcoin_core = self
                         .new_client_builder(wallet_name)
@@ -117,7 +273,7 @@ impl BitcoinOpts {
         cfg_if::cfg_if! {
             if #[cfg(feature = "light-client")] {
                 Ok(if self.light {
-                    Arc::new(self.new_light_client()?)
+                    self.new_light_client()?
                 } else {
                     Arc::new(self.new_client_builder(wallet_name).build_with_network(network)?)
                 })
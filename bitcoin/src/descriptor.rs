@@ -0,0 +1,269 @@
+//! Output descriptors for importing keys into any descriptor wallet with `importdescriptors`.
+
+use crate::{
+    bitcoin_primitives::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey},
+    json::Timestamp,
+    secp256k1::Secp256k1,
+    PrivateKey,
+};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// A single request of the Bitcoin Core `importdescriptors` rpc.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ImportDescriptorRequest {
+    /// the descriptor, including its checksum
+    pub desc: String,
+    /// unix time from which the wallet rescans for transactions, `now` to skip the rescan
+    pub timestamp: Timestamp,
+    pub label: String,
+    /// for ranged descriptors, the first and last index of the keys to import
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<(u32, u32)>,
+    /// for ranged descriptors, the index of the next key the wallet hands out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_index: Option<u32>,
+}
+
+impl ImportDescriptorRequest {
+    /// P2WPKH descriptor containing the private key, so the imported wallet can spend the funds.
+    pub fn wpkh(private_key: &PrivateKey, timestamp: Timestamp, label: String) -> Self {
+        Self {
+            desc: with_checksum(&format!("wpkh({})", private_key.to_wif())),
+            timestamp,
+            label,
+            range: None,
+            next_index: None,
+        }
+    }
+
+    /// Descriptor for all (P2PK, P2PKH, P2WPKH and P2SH-P2WPKH) outputs of the private key,
+    /// which matches the scripts a legacy wallet watches for an imported key.
+    pub fn combo(private_key: &PrivateKey, timestamp: Timestamp, label: String) -> Self {
+        Self {
+            desc: with_checksum(&format!("combo({})", private_key.to_wif())),
+            timestamp,
+            label,
+            range: None,
+            next_index: None,
+        }
+    }
+
+    /// Ranged descriptor, e.g. `wpkh(xprv.../0/*)`, importing the keys from `range.0` to
+    /// `range.1`. The wallet continues to derive new keys from `next_index`.
+    pub fn ranged(descriptor: &str, range: (u32, u32), next_index: u32, timestamp: Timestamp, label: String) -> Self {
+        Self {
+            desc: with_checksum(descriptor.split('#').next().unwrap_or_default()),
+            timestamp,
+            label,
+            range: Some(range),
+            next_index: Some(next_index),
+        }
+    }
+}
+
+/// Returns the private key of a single key `wpkh` or `combo` descriptor as listed
+/// by `listdescriptors true`, or `None` for any other descriptor.
+pub(crate) fn single_private_key(descriptor: &str) -> Option<PrivateKey> {
+    let descriptor = descriptor.split('#').next()?;
+    let key = descriptor
+        .strip_prefix("wpkh(")
+        .or_else(|| descriptor.strip_prefix("combo("))?
+        .strip_suffix(')')?;
+    PrivateKey::from_wif(key).ok()
+}
+
+/// Derives the private key at `path` from a ranged `wpkh` descriptor as listed by
+/// `listdescriptors true`, e.g. `wpkh([d34db33f/84'/1'/0']tprv.../0/*)`. Returns `None`
+/// if the descriptor is not ranged or `path` is not one of its keys.
+pub(crate) fn derive_private_key(descriptor: &str, path: &DerivationPath) -> Option<PrivateKey> {
+    let descriptor = descriptor.split('#').next()?;
+    let key = descriptor.strip_prefix("wpkh(")?.strip_suffix(')')?;
+    // the key origin is the path from the master key to the extended key
+    let (origin, key) = match key.strip_prefix('[') {
+        Some(key) => {
+            let (origin, key) = key.split_once(']')?;
+            let origin = match origin.split_once('/') {
+                Some((_fingerprint, origin)) => DerivationPath::from_str(&format!("m/{origin}")).ok()?,
+                None => DerivationPath::master(),
+            };
+            (origin, key)
+        }
+        None => (DerivationPath::master(), key),
+    };
+    let (extended_key, ranged_path) = key.split_once('/')?;
+    let extended_key = ExtendedPrivKey::from_str(extended_key).ok()?;
+
+    let path = path.as_ref().strip_prefix(origin.as_ref())?;
+    let ranged_path = ranged_path.split('/').collect::<Vec<_>>();
+    if path.len() != ranged_path.len() || !ranged_path.contains(&"*") {
+        return None;
+    }
+    for (child, step) in path.iter().zip(ranged_path) {
+        if step != "*" && ChildNumber::from_str(step).ok()? != *child {
+            return None;
+        }
+    }
+    let private_key = extended_key.derive_priv(&Secp256k1::signing_only(), &path).ok()?;
+    Some(private_key.to_priv())
+}
+
+fn polymod(c: u64, val: u64) -> u64 {
+    const GENERATOR: [u64; 5] = [0xf5dee51989, 0xa9fdca3312, 0x1bab10e32d, 0x3706b1677a, 0x644d626ffd];
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+    for (i, generator) in GENERATOR.iter().enumerate() {
+        if c0 & (1 << i) != 0 {
+            c ^= generator;
+        }
+    }
+    c
+}
+
+/// Computes the descriptor checksum defined in BIP-380, returns `None` if the
+/// descriptor contains characters that are not allowed.
+pub fn descriptor_checksum(descriptor: &str) -> Option<String> {
+    let mut c = 1;
+    let mut class = 0;
+    let mut class_count = 0;
+    for ch in descriptor.chars() {
+        let pos = INPUT_CHARSET.find(ch)? as u64;
+        c = polymod(c, pos & 31);
+        // group the upper bits of three characters into one symbol
+        class = class * 3 + (pos >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            c = polymod(c, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        c = polymod(c, class);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    Some(
+        (0..8)
+            .map(|i| CHECKSUM_CHARSET[((c >> (5 * (7 - i))) & 31) as usize] as char)
+            .collect(),
+    )
+}
+
+fn with_checksum(descriptor: &str) -> String {
+    // keys are base58 encoded, so the charset is never violated
+    let checksum = descriptor_checksum(descriptor).expect("descriptor contains only valid characters");
+    format!("{descriptor}#{checksum}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Network, SecretKey};
+    use std::str::FromStr;
+
+    #[test]
+    fn should_compute_descriptor_checksum() {
+        // test vectors from BIP-380
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert_eq!(descriptor_checksum("raw(deadbeef)\u{e9}"), None);
+    }
+
+    #[test]
+    fn should_build_wpkh_descriptor() {
+        let private_key = PrivateKey::new(SecretKey::from_slice(&[1; 32]).unwrap(), Network::Testnet);
+
+        let request = ImportDescriptorRequest::wpkh(&private_key, Timestamp::Time(1_600_000_000), "issue".to_string());
+        let (descriptor, checksum) = request.desc.split_once('#').unwrap();
+        assert_eq!(descriptor, format!("wpkh({})", private_key.to_wif()));
+        assert_eq!(descriptor_checksum(descriptor).unwrap(), checksum);
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            serde_json::json!({
+                "desc": request.desc,
+                "timestamp": 1_600_000_000,
+                "label": "issue",
+            })
+        );
+        assert_eq!(single_private_key(&request.desc), Some(private_key));
+    }
+
+    #[test]
+    fn should_parse_single_private_key() {
+        let private_key = PrivateKey::new(SecretKey::from_slice(&[2; 32]).unwrap(), Network::Testnet);
+
+        let request = ImportDescriptorRequest::combo(&private_key, Timestamp::Now, "deposit".to_string());
+        assert!(request.desc.starts_with("combo("));
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["timestamp"],
+            serde_json::json!("now")
+        );
+        assert_eq!(single_private_key(&request.desc), Some(private_key));
+        // ranged descriptors don't have a single key
+        assert_eq!(
+            single_private_key("wpkh(tprv8ZgxMBicQKsPd7Uf69XL1XwhmjHopUGep8GuEiJDZmbQz6o58LninorQAfcKZWARbtRtfnLcJ5MQ2AtHcQJCCRUcMRvmDUjyEmNUWwx8UbK/0/*)"),
+            None
+        );
+    }
+
+    #[test]
+    fn should_build_ranged_descriptor() {
+        let descriptor = "wpkh(tprv8ZgxMBicQKsPd7Uf69XL1XwhmjHopUGep8GuEiJDZmbQz6o58LninorQAfcKZWARbtRtfnLcJ5MQ2AtHcQJCCRUcMRvmDUjyEmNUWwx8UbK/0/*)";
+
+        let request = ImportDescriptorRequest::ranged(descriptor, (0, 999), 5, Timestamp::Now, String::new());
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            serde_json::json!({
+                "desc": format!("{descriptor}#{}", descriptor_checksum(descriptor).unwrap()),
+                "timestamp": "now",
+                "label": "",
+                "range": [0, 999],
+                "next_index": 5,
+            })
+        );
+        // single key descriptors don't have a range
+        let private_key = PrivateKey::new(SecretKey::from_slice(&[1; 32]).unwrap(), Network::Testnet);
+        let request = ImportDescriptorRequest::wpkh(&private_key, Timestamp::Now, String::new());
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json.get("range").is_none() && json.get("next_index").is_none());
+        assert_eq!(
+            serde_json::from_value::<ImportDescriptorRequest>(json).unwrap(),
+            request
+        );
+    }
+
+    #[test]
+    fn should_derive_private_key_from_ranged_descriptor() {
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[1; 32]).unwrap();
+        let account = master
+            .derive_priv(&secp, &DerivationPath::from_str("m/84'/1'/0'").unwrap())
+            .unwrap();
+        let descriptor = with_checksum(&format!("wpkh([{}/84h/1h/0h]{account}/0/*)", master.fingerprint(&secp)));
+
+        let path = DerivationPath::from_str("m/84'/1'/0'/0/5").unwrap();
+        assert_eq!(
+            derive_private_key(&descriptor, &path),
+            Some(master.derive_priv(&secp, &path).unwrap().to_priv())
+        );
+        // keys of the change descriptor or another account
+        assert_eq!(
+            derive_private_key(&descriptor, &DerivationPath::from_str("m/84'/1'/0'/1/5").unwrap()),
+            None
+        );
+        assert_eq!(
+            derive_private_key(&descriptor, &DerivationPath::from_str("m/84'/1'/1'/0/5").unwrap()),
+            None
+        );
+        // single key descriptors are not ranged
+        let private_key = PrivateKey::new(SecretKey::from_slice(&[1; 32]).unwrap(), Network::Testnet);
+        let request = ImportDescriptorRequest::wpkh(&private_key, Timestamp::Now, String::new());
+        assert_eq!(derive_private_key(&request.desc, &path), None);
+    }
+}
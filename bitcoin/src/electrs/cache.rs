@@ -0,0 +1,324 @@
+use super::{DynElectrsApi, ElectrsApi, Error, TransactionValue, TxInfo, Utxo};
+use crate::{serialize, Address, Block, BlockHash, BlockHeader, OutPoint, Transaction, Txid, H256};
+use async_trait::async_trait;
+use bitcoincore_rpc::bitcoin::ScriptBuf;
+use futures::Future;
+use lru::LruCache;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+// blocks are large, so only keep a few of them
+const MAX_CACHED_BLOCKS: usize = 16;
+
+// totals of all caches in this process, for the metrics
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of requests that were answered from the electrs cache, and of those that were not.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ElectrsCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Cache statistics of all electrs clients in this process.
+pub fn electrs_cache_stats() -> ElectrsCacheStats {
+    ElectrsCacheStats {
+        hits: CACHE_HITS.load(Ordering::Relaxed),
+        misses: CACHE_MISSES.load(Ordering::Relaxed),
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // entries are only inserted once complete, so it's fine to ignore poisoning
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Counters {
+    fn record(&self, hit: bool) {
+        let (counter, total) = if hit {
+            (&self.hits, &CACHE_HITS)
+        } else {
+            (&self.misses, &CACHE_MISSES)
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        total.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct Cache<K: Hash + Eq, V> {
+    entries: Mutex<LruCache<K, V>>,
+    /// keys that are being fetched, concurrent requests for them wait for the first one
+    in_flight: Mutex<HashMap<K, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Cache<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        lock(&self.entries).get(key).cloned()
+    }
+
+    fn put(&self, key: K, value: V) {
+        lock(&self.entries).put(key, value);
+    }
+
+    /// Returns the cached value, or fetches it once for all concurrent callers. Errors
+    /// are not cached, so callers that waited for a failed request fetch it themselves.
+    async fn get_or_fetch<F, Fut>(&self, counters: &Counters, key: K, fetch: F) -> Result<V, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, Error>>,
+    {
+        if let Some(value) = self.get(&key) {
+            counters.record(true);
+            return Ok(value);
+        }
+
+        let request = lock(&self.in_flight).entry(key.clone()).or_default().clone();
+        let _guard = request.lock().await;
+        if let Some(value) = self.get(&key) {
+            counters.record(true);
+            return Ok(value);
+        }
+
+        counters.record(false);
+        let result = fetch().await;
+        if let Ok(ref value) = result {
+            self.put(key.clone(), value.clone());
+        }
+        lock(&self.in_flight).remove(&key);
+        result
+    }
+}
+
+/// Caches immutable data of the underlying indexer - transactions, block headers
+/// and blocks by hash - and merges concurrent requests for the same item. Anything
+/// that changes with new blocks or with the mempool is always requested.
+#[derive(Clone)]
+pub struct CachedElectrsClient {
+    inner: DynElectrsApi,
+    raw_txs: Arc<Cache<Txid, Vec<u8>>>,
+    prevouts: Arc<Cache<OutPoint, (u64, ScriptBuf)>>,
+    block_headers: Arc<Cache<BlockHash, BlockHeader>>,
+    blocks: Arc<Cache<BlockHash, Block>>,
+    coinbase_txids: Arc<Cache<BlockHash, Txid>>,
+    counters: Arc<Counters>,
+}
+
+impl CachedElectrsClient {
+    /// Keeps up to `capacity` items of each kind, except for blocks of which at most
+    /// [`MAX_CACHED_BLOCKS`] are kept.
+    pub fn new(inner: DynElectrsApi, capacity: usize) -> Self {
+        Self {
+            inner,
+            raw_txs: Arc::new(Cache::new(capacity)),
+            prevouts: Arc::new(Cache::new(capacity)),
+            block_headers: Arc::new(Cache::new(capacity)),
+            blocks: Arc::new(Cache::new(capacity.min(MAX_CACHED_BLOCKS))),
+            coinbase_txids: Arc::new(Cache::new(capacity)),
+            counters: Default::default(),
+        }
+    }
+
+    /// Cache statistics of this client.
+    pub fn stats(&self) -> ElectrsCacheStats {
+        ElectrsCacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The value and script of the output, both are requested if either is not cached.
+    async fn get_prevout(&self, outpoint: OutPoint) -> Result<(u64, ScriptBuf), Error> {
+        self.prevouts
+            .get_or_fetch(&self.counters, outpoint, move || async move {
+                futures::try_join!(
+                    self.inner.get_prev_value(&outpoint),
+                    self.inner.get_script_pubkey(outpoint)
+                )
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl ElectrsApi for CachedElectrsClient {
+    async fn get_raw_tx(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
+        self.raw_txs
+            .get_or_fetch(&self.counters, *txid, || self.inner.get_raw_tx(txid))
+            .await
+    }
+
+    async fn get_raw_tx_merkle_proof(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
+        // the proof changes if the transaction is reorged into another block
+        self.inner.get_raw_tx_merkle_proof(txid).await
+    }
+
+    async fn get_address_tx_history_full(&self, address: &str) -> Result<Vec<TransactionValue>, Error> {
+        self.inner.get_address_tx_history_full(address).await
+    }
+
+    async fn get_blocks_tip_height(&self) -> Result<u32, Error> {
+        self.inner.get_blocks_tip_height().await
+    }
+
+    async fn get_blocks_tip_hash(&self) -> Result<BlockHash, Error> {
+        self.inner.get_blocks_tip_hash().await
+    }
+
+    async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error> {
+        self.block_headers
+            .get_or_fetch(&self.counters, *hash, || self.inner.get_block_header(hash))
+            .await
+    }
+
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        self.blocks
+            .get_or_fetch(&self.counters, *hash, || self.inner.get_block(hash))
+            .await
+    }
+
+    async fn get_coinbase_txid(&self, block_hash: &BlockHash) -> Result<Txid, Error> {
+        self.coinbase_txids
+            .get_or_fetch(&self.counters, *block_hash, || self.inner.get_coinbase_txid(block_hash))
+            .await
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error> {
+        self.inner.get_block_hash(height).await
+    }
+
+    async fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error> {
+        self.inner.get_raw_mempool().await
+    }
+
+    async fn is_in_mempool(&self, txid: &Txid) -> Result<bool, Error> {
+        self.inner.is_in_mempool(txid).await
+    }
+
+    async fn get_tx_info(&self, txid: &Txid) -> Result<TxInfo, Error> {
+        self.inner.get_tx_info(txid).await
+    }
+
+    async fn get_utxos_for_address(&self, address: &Address) -> Result<Vec<Utxo>, Error> {
+        self.inner.get_utxos_for_address(address).await
+    }
+
+    async fn get_script_pubkey(&self, outpoint: OutPoint) -> Result<ScriptBuf, Error> {
+        Ok(self.get_prevout(outpoint).await?.1)
+    }
+
+    async fn get_prev_value(&self, outpoint: &OutPoint) -> Result<u64, Error> {
+        Ok(self.get_prevout(*outpoint).await?.0)
+    }
+
+    async fn send_transaction(&self, tx: Transaction) -> Result<Txid, Error> {
+        let raw_tx = serialize(&tx);
+        let txid = self.inner.send_transaction(tx).await?;
+        // the wallet fetches its own transactions, e.g. to bump their fees
+        self.raw_txs.put(txid, raw_tx);
+        Ok(txid)
+    }
+
+    async fn get_txs_by_scripthash(&self, script_hash: Vec<u8>) -> Result<Vec<TransactionValue>, Error> {
+        self.inner.get_txs_by_scripthash(script_hash).await
+    }
+
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<f64, Error> {
+        self.inner.estimate_fee_rate(target_blocks).await
+    }
+
+    async fn get_tx_for_op_return(&self, address: Address, amount: u128, data: H256) -> Result<Option<Txid>, Error> {
+        self.inner.get_tx_for_op_return(address, amount, data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{electrs::MockElectrs, Hash as _};
+    use std::time::Duration;
+
+    fn dummy_txid(value: u8) -> Txid {
+        Txid::from_byte_array([value; 32])
+    }
+
+    #[tokio::test]
+    async fn should_cache_transactions() {
+        let mut electrs = MockElectrs::default();
+        electrs
+            .expect_get_raw_tx()
+            .times(2)
+            .returning(|txid| Ok(txid.to_byte_array().to_vec()));
+        electrs
+            .expect_get_tx_info()
+            .times(2)
+            .returning(|_| Err(Error::TxNotConfirmed));
+
+        let client = CachedElectrsClient::new(Arc::new(electrs), 10);
+        for _ in 0..3 {
+            assert_eq!(client.get_raw_tx(&dummy_txid(1)).await.unwrap(), vec![1; 32]);
+        }
+        assert_eq!(client.get_raw_tx(&dummy_txid(2)).await.unwrap(), vec![2; 32]);
+        assert_eq!(client.stats(), ElectrsCacheStats { hits: 2, misses: 2 });
+
+        // mutable data is not cached
+        for _ in 0..2 {
+            assert!(client.get_tx_info(&dummy_txid(1)).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_cache_errors() {
+        let mut electrs = MockElectrs::default();
+        electrs
+            .expect_get_prev_value()
+            .times(2)
+            .returning(|_| Err(Error::NoPrevOut));
+        electrs.expect_get_script_pubkey().returning(|_| Ok(ScriptBuf::new()));
+
+        let client = CachedElectrsClient::new(Arc::new(electrs), 10);
+        let outpoint = OutPoint::new(dummy_txid(1), 0);
+        for _ in 0..2 {
+            assert!(matches!(client.get_prev_value(&outpoint).await, Err(Error::NoPrevOut)));
+        }
+    }
+
+    #[tokio::test]
+    async fn should_coalesce_concurrent_requests() {
+        let cache = Cache::<u8, u8>::new(10);
+        let counters = Counters::default();
+        let fetches = &AtomicU64::new(0);
+        let fetch = move || async move {
+            fetches.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Ok::<_, Error>(1)
+        };
+
+        let (first, second) = futures::join!(
+            cache.get_or_fetch(&counters, 1, fetch),
+            cache.get_or_fetch(&counters, 1, fetch)
+        );
+        assert_eq!((first.unwrap(), second.unwrap()), (1, 1));
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+        assert!(lock(&cache.in_flight).is_empty());
+    }
+}
@@ -0,0 +1,1254 @@
+use super::{
+    peer::{get_locator, Peer},
+    ElectrsApi, Error, TransactionStatus, TransactionValue, TxInfo, Utxo,
+};
+use crate::{
+    opcodes, serialize, sha256, Address, Block, BlockHash, BlockHeader, Builder as ScriptBuilder, Hash, Network,
+    OutPoint, PublicKey, Script, SignedAmount, Transaction, TxIn, TxOut, Txid, H256,
+};
+use async_trait::async_trait;
+use bitcoincore_rpc::bitcoin::{
+    bip158::BlockFilter,
+    blockdata::{constants::genesis_block, script::Instruction},
+    consensus::Params,
+    hash_types::{FilterHash, FilterHeader},
+    merkle_tree::MerkleBlock,
+    network::{
+        message::NetworkMessage,
+        message_blockdata::{GetHeadersMessage, Inventory},
+        message_filter::{GetCFHeaders, GetCFilters},
+    },
+    CompactTarget, ScriptBuf, Target,
+};
+use lru::LruCache;
+use num::BigUint;
+use reqwest::Url;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+// https://github.com/bitcoin/bips/blob/master/bip-0158.mediawiki#block-filters
+const BASIC_FILTER_TYPE: u8 = 0;
+// https://github.com/bitcoin/bips/blob/master/bip-0157.mediawiki#getcfheaders
+const MAX_CFHEADERS_PER_REQUEST: u32 = 2000;
+// https://github.com/bitcoin/bips/blob/master/bip-0157.mediawiki#getcfilters
+const MAX_CFILTERS_PER_REQUEST: u32 = 1000;
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/net_processing.cpp
+const MAX_HEADERS_RESULTS: usize = 2000;
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/kernel/mempool_options.h
+const MEMPOOL_EXPIRY: Duration = Duration::from_secs(336 * 60 * 60);
+// announced transactions that were not fetched yet, further announcements are dropped
+const MAX_ANNOUNCED_TXS: usize = 10_000;
+// transactions outside of the wallet that were fetched to resolve inputs
+const FOREIGN_TX_CACHE_SIZE: usize = 1000;
+
+/// The target that the block at `height` must have, see `GetNextWorkRequired` in bitcoind.
+fn get_next_work_required(
+    params: &Params,
+    height: u32,
+    time: u32,
+    get_header: impl Fn(u32) -> BlockHeader,
+) -> CompactTarget {
+    let pow_limit = Target::from_be_bytes(params.pow_limit.to_be_bytes()).to_compact_lossy();
+    let interval = params.difficulty_adjustment_interval() as u32;
+    let last = get_header(height - 1);
+
+    if height % interval != 0 {
+        if !params.allow_min_difficulty_blocks {
+            return last.bits;
+        }
+        // blocks more than 20 minutes apart may be mined at the minimum difficulty
+        if u64::from(time) > u64::from(last.time) + params.pow_target_spacing * 2 {
+            return pow_limit;
+        }
+        // otherwise use the target of the last block that was not mined at the minimum difficulty
+        let (mut prev_height, mut prev) = (height - 1, last);
+        while prev_height > 0 && prev_height % interval != 0 && prev.bits == pow_limit {
+            prev_height -= 1;
+            prev = get_header(prev_height);
+        }
+        return prev.bits;
+    }
+    if params.no_pow_retargeting {
+        return last.bits;
+    }
+
+    let first = get_header(height - interval);
+    let timespan = params.pow_target_timespan as i64;
+    let actual_timespan = (i64::from(last.time) - i64::from(first.time)).clamp(timespan / 4, timespan * 4);
+    let target = BigUint::from_bytes_be(&Target::from_compact(last.bits).to_be_bytes()) * actual_timespan as u64
+        / timespan as u64;
+    let target = target.min(BigUint::from_bytes_be(&params.pow_limit.to_be_bytes()));
+    let mut bytes = [0u8; 32];
+    let target = target.to_bytes_be();
+    bytes[32 - target.len()..].copy_from_slice(&target);
+    Target::from_be_bytes(bytes).to_compact_lossy()
+}
+
+/// The script of the output spent by `txin`, if it can be recovered from the
+/// input, i.e. for P2PKH, P2SH-P2WPKH and P2WPKH spends.
+fn get_spent_script(txin: &TxIn) -> Option<ScriptBuf> {
+    let public_key = |bytes: &[u8]| PublicKey::from_slice(bytes).ok();
+    let pushes = txin
+        .script_sig
+        .instructions()
+        .map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(bytes)) => Some(bytes.as_bytes()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    match (pushes.as_slice(), txin.witness.len()) {
+        // signature and public key
+        ([_, public_key_bytes], 0) => Some(ScriptBuf::new_p2pkh(&public_key(public_key_bytes)?.pubkey_hash())),
+        ([], 2) => Some(ScriptBuf::new_v0_p2wpkh(
+            &public_key(txin.witness.nth(1)?)?.wpubkey_hash()?,
+        )),
+        // the redeem script of a nested segwit output
+        ([redeem_script], 2) => Some(ScriptBuf::new_p2sh(&Script::from_bytes(redeem_script).script_hash())),
+        _ => None,
+    }
+}
+
+/// Headers and filter headers of the best chain known to the peer.
+struct ChainState {
+    params: Params,
+    hashes: Vec<BlockHash>,
+    headers: Vec<BlockHeader>,
+    heights: HashMap<BlockHash, u32>,
+    filter_headers: Vec<FilterHeader>,
+    // lowest height that was disconnected since the last wallet scan
+    reorg_height: Option<u32>,
+}
+
+impl ChainState {
+    fn new(network: Network) -> Self {
+        let genesis = genesis_block(network).header;
+        let genesis_hash = genesis.block_hash();
+        Self {
+            params: Params::new(network),
+            hashes: vec![genesis_hash],
+            headers: vec![genesis],
+            heights: vec![(genesis_hash, 0)].into_iter().collect(),
+            filter_headers: vec![],
+            reorg_height: None,
+        }
+    }
+
+    fn tip_height(&self) -> u32 {
+        // the chain always contains the genesis block
+        (self.headers.len() - 1) as u32
+    }
+
+    fn get_hash(&self, height: u32) -> Result<BlockHash, Error> {
+        self.hashes.get(height as usize).copied().ok_or(Error::BlockNotFound)
+    }
+
+    fn get_height(&self, hash: &BlockHash) -> Result<u32, Error> {
+        self.heights.get(hash).copied().ok_or(Error::BlockNotFound)
+    }
+
+    fn connect_headers(&mut self, headers: Vec<BlockHeader>) -> Result<(), Error> {
+        let first = match headers.first() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let fork_height = self
+            .heights
+            .get(&first.prev_blockhash)
+            .copied()
+            .ok_or(Error::PeerMisbehaving("headers do not connect to the chain"))?;
+
+        let first_new_height = fork_height + 1;
+        // the new headers replace our chain from `first_new_height`
+        let get_header = |height: u32| match height.checked_sub(first_new_height) {
+            Some(offset) => headers[offset as usize],
+            None => self.headers[height as usize],
+        };
+        let mut prev_blockhash = first.prev_blockhash;
+        for (height, header) in (first_new_height..).zip(headers.iter()) {
+            if header.prev_blockhash != prev_blockhash {
+                return Err(Error::PeerMisbehaving("headers are not continuous"));
+            }
+            if header.bits != get_next_work_required(&self.params, height, header.time, get_header) {
+                return Err(Error::PeerMisbehaving("header has unexpected difficulty"));
+            }
+            prev_blockhash = header
+                .validate_pow(header.target())
+                .map_err(|_| Error::PeerMisbehaving("header has invalid proof of work"))?;
+        }
+
+        let first_new_height = first_new_height as usize;
+        if first_new_height < self.headers.len() {
+            // only reorg to a chain with more work
+            let work = |headers: &[BlockHeader]| headers.iter().map(|header| header.work()).reduce(|a, b| a + b);
+            if work(&headers) <= work(&self.headers[first_new_height..]) {
+                return Ok(());
+            }
+            log::info!("Reorg at height {first_new_height}");
+            for hash in self.hashes.drain(first_new_height..) {
+                self.heights.remove(&hash);
+            }
+            self.headers.truncate(first_new_height);
+            self.filter_headers.truncate(first_new_height);
+            self.reorg_height = Some(
+                self.reorg_height
+                    .map_or(first_new_height as u32, |height| height.min(first_new_height as u32)),
+            );
+        }
+
+        for header in headers {
+            let hash = header.block_hash();
+            self.heights.insert(hash, self.headers.len() as u32);
+            self.hashes.push(hash);
+            self.headers.push(header);
+        }
+        Ok(())
+    }
+}
+
+struct IndexedTransaction {
+    transaction: Transaction,
+    // `None` if in the mempool
+    height: Option<u32>,
+    // when the transaction (re-)entered the mempool
+    first_seen: Instant,
+}
+
+/// Transactions that pay to or spend from the watched scripts.
+struct WalletIndex {
+    // each watched script is scanned up to (and including) this height, `None` if not scanned yet
+    scripts: HashMap<ScriptBuf, Option<u32>>,
+    transactions: HashMap<Txid, IndexedTransaction>,
+    // transactions spent by indexed transactions that are not indexed themselves, with the
+    // script of a spent output and the height at which they were spent (`u32::MAX` if unconfirmed)
+    funding: HashMap<Txid, (ScriptBuf, u32)>,
+    foreign: LruCache<Txid, Transaction>,
+}
+
+impl Default for WalletIndex {
+    fn default() -> Self {
+        Self {
+            scripts: Default::default(),
+            transactions: Default::default(),
+            funding: Default::default(),
+            foreign: LruCache::new(FOREIGN_TX_CACHE_SIZE),
+        }
+    }
+}
+
+impl WalletIndex {
+    fn is_watched(&self, script: &ScriptBuf) -> bool {
+        self.scripts.contains_key(script)
+    }
+
+    fn is_relevant(&self, transaction: &Transaction) -> bool {
+        transaction
+            .output
+            .iter()
+            .any(|tx_out| self.is_watched(&tx_out.script_pubkey))
+            || transaction
+                .input
+                .iter()
+                .any(|txin| matches!(self.get_prevout(&txin.previous_output), Some(prevout) if self.is_watched(&prevout.script_pubkey)))
+    }
+
+    fn get_transaction(&self, txid: &Txid) -> Option<&Transaction> {
+        self.transactions
+            .get(txid)
+            .map(|indexed| &indexed.transaction)
+            .or_else(|| self.foreign.peek(txid))
+    }
+
+    fn get_prevout(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+        self.get_transaction(&outpoint.txid)
+            .and_then(|transaction| transaction.output.get(outpoint.vout as usize))
+    }
+
+    fn add_transaction(&mut self, transaction: Transaction, height: Option<u32>, now: Instant) {
+        let txid = transaction.txid();
+        // a transaction that spends the same outputs was replaced or double spent
+        let spent: HashSet<_> = transaction.input.iter().map(|txin| txin.previous_output).collect();
+        let conflicts = self
+            .transactions
+            .iter()
+            .filter(|(other_txid, indexed)| {
+                **other_txid != txid
+                    && indexed.height.is_none()
+                    && indexed
+                        .transaction
+                        .input
+                        .iter()
+                        .any(|txin| spent.contains(&txin.previous_output))
+            })
+            .map(|(other_txid, _)| *other_txid)
+            .collect();
+        self.remove_with_descendants(conflicts);
+
+        for txin in transaction.input.iter() {
+            let funding_txid = txin.previous_output.txid;
+            if txin.previous_output.is_null() || self.get_transaction(&funding_txid).is_some() {
+                continue;
+            }
+            if let Some(script) = get_spent_script(txin) {
+                self.funding.insert(funding_txid, (script, height.unwrap_or(u32::MAX)));
+            }
+        }
+        self.foreign.pop(&txid);
+        self.transactions.insert(
+            txid,
+            IndexedTransaction {
+                transaction,
+                height,
+                first_seen: now,
+            },
+        );
+    }
+
+    fn add_block(&mut self, height: u32, block: Block, now: Instant) {
+        for transaction in block.txdata {
+            if self.is_relevant(&transaction) {
+                log::debug!("Found transaction {} at height {height}", transaction.txid());
+                self.add_transaction(transaction, Some(height), now);
+            }
+        }
+    }
+
+    /// Add a transaction from the mempool, returns false if it isn't relevant.
+    fn add_mempool_transaction(&mut self, transaction: Transaction, now: Instant) -> bool {
+        let txid = transaction.txid();
+        if self.transactions.contains_key(&txid) || !self.is_relevant(&transaction) {
+            return false;
+        }
+        log::debug!("Found transaction {txid} in the mempool");
+        self.add_transaction(transaction, None, now);
+        true
+    }
+
+    // unconfirmed transactions that spend `txids` are no longer valid either
+    fn remove_with_descendants(&mut self, mut txids: Vec<Txid>) {
+        while let Some(txid) = txids.pop() {
+            if self.transactions.remove(&txid).is_none() {
+                continue;
+            }
+            log::debug!("Evicting transaction {txid}");
+            txids.extend(
+                self.transactions
+                    .iter()
+                    .filter(|(_, indexed)| {
+                        indexed.height.is_none()
+                            && indexed
+                                .transaction
+                                .input
+                                .iter()
+                                .any(|txin| txin.previous_output.txid == txid)
+                    })
+                    .map(|(txid, _)| *txid),
+            );
+        }
+    }
+
+    // like bitcoind, drop transactions that were not mined for too long
+    fn evict_expired(&mut self, now: Instant) {
+        let expired = self
+            .transactions
+            .iter()
+            .filter(|(_, indexed)| {
+                indexed.height.is_none() && now.saturating_duration_since(indexed.first_seen) > MEMPOOL_EXPIRY
+            })
+            .map(|(txid, _)| *txid)
+            .collect();
+        self.remove_with_descendants(expired);
+    }
+
+    // transactions in disconnected blocks go back to the mempool
+    fn disconnect(&mut self, reorg_height: u32, now: Instant) {
+        for indexed in self.transactions.values_mut() {
+            if matches!(indexed.height, Some(height) if height >= reorg_height) {
+                indexed.height = None;
+                indexed.first_seen = now;
+            }
+        }
+        for scanned_height in self.scripts.values_mut() {
+            *scanned_height = scanned_height.map(|height| height.min(reorg_height.saturating_sub(1)));
+        }
+    }
+
+    fn get_transactions_for_script(&self, script: &ScriptBuf) -> Vec<&IndexedTransaction> {
+        let mut transactions: Vec<_> = self
+            .transactions
+            .values()
+            .filter(|indexed| {
+                indexed
+                    .transaction
+                    .output
+                    .iter()
+                    .any(|tx_out| &tx_out.script_pubkey == script)
+                    || indexed.transaction.input.iter().any(
+                        |txin| matches!(self.get_prevout(&txin.previous_output), Some(prevout) if &prevout.script_pubkey == script),
+                    )
+            })
+            .collect();
+        // most recent first, like esplora
+        transactions.sort_by_key(|indexed| std::cmp::Reverse(indexed.height.unwrap_or(u32::MAX)));
+        transactions
+    }
+
+    fn get_prevouts(&self, transaction: &Transaction) -> Vec<Option<TxOut>> {
+        transaction
+            .input
+            .iter()
+            .map(|txin| self.get_prevout(&txin.previous_output).cloned())
+            .collect()
+    }
+}
+
+struct State {
+    chain: ChainState,
+    index: WalletIndex,
+    // transactions announced by the peer that are not requested yet
+    announced: HashSet<Txid>,
+}
+
+/// Light client that syncs headers and compact block filters (BIP157/158) from
+/// a single bitcoin node over P2P. Only blocks whose filter matches a watched
+/// script are downloaded and merkle proofs are built from the full blocks, so
+/// no indexer needs to be trusted. Unlike an indexer this can only see the
+/// history of scripts that are queried, starting at the `birthday` height, and
+/// the mempool transactions that the peer announces after connecting.
+#[derive(Clone)]
+pub struct CompactFilterClient {
+    host: String,
+    port: u16,
+    network: Network,
+    birthday: u32,
+    // locked for the duration of a request to the peer, the chain only changes while this is held
+    peer: Arc<tokio::sync::Mutex<Option<Peer>>>,
+    // never held across network i/o
+    state: Arc<Mutex<State>>,
+}
+
+impl CompactFilterClient {
+    /// Connect to the peer at `p2p://host:port`, an optional `birthday`
+    /// query parameter sets the height from which scripts are scanned.
+    pub fn new(peer_url: &str, network: Network) -> Result<Self, Error> {
+        let url: Url = peer_url.parse()?;
+        let (host, port) = match (url.scheme(), url.host_str(), url.port()) {
+            ("p2p", Some(host), Some(port)) => (host.to_string(), port),
+            _ => return Err(Error::InvalidPeerUrl(peer_url.to_string())),
+        };
+        let birthday = match url.query_pairs().find(|(key, _)| key == "birthday") {
+            Some((_, birthday)) => birthday.parse()?,
+            None => 0,
+        };
+        Ok(Self {
+            host,
+            port,
+            network,
+            birthday,
+            peer: Arc::new(tokio::sync::Mutex::new(None)),
+            state: Arc::new(Mutex::new(State {
+                chain: ChainState::new(network),
+                index: Default::default(),
+                announced: Default::default(),
+            })),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // updates are applied while holding the lock without awaiting, so a panic can't leave them half done
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // the stream may be in an inconsistent state, reconnect on the next call
+    fn check<T>(peer: &mut Option<Peer>, result: Result<T, Error>) -> Result<T, Error> {
+        if matches!(
+            result,
+            Err(Error::IoError(_) | Error::BitcoinEncodeError(_) | Error::PeerMisbehaving(_))
+        ) {
+            *peer = None;
+        }
+        result
+    }
+
+    async fn get_peer<'a>(&self, peer: &'a mut Option<Peer>) -> Result<&'a mut Peer, Error> {
+        if peer.is_none() {
+            *peer = Some(Peer::connect(&self.host, self.port, self.network).await?);
+        }
+        Ok(peer.as_mut().expect("peer is connected; qed"))
+    }
+
+    /// Receive the next message that isn't a transaction announcement or a transaction.
+    async fn receive(&self, peer: &mut Peer) -> Result<NetworkMessage, Error> {
+        loop {
+            match peer.receive().await? {
+                NetworkMessage::Inv(inventory) => {
+                    let mut state = self.state();
+                    for inventory in inventory {
+                        if let Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) = inventory {
+                            if state.announced.len() < MAX_ANNOUNCED_TXS
+                                && !state.index.transactions.contains_key(&txid)
+                            {
+                                state.announced.insert(txid);
+                            }
+                        }
+                    }
+                }
+                NetworkMessage::Tx(transaction) => {
+                    self.state().index.add_mempool_transaction(transaction, Instant::now());
+                }
+                // the announced transaction was already evicted
+                NetworkMessage::NotFound(inventory)
+                    if inventory.iter().all(|inventory| {
+                        matches!(inventory, Inventory::Transaction(_) | Inventory::WitnessTransaction(_))
+                    }) => {}
+                message => return Ok(message),
+            }
+        }
+    }
+
+    async fn sync(&self, peer: &mut Peer) -> Result<(), Error> {
+        loop {
+            let locator = get_locator(&self.state().chain.hashes);
+            peer.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(
+                locator,
+                BlockHash::all_zeros(),
+            )))
+            .await?;
+            let headers = loop {
+                if let NetworkMessage::Headers(headers) = self.receive(peer).await? {
+                    break headers;
+                }
+            };
+            let num_headers = headers.len();
+            self.state().chain.connect_headers(headers)?;
+            if num_headers < MAX_HEADERS_RESULTS {
+                break;
+            }
+        }
+
+        loop {
+            let (start_height, stop_height, stop_hash) = {
+                let state = self.state();
+                let chain = &state.chain;
+                if chain.filter_headers.len() >= chain.headers.len() {
+                    break;
+                }
+                let start_height = chain.filter_headers.len() as u32;
+                let stop_height = chain.tip_height().min(start_height + MAX_CFHEADERS_PER_REQUEST - 1);
+                (start_height, stop_height, chain.get_hash(stop_height)?)
+            };
+            peer.send(NetworkMessage::GetCFHeaders(GetCFHeaders {
+                filter_type: BASIC_FILTER_TYPE,
+                start_height,
+                stop_hash,
+            }))
+            .await?;
+            let cfheaders = loop {
+                match self.receive(peer).await? {
+                    NetworkMessage::CFHeaders(cfheaders) if cfheaders.stop_hash == stop_hash => break cfheaders,
+                    _ => {}
+                }
+            };
+
+            let mut state = self.state();
+            let chain = &mut state.chain;
+            let mut previous_filter_header = chain
+                .filter_headers
+                .last()
+                .copied()
+                .unwrap_or_else(FilterHeader::all_zeros);
+            if cfheaders.previous_filter_header != previous_filter_header
+                || cfheaders.filter_hashes.len() != (stop_height - start_height + 1) as usize
+            {
+                return Err(Error::PeerMisbehaving("filter headers do not connect"));
+            }
+            for filter_hash in cfheaders.filter_hashes {
+                previous_filter_header = filter_hash.filter_header(&previous_filter_header);
+                chain.filter_headers.push(previous_filter_header);
+            }
+        }
+
+        let announced: Vec<_> = {
+            let now = Instant::now();
+            let mut state = self.state();
+            if let Some(reorg_height) = state.chain.reorg_height.take() {
+                state.index.disconnect(reorg_height, now);
+            }
+            state.index.evict_expired(now);
+            state.announced.drain().collect()
+        };
+        // the transactions are added once the peer sends them
+        if !announced.is_empty() {
+            peer.send(NetworkMessage::GetData(
+                announced.into_iter().map(Inventory::WitnessTransaction).collect(),
+            ))
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn get_block_from_peer(&self, peer: &mut Peer, hash: BlockHash) -> Result<Block, Error> {
+        peer.send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(hash)]))
+            .await?;
+        loop {
+            match self.receive(peer).await? {
+                NetworkMessage::Block(block) if block.block_hash() == hash => {
+                    if !block.check_merkle_root() || !block.check_witness_commitment() {
+                        return Err(Error::PeerMisbehaving("block does not match its header"));
+                    }
+                    return Ok(block);
+                }
+                NetworkMessage::NotFound(_) => return Err(Error::BlockNotFound),
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the heights in `start_height..=stop_height` of blocks whose filter matches any
+    /// of the `scripts`, which are paired with the height from which they are matched.
+    async fn scan_filters(
+        &self,
+        peer: &mut Peer,
+        scripts: &[(ScriptBuf, u32)],
+        start_height: u32,
+        stop_height: u32,
+    ) -> Result<Vec<u32>, Error> {
+        let mut matches = vec![];
+        if scripts.is_empty() {
+            return Ok(matches);
+        }
+        let mut batch_start = start_height;
+        while batch_start <= stop_height {
+            let batch_stop = stop_height.min(batch_start + MAX_CFILTERS_PER_REQUEST - 1);
+            let stop_hash = self.state().chain.get_hash(batch_stop)?;
+            peer.send(NetworkMessage::GetCFilters(GetCFilters {
+                filter_type: BASIC_FILTER_TYPE,
+                start_height: batch_start,
+                stop_hash,
+            }))
+            .await?;
+
+            // filters are sent in order of height
+            let mut height = batch_start;
+            while height <= batch_stop {
+                let cfilter = match self.receive(peer).await? {
+                    NetworkMessage::CFilter(cfilter) => cfilter,
+                    _ => continue,
+                };
+                let (block_hash, previous_filter_header, expected_filter_header) = {
+                    let state = self.state();
+                    let previous_filter_header = match height.checked_sub(1) {
+                        Some(prev_height) => state.chain.filter_headers[prev_height as usize],
+                        None => FilterHeader::all_zeros(),
+                    };
+                    (
+                        state.chain.get_hash(height)?,
+                        previous_filter_header,
+                        state.chain.filter_headers[height as usize],
+                    )
+                };
+                let filter_header = FilterHash::hash(&cfilter.filter).filter_header(&previous_filter_header);
+                if cfilter.block_hash != block_hash || filter_header != expected_filter_header {
+                    return Err(Error::PeerMisbehaving("filter does not match its filter header"));
+                }
+                if BlockFilter::new(&cfilter.filter)
+                    .match_any(
+                        &block_hash,
+                        &mut scripts
+                            .iter()
+                            .filter(|(_, start_height)| *start_height <= height)
+                            .map(|(script, _)| script.as_bytes()),
+                    )
+                    .map_err(|_| Error::PeerMisbehaving("invalid filter"))?
+                {
+                    matches.push(height);
+                }
+                height += 1;
+            }
+            batch_start = batch_stop + 1;
+        }
+        Ok(matches)
+    }
+
+    /// Sync the chain and index all transactions of the `scripts`.
+    async fn sync_scripts(&self, peer: &mut Peer, scripts: Vec<ScriptBuf>) -> Result<(), Error> {
+        self.sync(peer).await?;
+
+        // continue each script's scan where it left off, new scripts start at the birthday
+        let (tip_height, scripts) = {
+            let mut state = self.state();
+            let tip_height = state.chain.tip_height();
+            for script in scripts {
+                state.index.scripts.entry(script).or_insert(None);
+            }
+            let scripts: Vec<_> = state
+                .index
+                .scripts
+                .iter()
+                .map(|(script, scanned_height)| {
+                    let start_height = scanned_height.map_or(self.birthday, |height| height + 1);
+                    (script.clone(), start_height.max(self.birthday))
+                })
+                .filter(|(_, start_height)| *start_height <= tip_height)
+                .collect();
+            (tip_height, scripts)
+        };
+
+        if let Some(start_height) = scripts.iter().map(|(_, start_height)| *start_height).min() {
+            for height in self.scan_filters(peer, &scripts, start_height, tip_height).await? {
+                let hash = self.state().chain.get_hash(height)?;
+                let block = self.get_block_from_peer(peer, hash).await?;
+                self.state().index.add_block(height, block, Instant::now());
+            }
+        }
+
+        let mut state = self.state();
+        for (script, _) in scripts {
+            state.index.scripts.insert(script, Some(tip_height));
+        }
+        Ok(())
+    }
+
+    /// Find a transaction that is spent by an indexed transaction, by scanning the
+    /// filters for the script of the spent output.
+    async fn find_funding_transaction(&self, peer: &mut Peer, txid: &Txid) -> Result<Transaction, Error> {
+        let (script, stop_height) = {
+            let state = self.state();
+            let (script, spent_height) = state.index.funding.get(txid).cloned().ok_or(Error::TxNotFound(*txid))?;
+            (script, spent_height.min(state.chain.tip_height()))
+        };
+        if self.birthday > stop_height {
+            return Err(Error::TxNotFound(*txid));
+        }
+        let heights = self
+            .scan_filters(peer, &[(script, self.birthday)], self.birthday, stop_height)
+            .await?;
+        // the funding transaction is most likely recent
+        for height in heights.into_iter().rev() {
+            let hash = self.state().chain.get_hash(height)?;
+            let block = self.get_block_from_peer(peer, hash).await?;
+            if let Some(transaction) = block.txdata.into_iter().find(|transaction| transaction.txid() == *txid) {
+                self.state().index.foreign.put(*txid, transaction.clone());
+                return Ok(transaction);
+            }
+        }
+        Err(Error::TxNotFound(*txid))
+    }
+
+    /// Get an indexed transaction, or a transaction spent by one.
+    async fn get_transaction(&self, txid: &Txid) -> Result<Transaction, Error> {
+        if let Some(transaction) = self.state().index.get_transaction(txid) {
+            return Ok(transaction.clone());
+        }
+        let mut peer = self.peer.lock().await;
+        let result = match self.get_peer(&mut peer).await {
+            Ok(connected) => self.find_funding_transaction(connected, txid).await,
+            Err(err) => Err(err),
+        };
+        Self::check(&mut peer, result)
+    }
+
+    async fn get_tx_out(&self, outpoint: &OutPoint) -> Result<TxOut, Error> {
+        self.get_transaction(&outpoint.txid)
+            .await?
+            .output
+            .get(usize::try_from(outpoint.vout)?)
+            .cloned()
+            .ok_or(Error::NoPrevOut)
+    }
+
+    fn get_transaction_value(&self, state: &State, indexed: &IndexedTransaction) -> Result<TransactionValue, Error> {
+        let status = match indexed.height {
+            Some(height) => TransactionStatus::confirmed(height, &state.chain.headers[height as usize])?,
+            None => TransactionStatus::unconfirmed(),
+        };
+        TransactionValue::new(
+            &indexed.transaction,
+            state.index.get_prevouts(&indexed.transaction),
+            status,
+            self.network,
+        )
+    }
+
+    fn get_indexed_transaction<'a>(state: &'a State, txid: &Txid) -> Result<&'a IndexedTransaction, Error> {
+        state.index.transactions.get(txid).ok_or(Error::TxNotFound(*txid))
+    }
+
+    /// Sync the chain and index all transactions of the `scripts`, if any.
+    async fn sync_peer(&self, scripts: Vec<ScriptBuf>) -> Result<(), Error> {
+        let mut peer = self.peer.lock().await;
+        let result = match self.get_peer(&mut peer).await {
+            Ok(connected) if scripts.is_empty() => self.sync(connected).await,
+            Ok(connected) => self.sync_scripts(connected, scripts).await,
+            Err(err) => Err(err),
+        };
+        Self::check(&mut peer, result)
+    }
+}
+
+#[async_trait]
+impl ElectrsApi for CompactFilterClient {
+    // transactions outside of the wallet are only found if they fund an indexed transaction
+    async fn get_raw_tx(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
+        Ok(serialize(&self.get_transaction(txid).await?))
+    }
+
+    async fn get_raw_tx_merkle_proof(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
+        let mut peer = self.peer.lock().await;
+        let result = async {
+            let connected = self.get_peer(&mut peer).await?;
+            self.sync(connected).await?;
+            let hash = {
+                let state = self.state();
+                let height = Self::get_indexed_transaction(&state, txid)?
+                    .height
+                    .ok_or(Error::TxNotConfirmed)?;
+                state.chain.get_hash(height)?
+            };
+            let block = self.get_block_from_peer(connected, hash).await?;
+            Ok(serialize(&MerkleBlock::from_block_with_predicate(&block, |t| {
+                t == txid
+            })))
+        }
+        .await;
+        Self::check(&mut peer, result)
+    }
+
+    async fn get_address_tx_history_full(&self, address: &str) -> Result<Vec<TransactionValue>, Error> {
+        let script = Address::from_str(address)?
+            .require_network(self.network)?
+            .script_pubkey();
+        self.sync_peer(vec![script.clone()]).await?;
+        let state = self.state();
+        // like esplora's `txs/chain` only return confirmed transactions
+        state
+            .index
+            .get_transactions_for_script(&script)
+            .into_iter()
+            .filter(|indexed| indexed.height.is_some())
+            .map(|indexed| self.get_transaction_value(&state, indexed))
+            .collect()
+    }
+
+    async fn get_blocks_tip_height(&self) -> Result<u32, Error> {
+        self.sync_peer(vec![]).await?;
+        Ok(self.state().chain.tip_height())
+    }
+
+    async fn get_blocks_tip_hash(&self) -> Result<BlockHash, Error> {
+        self.sync_peer(vec![]).await?;
+        let state = self.state();
+        state.chain.get_hash(state.chain.tip_height())
+    }
+
+    async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error> {
+        let state = self.state();
+        let height = state.chain.get_height(hash)?;
+        Ok(state.chain.headers[height as usize])
+    }
+
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        let mut peer = self.peer.lock().await;
+        let result = match self.get_peer(&mut peer).await {
+            Ok(connected) => self.get_block_from_peer(connected, *hash).await,
+            Err(err) => Err(err),
+        };
+        Self::check(&mut peer, result)
+    }
+
+    async fn get_coinbase_txid(&self, block_hash: &BlockHash) -> Result<Txid, Error> {
+        let block = self.get_block(block_hash).await?;
+        block
+            .txdata
+            .first()
+            .map(|coinbase| coinbase.txid())
+            .ok_or(Error::EmptyBlock)
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error> {
+        if height > self.state().chain.tip_height() {
+            self.sync_peer(vec![]).await?;
+        }
+        self.state().chain.get_hash(height)
+    }
+
+    // only transactions of the watched scripts are known
+    async fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error> {
+        self.sync_peer(vec![]).await?;
+        Ok(self
+            .state()
+            .index
+            .transactions
+            .iter()
+            .filter(|(_, indexed)| indexed.height.is_none())
+            .map(|(txid, _)| *txid)
+            .collect())
+    }
+
+    async fn is_in_mempool(&self, txid: &Txid) -> Result<bool, Error> {
+        Ok(self.get_raw_mempool().await?.contains(txid))
+    }
+
+    async fn get_tx_info(&self, txid: &Txid) -> Result<TxInfo, Error> {
+        self.sync_peer(vec![]).await?;
+        let state = self.state();
+        let indexed = Self::get_indexed_transaction(&state, txid)?;
+        let height = indexed.height.ok_or(Error::TxNotConfirmed)?;
+        let transaction_value = self.get_transaction_value(&state, indexed)?;
+        Ok(TxInfo {
+            confirmations: state.chain.tip_height().saturating_sub(height),
+            height,
+            hash: state.chain.get_hash(height)?,
+            fee: SignedAmount::from_sat(i64::try_from(transaction_value.fee)?),
+        })
+    }
+
+    async fn get_utxos_for_address(&self, address: &Address) -> Result<Vec<Utxo>, Error> {
+        let script = address.script_pubkey();
+        self.sync_peer(vec![script.clone()]).await?;
+        let state = self.state();
+        let spent: HashSet<_> = state
+            .index
+            .transactions
+            .values()
+            .flat_map(|indexed| indexed.transaction.input.iter().map(|txin| txin.previous_output))
+            .collect();
+        let mut utxos = BTreeMap::new();
+        for (txid, indexed) in state.index.transactions.iter() {
+            for (vout, tx_out) in indexed.transaction.output.iter().enumerate() {
+                let outpoint = OutPoint {
+                    txid: *txid,
+                    vout: u32::try_from(vout)?,
+                };
+                if tx_out.script_pubkey == script && !spent.contains(&outpoint) {
+                    utxos.insert(
+                        outpoint,
+                        Utxo {
+                            outpoint,
+                            value: tx_out.value,
+                            height: indexed.height,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(utxos.into_values().collect())
+    }
+
+    async fn get_script_pubkey(&self, outpoint: OutPoint) -> Result<ScriptBuf, Error> {
+        Ok(self.get_tx_out(&outpoint).await?.script_pubkey)
+    }
+
+    async fn get_prev_value(&self, outpoint: &OutPoint) -> Result<u64, Error> {
+        Ok(self.get_tx_out(outpoint).await?.value)
+    }
+
+    async fn send_transaction(&self, tx: Transaction) -> Result<Txid, Error> {
+        let txid = tx.txid();
+        let mut peer = self.peer.lock().await;
+        let result = match self.get_peer(&mut peer).await {
+            // NOTE: the peer does not confirm that the transaction was accepted
+            Ok(connected) => connected.send(NetworkMessage::Tx(tx.clone())).await,
+            Err(err) => Err(err),
+        };
+        Self::check(&mut peer, result)?;
+        self.state().index.add_transaction(tx, None, Instant::now());
+        Ok(txid)
+    }
+
+    // the script can't be recovered from its hash, so only watched scripts are found
+    async fn get_txs_by_scripthash(&self, script_hash: Vec<u8>) -> Result<Vec<TransactionValue>, Error> {
+        let state = self.state();
+        match state
+            .index
+            .scripts
+            .keys()
+            .find(|script| sha256::Hash::hash(script.as_bytes()).as_byte_array()[..] == script_hash[..])
+        {
+            Some(script) => state
+                .index
+                .get_transactions_for_script(script)
+                .into_iter()
+                .map(|indexed| self.get_transaction_value(&state, indexed))
+                .collect(),
+            None => Ok(vec![]),
+        }
+    }
+
+    // peers don't relay fee estimates
+    async fn estimate_fee_rate(&self, _target_blocks: u16) -> Result<f64, Error> {
+        Err(Error::NoFeeEstimate)
+    }
+
+    // `OP_RETURN` outputs are not included in the basic filter, so scan for the payments to `address` instead
+    async fn get_tx_for_op_return(&self, address: Address, amount: u128, data: H256) -> Result<Option<Txid>, Error> {
+        let op_return = ScriptBuilder::new()
+            .push_opcode(opcodes::OP_RETURN)
+            .push_slice(data.as_fixed_bytes())
+            .into_script();
+        let script = address.script_pubkey();
+        self.sync_peer(vec![script.clone()]).await?;
+
+        Ok(self
+            .state()
+            .index
+            .get_transactions_for_script(&script)
+            .into_iter()
+            .map(|indexed| &indexed.transaction)
+            .find(|transaction| {
+                let largest = transaction
+                    .output
+                    .iter()
+                    .filter(|tx_out| tx_out.script_pubkey == script)
+                    .map(|tx_out| tx_out.value)
+                    .max()
+                    .unwrap_or_default();
+                transaction
+                    .output
+                    .iter()
+                    .any(|tx_out| tx_out.script_pubkey == op_return)
+                    && largest as u128 >= amount
+            })
+            .map(|transaction| transaction.txid()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{secp256k1::Secp256k1, LockTime, PrivateKey, SecretKey, TxMerkleNode};
+    use bitcoincore_rpc::bitcoin::{block::Version, Sequence, Witness};
+
+    // regtest proof of work limit
+    const REGTEST_BITS: u32 = 0x207fffff;
+    const MAINNET_BITS: u32 = 0x1d00ffff;
+
+    fn mine_header_with_bits(prev_blockhash: BlockHash, nonce: u32, bits: u32) -> BlockHeader {
+        let mut header = BlockHeader {
+            version: Version::ONE,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: nonce,
+            bits: CompactTarget::from_consensus(bits),
+            nonce: 0,
+        };
+        while header.validate_pow(header.target()).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    fn mine_header(prev_blockhash: BlockHash, nonce: u32) -> BlockHeader {
+        mine_header_with_bits(prev_blockhash, nonce, REGTEST_BITS)
+    }
+
+    fn mine_headers(prev_blockhash: BlockHash, count: u32, seed: u32) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        for i in 0..count {
+            let prev_blockhash = headers.last().map_or(prev_blockhash, |header| header.block_hash());
+            headers.push(mine_header(prev_blockhash, seed + i));
+        }
+        headers
+    }
+
+    #[test]
+    fn should_connect_headers_and_reorg() -> Result<(), Box<dyn std::error::Error>> {
+        let mut chain = ChainState::new(Network::Regtest);
+        let genesis_hash = chain.get_hash(0)?;
+        chain.connect_headers(mine_headers(genesis_hash, 3, 0))?;
+        assert_eq!(chain.tip_height(), 3);
+
+        // shorter fork is ignored
+        let fork_hash = chain.get_hash(1)?;
+        chain.connect_headers(mine_headers(fork_hash, 1, 100))?;
+        assert_eq!(chain.tip_height(), 3);
+        assert_eq!(chain.reorg_height, None);
+
+        // longer fork replaces the tip
+        let fork = mine_headers(fork_hash, 3, 200);
+        chain.connect_headers(fork.clone())?;
+        assert_eq!(chain.tip_height(), 4);
+        assert_eq!(chain.get_hash(4)?, fork[2].block_hash());
+        assert_eq!(chain.reorg_height, Some(2));
+
+        // headers must connect to the known chain
+        assert!(matches!(
+            chain.connect_headers(mine_headers(BlockHash::all_zeros(), 1, 300)),
+            Err(Error::PeerMisbehaving(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_unexpected_difficulty() {
+        let mut chain = ChainState::new(Network::Regtest);
+        let genesis_hash = chain.get_hash(0).unwrap();
+        // more work than required is still invalid
+        let header = mine_header_with_bits(genesis_hash, 0, 0x2000ffff);
+        assert!(matches!(
+            chain.connect_headers(vec![header]),
+            Err(Error::PeerMisbehaving("header has unexpected difficulty"))
+        ));
+        assert_eq!(chain.tip_height(), 0);
+    }
+
+    #[test]
+    fn should_compute_next_work_required() {
+        let header = |time: u32, bits: u32| BlockHeader {
+            version: Version::ONE,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time,
+            bits: CompactTarget::from_consensus(bits),
+            nonce: 0,
+        };
+        let next_work_required = |network: Network, height: u32, time: u32, last_time: u32, last_bits: u32| {
+            let params = Params::new(network);
+            let interval = params.difficulty_adjustment_interval() as u32;
+            get_next_work_required(&params, height, time, |h| {
+                if h == height - 1 {
+                    header(last_time, last_bits)
+                } else if h + interval == height {
+                    header(0, last_bits)
+                } else {
+                    header(0, MAINNET_BITS)
+                }
+            })
+            .to_consensus()
+        };
+        let timespan = 14 * 24 * 60 * 60;
+
+        // the difficulty only changes every 2016 blocks
+        assert_eq!(next_work_required(Network::Bitcoin, 2017, 0, 0, 0x1c7fff80), 0x1c7fff80);
+        // blocks were twice as fast as expected
+        assert_eq!(
+            next_work_required(Network::Bitcoin, 2016, 0, timespan / 2, MAINNET_BITS),
+            0x1c7fff80
+        );
+        // the adjustment is limited to a factor of 4
+        assert_eq!(
+            next_work_required(Network::Bitcoin, 2016, 0, 1, MAINNET_BITS),
+            0x1c3fffc0
+        );
+        // and never below the minimum difficulty
+        assert_eq!(
+            next_work_required(Network::Bitcoin, 2016, 0, timespan * 2, MAINNET_BITS),
+            MAINNET_BITS
+        );
+        // testnet allows minimum difficulty blocks after 20 minutes
+        assert_eq!(
+            next_work_required(Network::Testnet, 2017, 1201, 0, 0x1c7fff80),
+            MAINNET_BITS
+        );
+        assert_eq!(
+            next_work_required(Network::Testnet, 2017, 1200, 0, 0x1c7fff80),
+            0x1c7fff80
+        );
+        // regtest never retargets
+        assert_eq!(
+            next_work_required(Network::Regtest, 2016, 0, timespan / 2, REGTEST_BITS),
+            REGTEST_BITS
+        );
+    }
+
+    fn new_transaction(inputs: Vec<(OutPoint, Witness)>, script_pubkey: ScriptBuf) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .into_iter()
+                .map(|(previous_output, witness)| TxIn {
+                    previous_output,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness,
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey,
+            }],
+        }
+    }
+
+    fn new_script(value: u8) -> ScriptBuf {
+        ScriptBuf::from(vec![value; 22])
+    }
+
+    #[test]
+    fn should_evict_conflicting_and_expired_transactions() {
+        let mut index = WalletIndex::default();
+        let script = new_script(1);
+        index.scripts.insert(script.clone(), None);
+        let now = Instant::now();
+        let outpoint = |transaction: &Transaction| OutPoint::new(transaction.txid(), 0);
+
+        let funding = new_transaction(vec![], script.clone());
+        index.add_transaction(funding.clone(), Some(1), now);
+        let payment = new_transaction(vec![(outpoint(&funding), Witness::new())], script.clone());
+        assert!(index.add_mempool_transaction(payment.clone(), now));
+        let child = new_transaction(vec![(outpoint(&payment), Witness::new())], new_script(2));
+        assert!(index.add_mempool_transaction(child.clone(), now));
+        // not relevant
+        assert!(!index.add_mempool_transaction(new_transaction(vec![], new_script(2)), now));
+
+        // the replacement also evicts the child of the replaced transaction
+        let replacement = new_transaction(vec![(outpoint(&funding), Witness::new())], new_script(3));
+        assert!(index.add_mempool_transaction(replacement.clone(), now));
+        assert!(!index.transactions.contains_key(&payment.txid()));
+        assert!(!index.transactions.contains_key(&child.txid()));
+
+        index.evict_expired(now + MEMPOOL_EXPIRY);
+        assert!(index.transactions.contains_key(&replacement.txid()));
+        index.evict_expired(now + MEMPOOL_EXPIRY + Duration::from_secs(1));
+        assert!(!index.transactions.contains_key(&replacement.txid()));
+        // confirmed transactions never expire
+        assert!(index.transactions.contains_key(&funding.txid()));
+    }
+
+    #[test]
+    fn should_rescan_scripts_after_reorg() {
+        let mut index = WalletIndex::default();
+        index.scripts.insert(new_script(1), Some(10));
+        index.scripts.insert(new_script(2), Some(3));
+        index.scripts.insert(new_script(3), None);
+        let transaction = new_transaction(vec![], new_script(1));
+        index.add_transaction(transaction.clone(), Some(5), Instant::now());
+
+        index.disconnect(5, Instant::now());
+        assert_eq!(index.scripts.get(&new_script(1)), Some(&Some(4)));
+        assert_eq!(index.scripts.get(&new_script(2)), Some(&Some(3)));
+        assert_eq!(index.scripts.get(&new_script(3)), Some(&None));
+        assert_eq!(index.transactions.get(&transaction.txid()).unwrap().height, None);
+    }
+
+    #[test]
+    fn should_record_script_of_foreign_input() {
+        let secp = Secp256k1::new();
+        let public_key = PrivateKey::new(SecretKey::from_slice(&[1; 32]).unwrap(), Network::Regtest).public_key(&secp);
+        let foreign_outpoint = OutPoint::new(Txid::from_byte_array([1; 32]), 0);
+        let witness = Witness::from_slice(&[vec![0; 72], public_key.to_bytes()]);
+
+        let mut index = WalletIndex::default();
+        let transaction = new_transaction(vec![(foreign_outpoint, witness)], new_script(1));
+        index.add_transaction(transaction, Some(7), Instant::now());
+        assert_eq!(
+            index.funding.get(&foreign_outpoint.txid),
+            Some(&(ScriptBuf::new_v0_p2wpkh(&public_key.wpubkey_hash().unwrap()), 7))
+        );
+    }
+
+    #[test]
+    fn should_get_locator() {
+        let hashes: Vec<_> = (0..=100u8).map(|i| BlockHash::from_byte_array([i; 32])).collect();
+        let locator = get_locator(&hashes);
+        assert_eq!(locator[0], hashes[100]);
+        assert_eq!(locator[9], hashes[91]);
+        assert_eq!(locator[10], hashes[89]);
+        assert_eq!(locator.last(), Some(&hashes[0]));
+    }
+}
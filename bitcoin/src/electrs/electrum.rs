@@ -0,0 +1,934 @@
+use super::{ElectrsApi, Error, TransactionStatus, TransactionValue, TxInfo, Utxo};
+use crate::{
+    deserialize, serialize, sha256, Address, Block, BlockHash, BlockHeader, FromHex, Hash, Network, OutPoint, Script,
+    SignedAmount, Transaction, TxMerkleNode, TxOut, Txid,
+};
+use async_trait::async_trait;
+use bitcoincore_rpc::bitcoin::{merkle_tree::MerkleBlock, ScriptBuf};
+use futures::future::try_join_all;
+use reqwest::Url;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io::{Error as IoError, ErrorKind},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::{Mutex, RwLock},
+    time::timeout,
+};
+use tokio_native_tls::{native_tls, TlsConnector};
+
+// https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html
+const ELECTRUM_PROTOCOL_VERSION: &str = "1.4";
+const ELECTRUM_CLIENT_NAME: &str = "interbtc-clients";
+const ELECTRUM_TIMEOUT: Duration = Duration::from_secs(30);
+// requests are spread over this many connections so that a slow request doesn't block the others
+const ELECTRUM_CONNECTIONS: usize = 4;
+// the maximum number of headers returned by `blockchain.block.headers`
+const ELECTRUM_MAX_HEADERS: u32 = 2016;
+// how far below the tip to search for a block hash whose height is unknown
+const ELECTRUM_BLOCK_SEARCH_DEPTH: u32 = 2 * ELECTRUM_MAX_HEADERS;
+
+trait AsyncReadWrite: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncReadWrite for T {}
+
+type Connection = BufReader<Box<dyn AsyncReadWrite>>;
+
+#[derive(Deserialize)]
+struct JsonRpcResponse {
+    id: Option<usize>,
+    result: Option<Value>,
+    error: Option<Value>,
+}
+
+// https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-headers-subscribe
+#[derive(Deserialize)]
+struct HeaderNotification {
+    height: u32,
+    hex: String,
+}
+
+// https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-block-headers
+#[derive(Deserialize)]
+struct HeadersResult {
+    hex: String,
+}
+
+// https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-history
+#[derive(Deserialize)]
+struct HistoryItem {
+    // zero or negative if unconfirmed
+    height: i64,
+    tx_hash: Txid,
+}
+
+// https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-listunspent
+#[derive(Deserialize)]
+struct UnspentItem {
+    height: i64,
+    tx_pos: u32,
+    tx_hash: Txid,
+    value: u64,
+}
+
+// https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-transaction-get-merkle
+#[derive(Deserialize)]
+struct MerkleResult {
+    block_height: u32,
+    merkle: Vec<TxMerkleNode>,
+    pos: usize,
+}
+
+fn get_script_hash(script: &Script) -> Vec<u8> {
+    sha256::Hash::hash(script.as_bytes()).to_byte_array().to_vec()
+}
+
+// electrum script hashes are displayed in reverse byte order
+fn to_electrum_script_hash(script_hash: &[u8]) -> String {
+    let mut script_hash = script_hash.to_vec();
+    script_hash.reverse();
+    hex::encode(script_hash)
+}
+
+// the response to `blockchain.transaction.id_from_pos` for a position past the last transaction,
+// ElectrumX and Fulcrum reply "no tx at position ..." and electrs "invalid tx_pos ..."
+fn is_tx_pos_out_of_range(err: &Error) -> bool {
+    match err {
+        Error::ElectrumError(message) => {
+            let message = message.to_lowercase();
+            message.contains("position") || message.contains("tx_pos")
+        }
+        _ => false,
+    }
+}
+
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/merkleblock.h
+fn calc_tree_width(num_transactions: u32, height: u32) -> u32 {
+    (num_transactions + (1 << height) - 1) >> height
+}
+
+struct SingleMatchTree<'a> {
+    num_transactions: u32,
+    txid: Txid,
+    pos: u32,
+    branch: &'a [TxMerkleNode],
+    bits: Vec<bool>,
+    hashes: Vec<TxMerkleNode>,
+}
+
+impl<'a> SingleMatchTree<'a> {
+    // https://github.com/bitcoin/bitcoin/blob/v24.0/src/merkleblock.cpp
+    fn traverse_and_build(&mut self, height: u32, pos: u32) {
+        let is_parent_of_match = pos == self.pos >> height;
+        self.bits.push(is_parent_of_match);
+        if height == 0 && is_parent_of_match {
+            self.hashes
+                .push(TxMerkleNode::from_byte_array(self.txid.to_byte_array()));
+        } else if !is_parent_of_match {
+            // every other node on this level is a sibling of the path to the match
+            self.hashes.push(self.branch[height as usize]);
+        } else {
+            self.traverse_and_build(height - 1, pos * 2);
+            if pos * 2 + 1 < calc_tree_width(self.num_transactions, height - 1) {
+                self.traverse_and_build(height - 1, pos * 2 + 1);
+            }
+        }
+    }
+}
+
+/// Encode the merkle `branch` of the transaction at `pos` as a `MerkleBlock`,
+/// the format returned by Esplora's `merkleblock-proof` endpoint.
+fn build_merkle_block(
+    header: &BlockHeader,
+    txid: Txid,
+    pos: usize,
+    branch: &[TxMerkleNode],
+    num_transactions: u32,
+) -> Result<Vec<u8>, Error> {
+    let mut height = 0;
+    while calc_tree_width(num_transactions, height) > 1 {
+        height += 1;
+    }
+    let pos = u32::try_from(pos)?;
+    if branch.len() != height as usize || pos >= num_transactions {
+        return Err(Error::InvalidMerkleProof);
+    }
+
+    let mut tree = SingleMatchTree {
+        num_transactions,
+        txid,
+        pos,
+        branch,
+        bits: vec![],
+        hashes: vec![],
+    };
+    tree.traverse_and_build(height, 0);
+
+    let mut flag_bytes = vec![0u8; (tree.bits.len() + 7) / 8];
+    for (i, bit) in tree.bits.iter().enumerate() {
+        flag_bytes[i / 8] |= (*bit as u8) << (i % 8);
+    }
+
+    let mut raw_merkle_block = serialize(header);
+    raw_merkle_block.extend(serialize(&num_transactions));
+    raw_merkle_block.extend(serialize(&tree.hashes));
+    raw_merkle_block.extend(serialize(&flag_bytes));
+
+    // make sure the server did not give us an invalid branch
+    let merkle_block: MerkleBlock = deserialize(&raw_merkle_block)?;
+    let mut matches = vec![];
+    let mut indexes = vec![];
+    let merkle_root = merkle_block
+        .txn
+        .extract_matches(&mut matches, &mut indexes)
+        .map_err(|_| Error::InvalidMerkleProof)?;
+    if merkle_root != header.merkle_root || matches != [txid] {
+        return Err(Error::InvalidMerkleProof);
+    }
+
+    Ok(raw_merkle_block)
+}
+
+/// Client for servers that implement the Electrum protocol over TCP or TLS,
+/// such as Fulcrum, ElectrumX or electrs in electrum mode.
+#[derive(Clone)]
+pub struct ElectrumClient {
+    host: String,
+    port: u16,
+    use_tls: bool,
+    network: Network,
+    connections: Arc<Vec<Mutex<Option<Connection>>>>,
+    next_id: Arc<AtomicUsize>,
+    // the electrum protocol only looks up blocks by height
+    block_heights: Arc<RwLock<HashMap<BlockHash, u32>>>,
+}
+
+impl ElectrumClient {
+    pub fn new(electrum_url: &str, network: Network) -> Result<Self, Error> {
+        let url: Url = electrum_url.parse()?;
+        let use_tls = match url.scheme() {
+            "tcp" => false,
+            "ssl" => true,
+            _ => return Err(Error::InvalidElectrumUrl(electrum_url.to_string())),
+        };
+        let (host, port) = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => (host.to_string(), port),
+            _ => return Err(Error::InvalidElectrumUrl(electrum_url.to_string())),
+        };
+        Ok(Self {
+            host,
+            port,
+            use_tls,
+            network,
+            connections: Arc::new((0..ELECTRUM_CONNECTIONS).map(|_| Mutex::new(None)).collect()),
+            next_id: Arc::new(AtomicUsize::new(0)),
+            block_heights: Default::default(),
+        })
+    }
+
+    pub(super) fn is_electrum_url(url: &str) -> bool {
+        url.starts_with("tcp://") || url.starts_with("ssl://")
+    }
+
+    async fn connect(&self) -> Result<Connection, Error> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let stream: Box<dyn AsyncReadWrite> = if self.use_tls {
+            let connector = TlsConnector::from(native_tls::TlsConnector::new()?);
+            Box::new(connector.connect(&self.host, stream).await?)
+        } else {
+            Box::new(stream)
+        };
+        let mut connection = BufReader::new(stream);
+
+        // the version must be negotiated before any other request
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        Self::request(
+            &mut connection,
+            id,
+            "server.version",
+            vec![json!(ELECTRUM_CLIENT_NAME), json!(ELECTRUM_PROTOCOL_VERSION)],
+        )
+        .await?;
+
+        Ok(connection)
+    }
+
+    async fn request(connection: &mut Connection, id: usize, method: &str, params: Vec<Value>) -> Result<Value, Error> {
+        let mut request = serde_json::to_vec(&json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        }))?;
+        request.push(b'\n');
+        connection.write_all(&request).await?;
+        connection.flush().await?;
+
+        loop {
+            let mut line = String::new();
+            if connection.read_line(&mut line).await? == 0 {
+                return Err(IoError::from(ErrorKind::UnexpectedEof).into());
+            }
+            let response: JsonRpcResponse = serde_json::from_str(&line)?;
+            if response.id != Some(id) {
+                // skip subscription notifications
+                continue;
+            }
+            return match response.error {
+                Some(error) => Err(Error::ElectrumError(error.to_string())),
+                None => Ok(response.result.unwrap_or_default()),
+            };
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: Vec<Value>) -> Result<T, Error> {
+        // requests are sent one at a time per connection so that responses can't interleave,
+        // prefer an idle connection and otherwise queue on one of them
+        let mut connection = match self
+            .connections
+            .iter()
+            .find_map(|connection| connection.try_lock().ok())
+        {
+            Some(connection) => connection,
+            None => {
+                let index = self.next_id.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+                self.connections[index].lock().await
+            }
+        };
+        let result = match timeout(ELECTRUM_TIMEOUT, async {
+            if connection.is_none() {
+                *connection = Some(self.connect().await?);
+            }
+            let connection = connection.as_mut().expect("connection is established; qed");
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            Self::request(connection, id, method, params).await
+        })
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(IoError::from(ErrorKind::TimedOut).into()),
+        };
+
+        if matches!(
+            result,
+            Err(Error::IoError(_) | Error::TlsError(_) | Error::SerdeJsonError(_))
+        ) {
+            // the stream may be in an inconsistent state, reconnect on the next call
+            *connection = None;
+        }
+
+        Ok(serde_json::from_value(result?)?)
+    }
+
+    async fn get_tip(&self) -> Result<(u32, BlockHeader), Error> {
+        let tip: HeaderNotification = self.call("blockchain.headers.subscribe", vec![]).await?;
+        let header: BlockHeader = deserialize(&Vec::<u8>::from_hex(&tip.hex)?)?;
+        self.block_heights.write().await.insert(header.block_hash(), tip.height);
+        Ok((tip.height, header))
+    }
+
+    async fn get_header_at(&self, height: u32) -> Result<BlockHeader, Error> {
+        let raw_header: String = match self.call("blockchain.block.header", vec![json!(height)]).await {
+            // the server rejects heights above the tip
+            Err(Error::ElectrumError(_)) => return Err(Error::BlockNotFound),
+            result => result?,
+        };
+        let header: BlockHeader = deserialize(&Vec::<u8>::from_hex(&raw_header)?)?;
+        self.block_heights.write().await.insert(header.block_hash(), height);
+        Ok(header)
+    }
+
+    async fn get_headers(&self, start_height: u32, count: u32) -> Result<Vec<BlockHeader>, Error> {
+        let result: HeadersResult = self
+            .call("blockchain.block.headers", vec![json!(start_height), json!(count)])
+            .await?;
+        let headers = Vec::<u8>::from_hex(&result.hex)?
+            .chunks(80)
+            .map(deserialize)
+            .collect::<Result<Vec<BlockHeader>, _>>()?;
+        let mut block_heights = self.block_heights.write().await;
+        for (height, header) in (start_height..).zip(headers.iter()) {
+            block_heights.insert(header.block_hash(), height);
+        }
+        Ok(headers)
+    }
+
+    async fn get_height(&self, hash: &BlockHash) -> Result<u32, Error> {
+        if let Some(height) = self.block_heights.read().await.get(hash) {
+            return Ok(*height);
+        }
+        // the hash was not seen before, search the most recent headers for it
+        let (tip_height, _) = self.get_tip().await?;
+        let lowest_height = tip_height.saturating_sub(ELECTRUM_BLOCK_SEARCH_DEPTH);
+        let mut end_height = tip_height.saturating_add(1);
+        while end_height > lowest_height {
+            let start_height = std::cmp::max(end_height.saturating_sub(ELECTRUM_MAX_HEADERS), lowest_height);
+            let headers = self.get_headers(start_height, end_height - start_height).await?;
+            if let Some(pos) = headers.iter().position(|header| header.block_hash() == *hash) {
+                return Ok(start_height + u32::try_from(pos)?);
+            }
+            end_height = start_height;
+        }
+        Err(Error::UnknownBlockHash(*hash))
+    }
+
+    // fetch the header by its (previously seen) hash, fails if the block was reorged
+    async fn get_header_by_hash(&self, hash: &BlockHash) -> Result<(u32, BlockHeader), Error> {
+        let height = self.get_height(hash).await?;
+        let header = self.get_header_at(height).await?;
+        if header.block_hash() != *hash {
+            return Err(Error::BlockNotFound);
+        }
+        Ok((height, header))
+    }
+
+    async fn get_txid_at(&self, height: u32, pos: usize) -> Result<Txid, Error> {
+        self.call("blockchain.transaction.id_from_pos", vec![json!(height), json!(pos)])
+            .await
+    }
+
+    // the number of transactions is needed to encode a partial merkle tree but is not
+    // returned by the server, so search for the last valid position in the block
+    async fn get_num_transactions(&self, height: u32, pos: usize, depth: usize) -> Result<u32, Error> {
+        if depth == 0 {
+            return Ok(1);
+        }
+        // a tree of this depth has more than 2^(depth - 1) leaves
+        let mut valid = std::cmp::max(pos, 1 << (depth - 1));
+        let mut invalid = 1 << depth;
+        while invalid - valid > 1 {
+            let mid = valid + (invalid - valid) / 2;
+            match self.get_txid_at(height, mid).await {
+                Ok(_) => valid = mid,
+                Err(err) if is_tx_pos_out_of_range(&err) => invalid = mid,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(u32::try_from(valid + 1)?)
+    }
+
+    async fn get_tx(&self, txid: &Txid) -> Result<Transaction, Error> {
+        Ok(deserialize(&self.get_raw_tx(txid).await?)?)
+    }
+
+    async fn get_history(&self, script_hash: &[u8]) -> Result<Vec<HistoryItem>, Error> {
+        self.call(
+            "blockchain.scripthash.get_history",
+            vec![json!(to_electrum_script_hash(script_hash))],
+        )
+        .await
+    }
+
+    // the electrum protocol can't look up the status of a transaction directly so we search
+    // for it in the history of one of its outputs, returns `None` if the transaction is unknown
+    async fn get_tx_history_height(&self, txid: &Txid) -> Result<Option<i64>, Error> {
+        let tx = match self.get_tx(txid).await {
+            Ok(tx) => tx,
+            Err(Error::ElectrumError(_)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let script_pubkey = match tx.output.iter().find(|tx_out| !tx_out.script_pubkey.is_op_return()) {
+            Some(tx_out) => &tx_out.script_pubkey,
+            None => return Ok(None),
+        };
+        let history = self.get_history(&get_script_hash(script_pubkey)).await?;
+        Ok(history
+            .into_iter()
+            .find(|item| &item.tx_hash == txid)
+            .map(|item| item.height))
+    }
+
+    async fn get_confirmed_height(&self, txid: &Txid) -> Result<u32, Error> {
+        match self.get_tx_history_height(txid).await? {
+            Some(height) if height > 0 => Ok(u32::try_from(height)?),
+            _ => Err(Error::TxNotConfirmed),
+        }
+    }
+
+    async fn get_prevouts(&self, tx: &Transaction) -> Result<Vec<Option<TxOut>>, Error> {
+        try_join_all(tx.input.iter().map(|txin| async move {
+            if txin.previous_output.is_null() {
+                // coinbase
+                return Ok(None);
+            }
+            let prev_tx = self.get_tx(&txin.previous_output.txid).await?;
+            let prevout = prev_tx
+                .output
+                .get(usize::try_from(txin.previous_output.vout)?)
+                .ok_or(Error::NoPrevOut)?;
+            Ok(Some(prevout.clone()))
+        }))
+        .await
+    }
+
+    // build the same representation that esplora returns
+    async fn get_transaction_value(&self, txid: &Txid, height: Option<u32>) -> Result<TransactionValue, Error> {
+        let tx = self.get_tx(txid).await?;
+        let prevouts = self.get_prevouts(&tx).await?;
+        let status = match height {
+            Some(height) => TransactionStatus::confirmed(height, &self.get_header_at(height).await?)?,
+            None => TransactionStatus::unconfirmed(),
+        };
+        TransactionValue::new(&tx, prevouts, status, self.network)
+    }
+}
+
+#[async_trait]
+impl ElectrsApi for ElectrumClient {
+    async fn get_raw_tx(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
+        let tx_hex: String = self.call("blockchain.transaction.get", vec![json!(txid)]).await?;
+        Ok(Vec::<u8>::from_hex(&tx_hex)?)
+    }
+
+    async fn get_raw_tx_merkle_proof(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
+        let height = self.get_confirmed_height(txid).await?;
+        let merkle: MerkleResult = self
+            .call("blockchain.transaction.get_merkle", vec![json!(txid), json!(height)])
+            .await?;
+        let header = self.get_header_at(merkle.block_height).await?;
+        let num_transactions = self
+            .get_num_transactions(merkle.block_height, merkle.pos, merkle.merkle.len())
+            .await?;
+        build_merkle_block(&header, *txid, merkle.pos, &merkle.merkle, num_transactions)
+    }
+
+    async fn get_address_tx_history_full(&self, address: &str) -> Result<Vec<TransactionValue>, Error> {
+        let address = Address::from_str(address)?.require_network(self.network)?;
+        let history = self.get_history(&get_script_hash(&address.script_pubkey())).await?;
+        // like esplora's `txs/chain` only return confirmed transactions
+        try_join_all(
+            history
+                .into_iter()
+                .filter(|item| item.height > 0)
+                .map(|item| async move {
+                    let height = u32::try_from(item.height)?;
+                    self.get_transaction_value(&item.tx_hash, Some(height)).await
+                }),
+        )
+        .await
+    }
+
+    async fn get_blocks_tip_height(&self) -> Result<u32, Error> {
+        Ok(self.get_tip().await?.0)
+    }
+
+    async fn get_blocks_tip_hash(&self) -> Result<BlockHash, Error> {
+        Ok(self.get_tip().await?.1.block_hash())
+    }
+
+    async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error> {
+        Ok(self.get_header_by_hash(hash).await?.1)
+    }
+
+    // NOTE: this is expensive since every txid is looked up separately
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        let (height, header) = self.get_header_by_hash(hash).await?;
+        let mut txids = vec![];
+        loop {
+            match self.get_txid_at(height, txids.len()).await {
+                Ok(txid) => txids.push(txid),
+                // no more transactions in the block
+                Err(err) if !txids.is_empty() && is_tx_pos_out_of_range(&err) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        let txdata = try_join_all(txids.iter().map(|txid| self.get_tx(txid))).await?;
+        Ok(Block { header, txdata })
+    }
+
+    async fn get_coinbase_txid(&self, block_hash: &BlockHash) -> Result<Txid, Error> {
+        let height = self.get_height(block_hash).await?;
+        self.get_txid_at(height, 0).await
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error> {
+        Ok(self.get_header_at(height).await?.block_hash())
+    }
+
+    async fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error> {
+        Err(Error::Unsupported("listing the mempool"))
+    }
+
+    async fn is_in_mempool(&self, txid: &Txid) -> Result<bool, Error> {
+        Ok(matches!(self.get_tx_history_height(txid).await?, Some(height) if height <= 0))
+    }
+
+    async fn get_tx_info(&self, txid: &Txid) -> Result<TxInfo, Error> {
+        let height = self.get_confirmed_height(txid).await?;
+        let tx = self.get_tx(txid).await?;
+        let prevouts = self.get_prevouts(&tx).await?;
+        let (tip, header) = futures::try_join!(self.get_blocks_tip_height(), self.get_header_at(height))?;
+
+        let input_value = prevouts.iter().flatten().map(|prevout| prevout.value).sum::<u64>();
+        let output_value = tx.output.iter().map(|tx_out| tx_out.value).sum::<u64>();
+        Ok(TxInfo {
+            confirmations: tip.saturating_sub(height),
+            height,
+            hash: header.block_hash(),
+            fee: SignedAmount::from_sat(i64::try_from(input_value.saturating_sub(output_value))?),
+        })
+    }
+
+    async fn get_utxos_for_address(&self, address: &Address) -> Result<Vec<Utxo>, Error> {
+        let utxos: Vec<UnspentItem> = self
+            .call(
+                "blockchain.scripthash.listunspent",
+                vec![json!(to_electrum_script_hash(&get_script_hash(
+                    &address.script_pubkey()
+                )))],
+            )
+            .await?;
+        // NOTE: includes unconfirmed mempool txs
+        utxos
+            .into_iter()
+            .map(|utxo| {
+                Ok(Utxo {
+                    outpoint: OutPoint {
+                        txid: utxo.tx_hash,
+                        vout: utxo.tx_pos,
+                    },
+                    value: utxo.value,
+                    height: if utxo.height > 0 {
+                        Some(u32::try_from(utxo.height)?)
+                    } else {
+                        None
+                    },
+                })
+            })
+            .collect()
+    }
+
+    async fn get_script_pubkey(&self, outpoint: OutPoint) -> Result<ScriptBuf, Error> {
+        let tx = self.get_tx(&outpoint.txid).await?;
+        Ok(tx
+            .output
+            .get(usize::try_from(outpoint.vout)?)
+            .ok_or(Error::NoPrevOut)?
+            .script_pubkey
+            .clone())
+    }
+
+    async fn get_prev_value(&self, outpoint: &OutPoint) -> Result<u64, Error> {
+        let tx = self.get_tx(&outpoint.txid).await?;
+        Ok(tx
+            .output
+            .get(usize::try_from(outpoint.vout)?)
+            .ok_or(Error::NoPrevOut)?
+            .value)
+    }
+
+    async fn send_transaction(&self, tx: Transaction) -> Result<Txid, Error> {
+        self.call(
+            "blockchain.transaction.broadcast",
+            vec![json!(hex::encode(serialize(&tx)))],
+        )
+        .await
+    }
+
+    async fn get_txs_by_scripthash(&self, script_hash: Vec<u8>) -> Result<Vec<TransactionValue>, Error> {
+        let history = self.get_history(&script_hash).await?;
+        try_join_all(history.into_iter().map(|item| async move {
+            let height = if item.height > 0 {
+                Some(u32::try_from(item.height)?)
+            } else {
+                None
+            };
+            self.get_transaction_value(&item.tx_hash, height).await
+        }))
+        .await
+    }
+
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<f64, Error> {
+        // in BTC/kB, or -1 if the server has no estimate
+        let fee_rate: f64 = self.call("blockchain.estimatefee", vec![json!(target_blocks)]).await?;
+        if fee_rate < 0.0 {
+            return Err(Error::NoFeeEstimate);
+        }
+        Ok(fee_rate * 100_000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashes::HashEngine;
+    use bitcoincore_rpc::bitcoin::{block::Version, CompactTarget};
+    use futures::future::{BoxFuture, FutureExt};
+    use tokio::{net::TcpListener, sync::Barrier};
+
+    type Handler = Arc<dyn Fn(String, Vec<Value>) -> BoxFuture<'static, Result<Value, String>> + Send + Sync>;
+
+    // serve the electrum protocol on a local port, requests are answered by `handler`
+    async fn new_test_client(handler: Handler) -> ElectrumClient {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let request: Value = serde_json::from_str(&line).unwrap();
+                        let method = request["method"].as_str().unwrap().to_string();
+                        let params = request["params"].as_array().cloned().unwrap_or_default();
+                        let response = match handler(method, params).await {
+                            Ok(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }),
+                            Err(message) => json!({
+                                "jsonrpc": "2.0",
+                                "id": request["id"],
+                                "error": { "code": 1, "message": message },
+                            }),
+                        };
+                        let mut response = serde_json::to_vec(&response).unwrap();
+                        response.push(b'\n');
+                        writer.write_all(&response).await.unwrap();
+                    }
+                });
+            }
+        });
+        ElectrumClient::new(&format!("tcp://127.0.0.1:{}", port), Network::Regtest).unwrap()
+    }
+
+    // answers `server.version` and `blockchain.transaction.id_from_pos` for a block of `num_transactions`
+    fn new_block_handler(num_transactions: u64) -> Handler {
+        Arc::new(move |method, params| {
+            async move {
+                match method.as_str() {
+                    "server.version" => Ok(json!([ELECTRUM_CLIENT_NAME, ELECTRUM_PROTOCOL_VERSION])),
+                    "blockchain.transaction.id_from_pos" => {
+                        let (height, pos) = (params[0].as_u64().unwrap(), params[1].as_u64().unwrap());
+                        if pos < num_transactions {
+                            Ok(json!(Txid::from_byte_array([pos as u8; 32])))
+                        } else {
+                            Err(format!("no tx at position {} in block at height {}", pos, height))
+                        }
+                    }
+                    _ => Err("excessive resource usage".to_string()),
+                }
+            }
+            .boxed()
+        })
+    }
+
+    fn new_headers(count: u32) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        for nonce in 0..count {
+            headers.push(BlockHeader {
+                version: Version::ONE,
+                prev_blockhash: headers
+                    .last()
+                    .map_or(BlockHash::all_zeros(), |header| header.block_hash()),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce,
+            });
+        }
+        headers
+    }
+
+    fn get_merkle_branch(txids: &[Txid], pos: usize) -> (TxMerkleNode, Vec<TxMerkleNode>) {
+        let mut level = txids
+            .iter()
+            .map(|txid| TxMerkleNode::from_byte_array(txid.to_byte_array()))
+            .collect::<Vec<_>>();
+        let mut index = pos;
+        let mut branch = vec![];
+        while level.len() > 1 {
+            if level.len() % 2 == 1 {
+                level.push(*level.last().unwrap());
+            }
+            branch.push(level[index ^ 1]);
+            level = level
+                .chunks(2)
+                .map(|pair| {
+                    let mut engine = TxMerkleNode::engine();
+                    engine.input(pair[0].as_byte_array());
+                    engine.input(pair[1].as_byte_array());
+                    TxMerkleNode::from_engine(engine)
+                })
+                .collect();
+            index /= 2;
+        }
+        (level[0], branch)
+    }
+
+    #[test]
+    fn should_build_merkle_block() -> Result<(), Box<dyn std::error::Error>> {
+        for num_transactions in 1..=7 {
+            let txids = (0..num_transactions)
+                .map(|i| Txid::from_byte_array([i as u8 + 1; 32]))
+                .collect::<Vec<_>>();
+            for (pos, txid) in txids.iter().enumerate() {
+                let (merkle_root, branch) = get_merkle_branch(&txids, pos);
+                let header = BlockHeader {
+                    version: Version::ONE,
+                    prev_blockhash: BlockHash::all_zeros(),
+                    merkle_root,
+                    time: 0,
+                    bits: CompactTarget::from_consensus(0),
+                    nonce: 0,
+                };
+
+                let expected = MerkleBlock::from_header_txids_with_predicate(&header, &txids, |t| t == txid);
+                assert_eq!(
+                    build_merkle_block(&header, *txid, pos, &branch, num_transactions as u32)?,
+                    serialize(&expected)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_invalid_merkle_branch() -> Result<(), Box<dyn std::error::Error>> {
+        let txids = (0..5u8).map(|i| Txid::from_byte_array([i + 1; 32])).collect::<Vec<_>>();
+        let (merkle_root, mut branch) = get_merkle_branch(&txids, 2);
+        let header = BlockHeader {
+            version: Version::ONE,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root,
+            time: 0,
+            bits: CompactTarget::from_consensus(0),
+            nonce: 0,
+        };
+        branch[0] = TxMerkleNode::all_zeros();
+        assert!(matches!(
+            build_merkle_block(&header, txids[2], 2, &branch, 5),
+            Err(Error::InvalidMerkleProof)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_get_electrum_script_hash() -> Result<(), Box<dyn std::error::Error>> {
+        // sha256 of the script 76a91477bff20c60e522dfaa3350c39b030a5d004e839a88ac, reversed
+        let address = Address::from_str("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2")?.require_network(Network::Bitcoin)?;
+        assert_eq!(
+            to_electrum_script_hash(&get_script_hash(&address.script_pubkey())),
+            "eafd9bc024177ba93572c1cc3a83f555dadbb81ca94cd9761ef5211ce794cea9"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_parse_electrum_url() {
+        assert!(ElectrumClient::is_electrum_url("ssl://electrum.blockstream.info:50002"));
+        assert!(!ElectrumClient::is_electrum_url("https://btc-mainnet.interlay.io"));
+
+        let client = ElectrumClient::new("ssl://electrum.blockstream.info:50002", Network::Bitcoin).unwrap();
+        assert!(client.use_tls);
+        assert_eq!(client.port, 50002);
+        assert!(matches!(
+            ElectrumClient::new("tcp://localhost", Network::Regtest),
+            Err(Error::InvalidElectrumUrl(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_count_transactions_until_out_of_range() -> Result<(), Box<dyn std::error::Error>> {
+        let client = new_test_client(new_block_handler(5)).await;
+        assert_eq!(client.get_num_transactions(1, 2, 3).await?, 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_propagate_other_errors_when_counting_transactions() {
+        let handler: Handler = Arc::new(|method, _| {
+            async move {
+                match method.as_str() {
+                    "server.version" => Ok(json!([ELECTRUM_CLIENT_NAME, ELECTRUM_PROTOCOL_VERSION])),
+                    _ => Err("excessive resource usage".to_string()),
+                }
+            }
+            .boxed()
+        });
+        let client = new_test_client(handler).await;
+        assert!(matches!(
+            client.get_num_transactions(1, 2, 3).await,
+            Err(Error::ElectrumError(message)) if message.contains("excessive resource usage")
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_get_coinbase_txid_of_unseen_block() -> Result<(), Box<dyn std::error::Error>> {
+        let headers = Arc::new(new_headers(10));
+        let handler: Handler = {
+            let headers = headers.clone();
+            Arc::new(move |method, params| {
+                let headers = headers.clone();
+                async move {
+                    match method.as_str() {
+                        "server.version" => Ok(json!([ELECTRUM_CLIENT_NAME, ELECTRUM_PROTOCOL_VERSION])),
+                        "blockchain.headers.subscribe" => Ok(json!({
+                            "height": headers.len() - 1,
+                            "hex": hex::encode(serialize(headers.last().unwrap())),
+                        })),
+                        "blockchain.block.headers" => {
+                            let start = params[0].as_u64().unwrap() as usize;
+                            let end = std::cmp::min(start + params[1].as_u64().unwrap() as usize, headers.len());
+                            let hex = headers[start..end]
+                                .iter()
+                                .map(|header| hex::encode(serialize(header)))
+                                .collect::<String>();
+                            Ok(json!({ "count": end - start, "hex": hex, "max": ELECTRUM_MAX_HEADERS }))
+                        }
+                        // the coinbase txid encodes the height of the block
+                        "blockchain.transaction.id_from_pos" => {
+                            Ok(json!(Txid::from_byte_array([params[0].as_u64().unwrap() as u8; 32])))
+                        }
+                        _ => Err("unexpected request".to_string()),
+                    }
+                }
+                .boxed()
+            })
+        };
+        let client = new_test_client(handler).await;
+
+        assert_eq!(
+            client.get_coinbase_txid(&headers[3].block_hash()).await?,
+            Txid::from_byte_array([3; 32])
+        );
+        assert!(matches!(
+            client.get_coinbase_txid(&BlockHash::all_zeros()).await,
+            Err(Error::UnknownBlockHash(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_send_concurrent_requests_over_separate_connections() -> Result<(), Box<dyn std::error::Error>> {
+        // neither request is answered until both have been received
+        let barrier = Arc::new(Barrier::new(2));
+        let handler: Handler = Arc::new(move |method, _| {
+            let barrier = barrier.clone();
+            async move {
+                match method.as_str() {
+                    "server.version" => Ok(json!([ELECTRUM_CLIENT_NAME, ELECTRUM_PROTOCOL_VERSION])),
+                    _ => {
+                        barrier.wait().await;
+                        Ok(json!(0.0001))
+                    }
+                }
+            }
+            .boxed()
+        });
+        let client = new_test_client(handler).await;
+
+        let (first, second) = futures::try_join!(client.estimate_fee_rate(1), client.estimate_fee_rate(2))?;
+        assert_eq!((first, second), (10.0, 10.0));
+        Ok(())
+    }
+}
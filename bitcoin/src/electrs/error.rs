@@ -1,11 +1,16 @@
+use crate::{BlockHash, Txid};
 use bitcoincore_rpc::bitcoin::{
     address::Error as BitcoinAddressError, consensus::encode::Error as BitcoinEncodeError,
     hashes::hex::Error as HexError,
 };
 use reqwest::{Error as ReqwestError, StatusCode};
 use serde_json::Error as SerdeJsonError;
-use std::num::{ParseIntError, TryFromIntError};
+use std::{
+    io::Error as IoError,
+    num::{ParseIntError, TryFromIntError},
+};
 use thiserror::Error;
+use tokio_native_tls::native_tls::Error as TlsError;
 use url::ParseError;
 
 #[derive(Error, Debug)]
@@ -38,10 +43,45 @@ pub enum Error {
 
     #[error("No txids in block")]
     EmptyBlock,
+
+    #[error("IoError: {0}")]
+    IoError(#[from] IoError),
+    #[error("TlsError: {0}")]
+    TlsError(#[from] TlsError),
+    #[error("Electrum server error: {0}")]
+    ElectrumError(String),
+    #[error("Invalid electrum url: {0}")]
+    InvalidElectrumUrl(String),
+    #[error("Not supported by the electrum protocol: {0}")]
+    Unsupported(&'static str),
+    #[error("Block not found")]
+    BlockNotFound,
+    #[error("Height of block {0} is unknown")]
+    UnknownBlockHash(BlockHash),
+    #[error("Transaction is not confirmed")]
+    TxNotConfirmed,
+    #[error("Merkle proof does not match the block header")]
+    InvalidMerkleProof,
+    #[error("Quorum of {quorum} is invalid for {endpoints} electrs endpoints")]
+    InvalidQuorum { quorum: usize, endpoints: usize },
+    #[error("Fewer than {0} electrs endpoints agree on the response")]
+    NoQuorum(usize),
+    #[error("Invalid peer url: {0}")]
+    InvalidPeerUrl(String),
+    #[error("Peer misbehaving: {0}")]
+    PeerMisbehaving(&'static str),
+    #[error("Transaction {0} not found")]
+    TxNotFound(Txid),
+    #[error("No fee estimate available")]
+    NoFeeEstimate,
 }
 
 impl Error {
     pub fn is_not_found(&self) -> bool {
-        matches!(self, Error::ReqwestError(err) if err.status().as_ref() == Some(&StatusCode::NOT_FOUND))
+        match self {
+            Error::ReqwestError(err) => err.status().as_ref() == Some(&StatusCode::NOT_FOUND),
+            Error::BlockNotFound | Error::TxNotFound(_) => true,
+            _ => false,
+        }
     }
 }
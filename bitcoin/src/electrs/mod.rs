@@ -1,18 +1,30 @@
+mod cache;
+mod cbf;
+mod electrum;
 mod error;
+mod multi;
+mod peer;
+mod rate_limit;
 mod types;
 
 use bitcoincore_rpc::bitcoin::ScriptBuf;
+pub use cache::{electrs_cache_stats, CachedElectrsClient, ElectrsCacheStats};
+pub use cbf::CompactFilterClient;
+pub use electrum::ElectrumClient;
 pub use error::Error;
+pub use multi::MultiElectrsClient;
 pub use types::*;
 
 use crate::{
     deserialize, opcodes, serialize, Address, Block, BlockHash, BlockHeader, Builder as ScriptBuilder, FromHex,
     Network, OutPoint, SignedAmount, Transaction, Txid, H256,
 };
+use async_trait::async_trait;
 use futures::future::{join_all, try_join};
+use rate_limit::RateLimiter;
 use reqwest::{Client, Url};
 use sha2::{Digest, Sha256};
-use std::{convert::TryFrom, str::FromStr};
+use std::{collections::HashMap, convert::TryFrom, num::NonZeroU32, str::FromStr, sync::Arc};
 
 // https://github.com/Blockstream/electrs/blob/adedee15f1fe460398a7045b292604df2161adc0/src/rest.rs#L42
 const ELECTRS_TRANSACTIONS_PER_PAGE: usize = 25;
@@ -25,6 +37,8 @@ const ELECTRS_LOCALHOST_URL: &str = "http://localhost:3002";
 pub struct Utxo {
     pub outpoint: OutPoint,
     pub value: u64,
+    /// Height of the block that includes the output, `None` if unconfirmed.
+    pub height: Option<u32>,
 }
 
 #[derive(Debug)]
@@ -35,12 +49,159 @@ pub struct TxInfo {
     pub fee: SignedAmount,
 }
 
+pub type DynElectrsApi = Arc<dyn ElectrsApi + Send + Sync>;
+
+/// Esplora only estimates some confirmation targets, use the largest one that is
+/// not above `target_blocks`, or the smallest one if all of them are.
+fn select_fee_estimate(fee_estimates: &HashMap<String, f64>, target_blocks: u16) -> Result<f64, Error> {
+    let fee_estimates = fee_estimates
+        .iter()
+        .map(|(target, fee_rate)| Ok((target.parse::<u16>()?, *fee_rate)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    fee_estimates
+        .iter()
+        .filter(|(target, _)| *target <= target_blocks)
+        .max_by_key(|(target, _)| *target)
+        .or_else(|| fee_estimates.iter().min_by_key(|(target, _)| *target))
+        .map(|(_, fee_rate)| *fee_rate)
+        .ok_or(Error::NoFeeEstimate)
+}
+
+fn new_endpoint(
+    electrs_url: Option<String>,
+    rate_limit: Option<NonZeroU32>,
+    network: Network,
+) -> Result<DynElectrsApi, Error> {
+    match electrs_url {
+        Some(electrs_url) if ElectrumClient::is_electrum_url(&electrs_url) => {
+            Ok(Arc::new(ElectrumClient::new(&electrs_url, network)?))
+        }
+        electrs_url => Ok(Arc::new(
+            ElectrsClient::new(electrs_url, network)?.set_rate_limit(rate_limit),
+        )),
+    }
+}
+
+/// Connect to the indexers at `electrs_urls`, `tcp://` and `ssl://` urls are
+/// served over the Electrum protocol, any other url uses the Esplora REST api.
+/// Multiple urls are combined into a [`MultiElectrsClient`] which requires
+/// `quorum` endpoints to agree on block hashes, headers and merkle proofs.
+/// Up to `cache_size` transactions and block headers are cached (0 disables
+/// the cache), and each Esplora server receives at most `rate_limit` requests
+/// per second.
+pub fn new_electrs_api(
+    electrs_urls: Vec<String>,
+    quorum: usize,
+    cache_size: usize,
+    rate_limit: Option<NonZeroU32>,
+    network: Network,
+) -> Result<DynElectrsApi, Error> {
+    let electrs_api = if electrs_urls.len() <= 1 && quorum <= 1 {
+        new_endpoint(electrs_urls.into_iter().next(), rate_limit, network)?
+    } else {
+        let endpoints = electrs_urls
+            .into_iter()
+            .map(|electrs_url| new_endpoint(Some(electrs_url), rate_limit, network))
+            .collect::<Result<_, _>>()?;
+        Arc::new(MultiElectrsClient::new(endpoints, quorum)?)
+    };
+    if cache_size == 0 {
+        return Ok(electrs_api);
+    }
+    Ok(Arc::new(CachedElectrsClient::new(electrs_api, cache_size)))
+}
+
+/// Queries answered by a blockchain indexer, either Esplora or an Electrum server.
+#[async_trait]
+pub trait ElectrsApi {
+    async fn get_raw_tx(&self, txid: &Txid) -> Result<Vec<u8>, Error>;
+
+    /// Returns the serialized `MerkleBlock` proving the inclusion of `txid`.
+    async fn get_raw_tx_merkle_proof(&self, txid: &Txid) -> Result<Vec<u8>, Error>;
+
+    /// Returns all confirmed transactions that send to or spend from `address`.
+    async fn get_address_tx_history_full(&self, address: &str) -> Result<Vec<TransactionValue>, Error>;
+
+    async fn get_blocks_tip_height(&self) -> Result<u32, Error>;
+
+    async fn get_blocks_tip_hash(&self) -> Result<BlockHash, Error>;
+
+    async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error>;
+
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error>;
+
+    async fn get_coinbase_txid(&self, block_hash: &BlockHash) -> Result<Txid, Error>;
+
+    async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error>;
+
+    async fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error>;
+
+    async fn is_in_mempool(&self, txid: &Txid) -> Result<bool, Error>;
+
+    async fn get_tx_info(&self, txid: &Txid) -> Result<TxInfo, Error>;
+
+    async fn get_utxos_for_address(&self, address: &Address) -> Result<Vec<Utxo>, Error>;
+
+    async fn get_script_pubkey(&self, outpoint: OutPoint) -> Result<ScriptBuf, Error>;
+
+    async fn get_prev_value(&self, outpoint: &OutPoint) -> Result<u64, Error>;
+
+    async fn send_transaction(&self, tx: Transaction) -> Result<Txid, Error>;
+
+    /// Returns the transactions that pay to the script with the (sha256) `script_hash`.
+    async fn get_txs_by_scripthash(&self, script_hash: Vec<u8>) -> Result<Vec<TransactionValue>, Error>;
+
+    /// Returns the fee rate in sat/vByte needed for confirmation within `target_blocks`.
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<f64, Error>;
+
+    /// Returns the *largest* payment to the `address` which is
+    /// greater than or equal to the specified `amount` and contains
+    /// an `OP_RETURN` output with `data`.
+    async fn get_tx_for_op_return(&self, address: Address, amount: u128, data: H256) -> Result<Option<Txid>, Error> {
+        let script = ScriptBuilder::new()
+            .push_opcode(opcodes::OP_RETURN)
+            .push_slice(&data.as_fixed_bytes())
+            .into_script();
+
+        let script_hash = {
+            let mut hasher = Sha256::default();
+            hasher.input(script.as_bytes());
+            hasher.result().as_slice().to_vec()
+        };
+
+        let txs = self.get_txs_by_scripthash(script_hash).await?;
+        log::info!("Found {} transactions", txs.len());
+
+        let address = address.to_string();
+        for tx in txs {
+            let largest = tx
+                .vout
+                .iter()
+                .filter_map(|vout| {
+                    if vout.scriptpubkey_address.as_ref() == Some(&address) {
+                        Some(vout.value)
+                    } else {
+                        None
+                    }
+                })
+                .max()
+                .unwrap_or_default();
+
+            if largest as u128 >= amount {
+                return Ok(Some(tx.txid));
+            }
+        }
+        Ok(None)
+    }
+}
+
 // NOTE: the `esplora_btc_api` OpenAPI lib build cannot decode plain strings
 // (using `serde_json::from_str`) and it doesn't support paged api calls
 #[derive(Clone)]
 pub struct ElectrsClient {
     url: Url,
     cli: Client,
+    rate_limiter: Option<RateLimiter>,
 }
 
 impl ElectrsClient {
@@ -57,11 +218,26 @@ impl ElectrsClient {
                 })
                 .parse()?,
             cli: Client::new(),
+            rate_limiter: None,
         })
     }
 
+    /// Limit the number of requests per second, e.g. to avoid being throttled by
+    /// a public Esplora instance.
+    pub fn set_rate_limit(mut self, requests_per_second: Option<NonZeroU32>) -> Self {
+        self.rate_limiter = requests_per_second.map(RateLimiter::new);
+        self
+    }
+
+    async fn wait_for_rate_limit(&self) {
+        if let Some(ref rate_limiter) = self.rate_limiter {
+            rate_limiter.wait().await;
+        }
+    }
+
     async fn get(&self, path: &str) -> Result<String, Error> {
         let url = self.url.join(path)?;
+        self.wait_for_rate_limit().await;
         Ok(self.cli.get(url).send().await?.error_for_status()?.text().await?)
     }
 
@@ -71,23 +247,37 @@ impl ElectrsClient {
         Ok(serde_json::from_str(&body)?)
     }
 
-    pub(crate) async fn get_tx_hex(&self, txid: &Txid) -> Result<String, Error> {
-        self.get(&format!("/tx/{txid}/hex")).await
-    }
-
-    pub(crate) async fn get_tx_merkle_block_proof(&self, txid: &Txid) -> Result<String, Error> {
-        self.get(&format!("/tx/{txid}/merkleblock-proof")).await
+    // TODO: this is expensive and not strictly required by the light-client, deprecate?
+    async fn get_transactions_in_block(&self, hash: &BlockHash) -> Result<Vec<Transaction>, Error> {
+        let raw_txids: Vec<String> = self.get_and_decode(&format!("/block/{hash}/txids")).await?;
+        let txids: Vec<Txid> = raw_txids
+            .iter()
+            .map(|txid| Txid::from_str(txid))
+            .collect::<Result<Vec<_>, _>>()?;
+        let txs = join_all(txids.iter().map(|txid| self.get_raw_tx(txid)))
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(|raw_tx| deserialize(&raw_tx))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(txs)
     }
+}
 
-    pub(crate) async fn get_raw_tx(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
-        Ok(Vec::<u8>::from_hex(&self.get_tx_hex(txid).await?)?)
+#[async_trait]
+impl ElectrsApi for ElectrsClient {
+    async fn get_raw_tx(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
+        let raw_tx: String = self.get(&format!("/tx/{txid}/hex")).await?;
+        Ok(Vec::<u8>::from_hex(&raw_tx)?)
     }
 
-    pub(crate) async fn get_raw_tx_merkle_proof(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
-        Ok(Vec::<u8>::from_hex(&self.get_tx_merkle_block_proof(txid).await?)?)
+    async fn get_raw_tx_merkle_proof(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
+        let raw_merkle_proof: String = self.get(&format!("/tx/{txid}/merkleblock-proof")).await?;
+        Ok(Vec::<u8>::from_hex(&raw_merkle_proof)?)
     }
 
-    pub async fn get_address_tx_history_full(&self, address: &str) -> Result<Vec<TransactionValue>, Error> {
+    async fn get_address_tx_history_full(&self, address: &str) -> Result<Vec<TransactionValue>, Error> {
         let mut last_seen_txid = Default::default();
         let mut ret = Vec::<TransactionValue>::new();
         loop {
@@ -105,38 +295,21 @@ impl ElectrsClient {
         Ok(ret)
     }
 
-    pub async fn get_blocks_tip_height(&self) -> Result<u32, Error> {
+    async fn get_blocks_tip_height(&self) -> Result<u32, Error> {
         Ok(self.get("/blocks/tip/height").await?.parse()?)
     }
 
-    pub(crate) async fn get_blocks_tip_hash(&self) -> Result<BlockHash, Error> {
+    async fn get_blocks_tip_hash(&self) -> Result<BlockHash, Error> {
         let response = self.get("/blocks/tip/hash").await?;
         Ok(BlockHash::from_str(&response)?)
     }
 
-    pub async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error> {
+    async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error> {
         let raw_block_header = Vec::<u8>::from_hex(&self.get(&format!("/block/{hash}/header")).await?)?;
         Ok(deserialize(&raw_block_header)?)
     }
 
-    // TODO: this is expensive and not strictly required by the light-client, deprecate?
-    pub(crate) async fn get_transactions_in_block(&self, hash: &BlockHash) -> Result<Vec<Transaction>, Error> {
-        let raw_txids: Vec<String> = self.get_and_decode(&format!("/block/{hash}/txids")).await?;
-        let txids: Vec<Txid> = raw_txids
-            .iter()
-            .map(|txid| Txid::from_str(txid))
-            .collect::<Result<Vec<_>, _>>()?;
-        let txs = join_all(txids.iter().map(|txid| self.get_raw_tx(txid)))
-            .await
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()?
-            .into_iter()
-            .map(|raw_tx| deserialize(&raw_tx))
-            .collect::<Result<Vec<_>, _>>()?;
-        Ok(txs)
-    }
-
-    pub(crate) async fn get_coinbase_txid(&self, block_hash: &BlockHash) -> Result<Txid, Error> {
+    async fn get_coinbase_txid(&self, block_hash: &BlockHash) -> Result<Txid, Error> {
         self.get_and_decode::<Vec<String>>(&format!("/block/{block_hash}/txids"))
             .await?
             .first()
@@ -144,17 +317,17 @@ impl ElectrsClient {
             .and_then(|raw_txid| Ok(Txid::from_str(raw_txid)?))
     }
 
-    pub(crate) async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
         let (header, txdata) = try_join(self.get_block_header(hash), self.get_transactions_in_block(hash)).await?;
         Ok(Block { header, txdata })
     }
 
-    pub async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error> {
+    async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error> {
         let response = self.get(&format!("/block-height/{height}")).await?;
         Ok(BlockHash::from_str(&response)?)
     }
 
-    pub(crate) async fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error> {
+    async fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error> {
         let txs: Vec<String> = self.get_and_decode("/mempool/txids").await?;
         Ok(txs
             .iter()
@@ -162,7 +335,12 @@ impl ElectrsClient {
             .collect::<Result<Vec<_>, _>>()?)
     }
 
-    pub(crate) async fn get_tx_info(&self, txid: &Txid) -> Result<TxInfo, Error> {
+    async fn is_in_mempool(&self, txid: &Txid) -> Result<bool, Error> {
+        let txids = self.get_raw_mempool().await?;
+        Ok(txids.into_iter().any(|mempool_txid| &mempool_txid == txid))
+    }
+
+    async fn get_tx_info(&self, txid: &Txid) -> Result<TxInfo, Error> {
         let tx: TransactionValue = self.get_and_decode(&format!("/tx/{txid}")).await?;
         let tip = self.get_blocks_tip_height().await?;
         let (height, hash) = match tx.status.map(|status| (status.block_height, status.block_hash)) {
@@ -177,7 +355,7 @@ impl ElectrsClient {
         })
     }
 
-    pub async fn get_utxos_for_address(&self, address: &Address) -> Result<Vec<Utxo>, Error> {
+    async fn get_utxos_for_address(&self, address: &Address) -> Result<Vec<Utxo>, Error> {
         let utxos: Vec<UtxoValue> = self.get_and_decode(&format!("/address/{address}/utxo")).await?;
         // NOTE: includes unconfirmed mempool txs
         utxos
@@ -189,12 +367,20 @@ impl ElectrsClient {
                         vout: utxo.vout,
                     },
                     value: utxo.value,
+                    height: match utxo.status {
+                        Some(TransactionStatus {
+                            confirmed: true,
+                            block_height: Some(height),
+                            ..
+                        }) => Some(u32::try_from(height)?),
+                        _ => None,
+                    },
                 })
             })
             .collect::<Result<Vec<_>, Error>>()
     }
 
-    pub(crate) async fn get_script_pubkey(&self, outpoint: OutPoint) -> Result<ScriptBuf, Error> {
+    async fn get_script_pubkey(&self, outpoint: OutPoint) -> Result<ScriptBuf, Error> {
         let tx: TransactionValue = self
             .get_and_decode(&format!("/tx/{txid}", txid = outpoint.txid))
             .await?;
@@ -206,7 +392,7 @@ impl ElectrsClient {
             .clone())
     }
 
-    pub(crate) async fn get_prev_value(&self, outpoint: &OutPoint) -> Result<u64, Error> {
+    async fn get_prev_value(&self, outpoint: &OutPoint) -> Result<u64, Error> {
         let tx: TransactionValue = self
             .get_and_decode(&format!("/tx/{txid}", txid = outpoint.txid))
             .await?;
@@ -219,8 +405,9 @@ impl ElectrsClient {
 
     // TODO: modify upstream to return a human-readable error
     // or maybe add an endpoint for `testmempoolaccept`
-    pub(crate) async fn send_transaction(&self, tx: Transaction) -> Result<Txid, Error> {
+    async fn send_transaction(&self, tx: Transaction) -> Result<Txid, Error> {
         let url = self.url.join("/tx")?;
+        self.wait_for_rate_limit().await;
         let txid = self
             .cli
             .post(url)
@@ -233,7 +420,7 @@ impl ElectrsClient {
         Ok(Txid::from_str(&txid)?)
     }
 
-    pub(crate) async fn get_txs_by_scripthash(&self, script_hash: Vec<u8>) -> Result<Vec<TransactionValue>, Error> {
+    async fn get_txs_by_scripthash(&self, script_hash: Vec<u8>) -> Result<Vec<TransactionValue>, Error> {
         let mut last_seen_txid = Default::default();
         let mut txs = Vec::<TransactionValue>::new();
         loop {
@@ -255,49 +442,36 @@ impl ElectrsClient {
         Ok(txs)
     }
 
-    /// Returns the *largest* payment to the `address` which is
-    /// greater than or equal to the specified `amount` and contains
-    /// an `OP_RETURN` output with `data`.
-    pub(crate) async fn get_tx_for_op_return(
-        &self,
-        address: Address,
-        amount: u128,
-        data: H256,
-    ) -> Result<Option<Txid>, Error> {
-        let script = ScriptBuilder::new()
-            .push_opcode(opcodes::OP_RETURN)
-            .push_slice(&data.as_fixed_bytes())
-            .into_script();
-
-        let script_hash = {
-            let mut hasher = Sha256::default();
-            hasher.input(script.as_bytes());
-            hasher.result().as_slice().to_vec()
-        };
-
-        let txs = self.get_txs_by_scripthash(script_hash).await?;
-        log::info!("Found {} transactions", txs.len());
-
-        let address = address.to_string();
-        for tx in txs {
-            let largest = tx
-                .vout
-                .iter()
-                .filter_map(|vout| {
-                    if vout.scriptpubkey_address.as_ref() == Some(&address) {
-                        Some(vout.value)
-                    } else {
-                        None
-                    }
-                })
-                .max()
-                .unwrap_or_default();
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<f64, Error> {
+        let fee_estimates: HashMap<String, f64> = self.get_and_decode("/fee-estimates").await?;
+        select_fee_estimate(&fee_estimates, target_blocks)
+    }
+}
 
-            if largest as u128 >= amount {
-                return Ok(Some(tx.txid));
-            }
-        }
-        Ok(None)
+#[cfg(test)]
+mockall::mock! {
+    pub Electrs {}
+
+    #[async_trait]
+    impl ElectrsApi for Electrs {
+        async fn get_raw_tx(&self, txid: &Txid) -> Result<Vec<u8>, Error>;
+        async fn get_raw_tx_merkle_proof(&self, txid: &Txid) -> Result<Vec<u8>, Error>;
+        async fn get_address_tx_history_full(&self, address: &str) -> Result<Vec<TransactionValue>, Error>;
+        async fn get_blocks_tip_height(&self) -> Result<u32, Error>;
+        async fn get_blocks_tip_hash(&self) -> Result<BlockHash, Error>;
+        async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error>;
+        async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error>;
+        async fn get_coinbase_txid(&self, block_hash: &BlockHash) -> Result<Txid, Error>;
+        async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error>;
+        async fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error>;
+        async fn is_in_mempool(&self, txid: &Txid) -> Result<bool, Error>;
+        async fn get_tx_info(&self, txid: &Txid) -> Result<TxInfo, Error>;
+        async fn get_utxos_for_address(&self, address: &Address) -> Result<Vec<Utxo>, Error>;
+        async fn get_script_pubkey(&self, outpoint: OutPoint) -> Result<ScriptBuf, Error>;
+        async fn get_prev_value(&self, outpoint: &OutPoint) -> Result<u64, Error>;
+        async fn send_transaction(&self, tx: Transaction) -> Result<Txid, Error>;
+        async fn get_txs_by_scripthash(&self, script_hash: Vec<u8>) -> Result<Vec<TransactionValue>, Error>;
+        async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<f64, Error>;
     }
 }
 
@@ -305,15 +479,44 @@ impl ElectrsClient {
 mod tests {
     use super::*;
 
+    use crate::{
+        testing::{EsploraServer, InMemoryChain},
+        TxOut,
+    };
     use bitcoincore_rpc::bitcoin::hashes::{hex::FromHex, sha256::Hash as Sha256Hash, Hash};
 
-    // TODO: mock the electrs endpoint
-    async fn test_electrs(url: &str, script_hex: &str, expected_txid: &str) {
+    #[test]
+    fn test_select_fee_estimate() {
+        let fee_estimates: HashMap<String, f64> =
+            serde_json::from_str(r#"{"1": 87.882, "2": 87.882, "3": 87.882, "6": 68.285, "144": 1.027}"#).unwrap();
+        assert_eq!(select_fee_estimate(&fee_estimates, 1).unwrap(), 87.882);
+        assert_eq!(select_fee_estimate(&fee_estimates, 10).unwrap(), 68.285);
+        assert_eq!(select_fee_estimate(&fee_estimates, 1008).unwrap(), 1.027);
+        // no estimate for the next block, use the fastest one
+        let fee_estimates = HashMap::from([("2".to_string(), 20.0), ("6".to_string(), 10.0)]);
+        assert_eq!(select_fee_estimate(&fee_estimates, 1).unwrap(), 20.0);
+        assert!(matches!(
+            select_fee_estimate(&HashMap::new(), 1),
+            Err(Error::NoFeeEstimate)
+        ));
+    }
+
+    /// Mine a transaction paying to `script_hex` and check that the electrs
+    /// client finds it by the script hash.
+    async fn test_electrs(script_hex: &str) {
+        let chain = InMemoryChain::new();
+        let server = EsploraServer::start(chain.clone()).unwrap();
         let script_bytes = Vec::from_hex(script_hex).unwrap();
         let script_hash = Sha256Hash::hash(&script_bytes);
-        let expected_txid = Txid::from_str(expected_txid).unwrap();
+        let expected_txid = chain
+            .send_external_transaction(vec![TxOut {
+                value: 0,
+                script_pubkey: ScriptBuf::from(script_bytes),
+            }])
+            .unwrap();
+        chain.mine_blocks(1);
 
-        let electrs_client = ElectrsClient::new(Some(url.to_owned()), Network::Bitcoin).unwrap();
+        let electrs_client = ElectrsClient::new(Some(server.url()), Network::Regtest).unwrap();
         let txs = electrs_client
             .get_txs_by_scripthash(script_hash.to_byte_array().to_vec())
             .await
@@ -322,23 +525,20 @@ mod tests {
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn test_find_esplora_mainnet() {
+    async fn test_find_esplora_witness_commitment() {
         let script_hex = "6a24aa21a9ed932d00baa7d428106db4f785d398d60d0b9c1369c38448717db4a8f36d2512e3";
-        let expected_txid = "d734d56c70ee7ac67d31a22f4b9a781619c5cff1803942b52036cd7eab1692e7";
-        test_electrs(ELECTRS_MAINNET_URL, script_hex, expected_txid).await;
+        test_electrs(script_hex).await;
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn test_find_esplora_testnet() {
+    async fn test_find_esplora_op_return() {
         let script_hex = "6a208b26f7cf49e1ad4d9f81d237933da8810644a85ac25b3c22a6a2324e1ba02efc";
-        let expected_txid = "ec736ccba2cb7d1a97145a7e98d32f8eec362cd140e917ce40842a492f43b49b";
-        test_electrs(ELECTRS_TESTNET_URL, script_hex, expected_txid).await;
+        test_electrs(script_hex).await;
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn test_find_esplora_testnet2() {
+    async fn test_find_esplora_op_return_pushdata() {
         let script_hex = "6a4c5054325b0f43c54432b8df76322d225c9759359f73b283e108441862c2ee6fe4a021f6825bee72311ec0f53dd7197d0e325dca9a45aa3af296294b42c667b6db214a5174001fe7f40004001f7a07000b02";
-        let expected_txid = "ddfaa4f63b9cbdf72299b91074fbff13b02816f2a29109b2fecfd912a7476807";
-        test_electrs(ELECTRS_TESTNET_URL, script_hex, expected_txid).await;
+        test_electrs(script_hex).await;
     }
 }
@@ -0,0 +1,386 @@
+use super::{DynElectrsApi, ElectrsApi, Error, TransactionValue, TxInfo, Utxo};
+use crate::{Address, Block, BlockHash, BlockHeader, OutPoint, Transaction, Txid};
+use async_trait::async_trait;
+use bitcoincore_rpc::bitcoin::ScriptBuf;
+use futures::{future::join_all, Future};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+// endpoints whose tip is further behind the best known tip are considered stale
+const MAX_TIP_LAG: u32 = 2;
+
+#[derive(Clone, Copy)]
+struct EndpointState {
+    healthy: bool,
+    stale: bool,
+}
+
+impl EndpointState {
+    fn is_available(&self) -> bool {
+        self.healthy && !self.stale
+    }
+}
+
+struct State {
+    preferred: usize,
+    endpoints: Vec<EndpointState>,
+}
+
+/// Spreads requests over multiple indexers: requests are sent to a healthy
+/// endpoint and fail over to the next one on errors or if its tip is stale.
+/// Block hashes, block headers and merkle proofs are only accepted once
+/// `quorum` endpoints agree on them.
+#[derive(Clone)]
+pub struct MultiElectrsClient {
+    endpoints: Vec<DynElectrsApi>,
+    quorum: usize,
+    state: Arc<Mutex<State>>,
+}
+
+impl MultiElectrsClient {
+    pub fn new(endpoints: Vec<DynElectrsApi>, quorum: usize) -> Result<Self, Error> {
+        if quorum == 0 || quorum > endpoints.len() {
+            return Err(Error::InvalidQuorum {
+                quorum,
+                endpoints: endpoints.len(),
+            });
+        }
+        let state = State {
+            preferred: 0,
+            endpoints: vec![
+                EndpointState {
+                    healthy: true,
+                    stale: false,
+                };
+                endpoints.len()
+            ],
+        };
+        Ok(Self {
+            endpoints,
+            quorum,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // the state only contains hints, so it's fine to ignore poisoning
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // available endpoints first, starting with the preferred one
+    fn get_order(&self) -> Vec<usize> {
+        let state = self.state();
+        let num_endpoints = self.endpoints.len();
+        let mut order: Vec<_> = (0..num_endpoints)
+            .map(|i| (state.preferred + i) % num_endpoints)
+            .collect();
+        order.sort_by_key(|&index| !state.endpoints[index].is_available());
+        order
+    }
+
+    fn set_healthy(&self, index: usize, healthy: bool) {
+        self.state().endpoints[index].healthy = healthy;
+    }
+
+    async fn with_failover<T, F, Fut>(&self, f: F) -> Result<T, Error>
+    where
+        F: Fn(DynElectrsApi) -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut not_found = None;
+        let mut last_error = None;
+        for index in self.get_order() {
+            match f(self.endpoints[index].clone()).await {
+                Ok(value) => {
+                    let mut state = self.state();
+                    state.endpoints[index].healthy = true;
+                    state.preferred = index;
+                    return Ok(value);
+                }
+                // not an endpoint failure, but the endpoint may be lagging behind the others
+                Err(err) if err.is_not_found() => {
+                    self.set_healthy(index, true);
+                    not_found = Some(err);
+                }
+                Err(err) => {
+                    log::warn!("Electrs endpoint {index} failed: {err}");
+                    self.set_healthy(index, false);
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(not_found.or(last_error).expect("there is at least one endpoint; qed"))
+    }
+
+    async fn with_quorum<T, F, Fut>(&self, f: F) -> Result<T, Error>
+    where
+        T: PartialEq,
+        F: Fn(DynElectrsApi) -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        if self.quorum <= 1 {
+            return self.with_failover(f).await;
+        }
+
+        let results = join_all(self.endpoints.iter().cloned().map(f)).await;
+        let mut responses: Vec<(T, usize)> = vec![];
+        let mut not_found = vec![];
+        for (index, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(value) => {
+                    self.set_healthy(index, true);
+                    match responses.iter_mut().find(|(response, _)| response == &value) {
+                        Some((_, count)) => *count += 1,
+                        None => responses.push((value, 1)),
+                    }
+                }
+                Err(err) if err.is_not_found() => not_found.push(err),
+                Err(err) => {
+                    log::warn!("Electrs endpoint {index} failed: {err}");
+                    self.set_healthy(index, false);
+                }
+            }
+        }
+
+        if let Some(index) = responses.iter().position(|(_, count)| *count >= self.quorum) {
+            Ok(responses.swap_remove(index).0)
+        } else if not_found.len() >= self.quorum {
+            Err(not_found.swap_remove(0))
+        } else {
+            Err(Error::NoQuorum(self.quorum))
+        }
+    }
+}
+
+#[async_trait]
+impl ElectrsApi for MultiElectrsClient {
+    async fn get_raw_tx(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
+        let txid = *txid;
+        self.with_failover(|api| async move { api.get_raw_tx(&txid).await })
+            .await
+    }
+
+    async fn get_raw_tx_merkle_proof(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
+        let txid = *txid;
+        self.with_quorum(|api| async move { api.get_raw_tx_merkle_proof(&txid).await })
+            .await
+    }
+
+    async fn get_address_tx_history_full(&self, address: &str) -> Result<Vec<TransactionValue>, Error> {
+        self.with_failover(|api| async move { api.get_address_tx_history_full(address).await })
+            .await
+    }
+
+    // also used to detect endpoints that fell behind
+    async fn get_blocks_tip_height(&self) -> Result<u32, Error> {
+        let mut results = join_all(self.endpoints.iter().map(|api| api.get_blocks_tip_height())).await;
+        let best_tip = results.iter().filter_map(|result| result.as_ref().ok()).max().copied();
+
+        let mut state = self.state();
+        for (index, result) in results.iter().enumerate() {
+            let endpoint = &mut state.endpoints[index];
+            match result {
+                Ok(tip) => {
+                    endpoint.healthy = true;
+                    endpoint.stale = best_tip.map_or(false, |best_tip| tip.saturating_add(MAX_TIP_LAG) < best_tip);
+                    if endpoint.stale {
+                        log::warn!("Electrs endpoint {index} is stale at height {tip}");
+                    }
+                }
+                Err(err) => {
+                    log::warn!("Electrs endpoint {index} failed: {err}");
+                    endpoint.healthy = false;
+                }
+            }
+        }
+
+        // only switch if the preferred endpoint is unavailable
+        if !state.endpoints[state.preferred].is_available() {
+            if let Some(index) = state.endpoints.iter().position(EndpointState::is_available) {
+                state.preferred = index;
+            }
+        }
+        // errors if all endpoints failed
+        results.swap_remove(state.preferred)
+    }
+
+    async fn get_blocks_tip_hash(&self) -> Result<BlockHash, Error> {
+        self.with_failover(|api| async move { api.get_blocks_tip_hash().await })
+            .await
+    }
+
+    async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error> {
+        let hash = *hash;
+        self.with_quorum(|api| async move { api.get_block_header(&hash).await })
+            .await
+    }
+
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        let hash = *hash;
+        self.with_failover(|api| async move { api.get_block(&hash).await })
+            .await
+    }
+
+    async fn get_coinbase_txid(&self, block_hash: &BlockHash) -> Result<Txid, Error> {
+        let block_hash = *block_hash;
+        self.with_failover(|api| async move { api.get_coinbase_txid(&block_hash).await })
+            .await
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error> {
+        self.with_quorum(|api| async move { api.get_block_hash(height).await })
+            .await
+    }
+
+    async fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error> {
+        self.with_failover(|api| async move { api.get_raw_mempool().await })
+            .await
+    }
+
+    async fn is_in_mempool(&self, txid: &Txid) -> Result<bool, Error> {
+        let txid = *txid;
+        self.with_failover(|api| async move { api.is_in_mempool(&txid).await })
+            .await
+    }
+
+    async fn get_tx_info(&self, txid: &Txid) -> Result<TxInfo, Error> {
+        let txid = *txid;
+        self.with_failover(|api| async move { api.get_tx_info(&txid).await })
+            .await
+    }
+
+    async fn get_utxos_for_address(&self, address: &Address) -> Result<Vec<Utxo>, Error> {
+        self.with_failover(|api| async move { api.get_utxos_for_address(address).await })
+            .await
+    }
+
+    async fn get_script_pubkey(&self, outpoint: OutPoint) -> Result<ScriptBuf, Error> {
+        self.with_failover(|api| async move { api.get_script_pubkey(outpoint).await })
+            .await
+    }
+
+    async fn get_prev_value(&self, outpoint: &OutPoint) -> Result<u64, Error> {
+        let outpoint = *outpoint;
+        self.with_failover(|api| async move { api.get_prev_value(&outpoint).await })
+            .await
+    }
+
+    async fn send_transaction(&self, tx: Transaction) -> Result<Txid, Error> {
+        self.with_failover(|api| {
+            let tx = tx.clone();
+            async move { api.send_transaction(tx).await }
+        })
+        .await
+    }
+
+    async fn get_txs_by_scripthash(&self, script_hash: Vec<u8>) -> Result<Vec<TransactionValue>, Error> {
+        self.with_failover(|api| {
+            let script_hash = script_hash.clone();
+            async move { api.get_txs_by_scripthash(script_hash).await }
+        })
+        .await
+    }
+
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<f64, Error> {
+        self.with_failover(|api| async move { api.estimate_fee_rate(target_blocks).await })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{electrs::MockElectrs, Hash};
+
+    fn dummy_hash(value: u8) -> BlockHash {
+        BlockHash::from_byte_array([value; 32])
+    }
+
+    #[tokio::test]
+    async fn should_fail_over_on_error() {
+        let mut failing = MockElectrs::default();
+        failing
+            .expect_get_block_hash()
+            .times(1)
+            .returning(|_| Err(Error::EmptyBlock));
+        let mut healthy = MockElectrs::default();
+        healthy
+            .expect_get_block_hash()
+            .times(2)
+            .returning(|_| Ok(dummy_hash(1)));
+
+        let client = MultiElectrsClient::new(vec![Arc::new(failing), Arc::new(healthy)], 1).unwrap();
+        assert_eq!(client.get_block_hash(1).await.unwrap(), dummy_hash(1));
+        // the failing endpoint is not retried first
+        assert_eq!(client.get_block_hash(1).await.unwrap(), dummy_hash(1));
+    }
+
+    #[tokio::test]
+    async fn should_try_other_endpoints_if_not_found() {
+        let mut lagging = MockElectrs::default();
+        lagging
+            .expect_get_block_hash()
+            .times(2)
+            .returning(|_| Err(Error::BlockNotFound));
+        let mut synced = MockElectrs::default();
+        synced
+            .expect_get_block_hash()
+            .times(2)
+            .returning(|height| match height {
+                1 => Ok(dummy_hash(1)),
+                _ => Err(Error::BlockNotFound),
+            });
+        let mut failing = MockElectrs::default();
+        failing.expect_get_block_hash().returning(|_| Err(Error::EmptyBlock));
+
+        let client = MultiElectrsClient::new(vec![Arc::new(lagging), Arc::new(synced), Arc::new(failing)], 1).unwrap();
+        assert_eq!(client.get_block_hash(1).await.unwrap(), dummy_hash(1));
+        // not found by any endpoint that responded
+        assert!(matches!(client.get_block_hash(2).await, Err(Error::BlockNotFound)));
+    }
+
+    #[tokio::test]
+    async fn should_skip_stale_endpoint() {
+        let mut stale = MockElectrs::default();
+        stale.expect_get_blocks_tip_height().returning(|| Ok(100));
+        stale.expect_get_blocks_tip_hash().times(0);
+        let mut synced = MockElectrs::default();
+        synced.expect_get_blocks_tip_height().returning(|| Ok(110));
+        synced
+            .expect_get_blocks_tip_hash()
+            .times(1)
+            .returning(|| Ok(dummy_hash(2)));
+
+        let client = MultiElectrsClient::new(vec![Arc::new(stale), Arc::new(synced)], 1).unwrap();
+        assert_eq!(client.get_blocks_tip_height().await.unwrap(), 110);
+        assert_eq!(client.get_blocks_tip_hash().await.unwrap(), dummy_hash(2));
+    }
+
+    #[tokio::test]
+    async fn should_require_quorum() {
+        let new_endpoint = |hash: BlockHash| {
+            let mut endpoint = MockElectrs::default();
+            endpoint.expect_get_block_hash().returning(move |_| Ok(hash));
+            Arc::new(endpoint) as DynElectrsApi
+        };
+
+        let client = MultiElectrsClient::new(
+            vec![
+                new_endpoint(dummy_hash(1)),
+                new_endpoint(dummy_hash(2)),
+                new_endpoint(dummy_hash(1)),
+            ],
+            2,
+        )
+        .unwrap();
+        assert_eq!(client.get_block_hash(1).await.unwrap(), dummy_hash(1));
+
+        let client =
+            MultiElectrsClient::new(vec![new_endpoint(dummy_hash(1)), new_endpoint(dummy_hash(2))], 2).unwrap();
+        assert!(matches!(client.get_block_hash(1).await, Err(Error::NoQuorum(2))));
+
+        assert!(matches!(
+            MultiElectrsClient::new(vec![new_endpoint(dummy_hash(1))], 2),
+            Err(Error::InvalidQuorum { .. })
+        ));
+    }
+}
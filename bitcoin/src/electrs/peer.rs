@@ -0,0 +1,142 @@
+use super::Error;
+use crate::{deserialize, serialize, BlockHash, Network};
+use bitcoincore_rpc::bitcoin::network::{
+    address::Address as PeerAddress,
+    constants::{Magic, ServiceFlags},
+    message::{NetworkMessage, RawNetworkMessage, MAX_MSG_SIZE},
+    message_network::VersionMessage,
+};
+use std::{
+    convert::TryFrom,
+    io::{Error as IoError, ErrorKind},
+    net::{Ipv4Addr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{timeout, Duration},
+};
+
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/version.h
+const PROTOCOL_VERSION: u32 = 70016;
+const USER_AGENT: &str = "/interbtc-clients/";
+// magic, command, length and checksum
+const HEADER_SIZE: usize = 24;
+const PEER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A P2P connection to a single bitcoin node.
+pub(super) struct Peer {
+    stream: TcpStream,
+    magic: Magic,
+}
+
+impl Peer {
+    /// Connect and perform the version handshake, fails if the
+    /// node doesn't serve compact block filters (`-peerblockfilters`).
+    pub(super) async fn connect(host: &str, port: u16, network: Network) -> Result<Self, Error> {
+        let stream = TcpStream::connect((host, port)).await?;
+        let receiver = stream.peer_addr()?;
+        let mut peer = Self {
+            stream,
+            magic: network.magic(),
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or_default();
+        let mut version = VersionMessage::new(
+            ServiceFlags::NONE,
+            timestamp,
+            PeerAddress::new(&receiver, ServiceFlags::NONE),
+            PeerAddress::new(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), ServiceFlags::NONE),
+            rand::random(),
+            USER_AGENT.to_string(),
+            0,
+        );
+        version.version = PROTOCOL_VERSION;
+        // announce new transactions, so that incoming payments are seen before they confirm
+        version.relay = true;
+        peer.send(NetworkMessage::Version(version)).await?;
+
+        let (mut has_version, mut has_verack) = (false, false);
+        while !(has_version && has_verack) {
+            match peer.receive().await? {
+                NetworkMessage::Version(version) => {
+                    if !version.services.has(ServiceFlags::COMPACT_FILTERS) {
+                        return Err(Error::PeerMisbehaving("peer does not serve compact block filters"));
+                    }
+                    peer.send(NetworkMessage::Verack).await?;
+                    has_version = true;
+                }
+                NetworkMessage::Verack => has_verack = true,
+                _ => {}
+            }
+        }
+
+        Ok(peer)
+    }
+
+    pub(super) async fn send(&mut self, payload: NetworkMessage) -> Result<(), Error> {
+        let message = RawNetworkMessage {
+            magic: self.magic,
+            payload,
+        };
+        self.stream.write_all(&serialize(&message)).await?;
+        Ok(())
+    }
+
+    async fn read_message(&mut self) -> Result<Vec<u8>, Error> {
+        let mut message = vec![0u8; HEADER_SIZE];
+        self.stream.read_exact(&mut message).await?;
+        let mut length = [0u8; 4];
+        length.copy_from_slice(&message[16..20]);
+        let length = usize::try_from(u32::from_le_bytes(length))?;
+        if length > MAX_MSG_SIZE {
+            return Err(IoError::from(ErrorKind::InvalidData).into());
+        }
+        message.resize(HEADER_SIZE + length, 0);
+        self.stream.read_exact(&mut message[HEADER_SIZE..]).await?;
+        Ok(message)
+    }
+
+    /// Receive the next message, pings are answered automatically.
+    pub(super) async fn receive(&mut self) -> Result<NetworkMessage, Error> {
+        loop {
+            let message = match timeout(PEER_TIMEOUT, self.read_message()).await {
+                Ok(message) => message?,
+                Err(_) => return Err(IoError::from(ErrorKind::TimedOut).into()),
+            };
+            let message: RawNetworkMessage = deserialize(&message)?;
+            if message.magic != self.magic {
+                return Err(Error::PeerMisbehaving("unexpected network magic"));
+            }
+            match message.payload {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce)).await?,
+                payload => return Ok(payload),
+            }
+        }
+    }
+}
+
+/// Block locator for `getheaders`, dense near the tip and exponentially sparser towards genesis.
+pub(super) fn get_locator(hashes: &[BlockHash]) -> Vec<BlockHash> {
+    let mut locator = vec![];
+    let mut index = hashes.len();
+    let mut step = 1;
+    while index > 0 {
+        index -= 1;
+        locator.push(hashes[index]);
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        index = index.saturating_sub(step - 1);
+    }
+    if let Some(genesis) = hashes.first() {
+        if locator.last() != Some(genesis) {
+            locator.push(*genesis);
+        }
+    }
+    locator
+}
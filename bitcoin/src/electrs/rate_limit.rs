@@ -0,0 +1,50 @@
+use std::{
+    num::NonZeroU32,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tokio::time::{sleep_until, Instant};
+
+/// Spaces out requests evenly, so that no more than the configured
+/// number of requests are started per second.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    interval: Duration,
+    next_slot: Arc<Mutex<Instant>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(requests_per_second: NonZeroU32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / requests_per_second.get(),
+            next_slot: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Waits until the next request may be sent.
+    pub(crate) async fn wait(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap_or_else(PoisonError::into_inner);
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+        sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn should_space_out_requests() {
+        let rate_limiter = RateLimiter::new(NonZeroU32::new(100).unwrap());
+        let start = Instant::now();
+        for _ in 0..5 {
+            rate_limiter.wait().await;
+        }
+        // the first request is sent immediately
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+}
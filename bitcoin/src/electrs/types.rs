@@ -1,9 +1,32 @@
-use crate::{BlockHash, Txid};
+use super::Error;
+use crate::{Address, BlockHash, BlockHeader, Network, Script, Transaction, TxOut, Txid};
 use bitcoincore_rpc::bitcoin::ScriptBuf;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+// https://github.com/Blockstream/electrs/blob/adedee15f1fe460398a7045b292604df2161adc0/src/util/script.rs
+fn get_script_type(script: &Script) -> &'static str {
+    if script.is_op_return() {
+        "op_return"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_v0_p2wpkh() {
+        "v0_p2wpkh"
+    } else if script.is_v0_p2wsh() {
+        "v0_p2wsh"
+    } else if script.is_v1_p2tr() {
+        "v1_p2tr"
+    } else {
+        "unknown"
+    }
+}
 
 // https://github.com/Blockstream/electrs/blob/adedee15f1fe460398a7045b292604df2161adc0/src/util/transaction.rs#L17-L26
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TransactionStatus {
     pub confirmed: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -14,8 +37,28 @@ pub struct TransactionStatus {
     pub block_time: Option<u32>,
 }
 
+impl TransactionStatus {
+    pub(crate) fn unconfirmed() -> Self {
+        Self {
+            confirmed: false,
+            block_height: None,
+            block_hash: None,
+            block_time: None,
+        }
+    }
+
+    pub(crate) fn confirmed(height: u32, header: &BlockHeader) -> Result<Self, Error> {
+        Ok(Self {
+            confirmed: true,
+            block_height: Some(usize::try_from(height)?),
+            block_hash: Some(header.block_hash()),
+            block_time: Some(header.time),
+        })
+    }
+}
+
 // https://github.com/Blockstream/electrs/blob/adedee15f1fe460398a7045b292604df2161adc0/src/rest.rs#L167-L189
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TxInValue {
     pub txid: Txid,
     pub vout: u32,
@@ -33,7 +76,7 @@ pub struct TxInValue {
 }
 
 // https://github.com/Blockstream/electrs/blob/adedee15f1fe460398a7045b292604df2161adc0/src/rest.rs#L239-L270
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TxOutValue {
     pub scriptpubkey: ScriptBuf,
     pub scriptpubkey_asm: String,
@@ -43,8 +86,22 @@ pub struct TxOutValue {
     pub value: u64,
 }
 
+impl TxOutValue {
+    pub(crate) fn new(tx_out: &TxOut, network: Network) -> Self {
+        Self {
+            scriptpubkey: tx_out.script_pubkey.clone(),
+            scriptpubkey_asm: tx_out.script_pubkey.to_asm_string(),
+            scriptpubkey_type: get_script_type(&tx_out.script_pubkey).to_string(),
+            scriptpubkey_address: Address::from_script(&tx_out.script_pubkey, network)
+                .ok()
+                .map(|address| address.to_string()),
+            value: tx_out.value,
+        }
+    }
+}
+
 // https://github.com/Blockstream/electrs/blob/adedee15f1fe460398a7045b292604df2161adc0/src/rest.rs#L115-L127
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TransactionValue {
     pub txid: Txid,
     pub version: u32,
@@ -58,11 +115,68 @@ pub struct TransactionValue {
     pub status: Option<TransactionStatus>,
 }
 
+impl TransactionValue {
+    /// Build the representation that esplora returns for `tx`, the `prevouts`
+    /// are `None` for coinbase inputs or if the spent output is unknown.
+    pub(crate) fn new(
+        tx: &Transaction,
+        prevouts: Vec<Option<TxOut>>,
+        status: TransactionStatus,
+        network: Network,
+    ) -> Result<Self, Error> {
+        let input_value = prevouts.iter().flatten().map(|prevout| prevout.value).sum::<u64>();
+        let output_value = tx.output.iter().map(|tx_out| tx_out.value).sum::<u64>();
+        // the fee is unknown without all prevouts
+        let fee = if prevouts.iter().all(Option::is_some) {
+            input_value.saturating_sub(output_value)
+        } else {
+            0
+        };
+        let vin = tx
+            .input
+            .iter()
+            .zip(prevouts)
+            .map(|(txin, prevout)| TxInValue {
+                txid: txin.previous_output.txid,
+                vout: txin.previous_output.vout,
+                prevout: prevout.map(|prevout| TxOutValue::new(&prevout, network)),
+                scriptsig: txin.script_sig.clone(),
+                scriptsig_asm: txin.script_sig.to_asm_string(),
+                witness: if txin.witness.is_empty() {
+                    None
+                } else {
+                    Some(txin.witness.iter().map(hex::encode).collect())
+                },
+                is_coinbase: txin.previous_output.is_null(),
+                sequence: txin.sequence.0,
+                inner_redeemscript_asm: None,
+                inner_witnessscript_asm: None,
+            })
+            .collect();
+
+        Ok(Self {
+            txid: tx.txid(),
+            version: tx.version as u32,
+            locktime: tx.lock_time.to_consensus_u32(),
+            vin,
+            vout: tx
+                .output
+                .iter()
+                .map(|tx_out| TxOutValue::new(tx_out, network))
+                .collect(),
+            size: u32::try_from(tx.size())?,
+            weight: u32::try_from(tx.weight().to_wu())?,
+            fee,
+            status: Some(status),
+        })
+    }
+}
+
 // https://github.com/Blockstream/electrs/blob/adedee15f1fe460398a7045b292604df2161adc0/src/rest.rs#L356-L396
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct UtxoValue {
     pub txid: Txid,
     pub vout: u32,
-//    pub status: TransactionStatus,
+    pub status: Option<TransactionStatus>,
     pub value: u64,
 }
@@ -1,10 +1,11 @@
-use crate::{BitcoinError, BitcoinLightError, ElectrsError};
+use crate::{BitcoinError, BitcoinLightError, ElectrsError, SignerError};
 use bitcoincore_rpc::{
     bitcoin::{
         address::Error as AddressError,
         consensus::encode::Error as BitcoinEncodeError,
         hashes::{hex::Error as HashHexError, Error as HashesError},
         key::Error as KeyError,
+        merkle_tree::MerkleBlockError,
         secp256k1::Error as Secp256k1Error,
     },
     jsonrpc::{error::RpcError, Error as JsonRpcError},
@@ -14,6 +15,7 @@ use serde_json::Error as SerdeJsonError;
 use std::{io::Error as IoError, num::TryFromIntError, string::FromUtf8Error};
 use thiserror::Error;
 use tokio::time::error::Elapsed;
+use zeromq::ZmqError;
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
@@ -26,6 +28,22 @@ pub enum KeyLoadingError {
     KeyError(#[from] KeyError),
 }
 
+#[derive(Error, Debug)]
+pub enum ProofError {
+    #[error("MerkleBlockError: {0}")]
+    MerkleBlockError(#[from] MerkleBlockError),
+    #[error("Merkle proof does not match the merkle root of the block header")]
+    MerkleRootMismatch,
+    #[error("Transaction is not included in the merkle proof")]
+    TransactionNotIncluded,
+    #[error("Transaction and coinbase proofs are for different blocks")]
+    BlockMismatch,
+    #[error("Coinbase proof is not for the first transaction of the block")]
+    InvalidCoinbase,
+    #[error("Block header has invalid proof of work")]
+    InvalidProofOfWork,
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("BitcoinEncodeError: {0}")]
@@ -50,6 +68,12 @@ pub enum Error {
     ElectrsError(#[from] ElectrsError),
     #[error("KeyLoadingError: {0}")]
     KeyLoadingError(#[from] KeyLoadingError),
+    #[error("ProofError: {0}")]
+    ProofError(#[from] ProofError),
+    #[error("SignerError: {0}")]
+    SignerError(#[from] SignerError),
+    #[error("ZmqError: {0}")]
+    ZmqError(#[from] ZmqError),
 
     #[error("Connected to incompatible bitcoin core version: {0}")]
     IncompatibleVersion(usize),
@@ -61,6 +85,12 @@ pub enum Error {
     TransactionSigningError,
     #[error("Failed to obtain public key")]
     MissingPublicKey,
+    #[error("Failed to obtain private key")]
+    MissingPrivateKey,
+    #[error("Failed to import descriptor: {0}")]
+    DescriptorImportError(String),
+    #[error("Wallet is not a descriptor wallet")]
+    NotDescriptorWallet,
     #[error("Failed to connect")]
     ConnectionRefused,
     #[error("Wallet not found")]
@@ -69,6 +99,12 @@ pub enum Error {
     InvalidBitcoinNetwork,
     #[error("Transaction contains more than one return-to-self utxo")]
     TooManyReturnToSelfAddresses,
+    #[error("Transaction has no return-to-self utxo")]
+    NoReturnToSelfOutput,
+    #[error("Cannot spend the return-to-self utxo in a child transaction")]
+    UnsupportedReturnToSelfOutput,
+    #[error("Return-to-self utxo is too small to pay for its parent")]
+    CannotPayForParent,
     #[error("ArithmeticError")]
     ArithmeticError,
     #[error("MissingBitcoinFeeInfo")]
@@ -79,6 +115,10 @@ pub enum Error {
     AddressError(#[from] AddressError),
     #[error("Failed to fetch coinbase tx")]
     CoinbaseFetchingFailure,
+    #[error("No passphrase or key file set for the light client key store")]
+    MissingKeyStorePassphrase,
+    #[error("Invalid zmq notification")]
+    InvalidZmqMessage,
 }
 
 impl Error {
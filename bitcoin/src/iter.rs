@@ -6,10 +6,26 @@ use bitcoincore_rpc::{
 };
 use futures::{prelude::*, stream::StreamExt};
 use log::trace;
-use std::{iter, sync::Arc};
+use std::{collections::VecDeque, iter, sync::Arc};
 
 type DynBitcoinCoreApi = Arc<dyn BitcoinCoreApi + Send + Sync>;
 
+/// Number of streamed blocks that are remembered to detect reorgs.
+const MAX_REORG_DEPTH: usize = 100;
+
+/// Number of blocks fetched at once when streaming in reverse.
+const REVERSE_BATCH_SIZE: u32 = 10;
+
+/// Item of the (forward) block and transaction streams.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainEvent<T> {
+    /// `T` was found in the block at the given height of the best chain.
+    Connected(u32, T),
+    /// The previously streamed blocks from `from_height` onwards are no longer part of the
+    /// best chain. The stream continues with the new branch at `from_height`.
+    Reorg { from_height: u32 },
+}
+
 /// Stream over transactions, starting with this in the mempool and continuing with
 /// transactions from previous in-chain block. The stream ends after the block at
 /// `stop_height` has been returned.
@@ -69,6 +85,10 @@ async fn reverse_stream_blocks(
     struct StreamState<B> {
         height: Option<u32>,
         prev_block: Option<Block>,
+        // blocks below `prev_block` that were fetched ahead of time
+        prefetched: VecDeque<(BlockHash, Block)>,
+        // cleared when a batch fails, e.g. because it contains a pruned block
+        batching: bool,
         rpc: B,
         stop_height: u32,
     }
@@ -76,6 +96,8 @@ async fn reverse_stream_blocks(
     let state = StreamState {
         height: None,
         prev_block: None,
+        prefetched: VecDeque::new(),
+        batching: true,
         rpc,
         stop_height,
     };
@@ -91,21 +113,41 @@ async fn reverse_stream_blocks(
                 },
             };
 
-            let result = if next_height < state.stop_height {
+            if next_height < state.stop_height {
                 return None;
-            } else {
-                match state.rpc.get_block(&next_hash).await {
-                    Ok(block) => {
-                        state.height = Some(next_height);
-                        state.prev_block = Some(block.clone());
-                        Ok(block)
+            }
+
+            if state.prefetched.is_empty() && state.batching {
+                match prefetch_blocks(state.rpc, next_height, next_hash, state.stop_height).await {
+                    Ok(blocks) => state.prefetched = blocks,
+                    Err(err) => {
+                        trace!("failed to prefetch blocks: {}", err);
+                        state.batching = false;
                     }
-                    Err(Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(err))))
-                        if BitcoinRpcError::from(err.clone()) == BitcoinRpcError::RpcMiscError =>
-                    {
-                        return None; // pruned block
+                }
+            }
+
+            let result = match state.prefetched.pop_front() {
+                Some((hash, block)) if hash == next_hash => {
+                    state.height = Some(next_height);
+                    state.prev_block = Some(block.clone());
+                    Ok(block)
+                }
+                _ => {
+                    state.prefetched.clear();
+                    match state.rpc.get_block(&next_hash).await {
+                        Ok(block) => {
+                            state.height = Some(next_height);
+                            state.prev_block = Some(block.clone());
+                            Ok(block)
+                        }
+                        Err(Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(err))))
+                            if BitcoinRpcError::from(err.clone()) == BitcoinRpcError::RpcMiscError =>
+                        {
+                            return None; // pruned block
+                        }
+                        Err(e) => Err(e),
                     }
-                    Err(e) => Err(e),
                 }
             };
             Some((result, state))
@@ -114,7 +156,7 @@ async fn reverse_stream_blocks(
     )
 }
 
-/// Stream all transactions in blocks produced by Bitcoin Core.
+/// Stream all transactions in blocks produced by Bitcoin Core, see `stream_blocks`.
 ///
 /// # Arguments:
 ///
@@ -125,24 +167,31 @@ pub async fn stream_in_chain_transactions(
     rpc: DynBitcoinCoreApi,
     from_height: u32,
     num_confirmations: u32,
-) -> impl Stream<Item = Result<(BlockHash, Transaction), Error>> + Unpin {
+) -> impl Stream<Item = Result<ChainEvent<(BlockHash, Transaction)>, Error>> + Unpin {
     Box::pin(
         stream_blocks(rpc, from_height, num_confirmations)
             .await
             .flat_map(|result| {
-                futures::stream::iter(result.map_or_else(
-                    |err| vec![Err(err)],
-                    |block| {
+                futures::stream::iter(match result {
+                    Ok(ChainEvent::Connected(height, block)) => {
                         let block_hash = block.block_hash();
-                        block.txdata.into_iter().map(|tx| Ok((block_hash, tx))).collect()
-                    },
-                ))
+                        block
+                            .txdata
+                            .into_iter()
+                            .map(|tx| Ok(ChainEvent::Connected(height, (block_hash, tx))))
+                            .collect()
+                    }
+                    Ok(ChainEvent::Reorg { from_height }) => vec![Ok(ChainEvent::Reorg { from_height })],
+                    Err(err) => vec![Err(err)],
+                })
             }),
     )
 }
 
 /// Stream blocks continuously `from_height` awaiting the production of
-/// new blocks as reported by Bitcoin core. The stream never ends.
+/// new blocks as reported by Bitcoin core. The stream never ends. If a
+/// streamed block is orphaned, a `ChainEvent::Reorg` is emitted and the
+/// blocks of the new branch are streamed from the fork onwards.
 ///
 /// # Arguments:
 ///
@@ -153,34 +202,96 @@ pub async fn stream_blocks(
     rpc: DynBitcoinCoreApi,
     from_height: u32,
     num_confirmations: u32,
-) -> impl Stream<Item = Result<Block, Error>> + Unpin {
-    struct StreamState<B> {
-        rpc: B,
-        next_height: u32,
-    }
-
-    let state = StreamState {
+) -> impl Stream<Item = Result<ChainEvent<Block>, Error>> + Unpin {
+    let state = BlockStreamState {
         rpc,
         next_height: from_height,
+        streamed: VecDeque::new(),
     };
 
     Box::pin(
         stream::unfold(state, move |mut state| async move {
-            // FIXME: if Bitcoin Core forks, this may skip a block
             let height = state.next_height;
-            match state.rpc.wait_for_block(height, num_confirmations).await {
-                Ok(block) => {
-                    trace!("found block {} at height {}", block.block_hash(), height);
-                    state.next_height += 1;
-                    Some((Ok(block), state))
-                }
-                Err(e) => Some((Err(e), state)),
+            let block = match state.rpc.wait_for_block(height, num_confirmations).await {
+                Ok(block) => block,
+                Err(e) => return Some((Err(e), state)),
+            };
+            if matches!(state.streamed.back(), Some(tip) if *tip != block.header.prev_blockhash) {
+                let result = state.rewind_to_fork().await;
+                return Some((result.map(|from_height| ChainEvent::Reorg { from_height }), state));
+            }
+
+            trace!("found block {} at height {}", block.block_hash(), height);
+            state.streamed.push_back(block.block_hash());
+            if state.streamed.len() > MAX_REORG_DEPTH {
+                state.streamed.pop_front();
             }
+            state.next_height += 1;
+            Some((Ok(ChainEvent::Connected(height, block)), state))
         })
         .fuse(),
     )
 }
 
+struct BlockStreamState {
+    rpc: DynBitcoinCoreApi,
+    next_height: u32,
+    // hashes of the most recently streamed blocks, the last one at `next_height - 1`
+    streamed: VecDeque<BlockHash>,
+}
+
+impl BlockStreamState {
+    /// Forget the streamed blocks that are no longer part of the best chain, returns the
+    /// height of the first of them. If the reorg is deeper than `MAX_REORG_DEPTH`, this is
+    /// the height of the oldest block we remember.
+    async fn rewind_to_fork(&mut self) -> Result<u32, Error> {
+        while let Some(hash) = self.streamed.back() {
+            let height = self.next_height - 1;
+            match self.rpc.get_block_hash(height).await {
+                Ok(best_hash) if best_hash == *hash => break,
+                Ok(_) | Err(Error::InvalidBitcoinHeight) => {
+                    self.streamed.pop_back();
+                    self.next_height = height;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self.next_height)
+    }
+}
+
+/// Fetch the blocks from `height` down to (at most `REVERSE_BATCH_SIZE` blocks, but not below)
+/// `stop_height` in a single batch, starting with the block with the given `hash`. Only the
+/// blocks that are linked to their successor are returned, so that a concurrent reorg does not
+/// mix up blocks of different branches.
+async fn prefetch_blocks(
+    rpc: &DynBitcoinCoreApi,
+    height: u32,
+    hash: BlockHash,
+    stop_height: u32,
+) -> Result<VecDeque<(BlockHash, Block)>, Error> {
+    let start_height = height.saturating_sub(REVERSE_BATCH_SIZE - 1).max(stop_height);
+    let mut hashes = rpc.get_block_hashes(start_height..height + 1).await?;
+    hashes.reverse();
+    if hashes.first() != Some(&hash) {
+        return Ok(VecDeque::new());
+    }
+
+    let blocks = rpc.get_blocks(&hashes).await?;
+    let mut prefetched: VecDeque<(BlockHash, Block)> = VecDeque::with_capacity(blocks.len());
+    for (hash, block) in hashes.into_iter().zip(blocks) {
+        let is_linked = match prefetched.back() {
+            Some((_, successor)) => successor.header.prev_blockhash == hash,
+            None => true,
+        };
+        if !is_linked {
+            break;
+        }
+        prefetched.push_back((hash, block));
+    }
+    Ok(prefetched)
+}
+
 /// small helper function for getting the block info of the best block. This simplifies
 /// error handling a little bit
 async fn get_best_block_info(rpc: &DynBitcoinCoreApi) -> Result<(u32, BlockHash), Error> {
@@ -205,8 +316,8 @@ mod tests {
             fn is_full_node(&self) -> bool;
             fn network(&self) -> Network;
             async fn wait_for_block(&self, height: u32, num_confirmations: u32) -> Result<Block, Error>;
-            fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, Error>;
-            fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, Error>;
+            async fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, Error>;
+            async fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, Error>;
             async fn get_block_count(&self) -> Result<u64, Error>;
             async fn get_raw_tx(&self, txid: &Txid, block_hash: &BlockHash) -> Result<Vec<u8>, Error>;
             async fn get_transaction(&self, txid: &Txid, block_hash: Option<BlockHash>) -> Result<Transaction, Error>;
@@ -256,15 +367,23 @@ mod tests {
                 &self,
                 addresses: Vec<Address>,
             ) -> Result<(), Error>;
-            fn get_utxo_count(&self) -> Result<usize, Error>;
+            async fn get_utxo_count(&self) -> Result<usize, Error>;
+            async fn consolidate_utxos(&self, max_inputs: usize, max_utxo_value: u64, fee_rate: SatPerVbyte) -> Result<Option<Txid>, Error>;
             async fn bump_fee(
                 &self,
                 txid: &Txid,
                 address: Address,
                 fee_rate: SatPerVbyte,
             ) -> Result<Txid, Error>;
+            async fn bump_fee_with_child(
+                &self,
+                txid: &Txid,
+                address: Address,
+                fee_rate: SatPerVbyte,
+            ) -> Result<Txid, Error>;
             async fn is_in_mempool(&self, txid: Txid) -> Result<bool, Error>;
             async fn fee_rate(&self, txid: Txid) -> Result<SatPerVbyte, Error>;
+            async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<SatPerVbyte, Error>;
             async fn get_tx_for_op_return(&self, address: Address, amount: u128, data: H256) -> Result<Option<Txid>, Error>;
         }
     }
@@ -326,8 +445,7 @@ mod tests {
         bitcoin.expect_get_block_count().times(1).returning(|| Ok(21));
         bitcoin
             .expect_get_block_hash()
-            .times(1)
-            .returning(|_| Ok(dummy_hash(1)));
+            .returning(|height| Ok(dummy_hash((21 - height + 1) as u8)));
 
         let btc_rpc: DynBitcoinCoreApi = Arc::new(bitcoin);
         let mut iter = reverse_stream_transactions(&btc_rpc, 20).await.unwrap();
@@ -373,8 +491,7 @@ mod tests {
         bitcoin.expect_get_block_count().times(1).returning(|| Ok(23));
         bitcoin
             .expect_get_block_hash()
-            .times(1)
-            .returning(|_| Ok(dummy_hash(1)));
+            .returning(|height| Ok(dummy_hash((23 - height + 1) as u8)));
 
         let btc_rpc: DynBitcoinCoreApi = Arc::new(bitcoin);
         let mut iter = reverse_stream_transactions(&btc_rpc, 20).await.unwrap();
@@ -397,8 +514,7 @@ mod tests {
         bitcoin.expect_get_block_count().times(1).returning(|| Ok(20));
         bitcoin
             .expect_get_block_hash()
-            .times(1)
-            .returning(|_| Ok(dummy_hash(1)));
+            .returning(|height| Ok(dummy_hash((20 - height + 1) as u8)));
 
         let btc_rpc: DynBitcoinCoreApi = Arc::new(bitcoin);
         let mut iter = reverse_stream_transactions(&btc_rpc, 21).await.unwrap();
@@ -417,8 +533,7 @@ mod tests {
         bitcoin.expect_get_block_count().times(1).returning(|| Ok(20));
         bitcoin
             .expect_get_block_hash()
-            .times(1)
-            .returning(|_| Ok(dummy_hash(1)));
+            .returning(|height| Ok(dummy_hash((20 - height + 1) as u8)));
 
         let btc_rpc: DynBitcoinCoreApi = Arc::new(bitcoin);
         let mut iter = reverse_stream_transactions(&btc_rpc, 21).await.unwrap();
@@ -444,13 +559,107 @@ mod tests {
         bitcoin.expect_get_block_count().times(1).returning(|| Ok(20));
         bitcoin
             .expect_get_block_hash()
+            .returning(|height| Ok(dummy_hash((20 - height + 1) as u8)));
+
+        let btc_rpc: DynBitcoinCoreApi = Arc::new(bitcoin);
+        let mut iter = reverse_stream_transactions(&btc_rpc, 20).await.unwrap();
+
+        assert_eq!(iter.next().await.unwrap().unwrap().version, 1);
+        assert!(iter.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_iterator_stops_at_pruned_block() {
+        let mut bitcoin = MockBitcoin::default();
+        bitcoin
+            .expect_get_mempool_transactions()
             .times(1)
-            .returning(|_| Ok(dummy_hash(1)));
+            .returning(|| Ok(Box::new(vec![].into_iter())));
+        bitcoin
+            .expect_get_block()
+            .withf(|&x| x == dummy_hash(1))
+            .returning(|_| Ok(dummy_block(vec![1], dummy_hash(2))));
+        bitcoin
+            .expect_get_block()
+            .withf(|&x| x == dummy_hash(2))
+            .returning(|_| Ok(dummy_block(vec![2], dummy_hash(3))));
+        // the batch fails, so the blocks are fetched one by one until the pruned block
+        bitcoin
+            .expect_get_block()
+            .withf(|&x| x == dummy_hash(3))
+            .returning(|_| {
+                Err(Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(
+                    RpcError {
+                        code: BitcoinRpcError::RpcMiscError as i32,
+                        message: "Block not available (pruned data)".to_string(),
+                        data: None,
+                    },
+                ))))
+            });
+        bitcoin.expect_get_block_count().times(1).returning(|| Ok(22));
+        bitcoin
+            .expect_get_block_hash()
+            .returning(|height| Ok(dummy_hash((22 - height + 1) as u8)));
 
         let btc_rpc: DynBitcoinCoreApi = Arc::new(bitcoin);
         let mut iter = reverse_stream_transactions(&btc_rpc, 20).await.unwrap();
 
         assert_eq!(iter.next().await.unwrap().unwrap().version, 1);
+        assert_eq!(iter.next().await.unwrap().unwrap().version, 2);
         assert!(iter.next().await.is_none());
     }
+
+    #[tokio::test]
+    async fn test_block_stream_detects_reorg() {
+        let block_a = dummy_block(vec![1], dummy_hash(0));
+        let block_b = dummy_block(vec![2], block_a.block_hash());
+        // competing block at the height of `block_b`
+        let mut block_b2 = dummy_block(vec![3], block_a.block_hash());
+        block_b2.header.nonce = 1;
+        let block_c2 = dummy_block(vec![4], block_b2.block_hash());
+
+        let mut bitcoin = MockBitcoin::default();
+        let blocks = std::sync::Mutex::new(VecDeque::from(vec![
+            (10, block_a.clone()),
+            (11, block_b.clone()),
+            (12, block_c2.clone()),
+            (11, block_b2.clone()),
+            (12, block_c2.clone()),
+        ]));
+        bitcoin.expect_wait_for_block().returning(move |height, _| {
+            let (expected_height, block) = blocks.lock().unwrap().pop_front().unwrap();
+            assert_eq!(height, expected_height);
+            Ok(block)
+        });
+        let (hash_a, hash_b2) = (block_a.block_hash(), block_b2.block_hash());
+        bitcoin.expect_get_block_hash().returning(move |height| match height {
+            10 => Ok(hash_a),
+            11 => Ok(hash_b2),
+            _ => Err(Error::InvalidBitcoinHeight),
+        });
+
+        let btc_rpc: DynBitcoinCoreApi = Arc::new(bitcoin);
+        let mut stream = stream_blocks(btc_rpc, 10, 1).await;
+
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            ChainEvent::Connected(10, block_a)
+        );
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            ChainEvent::Connected(11, block_b)
+        );
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            ChainEvent::Reorg { from_height: 11 }
+        );
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            ChainEvent::Connected(11, block_b2)
+        );
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            ChainEvent::Connected(12, block_c2)
+        );
+    }
 }
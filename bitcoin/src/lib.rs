@@ -2,14 +2,21 @@
 
 pub mod cli;
 pub mod light;
+pub mod signer;
+#[cfg(any(test, feature = "testing-utils"))]
+pub mod testing;
 
-pub use light::{BitcoinLight, Error as BitcoinLightError};
+pub use light::{BitcoinCompactFilter, BitcoinLight, Error as BitcoinLightError};
 
 mod addr;
+mod descriptor;
 mod electrs;
 mod error;
 mod iter;
+mod proof;
+mod zmq;
 
+pub use addr::calculate_deposit_secret_key;
 use async_trait::async_trait;
 use backoff::{backoff::Backoff, future::retry, ExponentialBackoff};
 pub use bitcoincore_rpc::{
@@ -36,29 +43,40 @@ pub use bitcoincore_rpc::{
     },
     bitcoincore_rpc_json::{
         CreateRawTransactionInput, FundRawTransactionOptions, GetBlockchainInfoResult, GetRawTransactionResult,
-        GetTransactionResult, GetTransactionResultDetailCategory, WalletTxInfo,
+        GetTransactionResult, GetTransactionResultDetailCategory, ImportMultiResult, Timestamp, WalletTxInfo,
     },
     json::{self, AddressType, GetBlockResult},
     jsonrpc::{self, error::RpcError, Error as JsonRpcError},
     Auth, Client, Error as BitcoinError, RpcApi,
 };
 use bitcoincore_rpc::{bitcoin::consensus::encode::serialize_hex, bitcoincore_rpc_json::ScanningDetails};
-pub use electrs::{ElectrsClient, Error as ElectrsError};
-pub use error::{BitcoinRpcError, ConversionError, Error};
-pub use iter::{reverse_stream_transactions, stream_blocks, stream_in_chain_transactions};
+pub use descriptor::{descriptor_checksum, ImportDescriptorRequest};
+pub use electrs::{
+    electrs_cache_stats, new_electrs_api, CachedElectrsClient, CompactFilterClient, DynElectrsApi, ElectrsApi,
+    ElectrsCacheStats, ElectrsClient, ElectrumClient, Error as ElectrsError, MultiElectrsClient,
+};
+pub use error::{BitcoinRpcError, ConversionError, Error, ProofError};
+pub use iter::{reverse_stream_transactions, stream_blocks, stream_in_chain_transactions, ChainEvent};
 use log::{info, trace, warn};
-use serde_json::error::Category as SerdeJsonCategory;
+pub use proof::verify_transaction_proof;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{error::Category as SerdeJsonCategory, Value as JsonValue};
+pub use signer::{new_external_signer, DynExternalSigner, Error as SignerError, ExternalSigner};
 pub use sp_core::H256;
 use std::{
+    collections::{HashMap, HashSet},
     convert::TryInto,
     future::Future,
-    sync::Arc,
+    num::NonZeroU32,
+    ops::Range,
+    sync::{Arc, PoisonError, RwLock},
     time::{Duration, Instant},
 };
 use tokio::{
     sync::{Mutex, OwnedMutexGuard},
     time::{sleep, timeout},
 };
+use zmq::{BlockWaiter, ZmqNotifier};
 
 #[macro_use]
 extern crate num_derive;
@@ -75,6 +93,12 @@ pub const DEFAULT_MAX_TX_COUNT: usize = 100_000_000;
 pub const BITCOIN_CORE_VERSION_23: usize = 230_000;
 const NOT_IN_MEMPOOL_ERROR_CODE: i32 = BitcoinRpcError::RpcInvalidAddressOrKey as i32;
 
+// Maximum number of requests sent to bitcoind in a single JSON-RPC batch.
+const MAX_RPC_BATCH_SIZE: usize = 100;
+
+// Maximum number of full blocks requested in a single JSON-RPC batch.
+const MAX_BLOCK_BATCH_SIZE: usize = 10;
+
 // Time to sleep before retry on startup.
 const RETRY_DURATION: Duration = Duration::from_millis(1000);
 
@@ -122,6 +146,31 @@ pub struct RawTransactionProof {
 #[derive(PartialEq, Eq, PartialOrd, Clone, Copy, Debug)]
 pub struct SatPerVbyte(pub u64);
 
+/// Fee that a child of `child_vsize` needs to pay so that it and its parent
+/// together pay `fee_rate`. The child always pays at least for itself.
+pub(crate) fn get_child_fee(parent_fee: u64, parent_vsize: u64, child_vsize: u64, fee_rate: SatPerVbyte) -> u64 {
+    let package_fee = fee_rate.0.saturating_mul(parent_vsize.saturating_add(child_vsize));
+    package_fee
+        .saturating_sub(parent_fee)
+        .max(fee_rate.0.saturating_mul(child_vsize))
+}
+
+/// Upper bound on the vsize of the unsigned `child` once its single input,
+/// which spends `script_pubkey`, is signed.
+pub(crate) fn get_max_child_vsize(script_pubkey: &Script, child: &Transaction) -> Result<u64, Error> {
+    // number of items, then the length prefixed signature (and public key)
+    let witness_size = if script_pubkey.is_v0_p2wpkh() {
+        1 + 1 + 72 + 1 + 33
+    } else if script_pubkey.is_v1_p2tr() {
+        1 + 1 + 65
+    } else {
+        return Err(Error::UnsupportedReturnToSelfOutput);
+    };
+    // the segwit marker and flag are part of the witness data
+    let weight = child.weight().to_wu() + 2 + witness_size;
+    Ok(weight.div_ceil(4))
+}
+
 #[derive(Debug, Clone)]
 pub struct TransactionMetadata {
     pub txid: Txid,
@@ -140,11 +189,16 @@ pub trait BitcoinCoreApi {
 
     async fn wait_for_block(&self, height: u32, num_confirmations: u32) -> Result<Block, Error>;
 
+    /// Wait until the node has a block at `height`, but at most `max_wait`.
+    async fn wait_for_new_block(&self, _height: u32, max_wait: Duration) {
+        sleep(max_wait).await
+    }
+
     async fn get_block_count(&self) -> Result<u64, Error>;
 
-    fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, Error>;
+    async fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, Error>;
 
-    fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, Error>;
+    async fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, Error>;
 
     async fn get_raw_tx(&self, txid: &Txid, block_hash: &BlockHash) -> Result<Vec<u8>, Error>;
 
@@ -172,6 +226,33 @@ pub trait BitcoinCoreApi {
 
     async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error>;
 
+    /// Get the hashes of the blocks at `heights`, in ascending order.
+    async fn get_block_hashes(&self, heights: Range<u32>) -> Result<Vec<BlockHash>, Error> {
+        let mut hashes = Vec::with_capacity(heights.len());
+        for height in heights {
+            hashes.push(self.get_block_hash(height).await?);
+        }
+        Ok(hashes)
+    }
+
+    /// Get the headers of the blocks at `heights`, in ascending order.
+    async fn get_block_headers(&self, heights: Range<u32>) -> Result<Vec<BlockHeader>, Error> {
+        let mut headers = Vec::with_capacity(heights.len());
+        for hash in self.get_block_hashes(heights).await? {
+            headers.push(self.get_block_header(&hash).await?);
+        }
+        Ok(headers)
+    }
+
+    /// Get the blocks with the given `hashes`, in the same order.
+    async fn get_blocks(&self, hashes: &[BlockHash]) -> Result<Vec<Block>, Error> {
+        let mut blocks = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            blocks.push(self.get_block(hash).await?);
+        }
+        Ok(blocks)
+    }
+
     async fn get_mempool_transactions<'a>(
         &'a self,
     ) -> Result<Box<dyn Iterator<Item = Result<Transaction, Error>> + Send + 'a>, Error>;
@@ -186,6 +267,11 @@ pub trait BitcoinCoreApi {
 
     async fn bump_fee(&self, txid: &Txid, address: Address, fee_rate: SatPerVbyte) -> Result<Txid, Error>;
 
+    /// Child-pays-for-parent: spend the return-to-self output of `txid` so that
+    /// both transactions together pay `fee_rate`, returns the txid of the child.
+    /// The child replaces any earlier child of `txid` created by this function.
+    async fn bump_fee_with_child(&self, txid: &Txid, address: Address, fee_rate: SatPerVbyte) -> Result<Txid, Error>;
+
     async fn create_and_send_transaction(
         &self,
         address: Address,
@@ -209,12 +295,25 @@ pub trait BitcoinCoreApi {
 
     async fn rescan_electrs_for_addresses(&self, addresses: Vec<Address>) -> Result<(), Error>;
 
-    fn get_utxo_count(&self) -> Result<usize, Error>;
+    async fn get_utxo_count(&self) -> Result<usize, Error>;
+
+    /// Sweep up to `max_inputs` of the smallest confirmed utxos worth at most `max_utxo_value`
+    /// satoshis into a single output back to the wallet, returns `None` if there is nothing to
+    /// consolidate.
+    async fn consolidate_utxos(
+        &self,
+        max_inputs: usize,
+        max_utxo_value: u64,
+        fee_rate: SatPerVbyte,
+    ) -> Result<Option<Txid>, Error>;
 
     async fn is_in_mempool(&self, txid: Txid) -> Result<bool, Error>;
 
     async fn fee_rate(&self, txid: Txid) -> Result<SatPerVbyte, Error>;
 
+    /// Local estimate of the fee rate needed for confirmation within `target_blocks`.
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<SatPerVbyte, Error>;
+
     async fn get_tx_for_op_return(&self, address: Address, amount: u128, data: H256) -> Result<Option<Txid>, Error>;
 }
 
@@ -239,6 +338,23 @@ struct ConnectionInfo {
     version: usize,
 }
 
+#[derive(Deserialize)]
+struct WalletKindResult {
+    // not returned by versions without descriptor wallet support
+    #[serde(default)]
+    descriptors: bool,
+}
+
+#[derive(Deserialize)]
+struct ListDescriptorsResult {
+    descriptors: Vec<ListDescriptorsResultEntry>,
+}
+
+#[derive(Deserialize)]
+struct ListDescriptorsResultEntry {
+    desc: String,
+}
+
 fn get_info(rpc: &Client) -> Result<ConnectionInfo, Error> {
     let blockchain_info = rpc.get_blockchain_info()?;
     let network_info = rpc.get_network_info()?;
@@ -296,7 +412,13 @@ pub struct BitcoinCoreBuilder {
     url: String,
     auth: Auth,
     wallet_name: Option<String>,
-    electrs_url: Option<String>,
+    electrs_urls: Vec<String>,
+    electrs_quorum: usize,
+    electrs_cache_size: usize,
+    electrs_rate_limit: Option<NonZeroU32>,
+    signer_url: Option<String>,
+    zmq_block_url: Option<String>,
+    descriptor_wallet: bool,
 }
 
 impl BitcoinCoreBuilder {
@@ -305,7 +427,13 @@ impl BitcoinCoreBuilder {
             url,
             auth: Auth::None,
             wallet_name: None,
-            electrs_url: None,
+            electrs_urls: vec![],
+            electrs_quorum: 1,
+            electrs_cache_size: 0,
+            electrs_rate_limit: None,
+            signer_url: None,
+            zmq_block_url: None,
+            descriptor_wallet: false,
         }
     }
 
@@ -319,11 +447,45 @@ impl BitcoinCoreBuilder {
         self
     }
 
-    pub fn set_electrs_url(mut self, electrs_url: Option<String>) -> Self {
-        self.electrs_url = electrs_url;
+    pub fn set_electrs_urls(mut self, electrs_urls: Vec<String>) -> Self {
+        self.electrs_urls = electrs_urls;
+        self
+    }
+
+    pub fn set_electrs_quorum(mut self, electrs_quorum: usize) -> Self {
+        self.electrs_quorum = electrs_quorum;
+        self
+    }
+
+    pub fn set_electrs_cache_size(mut self, electrs_cache_size: usize) -> Self {
+        self.electrs_cache_size = electrs_cache_size;
+        self
+    }
+
+    pub fn set_electrs_rate_limit(mut self, electrs_rate_limit: Option<NonZeroU32>) -> Self {
+        self.electrs_rate_limit = electrs_rate_limit;
+        self
+    }
+
+    pub fn set_signer_url(mut self, signer_url: Option<String>) -> Self {
+        self.signer_url = signer_url;
         self
     }
 
+    pub fn set_zmq_block_url(mut self, zmq_block_url: Option<String>) -> Self {
+        self.zmq_block_url = zmq_block_url;
+        self
+    }
+
+    pub fn set_descriptor_wallet(mut self, descriptor_wallet: bool) -> Self {
+        self.descriptor_wallet = descriptor_wallet;
+        self
+    }
+
+    fn new_external_signer(&self) -> Result<Option<DynExternalSigner>, Error> {
+        Ok(self.signer_url.as_deref().map(new_external_signer).transpose()?)
+    }
+
     fn new_client(&self) -> Result<Client, Error> {
         let url = match self.wallet_name {
             Some(ref x) => format!("{}/wallet/{}", self.url, x),
@@ -346,13 +508,47 @@ impl BitcoinCoreBuilder {
     }
 
     pub fn build_with_network(self, network: Network) -> Result<BitcoinCore, Error> {
-        BitcoinCore::new(self.new_client()?, self.wallet_name, network, self.electrs_url)
+        let electrs_client = new_electrs_api(
+            self.electrs_urls.clone(),
+            self.electrs_quorum,
+            self.electrs_cache_size,
+            self.electrs_rate_limit,
+            network,
+        )?;
+        let external_signer = self.new_external_signer()?;
+        let zmq = ZmqNotifier::new(self.zmq_block_url.clone());
+        BitcoinCore::new(
+            self.new_client()?,
+            self.wallet_name,
+            self.descriptor_wallet,
+            network,
+            electrs_client,
+            external_signer,
+            zmq,
+        )
     }
 
     pub async fn build_and_connect(self, connection_timeout: Duration) -> Result<BitcoinCore, Error> {
         let client = self.new_client()?;
+        let external_signer = self.new_external_signer()?;
         let network = connect(&client, connection_timeout).await?;
-        BitcoinCore::new(client, self.wallet_name, network, self.electrs_url)
+        let electrs_client = new_electrs_api(
+            self.electrs_urls,
+            self.electrs_quorum,
+            self.electrs_cache_size,
+            self.electrs_rate_limit,
+            network,
+        )?;
+        let zmq = ZmqNotifier::new(self.zmq_block_url);
+        BitcoinCore::new(
+            client,
+            self.wallet_name,
+            self.descriptor_wallet,
+            network,
+            electrs_client,
+            external_signer,
+            zmq,
+        )
     }
 }
 
@@ -360,9 +556,19 @@ impl BitcoinCoreBuilder {
 pub struct BitcoinCore {
     rpc: Arc<Client>,
     wallet_name: Option<String>,
+    // if set, new wallets are created as descriptor wallets, existing wallets are used as they are
+    descriptor_wallet: bool,
+    // whether the loaded wallet is a descriptor wallet, queried once it is loaded
+    is_descriptor_wallet: Arc<RwLock<Option<bool>>>,
+    // derivation keys by public key, so that descriptor wallets don't need to list all keys on every lookup
+    derivation_keys: Arc<RwLock<HashMap<PublicKey, PrivateKey>>>,
     network: Network,
     transaction_creation_lock: Arc<Mutex<()>>,
-    electrs_client: ElectrsClient,
+    electrs_client: DynElectrsApi,
+    // if set, payments are signed by the signer instead of the wallet
+    external_signer: Option<DynExternalSigner>,
+    // if set, new blocks are awaited via zmq rather than by polling
+    zmq: Option<ZmqNotifier>,
     #[cfg(feature = "regtest-manual-mining")]
     auto_mine: bool,
 }
@@ -371,15 +577,23 @@ impl BitcoinCore {
     fn new(
         client: Client,
         wallet_name: Option<String>,
+        descriptor_wallet: bool,
         network: Network,
-        electrs_url: Option<String>,
+        electrs_client: DynElectrsApi,
+        external_signer: Option<DynExternalSigner>,
+        zmq: Option<ZmqNotifier>,
     ) -> Result<Self, Error> {
         Ok(BitcoinCore {
             rpc: Arc::new(client),
             wallet_name,
+            descriptor_wallet,
+            is_descriptor_wallet: Default::default(),
+            derivation_keys: Default::default(),
             network,
             transaction_creation_lock: Arc::new(Mutex::new(())),
-            electrs_client: ElectrsClient::new(electrs_url, network)?,
+            electrs_client,
+            external_signer,
+            zmq,
             #[cfg(feature = "regtest-manual-mining")]
             auto_mine: false,
         })
@@ -433,6 +647,39 @@ impl BitcoinCore {
         Ok(self.rpc.call("createrawtransaction", &args)?)
     }
 
+    /// Convert a funded transaction to a PSBT that includes the utxo and key
+    /// origin information which an external signer needs.
+    fn create_psbt(&self, funded_raw_tx: &[u8]) -> Result<psbt::PartiallySignedTransaction, Error> {
+        let psbt: String = self
+            .rpc
+            .call("converttopsbt", &[serde_json::to_value(hex::encode(funded_raw_tx))?])?;
+        let processed_psbt = self.rpc.wallet_process_psbt(&psbt, Some(false), None, Some(true))?;
+        Ok(signer::decode_psbt(&processed_psbt.psbt)?)
+    }
+
+    /// Sign with the external signer if one is set, otherwise with the wallet.
+    async fn sign_transaction(&self, raw_tx: &[u8], expected_outputs: &[TxOut]) -> Result<Transaction, Error> {
+        if let Some(ref external_signer) = self.external_signer {
+            let psbt = self.create_psbt(raw_tx)?;
+            return Ok(signer::sign_with_external_signer(external_signer, psbt, expected_outputs).await?);
+        }
+
+        let signed_raw_tx = self.rpc.sign_raw_transaction_with_wallet(raw_tx, None, None)?;
+
+        // Make sure signing is successful
+        if signed_raw_tx.errors.is_some() {
+            log::warn!(
+                "Received bitcoin funding errors (complete={}): {:?}",
+                signed_raw_tx.complete,
+                signed_raw_tx.errors
+            );
+
+            return Err(Error::TransactionSigningError);
+        }
+
+        Ok(signed_raw_tx.transaction()?)
+    }
+
     async fn fund_and_sign_transaction(
         &self,
         fee_rate: SatPerVbyte,
@@ -458,23 +705,9 @@ impl BitcoinCore {
             // fund the transaction: adds required inputs, and possibly a return-to-self output
             let funded_raw_tx = self.rpc.fund_raw_transaction(raw_tx, Some(&funding_opts), None)?;
 
-            // sign the transaction
-            let signed_funded_raw_tx =
-                self.rpc
-                    .sign_raw_transaction_with_wallet(&funded_raw_tx.transaction()?, None, None)?;
-
-            // Make sure signing is successful
-            if signed_funded_raw_tx.errors.is_some() {
-                log::warn!(
-                    "Received bitcoin funding errors (complete={}): {:?}",
-                    signed_funded_raw_tx.complete,
-                    signed_funded_raw_tx.errors
-                );
-
-                return Err(Error::TransactionSigningError);
-            }
-
-            let transaction = signed_funded_raw_tx.transaction()?;
+            let expected_outputs =
+                deserialize::<Transaction>(&hex::decode(raw_tx).map_err(ConversionError::from)?)?.output;
+            let transaction = self.sign_transaction(&funded_raw_tx.hex, &expected_outputs).await?;
 
             Ok(LockedTransaction::new(transaction, recipient.to_string(), Some(lock)))
         })
@@ -558,6 +791,32 @@ impl BitcoinCore {
             .clone()
     }
 
+    /// Send one `method` request per entry in `params` as a single JSON-RPC batch,
+    /// returns the results in the same order.
+    fn batch_call<T: DeserializeOwned>(&self, method: &str, params: &[Vec<JsonValue>]) -> Result<Vec<T>, Error> {
+        if params.is_empty() {
+            return Ok(vec![]);
+        }
+        let client = self.rpc.get_jsonrpc_client();
+        let raw_params = params
+            .iter()
+            .map(|args| args.iter().map(serde_json::value::to_raw_value).collect())
+            .collect::<Result<Vec<Vec<_>>, _>>()?;
+        let requests: Vec<_> = raw_params
+            .iter()
+            .map(|args| client.build_request(method, args))
+            .collect();
+        client
+            .send_batch(&requests)
+            .map_err(BitcoinError::from)?
+            .into_iter()
+            .map(|response| {
+                let response = response.ok_or(BitcoinError::JsonRpc(JsonRpcError::WrongBatchResponseSize))?;
+                Ok(response.result().map_err(BitcoinError::from)?)
+            })
+            .collect()
+    }
+
     async fn with_retry_on_timeout<F, R, T>(&self, call: F) -> Result<T, Error>
     where
         F: Fn() -> R,
@@ -626,8 +885,176 @@ impl BitcoinCore {
     }
 
     pub async fn import_private_key(&self, privkey: PrivateKey) -> Result<(), Error> {
-        self.with_wallet(|| async { Ok(self.rpc.import_private_key(&privkey, None, None)?) })
-            .await
+        self.with_wallet(|| async {
+            if self.is_descriptor_wallet()? {
+                // keys are freshly generated, so there is no history to rescan for
+                let request = ImportDescriptorRequest::combo(&privkey, Timestamp::Now, String::new());
+                self.import_descriptors(&[request]).await
+            } else {
+                Ok(self.rpc.import_private_key(&privkey, None, None)?)
+            }
+        })
+        .await
+    }
+
+    /// Returns true if the loaded wallet is a descriptor wallet, false for legacy wallets.
+    pub fn is_descriptor_wallet(&self) -> Result<bool, Error> {
+        if let Some(is_descriptor_wallet) = *self.is_descriptor_wallet.read().unwrap_or_else(PoisonError::into_inner) {
+            return Ok(is_descriptor_wallet);
+        }
+        // the kind of a wallet never changes, so only ask once it is loaded
+        let wallet_info: WalletKindResult = self.rpc.call("getwalletinfo", &[])?;
+        *self
+            .is_descriptor_wallet
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(wallet_info.descriptors);
+        Ok(wallet_info.descriptors)
+    }
+
+    fn cache_derivation_key(&self, private_key: PrivateKey) {
+        let public_key = private_key.public_key(&secp256k1::Secp256k1::signing_only());
+        self.derivation_keys
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(public_key, private_key);
+    }
+
+    /// Import the descriptors into a descriptor wallet. If any of the timestamps is in the
+    /// past, this waits until the wallet has rescanned the chain from that time onwards.
+    pub async fn import_descriptors(&self, requests: &[ImportDescriptorRequest]) -> Result<(), Error> {
+        match self.import_descriptors_now(requests) {
+            Err(e) if e.is_transport_error() => {
+                // the import is still running, see `rescan_blockchain`
+                self.wait_for_rescan().await
+            }
+            x => x,
+        }
+    }
+
+    /// Like `import_descriptors`, but blocks on the rpc call. Only use without rescans.
+    fn import_descriptors_now(&self, requests: &[ImportDescriptorRequest]) -> Result<(), Error> {
+        let results: Vec<ImportMultiResult> = self.rpc.call("importdescriptors", &[serde_json::to_value(requests)?])?;
+        for result in results {
+            for warning in result.warnings {
+                warn!("Received warning while importing descriptor: {warning}");
+            }
+            if !result.success {
+                let message = result.error.map(|err| err.message).unwrap_or_default();
+                return Err(Error::DescriptorImportError(message));
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the private key to the wallet, without rescanning the chain.
+    fn import_key_without_rescan(&self, private_key: &PrivateKey, label: &str) -> Result<(), Error> {
+        if self.is_descriptor_wallet()? {
+            let request = ImportDescriptorRequest::wpkh(private_key, Timestamp::Now, label.to_string());
+            self.import_descriptors_now(&[request])
+        } else {
+            Ok(self.rpc.import_private_key(private_key, Some(label), Some(false))?)
+        }
+    }
+
+    /// Copy the private keys of all addresses of this legacy wallet into the descriptor wallet
+    /// `target`, keeping their labels. The target rescans the chain from the creation time of
+    /// the oldest key, so that it knows about all funds once this returns. Returns the number
+    /// of migrated keys.
+    pub async fn migrate_to_descriptor_wallet(&self, target: &BitcoinCore) -> Result<usize, Error> {
+        if self.is_descriptor_wallet()? {
+            info!("Wallet is already a descriptor wallet - nothing to migrate");
+            return Ok(0);
+        }
+        if !target.is_descriptor_wallet()? {
+            return Err(Error::NotDescriptorWallet);
+        }
+
+        // the address book contains all addresses handed out by the wallet, the
+        // unspent outputs additionally contain the funded change addresses
+        let mut addresses: Vec<_> = self
+            .rpc
+            .list_received_by_address(None, Some(0), Some(true), Some(false))?
+            .into_iter()
+            .map(|result| result.address)
+            .collect();
+        addresses.extend(
+            self.rpc
+                .list_unspent(Some(0), None, None, None, None)?
+                .into_iter()
+                .filter_map(|utxo| utxo.address),
+        );
+
+        let mut requests = Vec::new();
+        let mut migrated = HashSet::new();
+        for address in addresses {
+            let address = address.require_network(self.network)?;
+            if !migrated.insert(address.clone()) {
+                continue;
+            }
+            let address_info = self.rpc.get_address_info(&address)?;
+            if !address_info.is_mine.unwrap_or(false) || address_info.is_watchonly.unwrap_or(false) {
+                continue;
+            }
+            let private_key = self.rpc.dump_private_key(&address)?;
+            let label = address_info.labels.into_iter().next().map(|label| match label {
+                json::GetAddressInfoResultLabel::Simple(label) => label,
+                json::GetAddressInfoResultLabel::WithPurpose { name, .. } => name,
+            });
+            // keys without creation time may have been used at any time
+            let timestamp = Timestamp::Time(address_info.timestamp.unwrap_or(0));
+            requests.push(ImportDescriptorRequest::combo(
+                &private_key,
+                timestamp,
+                label.unwrap_or_default(),
+            ));
+        }
+
+        info!("Migrating {} keys to the descriptor wallet...", requests.len());
+        target.import_descriptors(&requests).await?;
+        Ok(requests.len())
+    }
+
+    /// Returns the block hash and (for wallet transactions) the fee of a transaction
+    /// once it has `num_confirmations`, `ConfirmationError` before.
+    fn get_transaction_confirmation(
+        &self,
+        txid: Txid,
+        num_confirmations: u32,
+        block_hash: Option<BlockHash>,
+        is_wallet: bool,
+    ) -> Result<(BlockHash, Option<SignedAmount>), Error> {
+        if is_wallet {
+            match self.rpc.get_transaction(&txid, None) {
+                Ok(GetTransactionResult {
+                    info:
+                        WalletTxInfo {
+                            confirmations,
+                            blockhash: Some(hash),
+                            ..
+                        },
+                    fee,
+                    ..
+                }) if confirmations >= 0 && confirmations as u32 >= num_confirmations => Ok((hash, fee)),
+                Ok(_) => Err(Error::ConfirmationError),
+                Err(e) => {
+                    log::error!("{}", e);
+                    Err(e.into())
+                }
+            }
+        } else {
+            match self.rpc.get_raw_transaction_info(&txid, block_hash.as_ref()) {
+                Ok(GetRawTransactionResult {
+                    confirmations: Some(num),
+                    blockhash: Some(hash),
+                    ..
+                }) if num >= num_confirmations => Ok((hash, None)),
+                Ok(_) => Err(Error::ConfirmationError),
+                Err(e) => {
+                    log::error!("{}", e);
+                    Err(e.into())
+                }
+            }
+        }
     }
 
     pub async fn wait_for_rescan(&self) -> Result<(), Error> {
@@ -661,6 +1088,17 @@ impl BitcoinCoreApi for BitcoinCore {
         self.network
     }
 
+    async fn wait_for_new_block(&self, height: u32, max_wait: Duration) {
+        // subscribe before querying the height so that no block is missed in between
+        let mut block_waiter = BlockWaiter::new(self.zmq.as_ref());
+        let _ = timeout(max_wait, async {
+            while !matches!(self.get_block_count().await, Ok(count) if count >= height.into()) {
+                block_waiter.wait(max_wait).await;
+            }
+        })
+        .await;
+    }
+
     /// Wait for a specified height to return a `BlockHash` or
     /// exit on error.
     ///
@@ -668,6 +1106,7 @@ impl BitcoinCoreApi for BitcoinCore {
     /// * `height` - block height to fetch
     /// * `num_confirmations` - minimum for a block to be accepted
     async fn wait_for_block(&self, height: u32, num_confirmations: u32) -> Result<Block, Error> {
+        let mut block_waiter = BlockWaiter::new(self.zmq.as_ref());
         loop {
             match self.rpc.get_block_hash(height.into()) {
                 Ok(hash) => {
@@ -675,7 +1114,7 @@ impl BitcoinCoreApi for BitcoinCore {
                     if info.confirmations >= num_confirmations as i32 {
                         return Ok(self.rpc.get_block(&hash)?);
                     } else {
-                        sleep(RETRY_DURATION).await;
+                        block_waiter.wait(RETRY_DURATION).await;
                         continue;
                     }
                 }
@@ -683,7 +1122,7 @@ impl BitcoinCoreApi for BitcoinCore {
                     if BitcoinRpcError::from(err.clone()) == BitcoinRpcError::RpcInvalidParameter =>
                 {
                     // block does not exist yet
-                    sleep(RETRY_DURATION).await;
+                    block_waiter.wait(RETRY_DURATION).await;
                     continue;
                 }
                 Err(err) => return Err(err.into()),
@@ -697,7 +1136,7 @@ impl BitcoinCoreApi for BitcoinCore {
     }
 
     /// Get wallet balance.
-    fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, Error> {
+    async fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, Error> {
         Ok(self
             .rpc
             .get_balance(min_confirmations.map(|x| x.try_into().unwrap_or_default()), None)?)
@@ -706,7 +1145,7 @@ impl BitcoinCoreApi for BitcoinCore {
     /// List the transaction in the wallet. `max_count` sets a limit on the amount of transactions returned.
     /// If none is provided, [`DEFAULT_MAX_TX_COUNT`] is used, which is an arbitrarily picked big number to
     /// effectively return all transactions.
-    fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, Error> {
+    async fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, Error> {
         // If no `max_count` is specified to the rpc call, bitcoin core only returns 10 items.
         Ok(self
             .rpc
@@ -779,33 +1218,80 @@ impl BitcoinCoreApi for BitcoinCore {
     }
 
     fn dump_derivation_key(&self, public_key: &PublicKey) -> Result<PrivateKey, Error> {
+        if let Some(private_key) = self
+            .derivation_keys
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(public_key)
+        {
+            return Ok(*private_key);
+        }
+        if self.is_descriptor_wallet()? {
+            // only list the (private) descriptors for keys that were not seen before
+            let result: ListDescriptorsResult = self.rpc.call("listdescriptors", &[true.into()])?;
+            for private_key in result
+                .descriptors
+                .iter()
+                .filter_map(|descriptor| descriptor::single_private_key(&descriptor.desc))
+            {
+                self.cache_derivation_key(PrivateKey {
+                    network: self.network,
+                    ..private_key
+                });
+            }
+            if let Some(private_key) = self
+                .derivation_keys
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(public_key)
+            {
+                return Ok(*private_key);
+            }
+            // keys returned by `get_new_public_key` are derived from the ranged descriptors
+            let address = Address::p2wpkh(public_key, self.network).map_err(ConversionError::from)?;
+            let hd_key_path = self
+                .rpc
+                .get_address_info(&address)?
+                .hd_key_path
+                .ok_or(Error::MissingPrivateKey)?;
+            let private_key = result
+                .descriptors
+                .iter()
+                .filter_map(|descriptor| descriptor::derive_private_key(&descriptor.desc, &hd_key_path))
+                .find(|private_key| private_key.public_key(&secp256k1::Secp256k1::signing_only()) == *public_key)
+                .ok_or(Error::MissingPrivateKey)?;
+            let private_key = PrivateKey {
+                network: self.network,
+                ..private_key
+            };
+            self.cache_derivation_key(private_key);
+            return Ok(private_key);
+        }
         let address = Address::p2wpkh(public_key, self.network).map_err(ConversionError::from)?;
-        Ok(self.rpc.dump_private_key(&address)?)
+        let private_key = self.rpc.dump_private_key(&address)?;
+        self.cache_derivation_key(private_key);
+        Ok(private_key)
     }
 
     fn import_derivation_key(&self, private_key: &PrivateKey) -> Result<(), Error> {
-        Ok(self
-            .rpc
-            .import_private_key(private_key, Some(DERIVATION_KEY_LABEL), Some(false))?)
+        self.import_key_without_rescan(private_key, DERIVATION_KEY_LABEL)?;
+        self.cache_derivation_key(*private_key);
+        Ok(())
     }
 
     /// Derive and import the private key for the master public key and public secret
     async fn add_new_deposit_key(&self, public_key: PublicKey, secret_key: Vec<u8>) -> Result<(), Error> {
-        let address = Address::p2wpkh(&public_key, self.network).map_err(ConversionError::from)?;
-        let private_key = self.rpc.dump_private_key(&address)?;
+        let private_key = self.dump_derivation_key(&public_key)?;
         let deposit_secret_key =
             addr::calculate_deposit_secret_key(private_key.inner, SecretKey::from_slice(&secret_key)?)?;
-        self.rpc.import_private_key(
+        self.import_key_without_rescan(
             &PrivateKey {
                 compressed: private_key.compressed,
                 network: self.network,
                 inner: deposit_secret_key,
             },
-            Some(DEPOSIT_LABEL),
-            // rescan true by default
-            Some(false),
-        )?;
-        Ok(())
+            DEPOSIT_LABEL,
+        )
     }
 
     async fn get_best_block_hash(&self) -> Result<BlockHash, Error> {
@@ -824,6 +1310,50 @@ impl BitcoinCoreApi for BitcoinCore {
         Ok(self.rpc.get_block_header(hash)?)
     }
 
+    async fn get_block_hashes(&self, heights: Range<u32>) -> Result<Vec<BlockHash>, Error> {
+        let params: Vec<_> = heights.map(|height| vec![height.into()]).collect();
+        let mut hashes = Vec::with_capacity(params.len());
+        for batch in params.chunks(MAX_RPC_BATCH_SIZE) {
+            match self.batch_call::<BlockHash>("getblockhash", batch) {
+                Ok(batch_hashes) => hashes.extend(batch_hashes),
+                // (some) blocks do not exist yet
+                Err(err) if err.is_invalid_parameter() => return Err(Error::InvalidBitcoinHeight),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(hashes)
+    }
+
+    async fn get_block_headers(&self, heights: Range<u32>) -> Result<Vec<BlockHeader>, Error> {
+        let params = self
+            .get_block_hashes(heights)
+            .await?
+            .iter()
+            .map(|hash| Ok(vec![serde_json::to_value(hash)?, false.into()]))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut headers = Vec::with_capacity(params.len());
+        for batch in params.chunks(MAX_RPC_BATCH_SIZE) {
+            for header_hex in self.batch_call::<String>("getblockheader", batch)? {
+                headers.push(deserialize(&hex::decode(header_hex).map_err(ConversionError::from)?)?);
+            }
+        }
+        Ok(headers)
+    }
+
+    async fn get_blocks(&self, hashes: &[BlockHash]) -> Result<Vec<Block>, Error> {
+        let params = hashes
+            .iter()
+            .map(|hash| Ok(vec![serde_json::to_value(hash)?, 0.into()]))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut blocks = Vec::with_capacity(params.len());
+        for batch in params.chunks(MAX_BLOCK_BATCH_SIZE) {
+            for block_hex in self.batch_call::<String>("getblock", batch)? {
+                blocks.push(deserialize(&hex::decode(block_hex).map_err(ConversionError::from)?)?);
+            }
+        }
+        Ok(blocks)
+    }
+
     /// Get the transactions that are currently in the mempool. Since `impl trait` is not
     /// allowed within trait method, we have to use trait objects.
     async fn get_mempool_transactions<'a>(
@@ -856,39 +1386,22 @@ impl BitcoinCoreApi for BitcoinCore {
         block_hash: Option<BlockHash>,
         is_wallet: bool,
     ) -> Result<TransactionMetadata, Error> {
+        // with zmq, await new blocks rather than backing off while the tx is unconfirmed
+        if self.zmq.is_some() {
+            let mut block_waiter = BlockWaiter::new(self.zmq.as_ref());
+            // bounded like the backoff below, which then reports the error
+            let _ = timeout(MAX_ELAPSED_TIME, async {
+                while let Err(Error::ConfirmationError) =
+                    self.get_transaction_confirmation(txid, num_confirmations, block_hash, is_wallet)
+                {
+                    block_waiter.wait(RETRY_DURATION).await;
+                }
+            })
+            .await;
+        }
+
         let (block_hash, fee) = retry(get_exponential_backoff(), || async {
-            if is_wallet {
-                Ok(match self.rpc.get_transaction(&txid, None) {
-                    Ok(GetTransactionResult {
-                        info:
-                            WalletTxInfo {
-                                confirmations,
-                                blockhash: Some(hash),
-                                ..
-                            },
-                        fee,
-                        ..
-                    }) if confirmations >= 0 && confirmations as u32 >= num_confirmations => Ok((hash, fee)),
-                    Ok(_) => Err(Error::ConfirmationError),
-                    Err(e) => {
-                        log::error!("{}", e);
-                        Err(e.into())
-                    }
-                }?)
-            } else {
-                Ok(match self.rpc.get_raw_transaction_info(&txid, block_hash.as_ref()) {
-                    Ok(GetRawTransactionResult {
-                        confirmations: Some(num),
-                        blockhash: Some(hash),
-                        ..
-                    }) if num >= num_confirmations => Ok((hash, None)),
-                    Ok(_) => Err(Error::ConfirmationError),
-                    Err(e) => {
-                        log::error!("{}", e);
-                        Err(e.into())
-                    }
-                }?)
-            }
+            Ok(self.get_transaction_confirmation(txid, num_confirmations, block_hash, is_wallet)?)
         })
         .await?;
 
@@ -963,6 +1476,58 @@ impl BitcoinCoreApi for BitcoinCore {
         Ok(txid)
     }
 
+    async fn bump_fee_with_child(&self, txid: &Txid, address: Address, fee_rate: SatPerVbyte) -> Result<Txid, Error> {
+        let transaction = self
+            .with_wallet_inner(false, || async {
+                // like `fund_and_sign_transaction`, hold the lock until the child is sent, so the
+                // change output is not spent by another transaction in the meantime
+                let lock = self.transaction_creation_lock.clone().lock_owned().await;
+                let parent = self.rpc.get_raw_transaction(txid, None)?;
+                let (vout, _) = parent
+                    .extract_return_to_self_address(&address.payload)?
+                    .ok_or(Error::NoReturnToSelfOutput)?;
+                let return_to_self = parent.output[vout].clone();
+
+                // the mempool entry also tells us if the parent is still unconfirmed
+                let mempool_entry = self.rpc.get_mempool_entry(txid)?;
+                let parent_fee = mempool_entry.fees.base.to_sat();
+
+                // send everything back to the same address, minus the fee
+                let mut child = Transaction {
+                    version: 2,
+                    lock_time: LockTime::ZERO,
+                    input: vec![TxIn {
+                        previous_output: OutPoint {
+                            txid: *txid,
+                            vout: vout.try_into()?,
+                        },
+                        sequence: bitcoincore_rpc::bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME,
+                        ..Default::default()
+                    }],
+                    output: vec![return_to_self.clone()],
+                };
+                let child_vsize = get_max_child_vsize(&return_to_self.script_pubkey, &child)?;
+                let child_fee = get_child_fee(parent_fee, mempool_entry.vsize, child_vsize, fee_rate);
+                child.output[0].value = return_to_self
+                    .value
+                    .checked_sub(child_fee)
+                    .filter(|value| *value >= return_to_self.script_pubkey.dust_value().to_sat())
+                    .ok_or(Error::CannotPayForParent)?;
+
+                let expected_outputs = child.output.clone();
+                let transaction = self.sign_transaction(&serialize(&child), &expected_outputs).await?;
+                Ok(LockedTransaction::new(transaction, address.to_string(), Some(lock)))
+            })
+            .await?;
+
+        let child_txid = self
+            .with_wallet_inner(false, || async {
+                Ok(self.rpc.send_raw_transaction(&transaction.transaction)?)
+            })
+            .await?;
+        Ok(child_txid)
+    }
+
     /// Send an amount of Bitcoin to an address, but only submit the transaction
     /// to the mempool; this method does not wait until the block is included in
     /// the blockchain.
@@ -1032,7 +1597,20 @@ impl BitcoinCoreApi for BitcoinCore {
             } else {
                 info!("Creating wallet {wallet_name}...");
                 // wallet does not exist, create
-                let result = self.rpc.create_wallet(wallet_name, None, None, None, None)?;
+                let result = if self.descriptor_wallet {
+                    // `createwallet` with `descriptors=true`, which the rpc client has no parameter for
+                    let args = [
+                        wallet_name.as_str().into(),
+                        false.into(),
+                        false.into(),
+                        JsonValue::Null,
+                        false.into(),
+                        true.into(),
+                    ];
+                    self.rpc.call::<json::LoadWalletResult>("createwallet", &args)?
+                } else {
+                    self.rpc.create_wallet(wallet_name, None, None, None, None)?
+                };
                 if let Some(warning) = result.warning {
                     if !warning.is_empty() {
                         warn!("Received warning while creating wallet {wallet_name}: {warning}");
@@ -1084,13 +1662,16 @@ impl BitcoinCoreApi for BitcoinCore {
             });
             for transaction in confirmed_payments_to {
                 let (raw_tx, raw_merkle_proof) = futures::future::try_join(
-                    self.electrs_client.get_tx_hex(&transaction.txid),
-                    self.electrs_client.get_tx_merkle_block_proof(&transaction.txid),
+                    self.electrs_client.get_raw_tx(&transaction.txid),
+                    self.electrs_client.get_raw_tx_merkle_proof(&transaction.txid),
                 )
                 .await?;
                 self.rpc.call::<()>(
                     "importprunedfunds",
-                    &[serde_json::to_value(raw_tx)?, serde_json::to_value(raw_merkle_proof)?],
+                    &[
+                        serde_json::to_value(hex::encode(raw_tx))?,
+                        serde_json::to_value(hex::encode(raw_merkle_proof))?,
+                    ],
                 )?;
             }
         }
@@ -1098,10 +1679,79 @@ impl BitcoinCoreApi for BitcoinCore {
     }
 
     /// Get the number of unspent transaction outputs.
-    fn get_utxo_count(&self) -> Result<usize, Error> {
+    async fn get_utxo_count(&self) -> Result<usize, Error> {
         Ok(self.rpc.list_unspent(None, None, None, None, None)?.len())
     }
 
+    async fn consolidate_utxos(
+        &self,
+        max_inputs: usize,
+        max_utxo_value: u64,
+        fee_rate: SatPerVbyte,
+    ) -> Result<Option<Txid>, Error> {
+        let transaction = self
+            .with_wallet(|| async {
+                let lock = self.transaction_creation_lock.clone().lock_owned().await;
+
+                let mut utxos = self.rpc.list_unspent(Some(1), None, None, Some(false), None)?;
+                utxos.retain(|utxo| utxo.amount.to_sat() <= max_utxo_value);
+                utxos.sort_by_key(|utxo| utxo.amount);
+                utxos.truncate(max_inputs);
+                if utxos.len() < 2 {
+                    return Ok(None);
+                }
+
+                let inputs = utxos
+                    .iter()
+                    .map(|utxo| CreateRawTransactionInput {
+                        txid: utxo.txid,
+                        vout: utxo.vout,
+                        sequence: None,
+                    })
+                    .collect::<Vec<_>>();
+                let address = self
+                    .rpc
+                    .get_raw_change_address(Some(AddressType::Bech32))?
+                    .require_network(self.network)?;
+                let amount = utxos.iter().map(|utxo| utxo.amount).sum::<Amount>();
+                let outputs = HashMap::from([(address.to_string(), amount)]);
+                let raw_tx = self
+                    .rpc
+                    .create_raw_transaction_hex(&inputs, &outputs, None, Some(true))?;
+
+                // only spend the selected utxos and pay the fee from the output
+                let funding_opts = FundRawTransactionOptions {
+                    add_inputs: Some(false),
+                    fee_rate: Some(Amount::from_sat(fee_rate.0.saturating_mul(1_000))),
+                    subtract_fee_from_outputs: Some(vec![0]),
+                    replaceable: Some(true),
+                    ..Default::default()
+                };
+                let funded_raw_tx = self.rpc.fund_raw_transaction(raw_tx, Some(&funding_opts), None)?;
+                // the only output pays the swept amount, minus the fee, back to the wallet
+                let expected_outputs = [TxOut {
+                    value: amount
+                        .checked_sub(funded_raw_tx.fee)
+                        .ok_or(Error::ArithmeticError)?
+                        .to_sat(),
+                    script_pubkey: address.script_pubkey(),
+                }];
+                let transaction = self.sign_transaction(&funded_raw_tx.hex, &expected_outputs).await?;
+
+                Ok(Some(LockedTransaction::new(
+                    transaction,
+                    address.to_string(),
+                    Some(lock),
+                )))
+            })
+            .await?;
+
+        match transaction {
+            Some(transaction) => Ok(Some(self.send_transaction(transaction).await?)),
+            None => Ok(None),
+        }
+    }
+
     async fn is_in_mempool(&self, txid: Txid) -> Result<bool, Error> {
         let get_tx_result = self.rpc.get_transaction(&txid, None)?;
         Ok(get_tx_result.info.confirmations == 0)
@@ -1130,6 +1780,16 @@ impl BitcoinCoreApi for BitcoinCore {
         Ok(SatPerVbyte(fee_rate.try_into()?))
     }
 
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<SatPerVbyte, Error> {
+        let estimate = self.rpc.estimate_smart_fee(target_blocks, None)?;
+        if let Some(ref errors) = estimate.errors {
+            log::debug!("Fee estimation errors: {:?}", errors);
+        }
+        // estimatesmartfee returns the rate per kvByte
+        let fee_rate = estimate.fee_rate.ok_or(Error::MissingBitcoinFeeInfo)?.to_sat();
+        Ok(SatPerVbyte(fee_rate.div_ceil(1_000)))
+    }
+
     async fn get_tx_for_op_return(&self, _address: Address, _amount: u128, _data: H256) -> Result<Option<Txid>, Error> {
         // direct lookup not supported by bitcoin core
         Ok(None)
@@ -1216,7 +1876,11 @@ impl TransactionExt for Transaction {
 
 #[cfg(test)]
 mod tests {
-    use bitcoincore_rpc::bitcoin::hashes::{hex::FromHex, sha256::Hash as Sha256Hash, Hash};
+    use super::*;
+    use bitcoincore_rpc::bitcoin::{
+        hashes::{hex::FromHex, sha256::Hash as Sha256Hash, Hash},
+        ScriptBuf,
+    };
 
     #[test]
     fn test_op_return_hashing() {
@@ -1228,4 +1892,32 @@ mod tests {
 
         assert_eq!(expected, script_hash);
     }
+
+    #[test]
+    fn test_child_fee_pays_for_parent() {
+        // the parent pays 1 sat/vbyte, the package needs 10 sat/vbyte
+        assert_eq!(get_child_fee(200, 200, 110, SatPerVbyte(10)), 3100 - 200);
+        // the parent already pays enough, the child still pays for itself
+        assert_eq!(get_child_fee(5000, 200, 110, SatPerVbyte(10)), 1100);
+    }
+
+    #[test]
+    fn test_max_child_vsize() {
+        let script_pubkey = ScriptBuf::from_hex("0014998fced992b90c49c2295c5724edf0daf4748dca").unwrap();
+        let child = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        // one p2wpkh input and output
+        assert_eq!(get_max_child_vsize(&script_pubkey, &child).unwrap(), 110);
+        assert!(matches!(
+            get_max_child_vsize(&ScriptBuf::new(), &child),
+            Err(Error::UnsupportedReturnToSelfOutput)
+        ));
+    }
 }
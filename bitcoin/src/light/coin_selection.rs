@@ -0,0 +1,573 @@
+use rand::{seq::SliceRandom, Rng};
+use std::{cmp::Reverse, str::FromStr};
+
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/wallet/coinselection.cpp
+const TOTAL_TRIES: usize = 100_000;
+
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/wallet/coinselection.cpp
+const APPROXIMATE_BEST_SUBSET_ITERATIONS: usize = 1000;
+
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/wallet/coinselection.h
+pub(super) const CHANGE_LOWER: u64 = 50_000;
+pub(super) const CHANGE_UPPER: u64 = 1_000_000;
+
+/// Strategy used by the light wallet to pick the inputs of a transaction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoinSelectionAlgorithm {
+    /// Run all algorithms and use the result with the lowest waste, like Bitcoin Core.
+    #[default]
+    LowestWaste,
+    /// Search for a changeless solution, fall back to knapsack if there is none.
+    BranchAndBound,
+    /// Randomized subset sum approximation that targets a change output.
+    Knapsack,
+    /// Randomly pick coins until the target is met, fall back to knapsack
+    /// if that leaves too little change.
+    SingleRandomDraw,
+}
+
+impl FromStr for CoinSelectionAlgorithm {
+    type Err = String;
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        match code {
+            "lowest-waste" => Ok(CoinSelectionAlgorithm::LowestWaste),
+            "branch-and-bound" => Ok(CoinSelectionAlgorithm::BranchAndBound),
+            "knapsack" => Ok(CoinSelectionAlgorithm::Knapsack),
+            "single-random-draw" => Ok(CoinSelectionAlgorithm::SingleRandomDraw),
+            _ => Err("Could not parse input as CoinSelectionAlgorithm".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(super) struct CoinOutput {
+    pub(super) value: u64,
+    // fee to spend at the current feerate
+    pub(super) fee: u64,
+    // fee to spend at the long term feerate
+    pub(super) long_term_fee: u64,
+}
+
+impl CoinOutput {
+    // output's value minus fees required to spend it
+    pub(super) fn get_effective_value(&self) -> u64 {
+        self.value.saturating_sub(self.fee)
+    }
+
+    // cost of spending now rather than at the long term feerate
+    fn get_waste(&self) -> i64 {
+        self.fee as i64 - self.long_term_fee as i64
+    }
+}
+
+pub(super) struct SelectCoins {
+    preset_inputs: Vec<CoinOutput>,
+    target_value: u64,
+}
+
+impl SelectCoins {
+    pub(super) fn new(target_value: u64) -> Self {
+        Self {
+            preset_inputs: vec![],
+            target_value,
+        }
+    }
+
+    pub(super) fn add(&mut self, coin_output: CoinOutput) {
+        self.preset_inputs.push(coin_output);
+    }
+
+    // https://github.com/bitcoin/bitcoin/blob/2bd9aa5a44b88c866c4d98f8a7bf7154049cba31/src/wallet/coinselection.cpp#L425
+    pub(super) fn get_selected_value(&self) -> u64 {
+        self.preset_inputs.iter().map(|input| input.value).sum()
+    }
+
+    // https://github.com/bitcoin/bitcoin/blob/2bd9aa5a44b88c866c4d98f8a7bf7154049cba31/src/wallet/coinselection.cpp#L430
+    pub(super) fn get_selected_effective_value(&self) -> u64 {
+        self.preset_inputs.iter().map(|input| input.get_effective_value()).sum()
+    }
+
+    // https://github.com/bitcoin/bitcoin/blob/2bd9aa5a44b88c866c4d98f8a7bf7154049cba31/src/wallet/coinselection.cpp#L495
+    pub(super) fn get_change(&self, min_viable_change: u64, change_fee: u64) -> u64 {
+        // change = SUM(inputs) - SUM(outputs) - fees
+        let change = self
+            .get_selected_effective_value()
+            .saturating_sub(self.target_value)
+            .saturating_sub(change_fee);
+
+        if change < min_viable_change {
+            0
+        } else {
+            change
+        }
+    }
+
+    // https://github.com/bitcoin/bitcoin/blob/v24.0/src/wallet/coinselection.cpp
+    fn get_waste(&self, params: &CoinSelectionParams) -> i64 {
+        let mut waste = self.preset_inputs.iter().map(CoinOutput::get_waste).sum::<i64>();
+        if self.get_change(params.min_viable_change, params.change_fee) > 0 {
+            waste += params.cost_of_change as i64;
+        } else {
+            // without change the excess is dropped to fees
+            waste += self.get_selected_effective_value() as i64 - self.target_value as i64;
+        }
+        waste
+    }
+}
+
+pub(super) struct CoinSelectionParams {
+    // fee to create the change output
+    pub(super) change_fee: u64,
+    // fee to create and later spend the change output
+    pub(super) cost_of_change: u64,
+    pub(super) min_viable_change: u64,
+    // change amount targeted by knapsack
+    pub(super) min_change_target: u64,
+}
+
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/wallet/coinselection.cpp
+pub(super) fn generate_change_target<R: Rng>(payment_value: u64, rng: &mut R) -> u64 {
+    if payment_value <= CHANGE_LOWER / 2 {
+        CHANGE_LOWER
+    } else {
+        // random value between CHANGE_LOWER and min(2 * payment_value, CHANGE_UPPER)
+        let upper_bound = std::cmp::min(payment_value.saturating_mul(2), CHANGE_UPPER);
+        rng.gen_range(CHANGE_LOWER, upper_bound)
+    }
+}
+
+// indices of all coins that are worth spending
+fn positive_coins(utxo_pool: &[CoinOutput]) -> Vec<usize> {
+    (0..utxo_pool.len())
+        .filter(|&index| utxo_pool[index].get_effective_value() > 0)
+        .collect()
+}
+
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/wallet/coinselection.cpp
+pub(super) fn select_coins_bnb(
+    utxo_pool: &[CoinOutput],
+    selection_target: u64,
+    cost_of_change: u64,
+) -> Option<Vec<usize>> {
+    // sort by descending effective value, the sort is stable so ties keep their order
+    let mut sorted = positive_coins(utxo_pool);
+    sorted.sort_by_key(|&index| Reverse(utxo_pool[index].get_effective_value()));
+    let coin = |position: usize| &utxo_pool[sorted[position]];
+
+    let mut curr_available_value = sorted
+        .iter()
+        .map(|&index| utxo_pool[index].get_effective_value())
+        .sum::<u64>();
+    if curr_available_value < selection_target {
+        return None;
+    }
+
+    let mut curr_value = 0;
+    let mut curr_waste = 0;
+    // positions in the sorted pool
+    let mut curr_selection: Vec<usize> = vec![];
+    let mut best_selection = vec![];
+    let mut best_waste = i64::MAX;
+    let is_feerate_high = sorted.first().map_or(false, |&index| {
+        let utxo = &utxo_pool[index];
+        utxo.fee > utxo.long_term_fee
+    });
+
+    // depth first search loop for choosing the utxos
+    let mut utxo_pool_index = 0;
+    for _ in 0..TOTAL_TRIES {
+        let mut backtrack = false;
+        if curr_value + curr_available_value < selection_target
+            || curr_value > selection_target + cost_of_change
+            || (curr_waste > best_waste && is_feerate_high)
+        {
+            // cannot possibly reach the target, or the selection is worse than the best one
+            backtrack = true;
+        } else if curr_value >= selection_target {
+            // the excess is waste since it goes to fees
+            let waste = curr_waste + (curr_value - selection_target) as i64;
+            if waste <= best_waste {
+                best_selection = curr_selection.clone();
+                best_waste = waste;
+            }
+            backtrack = true;
+        }
+
+        if backtrack {
+            let last = match curr_selection.last() {
+                Some(&last) => last,
+                // we have walked back to the first utxo and no branch is untraversed
+                None => break,
+            };
+            // add omitted utxos back before traversing the omission branch of the last included utxo
+            utxo_pool_index -= 1;
+            while utxo_pool_index > last {
+                curr_available_value += coin(utxo_pool_index).get_effective_value();
+                utxo_pool_index -= 1;
+            }
+            // the utxo was included on previous iterations, try excluding now
+            let utxo = coin(utxo_pool_index);
+            curr_value -= utxo.get_effective_value();
+            curr_waste -= utxo.get_waste();
+            curr_selection.pop();
+        } else {
+            let utxo = coin(utxo_pool_index);
+            curr_available_value -= utxo.get_effective_value();
+            // avoid searching a branch if the previous utxo has the same value and
+            // same waste and was excluded, since that would yield the same result
+            let is_equivalent_to_excluded = match curr_selection.last() {
+                None => false,
+                Some(&last) if last + 1 == utxo_pool_index => false,
+                Some(_) => {
+                    let prev_utxo = coin(utxo_pool_index - 1);
+                    utxo.get_effective_value() == prev_utxo.get_effective_value() && utxo.fee == prev_utxo.fee
+                }
+            };
+            if !is_equivalent_to_excluded {
+                // inclusion branch first (largest first exploration)
+                curr_selection.push(utxo_pool_index);
+                curr_value += utxo.get_effective_value();
+                curr_waste += utxo.get_waste();
+            }
+        }
+        utxo_pool_index += 1;
+    }
+
+    if best_selection.is_empty() {
+        None
+    } else {
+        Some(best_selection.into_iter().map(|position| sorted[position]).collect())
+    }
+}
+
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/wallet/coinselection.cpp
+fn approximate_best_subset<R: Rng>(
+    values: &[u64],
+    total_lower: u64,
+    target_value: u64,
+    rng: &mut R,
+) -> (Vec<bool>, u64) {
+    let mut best = vec![true; values.len()];
+    let mut n_best = total_lower;
+
+    for _ in 0..APPROXIMATE_BEST_SUBSET_ITERATIONS {
+        if n_best == target_value {
+            break;
+        }
+
+        let mut included = vec![false; values.len()];
+        let mut n_total = 0;
+        let mut reached_target = false;
+        for n_pass in 0..2 {
+            if reached_target {
+                break;
+            }
+            for (i, value) in values.iter().enumerate() {
+                // the randomness serves no real security purpose but is just
+                // needed to prevent degenerate behavior
+                let include = if n_pass == 0 { rng.gen::<bool>() } else { !included[i] };
+                if include {
+                    n_total += value;
+                    included[i] = true;
+                    if n_total >= target_value {
+                        reached_target = true;
+                        if n_total < n_best {
+                            n_best = n_total;
+                            best = included.clone();
+                        }
+                        n_total -= value;
+                        included[i] = false;
+                    }
+                }
+            }
+        }
+    }
+
+    (best, n_best)
+}
+
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/wallet/coinselection.cpp
+pub(super) fn knapsack_solver<R: Rng>(
+    utxo_pool: &[CoinOutput],
+    target_value: u64,
+    change_target: u64,
+    rng: &mut R,
+) -> Option<Vec<usize>> {
+    let mut shuffled = positive_coins(utxo_pool);
+    shuffled.shuffle(rng);
+    let effective_value = |index: usize| utxo_pool[index].get_effective_value();
+
+    let mut lowest_larger: Option<usize> = None;
+    let mut applicable_groups = vec![];
+    let mut total_lower = 0;
+    for index in shuffled {
+        let value = effective_value(index);
+        if value == target_value {
+            return Some(vec![index]);
+        } else if value < target_value + change_target {
+            applicable_groups.push(index);
+            total_lower += value;
+        } else if lowest_larger.map_or(true, |lowest_larger| value < effective_value(lowest_larger)) {
+            lowest_larger = Some(index);
+        }
+    }
+
+    if total_lower == target_value {
+        return Some(applicable_groups);
+    }
+
+    if total_lower < target_value {
+        return lowest_larger.map(|index| vec![index]);
+    }
+
+    // solve subset sum by stochastic approximation
+    applicable_groups.sort_by_key(|&index| Reverse(effective_value(index)));
+    let values = applicable_groups
+        .iter()
+        .map(|&index| effective_value(index))
+        .collect::<Vec<_>>();
+    let (mut best, mut n_best) = approximate_best_subset(&values, total_lower, target_value, rng);
+    if n_best != target_value && total_lower >= target_value + change_target {
+        (best, n_best) = approximate_best_subset(&values, total_lower, target_value + change_target, rng);
+    }
+
+    // if we have a bigger coin and (either the stochastic approximation didn't find
+    // a good solution, or the next bigger coin is closer), return the bigger coin
+    match lowest_larger {
+        Some(index)
+            if (n_best != target_value && n_best < target_value + change_target)
+                || effective_value(index) <= n_best =>
+        {
+            Some(vec![index])
+        }
+        _ => Some(
+            applicable_groups
+                .into_iter()
+                .zip(best)
+                .filter_map(|(index, is_included)| is_included.then_some(index))
+                .collect(),
+        ),
+    }
+}
+
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/wallet/coinselection.cpp
+pub(super) fn select_coins_srd<R: Rng>(utxo_pool: &[CoinOutput], target_value: u64, rng: &mut R) -> Option<Vec<usize>> {
+    let mut shuffled = positive_coins(utxo_pool);
+    shuffled.shuffle(rng);
+
+    let mut selected = vec![];
+    let mut selected_eff_value = 0;
+    for index in shuffled {
+        selected_eff_value += utxo_pool[index].get_effective_value();
+        selected.push(index);
+        if selected_eff_value >= target_value {
+            return Some(selected);
+        }
+    }
+    None
+}
+
+impl CoinSelectionAlgorithm {
+    /// Returns the indices of the coins in `utxo_pool` that should be spent
+    /// to fund `selection_target`, or `None` if the funds are insufficient.
+    // https://github.com/bitcoin/bitcoin/blob/v24.0/src/wallet/spend.cpp
+    pub(super) fn select_coins<R: Rng>(
+        &self,
+        utxo_pool: &[CoinOutput],
+        selection_target: u64,
+        params: &CoinSelectionParams,
+        rng: &mut R,
+    ) -> Option<Vec<usize>> {
+        let bnb = || select_coins_bnb(utxo_pool, selection_target, params.cost_of_change);
+        let knapsack = |rng: &mut R| knapsack_solver(utxo_pool, selection_target, params.min_change_target, rng);
+        // make sure the selection leaves enough value for a change output
+        let srd_target = selection_target + params.change_fee + CHANGE_LOWER;
+        let srd = |rng: &mut R| select_coins_srd(utxo_pool, srd_target, rng);
+
+        let results = match self {
+            Self::LowestWaste => vec![bnb(), knapsack(rng), srd(rng)],
+            Self::BranchAndBound => vec![bnb().or_else(|| knapsack(rng))],
+            Self::Knapsack => vec![knapsack(rng)],
+            Self::SingleRandomDraw => vec![srd(rng).or_else(|| knapsack(rng))],
+        };
+
+        results
+            .into_iter()
+            .flatten()
+            .map(|selection| {
+                let mut select_coins = SelectCoins::new(selection_target);
+                for &index in &selection {
+                    select_coins.add(utxo_pool[index]);
+                }
+                (select_coins.get_waste(params), selection)
+            })
+            // prefer the result with more inputs if the waste is equal
+            .min_by_key(|(waste, selection)| (*waste, Reverse(selection.len())))
+            .map(|(_, selection)| selection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn coin(value: u64) -> CoinOutput {
+        CoinOutput {
+            value,
+            fee: 0,
+            long_term_fee: 0,
+        }
+    }
+
+    fn selected_values(utxo_pool: &[CoinOutput], selection: Option<Vec<usize>>) -> Option<Vec<u64>> {
+        selection.map(|selection| {
+            let mut values = selection
+                .into_iter()
+                .map(|index| utxo_pool[index].value)
+                .collect::<Vec<_>>();
+            values.sort_unstable();
+            values
+        })
+    }
+
+    #[test]
+    fn should_get_waste() {
+        let params = CoinSelectionParams {
+            change_fee: 5,
+            cost_of_change: 20,
+            min_viable_change: 10,
+            min_change_target: CHANGE_LOWER,
+        };
+        let mut select_coins = SelectCoins::new(50);
+        select_coins.add(CoinOutput {
+            value: 60,
+            fee: 4,
+            long_term_fee: 1,
+        });
+        // no change, the excess of 6 is dropped to fees
+        assert_eq!(select_coins.get_waste(&params), 3 + 6);
+
+        select_coins.add(CoinOutput {
+            value: 30,
+            fee: 1,
+            long_term_fee: 3,
+        });
+        // change is created so we pay the cost of change instead
+        assert_eq!(select_coins.get_waste(&params), 3 - 2 + 20);
+    }
+
+    #[test]
+    fn should_select_coins_bnb() {
+        let utxo_pool = [1, 2, 3, 4].map(|value| coin(value * 100_000));
+
+        // exact matches
+        assert_eq!(
+            selected_values(&utxo_pool, select_coins_bnb(&utxo_pool, 100_000, 0)),
+            Some(vec![100_000])
+        );
+        assert_eq!(
+            selected_values(&utxo_pool, select_coins_bnb(&utxo_pool, 900_000, 0)),
+            Some(vec![200_000, 300_000, 400_000])
+        );
+        assert_eq!(
+            selected_values(&utxo_pool, select_coins_bnb(&utxo_pool, 1_000_000, 0)),
+            Some(vec![100_000, 200_000, 300_000, 400_000])
+        );
+
+        // within the cost of change
+        assert_eq!(
+            selected_values(&utxo_pool, select_coins_bnb(&utxo_pool, 850_000, 60_000)),
+            Some(vec![200_000, 300_000, 400_000])
+        );
+
+        // no changeless solution
+        assert_eq!(select_coins_bnb(&utxo_pool, 850_000, 10_000), None);
+        // insufficient funds
+        assert_eq!(select_coins_bnb(&utxo_pool, 1_100_000, 0), None);
+    }
+
+    #[test]
+    fn should_select_coins_bnb_with_least_waste() {
+        // spending more inputs is wasteful at a high feerate
+        let utxo_pool = [
+            CoinOutput {
+                value: 510,
+                fee: 10,
+                long_term_fee: 5,
+            },
+            CoinOutput {
+                value: 310,
+                fee: 10,
+                long_term_fee: 5,
+            },
+            CoinOutput {
+                value: 210,
+                fee: 10,
+                long_term_fee: 5,
+            },
+        ];
+        assert_eq!(
+            selected_values(&utxo_pool, select_coins_bnb(&utxo_pool, 500, 0)),
+            Some(vec![510])
+        );
+    }
+
+    #[test]
+    fn should_select_coins_knapsack() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let utxo_pool = [1, 2, 5, 10, 20].map(|value| coin(value * 100_000));
+
+        // exact match for a single coin
+        assert_eq!(
+            selected_values(&utxo_pool, knapsack_solver(&utxo_pool, 500_000, CHANGE_LOWER, &mut rng)),
+            Some(vec![500_000])
+        );
+        // all smaller coins sum to the target
+        assert_eq!(
+            selected_values(
+                &utxo_pool,
+                knapsack_solver(&utxo_pool, 3_800_000, CHANGE_LOWER, &mut rng)
+            ),
+            Some(vec![100_000, 200_000, 500_000, 1_000_000, 2_000_000])
+        );
+        // the smaller coins can't reach the target so use the next larger coin
+        assert_eq!(
+            selected_values(
+                &utxo_pool,
+                knapsack_solver(&utxo_pool, 1_900_000, CHANGE_LOWER, &mut rng)
+            ),
+            Some(vec![2_000_000])
+        );
+        // subset of the smaller coins that leaves enough change
+        assert_eq!(
+            selected_values(&utxo_pool, knapsack_solver(&utxo_pool, 550_000, CHANGE_LOWER, &mut rng)),
+            Some(vec![100_000, 500_000])
+        );
+        // insufficient funds
+        assert_eq!(knapsack_solver(&utxo_pool, 4_000_000, CHANGE_LOWER, &mut rng), None);
+    }
+
+    #[test]
+    fn should_select_coins_srd() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let utxo_pool = [1, 2, 5, 10, 20].map(|value| coin(value * 100_000));
+
+        let selection = select_coins_srd(&utxo_pool, 1_500_000, &mut rng).expect("funds are sufficient");
+        assert!(selection.iter().map(|&index| utxo_pool[index].value).sum::<u64>() >= 1_500_000);
+        // the last drawn coin is needed to reach the target
+        let without_last = &selection[..selection.len() - 1];
+        assert!(without_last.iter().map(|&index| utxo_pool[index].value).sum::<u64>() < 1_500_000);
+
+        assert_eq!(select_coins_srd(&utxo_pool, 4_000_000, &mut rng), None);
+    }
+
+    #[test]
+    fn should_parse_coin_selection_algorithm() {
+        assert_eq!(
+            "branch-and-bound".parse::<CoinSelectionAlgorithm>(),
+            Ok(CoinSelectionAlgorithm::BranchAndBound)
+        );
+        assert!("greedy".parse::<CoinSelectionAlgorithm>().is_err());
+    }
+}
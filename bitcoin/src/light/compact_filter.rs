@@ -0,0 +1,316 @@
+use super::*;
+use crate::electrs::CompactFilterClient;
+use bitcoincore_rpc::bitcoin::merkle_tree::MerkleBlock;
+use tokio::time::timeout;
+
+/// Find `txid` in `block`.
+fn find_transaction(block: Block, txid: &Txid) -> Result<Transaction, BitcoinError> {
+    block
+        .txdata
+        .into_iter()
+        .find(|transaction| transaction.txid() == *txid)
+        .ok_or_else(|| ElectrsError::TxNotFound(*txid).into())
+}
+
+/// Serialized `MerkleBlock` proving the inclusion of `txid` in `block`.
+fn get_merkle_proof(block: &Block, txid: &Txid) -> Vec<u8> {
+    serialize(&MerkleBlock::from_block_with_predicate(block, |t| t == txid))
+}
+
+/// Light client that syncs headers and compact block filters from a bitcoin node
+/// started with `-peerblockfilters` instead of querying an indexer. The wallet is
+/// the same as [`BitcoinLight`]'s, but transactions and proofs in a known block are
+/// taken from the block downloaded from the node, so they are also found for
+/// transactions that don't touch a watched script, like the coinbase.
+#[derive(Clone)]
+pub struct BitcoinCompactFilter {
+    light: BitcoinLight,
+    client: CompactFilterClient,
+}
+
+impl BitcoinCompactFilter {
+    /// Connect to the node at `p2p://host:port`, an optional `birthday` query
+    /// parameter sets the height from which the wallet is scanned.
+    pub fn new(
+        peer_url: &str,
+        private_key: PrivateKey,
+        key_store_file: Option<KeyStoreFile>,
+        coin_selection: CoinSelectionAlgorithm,
+    ) -> Result<Self, Error> {
+        let client = CompactFilterClient::new(peer_url, private_key.network)?;
+        let light = BitcoinLight::from_electrs(Arc::new(client.clone()), private_key, key_store_file, coin_selection)?;
+        Ok(Self { light, client })
+    }
+
+    pub fn set_external_signer(mut self, external_signer: Option<DynExternalSigner>) -> Self {
+        self.light = self.light.set_external_signer(external_signer);
+        self
+    }
+
+    pub fn set_long_term_fee_rate(mut self, fee_rate: SatPerVbyte) -> Self {
+        self.light = self.light.set_long_term_fee_rate(fee_rate);
+        self
+    }
+
+    async fn get_transaction_proof(
+        &self,
+        txid: Txid,
+        block_hash: BlockHash,
+    ) -> Result<RawTransactionProof, BitcoinError> {
+        let block = self.client.get_block(&block_hash).await?;
+        let coinbase_tx = block.txdata.first().ok_or(BitcoinError::CoinbaseFetchingFailure)?;
+        let user_tx = block
+            .txdata
+            .iter()
+            .find(|transaction| transaction.txid() == txid)
+            .ok_or(ElectrsError::TxNotFound(txid))?;
+        Ok(RawTransactionProof {
+            coinbase_tx_proof: get_merkle_proof(&block, &coinbase_tx.txid()),
+            raw_coinbase_tx: serialize(coinbase_tx),
+            user_tx_proof: get_merkle_proof(&block, &txid),
+            raw_user_tx: serialize(user_tx),
+        })
+    }
+}
+
+#[async_trait]
+impl BitcoinCoreApi for BitcoinCompactFilter {
+    fn is_full_node(&self) -> bool {
+        false
+    }
+
+    fn network(&self) -> Network {
+        self.light.network()
+    }
+
+    async fn wait_for_block(&self, height: u32, num_confirmations: u32) -> Result<Block, BitcoinError> {
+        self.light.wait_for_block(height, num_confirmations).await
+    }
+
+    // the headers are synced from the node, so poll until it announces the block
+    async fn wait_for_new_block(&self, height: u32, max_wait: Duration) {
+        let _ = timeout(max_wait, async {
+            while !matches!(self.client.get_blocks_tip_height().await, Ok(tip_height) if tip_height >= height) {
+                sleep(RETRY_DURATION).await;
+            }
+        })
+        .await;
+    }
+
+    async fn get_block_count(&self) -> Result<u64, BitcoinError> {
+        self.light.get_block_count().await
+    }
+
+    async fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, BitcoinError> {
+        self.light.get_balance(min_confirmations).await
+    }
+
+    async fn list_transactions(
+        &self,
+        max_count: Option<usize>,
+    ) -> Result<Vec<json::ListTransactionResult>, BitcoinError> {
+        self.light.list_transactions(max_count).await
+    }
+
+    async fn get_raw_tx(&self, txid: &Txid, block_hash: &BlockHash) -> Result<Vec<u8>, BitcoinError> {
+        let block = self.client.get_block(block_hash).await?;
+        Ok(serialize(&find_transaction(block, txid)?))
+    }
+
+    async fn get_transaction(&self, txid: &Txid, block_hash: Option<BlockHash>) -> Result<Transaction, BitcoinError> {
+        match block_hash {
+            Some(block_hash) => find_transaction(self.client.get_block(&block_hash).await?, txid),
+            // only transactions of the wallet are indexed
+            None => self.light.get_transaction(txid, None).await,
+        }
+    }
+
+    async fn get_proof(&self, txid: Txid, block_hash: &BlockHash) -> Result<Vec<u8>, BitcoinError> {
+        let block = self.client.get_block(block_hash).await?;
+        Ok(get_merkle_proof(&block, &txid))
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<BlockHash, BitcoinError> {
+        self.light.get_block_hash(height).await
+    }
+
+    async fn get_new_address(&self) -> Result<Address, BitcoinError> {
+        self.light.get_new_address().await
+    }
+
+    async fn get_new_public_key(&self) -> Result<PublicKey, BitcoinError> {
+        self.light.get_new_public_key().await
+    }
+
+    fn dump_derivation_key(&self, public_key: &PublicKey) -> Result<PrivateKey, BitcoinError> {
+        self.light.dump_derivation_key(public_key)
+    }
+
+    fn import_derivation_key(&self, private_key: &PrivateKey) -> Result<(), BitcoinError> {
+        self.light.import_derivation_key(private_key)
+    }
+
+    async fn add_new_deposit_key(&self, public_key: PublicKey, secret_key: Vec<u8>) -> Result<(), BitcoinError> {
+        self.light.add_new_deposit_key(public_key, secret_key).await
+    }
+
+    async fn get_best_block_hash(&self) -> Result<BlockHash, BitcoinError> {
+        self.light.get_best_block_hash().await
+    }
+
+    async fn get_pruned_height(&self) -> Result<u64, BitcoinError> {
+        self.light.get_pruned_height().await
+    }
+
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, BitcoinError> {
+        self.light.get_block(hash).await
+    }
+
+    async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, BitcoinError> {
+        self.light.get_block_header(hash).await
+    }
+
+    async fn get_mempool_transactions<'a>(
+        &'a self,
+    ) -> Result<Box<dyn Iterator<Item = Result<Transaction, BitcoinError>> + Send + 'a>, BitcoinError> {
+        self.light.get_mempool_transactions().await
+    }
+
+    async fn wait_for_transaction_metadata(
+        &self,
+        txid: Txid,
+        num_confirmations: u32,
+        _block_hash: Option<BlockHash>,
+        _is_wallet: bool,
+    ) -> Result<TransactionMetadata, BitcoinError> {
+        let (block_hash, fee) = self.light.wait_for_confirmations(txid, num_confirmations).await?;
+        let proof = retry(get_exponential_backoff(), || async {
+            Ok(self.get_transaction_proof(txid, block_hash).await?)
+        })
+        .await?;
+
+        Ok(TransactionMetadata {
+            txid,
+            proof,
+            block_hash,
+            fee: Some(fee),
+        })
+    }
+
+    async fn bump_fee(&self, txid: &Txid, address: Address, fee_rate: SatPerVbyte) -> Result<Txid, BitcoinError> {
+        self.light.bump_fee(txid, address, fee_rate).await
+    }
+
+    async fn bump_fee_with_child(
+        &self,
+        txid: &Txid,
+        address: Address,
+        fee_rate: SatPerVbyte,
+    ) -> Result<Txid, BitcoinError> {
+        self.light.bump_fee_with_child(txid, address, fee_rate).await
+    }
+
+    async fn create_and_send_transaction(
+        &self,
+        address: Address,
+        sat: u64,
+        fee_rate: SatPerVbyte,
+        request_id: Option<H256>,
+    ) -> Result<Txid, BitcoinError> {
+        self.light
+            .create_and_send_transaction(address, sat, fee_rate, request_id)
+            .await
+    }
+
+    async fn send_to_address(
+        &self,
+        address: Address,
+        sat: u64,
+        request_id: Option<H256>,
+        fee_rate: SatPerVbyte,
+        num_confirmations: u32,
+    ) -> Result<TransactionMetadata, BitcoinError> {
+        let txid = self
+            .create_and_send_transaction(address, sat, fee_rate, request_id)
+            .await?;
+
+        self.wait_for_transaction_metadata(txid, num_confirmations, None, true)
+            .await
+    }
+
+    async fn create_or_load_wallet(&self) -> Result<(), BitcoinError> {
+        self.light.create_or_load_wallet().await
+    }
+
+    async fn rescan_blockchain(&self, start_height: usize, end_height: usize) -> Result<(), BitcoinError> {
+        self.light.rescan_blockchain(start_height, end_height).await
+    }
+
+    async fn rescan_electrs_for_addresses(&self, addresses: Vec<Address>) -> Result<(), BitcoinError> {
+        self.light.rescan_electrs_for_addresses(addresses).await
+    }
+
+    async fn get_utxo_count(&self) -> Result<usize, BitcoinError> {
+        self.light.get_utxo_count().await
+    }
+
+    async fn consolidate_utxos(
+        &self,
+        max_inputs: usize,
+        max_utxo_value: u64,
+        fee_rate: SatPerVbyte,
+    ) -> Result<Option<Txid>, BitcoinError> {
+        self.light.consolidate_utxos(max_inputs, max_utxo_value, fee_rate).await
+    }
+
+    async fn is_in_mempool(&self, txid: Txid) -> Result<bool, BitcoinError> {
+        self.light.is_in_mempool(txid).await
+    }
+
+    async fn fee_rate(&self, txid: Txid) -> Result<SatPerVbyte, BitcoinError> {
+        self.light.fee_rate(txid).await
+    }
+
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<SatPerVbyte, BitcoinError> {
+        self.light.estimate_fee_rate(target_blocks).await
+    }
+
+    async fn get_tx_for_op_return(
+        &self,
+        address: Address,
+        amount: u128,
+        data: H256,
+    ) -> Result<Option<Txid>, BitcoinError> {
+        self.light.get_tx_for_op_return(address, amount, data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::blockdata::constants::genesis_block;
+
+    #[test]
+    fn should_find_transaction_and_proof_in_block() {
+        let block = genesis_block(Network::Regtest);
+        let coinbase_txid = block.txdata[0].txid();
+
+        assert_eq!(
+            find_transaction(block.clone(), &coinbase_txid).unwrap(),
+            block.txdata[0]
+        );
+        assert!(matches!(
+            find_transaction(block.clone(), &Txid::all_zeros()),
+            Err(BitcoinError::ElectrsError(ElectrsError::TxNotFound(_)))
+        ));
+
+        let merkle_block: MerkleBlock = deserialize(&get_merkle_proof(&block, &coinbase_txid)).unwrap();
+        let mut matches = vec![];
+        let mut indexes = vec![];
+        assert_eq!(
+            merkle_block.txn.extract_matches(&mut matches, &mut indexes).unwrap(),
+            block.header.merkle_root
+        );
+        assert_eq!(matches, vec![coinbase_txid]);
+    }
+}
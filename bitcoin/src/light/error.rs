@@ -1,8 +1,11 @@
 use crate::{
-    address::Error as AddressError, psbt::Error as PsbtError, secp256k1::Error as Secp256k1Error, ElectrsError,
+    address::Error as AddressError, key::Error as KeyError, psbt::Error as PsbtError,
+    secp256k1::Error as Secp256k1Error, ElectrsError,
 };
 use bitcoincore_rpc::bitcoin::sighash::Error as SighashError;
-use std::sync::PoisonError;
+use hex::FromHexError;
+use serde_json::Error as SerdeJsonError;
+use std::{io::Error as IoError, sync::PoisonError};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -21,6 +24,12 @@ pub enum Error {
     NoChangeAddress,
     #[error("Cannot open key store")]
     CannotOpenKeyStore,
+    #[error("Cannot encrypt key store")]
+    KeyStoreEncryption,
+    #[error("Cannot decrypt key store, is the passphrase correct?")]
+    KeyStoreDecryption,
+    #[error("Unsupported key store version: {0}")]
+    UnsupportedKeyStoreVersion(u8),
 
     #[error("Secp256k1Error: {0}")]
     Secp256k1Error(#[from] Secp256k1Error),
@@ -34,6 +43,15 @@ pub enum Error {
 
     #[error("SighashError: {0}")]
     SighashError(#[from] SighashError),
+
+    #[error("IoError: {0}")]
+    IoError(#[from] IoError),
+    #[error("SerdeJsonError: {0}")]
+    SerdeJsonError(#[from] SerdeJsonError),
+    #[error("FromHexError: {0}")]
+    FromHexError(#[from] FromHexError),
+    #[error("KeyError: {0}")]
+    KeyError(#[from] KeyError),
 }
 
 impl<T> From<PoisonError<T>> for Error {
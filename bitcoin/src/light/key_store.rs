@@ -0,0 +1,221 @@
+use super::error::Error;
+use crate::{Network, PrivateKey};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryInto,
+    fs,
+    io::Write,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const KEY_STORE_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+// https://cheatsheetseries.owasp.org/cheatsheets/Password_Storage_Cheat_Sheet.html#pbkdf2
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+#[derive(Serialize, Deserialize)]
+struct EncryptedKeyStore {
+    version: u8,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8]) -> Result<LessSafeKey, Error> {
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).expect("iterations is non-zero; qed"),
+        salt,
+        passphrase,
+        &mut key,
+    );
+    let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &key).map_err(|_| Error::KeyStoreEncryption)?;
+    Ok(LessSafeKey::new(unbound_key))
+}
+
+/// Encrypted file in which the light wallet persists its private keys
+/// so that deposit addresses survive a restart.
+#[derive(Clone)]
+pub struct KeyStoreFile {
+    path: PathBuf,
+    salt: [u8; SALT_LEN],
+    key: Arc<LessSafeKey>,
+}
+
+impl KeyStoreFile {
+    /// Derive the encryption key from the passphrase, using the salt of the existing
+    /// file or a fresh salt if the file does not exist yet.
+    pub fn open(path: PathBuf, passphrase: &[u8]) -> Result<Self, Error> {
+        let salt = match read_encrypted(&path)? {
+            Some(encrypted) => hex::decode(encrypted.salt)?
+                .try_into()
+                .map_err(|_| Error::KeyStoreDecryption)?,
+            None => {
+                let mut salt = [0u8; SALT_LEN];
+                SystemRandom::new()
+                    .fill(&mut salt)
+                    .map_err(|_| Error::KeyStoreEncryption)?;
+                salt
+            }
+        };
+        let key = Arc::new(derive_key(passphrase, &salt)?);
+        Ok(Self { path, salt, key })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Decrypt all keys stored in the file, returns an empty list if
+    /// the file does not exist yet.
+    pub fn load(&self, network: Network) -> Result<Vec<PrivateKey>, Error> {
+        let encrypted = match read_encrypted(&self.path)? {
+            Some(encrypted) => encrypted,
+            None => return Ok(vec![]),
+        };
+
+        // the key was derived with the salt of the file when it was opened
+        if hex::decode(encrypted.salt)? != self.salt {
+            return Err(Error::KeyStoreDecryption);
+        }
+        let nonce =
+            Nonce::try_assume_unique_for_key(&hex::decode(encrypted.nonce)?).map_err(|_| Error::KeyStoreDecryption)?;
+        let mut in_out = hex::decode(encrypted.ciphertext)?;
+
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| Error::KeyStoreDecryption)?;
+
+        let wifs: Vec<String> = serde_json::from_slice(plaintext)?;
+        wifs.iter()
+            .map(|wif| {
+                // NOTE: the wif encoding does not distinguish regtest from testnet
+                let private_key = PrivateKey::from_wif(wif)?;
+                Ok(PrivateKey { network, ..private_key })
+            })
+            .collect()
+    }
+
+    /// Encrypt and write all keys to the file, replacing any previous contents.
+    pub fn store<'a>(&self, private_keys: impl Iterator<Item = &'a PrivateKey>) -> Result<(), Error> {
+        let wifs = private_keys.map(|private_key| private_key.to_wif()).collect::<Vec<_>>();
+        let mut in_out = serde_json::to_vec(&wifs)?;
+
+        // the nonce must never be reused with the same key
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| Error::KeyStoreEncryption)?;
+
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut in_out)
+            .map_err(|_| Error::KeyStoreEncryption)?;
+
+        let encrypted = EncryptedKeyStore {
+            version: KEY_STORE_VERSION,
+            salt: hex::encode(self.salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(in_out),
+        };
+
+        // write to a temporary file first so that a crash can't leave us with a truncated key store
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        {
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(&tmp_path)?;
+            file.write_all(&serde_json::to_vec(&encrypted)?)?;
+            file.sync_all()?;
+        }
+        fs::rename(tmp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+fn read_encrypted(path: &Path) -> Result<Option<EncryptedKeyStore>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let encrypted: EncryptedKeyStore = serde_json::from_slice(&fs::read(path)?)?;
+    if encrypted.version != KEY_STORE_VERSION {
+        return Err(Error::UnsupportedKeyStoreVersion(encrypted.version));
+    }
+    Ok(Some(encrypted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secp256k1::SecretKey;
+
+    fn new_key_store_file() -> KeyStoreFile {
+        let path = std::env::temp_dir().join(format!("key-store-{}.json", rand::random::<u64>()));
+        KeyStoreFile::open(path, b"correct horse battery staple").unwrap()
+    }
+
+    #[test]
+    fn should_store_and_load_keys() -> Result<(), Box<dyn std::error::Error>> {
+        let key_store_file = new_key_store_file();
+        assert!(key_store_file.load(Network::Regtest)?.is_empty());
+
+        let private_keys = vec![
+            PrivateKey::new(SecretKey::from_slice(&[1; 32])?, Network::Regtest),
+            PrivateKey::new(SecretKey::from_slice(&[2; 32])?, Network::Regtest),
+        ];
+        key_store_file.store(private_keys.iter())?;
+        assert_eq!(key_store_file.load(Network::Regtest)?, private_keys);
+
+        // reopening derives the same key from the salt in the file
+        let reopened = KeyStoreFile::open(key_store_file.path().to_path_buf(), b"correct horse battery staple")?;
+        assert_eq!(reopened.load(Network::Regtest)?, private_keys);
+
+        fs::remove_file(key_store_file.path())?;
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_salt_and_use_new_nonce() -> Result<(), Box<dyn std::error::Error>> {
+        let key_store_file = new_key_store_file();
+        let private_key = PrivateKey::new(SecretKey::from_slice(&[1; 32])?, Network::Regtest);
+
+        key_store_file.store([private_key].iter())?;
+        let first = read_encrypted(key_store_file.path())?.unwrap();
+        key_store_file.store([private_key].iter())?;
+        let second = read_encrypted(key_store_file.path())?.unwrap();
+        assert_eq!(first.salt, second.salt);
+        assert_ne!(first.nonce, second.nonce);
+
+        fs::remove_file(key_store_file.path())?;
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_load_keys_with_wrong_passphrase() -> Result<(), Box<dyn std::error::Error>> {
+        let key_store_file = new_key_store_file();
+        let private_key = PrivateKey::new(SecretKey::from_slice(&[1; 32])?, Network::Regtest);
+        key_store_file.store([private_key].iter())?;
+
+        let wrong_key_store_file = KeyStoreFile::open(key_store_file.path().to_path_buf(), b"wrong")?;
+        assert!(matches!(
+            wrong_key_store_file.load(Network::Regtest),
+            Err(Error::KeyStoreDecryption)
+        ));
+
+        fs::remove_file(key_store_file.path())?;
+        Ok(())
+    }
+}
@@ -1,50 +1,215 @@
+mod coin_selection;
+mod compact_filter;
 mod error;
+mod key_store;
 mod wallet;
 
 pub use crate::{Error as BitcoinError, *};
-use bitcoincore_rpc::bitcoin::secp256k1::Scalar;
+use bitcoincore_rpc::bitcoin::{psbt::PartiallySignedTransaction, secp256k1::Scalar, ScriptBuf};
+pub use coin_selection::CoinSelectionAlgorithm;
+pub use compact_filter::BitcoinCompactFilter;
 pub use error::Error;
+pub use key_store::KeyStoreFile;
 
 use async_trait::async_trait;
 use backoff::future::retry;
 use futures::future::{join_all, try_join, try_join4, try_join_all};
-use std::{convert::TryFrom, sync::Arc, time::Duration};
+use std::{collections::HashSet, convert::TryFrom, num::NonZeroU32, sync::Arc, time::Duration};
 use tokio::{sync::Mutex, time::sleep};
 
 const RETRY_DURATION: Duration = Duration::from_millis(1000);
 
+/// Number of confirmations as reported by Bitcoin Core, i.e.
+/// a transaction included in the tip has one confirmation.
+fn get_confirmations(tip_height: u32, height: Option<u32>) -> u32 {
+    height.map_or(0, |height| tip_height.saturating_add(1).saturating_sub(height))
+}
+
+/// Convert a transaction to the entries that Bitcoin Core's `listtransactions` would return
+/// for a wallet that owns `wallet_addresses`.
+fn get_wallet_transaction_details(
+    tx: &electrs::TransactionValue,
+    tip_height: u32,
+    wallet_addresses: &HashSet<String>,
+) -> Result<Vec<json::ListTransactionResult>, BitcoinError> {
+    let is_wallet_address = |address: &Option<String>| {
+        address
+            .as_ref()
+            .map_or(false, |address| wallet_addresses.contains(address))
+    };
+
+    let (height, blockhash, blocktime) = match tx.status {
+        Some(electrs::TransactionStatus {
+            confirmed: true,
+            block_height,
+            block_hash,
+            block_time,
+        }) => (block_height.map(u32::try_from).transpose()?, block_hash, block_time),
+        _ => (None, None, None),
+    };
+    let info = WalletTxInfo {
+        confirmations: i32::try_from(get_confirmations(tip_height, height))?,
+        blockhash,
+        blockindex: None,
+        blocktime: blocktime.map(Into::into),
+        blockheight: height,
+        txid: tx.txid,
+        time: blocktime.unwrap_or_default().into(),
+        timereceived: blocktime.unwrap_or_default().into(),
+        bip125_replaceable: json::Bip125Replaceable::Unknown,
+        wallet_conflicts: vec![],
+    };
+
+    // if any input spends from the wallet this is a payment made by us
+    let is_send = tx.vin.iter().any(|input| {
+        input
+            .prevout
+            .as_ref()
+            .map_or(false, |prevout| is_wallet_address(&prevout.scriptpubkey_address))
+    });
+    let fee = i64::try_from(tx.fee)?;
+
+    let mut details = vec![];
+    for (vout, output) in tx.vout.iter().enumerate() {
+        let value = i64::try_from(output.value)?;
+        let (category, amount, fee) = match (is_send, is_wallet_address(&output.scriptpubkey_address)) {
+            (true, false) => (
+                GetTransactionResultDetailCategory::Send,
+                SignedAmount::from_sat(-value),
+                Some(SignedAmount::from_sat(-fee)),
+            ),
+            (false, true) => (
+                GetTransactionResultDetailCategory::Receive,
+                SignedAmount::from_sat(value),
+                None,
+            ),
+            // skip change and unrelated outputs
+            _ => continue,
+        };
+        details.push(json::ListTransactionResult {
+            info: info.clone(),
+            detail: json::GetTransactionResultDetail {
+                address: output
+                    .scriptpubkey_address
+                    .as_ref()
+                    .and_then(|address| address.parse().ok()),
+                category,
+                amount,
+                label: None,
+                vout: u32::try_from(vout)?,
+                fee,
+                abandoned: None,
+            },
+            trusted: None,
+            comment: None,
+        });
+    }
+    Ok(details)
+}
+
 #[derive(Clone)]
 pub struct BitcoinLight {
     private_key: PrivateKey,
     secp_ctx: secp256k1::Secp256k1<secp256k1::All>,
-    electrs: ElectrsClient,
+    electrs: DynElectrsApi,
     transaction_creation_lock: Arc<Mutex<()>>,
     wallet: wallet::Wallet,
+    // if set, payments are signed by the signer instead of the wallet
+    external_signer: Option<DynExternalSigner>,
 }
 
 impl BitcoinLight {
-    pub fn new(electrs_url: Option<String>, private_key: PrivateKey) -> Result<Self, Error> {
-        let network = private_key.network;
-        let electrs_client = ElectrsClient::new(electrs_url, network)?;
-        let wallet = wallet::Wallet::new(network, electrs_client.clone());
+    pub fn new(
+        electrs_urls: Vec<String>,
+        electrs_quorum: usize,
+        electrs_cache_size: usize,
+        electrs_rate_limit: Option<NonZeroU32>,
+        private_key: PrivateKey,
+        key_store_file: Option<KeyStoreFile>,
+        coin_selection: CoinSelectionAlgorithm,
+    ) -> Result<Self, Error> {
+        let electrs_client = electrs::new_electrs_api(
+            electrs_urls,
+            electrs_quorum,
+            electrs_cache_size,
+            electrs_rate_limit,
+            private_key.network,
+        )?;
+        Self::from_electrs(electrs_client, private_key, key_store_file, coin_selection)
+    }
+
+    fn from_electrs(
+        electrs_client: DynElectrsApi,
+        private_key: PrivateKey,
+        key_store_file: Option<KeyStoreFile>,
+        coin_selection: CoinSelectionAlgorithm,
+    ) -> Result<Self, Error> {
+        let wallet = wallet::Wallet::new(
+            private_key.network,
+            electrs_client.clone(),
+            key_store_file,
+            coin_selection,
+        );
+        // restore the deposit keys from previous runs
+        wallet.load_keys()?;
         // store the derivation key so it can be used for change
         wallet.put_p2wpkh_key(private_key.inner)?;
+        wallet.put_p2tr_key(private_key.inner)?;
         Ok(Self {
             private_key,
             secp_ctx: secp256k1::Secp256k1::new(),
             electrs: electrs_client,
             transaction_creation_lock: Arc::new(Mutex::new(())),
             wallet,
+            external_signer: None,
         })
     }
 
-    fn get_change_address(&self) -> Result<Address, Error> {
-        self.wallet
-            .key_store
-            .read()?
-            .first_key_value()
-            .map(|(address, _)| address.clone())
-            .ok_or(Error::NoChangeAddress)
+    pub fn set_external_signer(mut self, external_signer: Option<DynExternalSigner>) -> Self {
+        self.external_signer = external_signer;
+        self
+    }
+
+    /// Fee rate at which the coins are expected to be spent in the long term, coin selection
+    /// avoids spending more inputs than needed while fees are above it.
+    pub fn set_long_term_fee_rate(mut self, fee_rate: SatPerVbyte) -> Self {
+        self.wallet.set_long_term_fee_rate(fee_rate.0.saturating_mul(1000));
+        self
+    }
+
+    fn get_addresses(&self) -> Result<Vec<Address>, Error> {
+        Ok(self.wallet.key_store.read()?.keys().cloned().collect())
+    }
+
+    async fn get_utxos(&self) -> Result<Vec<electrs::Utxo>, BitcoinError> {
+        Ok(self
+            .get_utxos_with_scripts()
+            .await?
+            .into_iter()
+            .map(|(utxo, _)| utxo)
+            .collect())
+    }
+
+    /// Unspent outputs of the wallet along with the script of the address they pay to.
+    async fn get_utxos_with_scripts(&self) -> Result<Vec<(electrs::Utxo, ScriptBuf)>, BitcoinError> {
+        let addresses = self.get_addresses()?;
+        let utxos = try_join_all(addresses.iter().map(|address| async move {
+            let utxos = self.electrs.get_utxos_for_address(address).await?;
+            Ok::<_, ElectrsError>(
+                utxos
+                    .into_iter()
+                    .map(|utxo| (utxo, address.script_pubkey()))
+                    .collect::<Vec<_>>(),
+            )
+        }))
+        .await?;
+        Ok(utxos.into_iter().flatten().collect())
+    }
+
+    /// Change is sent to the key path only taproot output of the derivation key.
+    fn get_change_address(&self) -> Address {
+        let (internal_key, _parity) = self.private_key.inner.x_only_public_key(&self.secp_ctx);
+        Address::p2tr(&self.secp_ctx, internal_key, None, self.network())
     }
 
     pub async fn fund_and_sign_transaction(
@@ -56,15 +221,48 @@ impl BitcoinLight {
         prev_txid: Option<Txid>,
     ) -> Result<LockedTransaction, BitcoinError> {
         let lock = self.transaction_creation_lock.clone().lock_owned().await;
-        let mut psbt = self
+        let expected_outputs = unsigned_tx.output.clone();
+        let psbt = self
             .wallet
             .fund_transaction(unsigned_tx, change_address, fee_rate.0.saturating_mul(1000), prev_txid)
             .await?;
-        self.wallet.sign_transaction(&mut psbt)?;
-        let signed_tx = psbt.extract_tx();
+        let signed_tx = self.sign_psbt(psbt, &expected_outputs).await?;
         Ok(LockedTransaction::new(signed_tx, recipient.to_string(), Some(lock)))
     }
 
+    async fn sign_psbt(
+        &self,
+        mut psbt: PartiallySignedTransaction,
+        expected_outputs: &[TxOut],
+    ) -> Result<Transaction, BitcoinError> {
+        match self.external_signer {
+            Some(ref external_signer) => {
+                Ok(signer::sign_with_external_signer(external_signer, psbt, expected_outputs).await?)
+            }
+            None => {
+                self.wallet.sign_transaction(&mut psbt)?;
+                Ok(psbt.extract_tx())
+            }
+        }
+    }
+
+    /// The fee paid by `tx`, i.e. the value of its inputs minus the value of its outputs.
+    async fn get_fee(&self, tx: &Transaction) -> Result<u64, BitcoinError> {
+        let recipients_sum = tx.output.iter().map(|tx_out| tx_out.value).sum::<u64>();
+
+        let inputs = try_join_all(tx.input.iter().map(|input| async move {
+            let prev_tx = self.get_transaction(&input.previous_output.txid, None).await?;
+            let prev_out = prev_tx
+                .output
+                .get(usize::try_from(input.previous_output.vout)?)
+                .ok_or(Error::NoPrevOut)?;
+            Ok::<u64, BitcoinError>(prev_out.value)
+        }))
+        .await?;
+        let input_sum = inputs.iter().sum::<u64>();
+        Ok(input_sum.saturating_sub(recipients_sum))
+    }
+
     pub async fn create_transaction(
         &self,
         recipient: Address,
@@ -73,7 +271,7 @@ impl BitcoinLight {
         request_id: Option<H256>,
     ) -> Result<LockedTransaction, BitcoinError> {
         let unsigned_tx = self.wallet.create_transaction(recipient.clone(), sat, request_id);
-        let change_address = self.get_change_address()?;
+        let change_address = self.get_change_address();
         self.fund_and_sign_transaction(recipient, unsigned_tx, change_address, fee_rate, None)
             .await
     }
@@ -83,6 +281,28 @@ impl BitcoinLight {
         Ok(txid)
     }
 
+    /// Wait until `txid` has `num_confirmations`, returns the hash of the
+    /// block that includes it and the fee it paid.
+    async fn wait_for_confirmations(
+        &self,
+        txid: Txid,
+        num_confirmations: u32,
+    ) -> Result<(BlockHash, SignedAmount), BitcoinError> {
+        retry(get_exponential_backoff(), || async {
+            Ok(match self.electrs.get_tx_info(&txid).await {
+                Ok(electrs::TxInfo {
+                    confirmations,
+                    hash,
+                    fee,
+                    ..
+                }) if confirmations >= num_confirmations => Ok((hash, fee)),
+                Ok(_) => Err(BitcoinError::ConfirmationError),
+                Err(_e) => Err(BitcoinError::ConnectionRefused),
+            }?)
+        })
+        .await
+    }
+
     async fn get_coinbase_txid(&self, block_hash: &BlockHash) -> Result<Txid, BitcoinError> {
         Ok(self.electrs.get_coinbase_txid(&block_hash).await?)
     }
@@ -114,8 +334,8 @@ impl BitcoinCoreApi for BitcoinLight {
                         continue;
                     }
                 }
-                _ => {
-                    // TODO: handle error
+                Err(err) => {
+                    log::warn!("Failed to fetch block at height {height}: {err}");
                     sleep(RETRY_DURATION).await;
                     continue;
                 }
@@ -127,14 +347,61 @@ impl BitcoinCoreApi for BitcoinLight {
         Ok(self.electrs.get_blocks_tip_height().await?.into())
     }
 
-    fn get_balance(&self, _min_confirmations: Option<u32>) -> Result<Amount, BitcoinError> {
-        // TODO: implement
-        Ok(Default::default())
+    async fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, BitcoinError> {
+        let utxos = self.get_utxos().await?;
+        let tip_height = self.electrs.get_blocks_tip_height().await?;
+        let min_confirmations = min_confirmations.unwrap_or_default();
+        Ok(Amount::from_sat(
+            utxos
+                .iter()
+                .filter(|utxo| get_confirmations(tip_height, utxo.height) >= min_confirmations)
+                .map(|utxo| utxo.value)
+                .sum(),
+        ))
     }
 
-    fn list_transactions(&self, _max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, BitcoinError> {
-        // TODO: implement
-        Ok(Default::default())
+    /// List the confirmed transactions of all addresses in the wallet, oldest first.
+    async fn list_transactions(
+        &self,
+        max_count: Option<usize>,
+    ) -> Result<Vec<json::ListTransactionResult>, BitcoinError> {
+        let wallet_addresses = self
+            .get_addresses()?
+            .iter()
+            .map(ToString::to_string)
+            .collect::<HashSet<_>>();
+        let histories = try_join_all(
+            wallet_addresses
+                .iter()
+                .map(|address| self.electrs.get_address_tx_history_full(address)),
+        )
+        .await?;
+        let tip_height = self.electrs.get_blocks_tip_height().await?;
+
+        // the same transaction may show up in the history of multiple addresses
+        let mut seen = HashSet::new();
+        let mut transactions = histories
+            .into_iter()
+            .flatten()
+            .filter(|tx| seen.insert(tx.txid))
+            .collect::<Vec<_>>();
+        transactions.sort_by_key(|tx| {
+            tx.status
+                .as_ref()
+                .and_then(|status| status.block_height)
+                .unwrap_or(usize::MAX)
+        });
+
+        let mut details = vec![];
+        for tx in transactions.iter() {
+            details.append(&mut get_wallet_transaction_details(tx, tip_height, &wallet_addresses)?);
+        }
+
+        // only return the most recent entries
+        if let Some(max_count) = max_count {
+            details.drain(..details.len().saturating_sub(max_count));
+        }
+        Ok(details)
     }
 
     async fn get_raw_tx(&self, txid: &Txid, _block_hash: &BlockHash) -> Result<Vec<u8>, BitcoinError> {
@@ -159,7 +426,11 @@ impl BitcoinCoreApi for BitcoinLight {
     }
 
     async fn get_new_address(&self) -> Result<Address, BitcoinError> {
-        Ok(self.get_change_address()?)
+        // taproot addresses can't be registered on the parachain
+        Ok(Address::p2wpkh(
+            &self.private_key.public_key(&self.secp_ctx),
+            self.network(),
+        )?)
     }
 
     async fn get_new_public_key(&self) -> Result<PublicKey, BitcoinError> {
@@ -222,19 +493,7 @@ impl BitcoinCoreApi for BitcoinLight {
         _block_hash: Option<BlockHash>,
         _is_wallet: bool,
     ) -> Result<TransactionMetadata, BitcoinError> {
-        let (block_hash, fee) = retry(get_exponential_backoff(), || async {
-            Ok(match self.electrs.get_tx_info(&txid).await {
-                Ok(electrs::TxInfo {
-                    confirmations,
-                    hash,
-                    fee,
-                    ..
-                }) if confirmations >= num_confirmations => Ok((hash, fee)),
-                Ok(_) => Err(BitcoinError::ConfirmationError),
-                Err(_e) => Err(BitcoinError::ConnectionRefused),
-            }?)
-        })
-        .await?;
+        let (block_hash, fee) = self.wait_for_confirmations(txid, num_confirmations).await?;
 
         let proof = retry(get_exponential_backoff(), || async {
             let coinbase_txid = self.get_coinbase_txid(&block_hash).await?;
@@ -265,12 +524,14 @@ impl BitcoinCoreApi for BitcoinLight {
 
     async fn bump_fee(&self, txid: &Txid, address: Address, fee_rate: SatPerVbyte) -> Result<Txid, BitcoinError> {
         let mut existing_transaction = self.get_transaction(txid, None).await?;
+        // without a return-to-self output the replacement gets new change
         let return_to_self = existing_transaction
             .extract_return_to_self_address(&address.payload)?
             .map(|(idx, payload)| {
                 existing_transaction.output.remove(idx);
                 Address::new(self.network(), payload)
-            });
+            })
+            .unwrap_or_else(|| self.get_change_address());
 
         // clear the witnesses for fee estimation
         existing_transaction
@@ -278,18 +539,56 @@ impl BitcoinCoreApi for BitcoinLight {
             .iter_mut()
             .for_each(|txin| txin.witness.clear());
         let tx = self
-            .fund_and_sign_transaction(
-                address,
-                existing_transaction,
-                return_to_self.unwrap(),
-                fee_rate,
-                Some(*txid),
-            )
+            .fund_and_sign_transaction(address, existing_transaction, return_to_self, fee_rate, Some(*txid))
             .await?;
         let txid = self.send_transaction(tx).await?;
         Ok(txid)
     }
 
+    async fn bump_fee_with_child(
+        &self,
+        txid: &Txid,
+        address: Address,
+        fee_rate: SatPerVbyte,
+    ) -> Result<Txid, BitcoinError> {
+        let parent = self.get_transaction(txid, None).await?;
+        let (vout, _) = parent
+            .extract_return_to_self_address(&address.payload)?
+            .ok_or(BitcoinError::NoReturnToSelfOutput)?;
+        let return_to_self = parent.output[vout].clone();
+        let parent_fee = self.get_fee(&parent).await?;
+
+        let lock = self.transaction_creation_lock.clone().lock_owned().await;
+        // send everything back to the same address, minus the fee
+        let mut child = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: *txid,
+                    vout: u32::try_from(vout)?,
+                },
+                sequence: bitcoincore_rpc::bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            }],
+            output: vec![return_to_self.clone()],
+        };
+        let child_vsize = get_max_child_vsize(&return_to_self.script_pubkey, &child)?;
+        let child_fee = get_child_fee(parent_fee, parent.weight().to_vbytes_ceil(), child_vsize, fee_rate);
+        child.output[0].value = return_to_self
+            .value
+            .checked_sub(child_fee)
+            .filter(|value| *value >= return_to_self.script_pubkey.dust_value().to_sat())
+            .ok_or(BitcoinError::CannotPayForParent)?;
+
+        let expected_outputs = child.output.clone();
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(child).map_err(Error::from)?;
+        psbt.inputs[0].witness_utxo = Some(return_to_self);
+        let signed_tx = self.sign_psbt(psbt, &expected_outputs).await?;
+        self.send_transaction(LockedTransaction::new(signed_tx, address.to_string(), Some(lock)))
+            .await
+    }
+
     async fn create_and_send_transaction(
         &self,
         address: Address,
@@ -334,37 +633,77 @@ impl BitcoinCoreApi for BitcoinLight {
         Ok(())
     }
 
-    fn get_utxo_count(&self) -> Result<usize, BitcoinError> {
-        // TODO: implement
-        Ok(Default::default())
+    async fn get_utxo_count(&self) -> Result<usize, BitcoinError> {
+        let utxos = self.get_utxos().await?;
+        // like `listunspent`, only count confirmed outputs
+        Ok(utxos.iter().filter(|utxo| utxo.height.is_some()).count())
+    }
+
+    async fn consolidate_utxos(
+        &self,
+        max_inputs: usize,
+        max_utxo_value: u64,
+        fee_rate: SatPerVbyte,
+    ) -> Result<Option<Txid>, BitcoinError> {
+        let lock = self.transaction_creation_lock.clone().lock_owned().await;
+
+        let mut utxos = self
+            .get_utxos_with_scripts()
+            .await?
+            .into_iter()
+            .filter(|(utxo, _)| utxo.height.is_some() && utxo.value <= max_utxo_value)
+            .collect::<Vec<_>>();
+        utxos.sort_by_key(|(utxo, _)| utxo.value);
+        utxos.truncate(max_inputs);
+        if utxos.len() < 2 {
+            return Ok(None);
+        }
+
+        let change_address = self.get_change_address();
+        let psbt = self.wallet.create_consolidation_transaction(
+            utxos,
+            change_address.clone(),
+            fee_rate.0.saturating_mul(1000),
+        )?;
+        if psbt.inputs.len() < 2 {
+            // the other utxos are not worth spending at this fee rate
+            return Ok(None);
+        }
+
+        // the only output pays the swept amount, minus the fee, back to the wallet
+        let expected_outputs = [TxOut {
+            value: psbt.unsigned_tx.output[0].value,
+            script_pubkey: change_address.script_pubkey(),
+        }];
+        let signed_tx = self.sign_psbt(psbt, &expected_outputs).await?;
+        let txid = self
+            .send_transaction(LockedTransaction::new(
+                signed_tx,
+                change_address.to_string(),
+                Some(lock),
+            ))
+            .await?;
+        Ok(Some(txid))
     }
 
     async fn is_in_mempool(&self, txid: Txid) -> Result<bool, BitcoinError> {
-        let txids = self.electrs.get_raw_mempool().await?;
-        Ok(txids.into_iter().any(|mempool_txid| mempool_txid == txid))
+        Ok(self.electrs.is_in_mempool(&txid).await?)
     }
 
     async fn fee_rate(&self, txid: Txid) -> Result<SatPerVbyte, BitcoinError> {
         let tx = self.get_transaction(&txid, None).await?;
         let vsize = tx.weight().to_vbytes_ceil();
-        let recipients_sum = tx.output.iter().map(|tx_out| tx_out.value).sum::<u64>();
-
-        let inputs = try_join_all(tx.input.iter().map(|input| async move {
-            let prev_tx = self.get_transaction(&input.previous_output.txid, None).await?;
-            let prev_out = prev_tx
-                .output
-                .get(usize::try_from(input.previous_output.vout)?)
-                .ok_or(Error::NoPrevOut)?;
-            Ok::<u64, BitcoinError>(prev_out.value)
-        }))
-        .await?;
-        let input_sum = inputs.iter().sum::<u64>();
-        let fee = input_sum.saturating_sub(recipients_sum);
+        let fee = self.get_fee(&tx).await?;
 
         let fee_rate = fee.checked_div(vsize).ok_or(BitcoinError::ArithmeticError)?;
         Ok(SatPerVbyte(fee_rate))
     }
 
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<SatPerVbyte, BitcoinError> {
+        let fee_rate = self.electrs.estimate_fee_rate(target_blocks).await?;
+        Ok(SatPerVbyte(fee_rate.ceil() as u64))
+    }
+
     async fn get_tx_for_op_return(
         &self,
         address: Address,
@@ -374,3 +713,204 @@ impl BitcoinCoreApi for BitcoinLight {
         Ok(self.electrs.get_tx_for_op_return(address, amount, data).await?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{EsploraServer, InMemoryChain};
+
+    // spends from bcrt1qxu0en0v9dsywqchvpr6g9aa5vh9wyeupys2ka8, pays bcrt1qnx8uakvjhyxyns3ft3tjfm0smt68frw2c9adgx
+    // with change to bcrt1qwz2x0729sswxhd3cl8ss0h3fx03pfuw9anc7ww
+    const TRANSACTION: &str = r#"{
+        "txid": "5fae6a3f1c2e0ff4cd0ec7f3c5ba7dbb4e7ab2bb7c3a9ec6a4dc4e8f1c0be7f3",
+        "version": 2,
+        "locktime": 0,
+        "vin": [{
+            "txid": "dcd25c1eb82783b323a7e6582a6a46edd9ff9ef7954e16a5ba5352f39c607189",
+            "vout": 0,
+            "prevout": {
+                "scriptpubkey": "0014371f99bd856c08e062ec08f482f7b465cae26781",
+                "scriptpubkey_asm": "OP_0 OP_PUSHBYTES_20 371f99bd856c08e062ec08f482f7b465cae26781",
+                "scriptpubkey_type": "v0_p2wpkh",
+                "scriptpubkey_address": "bcrt1qxu0en0v9dsywqchvpr6g9aa5vh9wyeupys2ka8",
+                "value": 5000000000
+            },
+            "scriptsig": "",
+            "scriptsig_asm": "",
+            "is_coinbase": false,
+            "sequence": 4294967293
+        }],
+        "vout": [{
+            "scriptpubkey": "0014998fced992b90c49c2295c5724edf0daf4748dca",
+            "scriptpubkey_asm": "OP_0 OP_PUSHBYTES_20 998fced992b90c49c2295c5724edf0daf4748dca",
+            "scriptpubkey_type": "v0_p2wpkh",
+            "scriptpubkey_address": "bcrt1qnx8uakvjhyxyns3ft3tjfm0smt68frw2c9adgx",
+            "value": 100000
+        }, {
+            "scriptpubkey": "0014709467f945841c6bb638f9e107de2933e214f1c5",
+            "scriptpubkey_asm": "OP_0 OP_PUSHBYTES_20 709467f945841c6bb638f9e107de2933e214f1c5",
+            "scriptpubkey_type": "v0_p2wpkh",
+            "scriptpubkey_address": "bcrt1qwz2x0729sswxhd3cl8ss0h3fx03pfuw9anc7ww",
+            "value": 4999897180
+        }],
+        "size": 222,
+        "weight": 561,
+        "fee": 2820,
+        "status": {
+            "confirmed": true,
+            "block_height": 100,
+            "block_hash": "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+            "block_time": 1600000000
+        }
+    }"#;
+
+    fn wallet_addresses(addresses: &[&str]) -> HashSet<String> {
+        addresses.iter().map(ToString::to_string).collect()
+    }
+
+    /// Light client connected to an esplora server on top of `chain`, funded with `sat`.
+    async fn new_bitcoin_light(chain: &InMemoryChain, sat: u64) -> (EsploraServer, BitcoinLight) {
+        let server = EsploraServer::start(chain.clone()).unwrap();
+        let private_key = PrivateKey::new(SecretKey::from_slice(&[1; 32]).unwrap(), Network::Regtest);
+        let bitcoin_light =
+            BitcoinLight::new(vec![server.url()], 1, 100, None, private_key, None, Default::default()).unwrap();
+
+        let address = bitcoin_light.get_new_address().await.unwrap();
+        chain.send_external_payment(&address, sat, None).unwrap();
+        chain.mine_blocks(1);
+        (server, bitcoin_light)
+    }
+
+    #[test]
+    fn should_get_confirmations() {
+        assert_eq!(get_confirmations(100, None), 0);
+        assert_eq!(get_confirmations(100, Some(100)), 1);
+        assert_eq!(get_confirmations(100, Some(95)), 6);
+    }
+
+    #[test]
+    fn should_list_send_details() -> Result<(), Box<dyn std::error::Error>> {
+        let tx: electrs::TransactionValue = serde_json::from_str(TRANSACTION)?;
+        let details = get_wallet_transaction_details(
+            &tx,
+            101,
+            &wallet_addresses(&[
+                "bcrt1qxu0en0v9dsywqchvpr6g9aa5vh9wyeupys2ka8",
+                "bcrt1qwz2x0729sswxhd3cl8ss0h3fx03pfuw9anc7ww",
+            ]),
+        )?;
+
+        // change is not listed
+        assert_eq!(details.len(), 1);
+        assert_eq!(details[0].info.confirmations, 2);
+        assert_eq!(details[0].detail.category, GetTransactionResultDetailCategory::Send);
+        assert_eq!(details[0].detail.vout, 0);
+        assert_eq!(details[0].detail.amount, SignedAmount::from_sat(-100000));
+        assert_eq!(details[0].detail.fee, Some(SignedAmount::from_sat(-2820)));
+        Ok(())
+    }
+
+    #[test]
+    fn should_list_receive_details() -> Result<(), Box<dyn std::error::Error>> {
+        let tx: electrs::TransactionValue = serde_json::from_str(TRANSACTION)?;
+        let details = get_wallet_transaction_details(
+            &tx,
+            100,
+            &wallet_addresses(&["bcrt1qnx8uakvjhyxyns3ft3tjfm0smt68frw2c9adgx"]),
+        )?;
+
+        assert_eq!(details.len(), 1);
+        assert_eq!(details[0].info.confirmations, 1);
+        assert_eq!(details[0].detail.category, GetTransactionResultDetailCategory::Receive);
+        assert_eq!(details[0].detail.vout, 0);
+        assert_eq!(details[0].detail.amount, SignedAmount::from_sat(100000));
+        assert_eq!(details[0].detail.fee, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn should_send_and_prove_payment() {
+        let chain = InMemoryChain::new();
+        let (_server, bitcoin_light) = new_bitcoin_light(&chain, 100_000).await;
+        assert_eq!(bitcoin_light.get_balance(Some(1)).await.unwrap().to_sat(), 100_000);
+        assert_eq!(bitcoin_light.get_utxo_count().await.unwrap(), 1);
+
+        let recipient = chain.get_new_address().await.unwrap();
+        let request_id = H256::repeat_byte(1);
+        let txid = bitcoin_light
+            .create_and_send_transaction(recipient.clone(), 40_000, SatPerVbyte(2), Some(request_id))
+            .await
+            .unwrap();
+        assert!(bitcoin_light.is_in_mempool(txid).await.unwrap());
+        let fee = chain.fee(&txid).unwrap();
+        assert!(bitcoin_light.fee_rate(txid).await.unwrap() >= SatPerVbyte(2));
+
+        let block_hash = chain.mine_blocks(1);
+        let metadata = bitcoin_light
+            .wait_for_transaction_metadata(txid, 0, None, true)
+            .await
+            .unwrap();
+        assert_eq!(metadata.block_hash, block_hash);
+        assert_eq!(metadata.fee, Some(SignedAmount::from_sat(fee as i64)));
+        assert_eq!(verify_transaction_proof(&metadata.proof).unwrap(), block_hash);
+
+        assert_eq!(
+            bitcoin_light
+                .get_tx_for_op_return(recipient, 40_000, request_id)
+                .await
+                .unwrap(),
+            Some(txid)
+        );
+        assert_eq!(
+            bitcoin_light.get_balance(None).await.unwrap().to_sat(),
+            100_000 - 40_000 - fee
+        );
+
+        // the payment and the op_return are listed, the change is not
+        let details = bitcoin_light.list_transactions(None).await.unwrap();
+        assert_eq!(
+            details
+                .iter()
+                .map(|details| details.detail.category.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                GetTransactionResultDetailCategory::Receive,
+                GetTransactionResultDetailCategory::Send,
+                GetTransactionResultDetailCategory::Send
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_bump_fee() {
+        let chain = InMemoryChain::new();
+        let (_server, bitcoin_light) = new_bitcoin_light(&chain, 100_000).await;
+
+        let recipient = chain.get_new_address().await.unwrap();
+        let txid = bitcoin_light
+            .create_and_send_transaction(recipient.clone(), 40_000, SatPerVbyte(1), None)
+            .await
+            .unwrap();
+        let replacement = bitcoin_light.bump_fee(&txid, recipient, SatPerVbyte(5)).await.unwrap();
+
+        assert!(!bitcoin_light.is_in_mempool(txid).await.unwrap());
+        assert!(bitcoin_light.is_in_mempool(replacement).await.unwrap());
+        assert!(bitcoin_light.fee_rate(replacement).await.unwrap() >= SatPerVbyte(5));
+    }
+
+    #[tokio::test]
+    async fn should_not_find_future_blocks() {
+        let chain = InMemoryChain::new();
+        let (_server, bitcoin_light) = new_bitcoin_light(&chain, 100_000).await;
+
+        assert_eq!(bitcoin_light.get_block_count().await.unwrap(), 1);
+        assert_eq!(
+            bitcoin_light.get_best_block_hash().await.unwrap(),
+            chain.get_best_block_hash().await.unwrap()
+        );
+        assert!(matches!(
+            bitcoin_light.get_block_hash(2).await,
+            Err(BitcoinError::InvalidBitcoinHeight)
+        ));
+    }
+}
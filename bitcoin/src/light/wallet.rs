@@ -1,21 +1,32 @@
-use super::{electrs::ElectrsClient, error::Error};
+use super::{
+    coin_selection::{generate_change_target, CoinOutput, CoinSelectionAlgorithm, CoinSelectionParams, SelectCoins},
+    electrs::DynElectrsApi,
+    error::Error,
+    key_store::KeyStoreFile,
+};
 use crate::{
+    deserialize,
     electrs::Utxo,
     hashes::Hash,
     json::bitcoin::sighash::EcdsaSighashType,
     opcodes, psbt,
     psbt::PartiallySignedTransaction,
-    secp256k1::{All, Message, Secp256k1, SecretKey},
-    Address, Builder as ScriptBuilder, EcdsaSig, LockTime, Network, NonStandardSighashType, OutPoint, PrivateKey,
-    Script, Transaction, TxIn, TxOut, Txid, VarInt, H256,
+    secp256k1::{constants::SCHNORR_SIGNATURE_SIZE, All, KeyPair, Message, Secp256k1, SecretKey},
+    Address, Builder as ScriptBuilder, EcdsaSig, ElectrsError, LockTime, Network, NonStandardSighashType, OutPoint,
+    PrivateKey, Script, Transaction, TxIn, TxOut, Txid, VarInt, H256,
 };
 use bitcoincore_rpc::bitcoin::{
-    blockdata::constants::WITNESS_SCALE_FACTOR, sighash::SighashCache, PublicKey, Sequence, Witness,
+    blockdata::constants::WITNESS_SCALE_FACTOR,
+    key::TapTweak,
+    sighash::{Prevouts, SighashCache},
+    taproot, PublicKey, ScriptBuf, Sequence, Witness,
 };
 use futures::{stream, Stream, StreamExt};
+use rand::{rngs::StdRng, SeedableRng};
 use std::{
-    collections::{BTreeMap, VecDeque},
-    sync::{Arc, RwLock},
+    collections::{BTreeMap, HashSet, VecDeque},
+    convert::TryFrom,
+    sync::{Arc, Mutex, RwLock},
 };
 
 trait GetSerializeSize {
@@ -54,7 +65,14 @@ impl GetSerializeSize for Witness {
 }
 
 // https://github.com/bitcoin/bitcoin/blob/607d5a46aa0f5053d8643a3e2c31a69bfdeb6e9f/src/script/sign.cpp#L611
-fn dummy_sign_input(txin: &mut TxIn, public_key: PublicKey) {
+fn dummy_sign_input(txin: &mut TxIn, script_pubkey: &Script, public_key: PublicKey) {
+    if script_pubkey.is_v1_p2tr() {
+        // key path spends only contain the schnorr signature, which
+        // has a fixed size and no sighash byte with `TapSighashType::Default`
+        txin.witness = Witness::from_slice(&[vec![0; SCHNORR_SIGNATURE_SIZE]]);
+        return;
+    }
+
     // create a dummy signature that is a valid DER-encoding
     let dummy_signature = {
         // it is possible to "grind the signature" to encode an r-value
@@ -76,7 +94,7 @@ fn dummy_sign_input(txin: &mut TxIn, public_key: PublicKey) {
         vch_sig
     };
 
-    // update input (only works with segwit for now)
+    // update input (only works with segwit v0 for now)
     txin.witness = Witness::from_slice(&[dummy_signature.to_vec(), public_key.to_bytes()]);
 }
 
@@ -93,12 +111,12 @@ fn get_virtual_transaction_size(n_weight: u64) -> u64 {
 }
 
 // https://github.com/bitcoin/bitcoin/blob/01e1627e25bc5477c40f51da03c3c31b609a85c9/src/wallet/spend.cpp#L30
-fn calculate_maximum_signed_input_size(outpoint: OutPoint, public_key: PublicKey) -> u64 {
+fn calculate_maximum_signed_input_size(outpoint: OutPoint, script_pubkey: &Script, public_key: PublicKey) -> u64 {
     let mut txin = TxIn {
         previous_output: outpoint,
         ..Default::default()
     };
-    dummy_sign_input(&mut txin, public_key);
+    dummy_sign_input(&mut txin, script_pubkey, public_key);
 
     // GetVirtualTransactionInputSize = GetVirtualTransactionSize(GetTransactionInputWeight(txin));
     get_virtual_transaction_size(get_transaction_input_weight(txin))
@@ -112,13 +130,14 @@ fn calculate_maximum_signed_tx_size(psbt: &PartiallySignedTransaction, wallet: &
     for (i, txin) in tx.input.iter_mut().enumerate() {
         let tx_out = psbt.inputs[i].witness_utxo.as_ref().expect("psbt has witness utxo");
         let public_key = wallet.get_pub_key(&tx_out.script_pubkey).expect("wallet has key");
-        dummy_sign_input(txin, public_key)
+        dummy_sign_input(txin, &tx_out.script_pubkey, public_key)
     }
 
     // GetVirtualTransactionSize = GetVirtualTransactionSize(GetTransactionWeight(tx))
     tx.weight().to_vbytes_ceil()
 }
 
+#[derive(Clone, Copy)]
 struct FeeRate {
     // Fee rate in sat/kvB (satoshis per 1000 virtualbytes)
     n_satoshis_per_k: u64,
@@ -134,60 +153,10 @@ impl FeeRate {
 // https://github.com/bitcoin/bitcoin/blob/db03248070f39d795b64894c312311df33521427/src/policy/policy.h#L55
 const DUST_RELAY_TX_FEE: FeeRate = FeeRate { n_satoshis_per_k: 3000 };
 
-struct CoinOutput {
-    value: u64,
-    fee: u64,
-}
-
-impl CoinOutput {
-    // output's value minus fees required to spend it
-    fn get_effective_value(&self) -> u64 {
-        self.value.saturating_sub(self.fee)
-    }
-}
-
-struct SelectCoins {
-    preset_inputs: Vec<CoinOutput>,
-    target_value: u64,
-}
-
-impl SelectCoins {
-    fn new(target_value: u64) -> Self {
-        Self {
-            preset_inputs: vec![],
-            target_value,
-        }
-    }
-
-    fn add(&mut self, coin_output: CoinOutput) {
-        self.preset_inputs.push(coin_output);
-    }
-
-    // https://github.com/bitcoin/bitcoin/blob/2bd9aa5a44b88c866c4d98f8a7bf7154049cba31/src/wallet/coinselection.cpp#L425
-    fn get_selected_value(&self) -> u64 {
-        self.preset_inputs.iter().map(|input| input.value).sum()
-    }
-
-    // https://github.com/bitcoin/bitcoin/blob/2bd9aa5a44b88c866c4d98f8a7bf7154049cba31/src/wallet/coinselection.cpp#L430
-    fn get_selected_effective_value(&self) -> u64 {
-        self.preset_inputs.iter().map(|input| input.get_effective_value()).sum()
-    }
-
-    // https://github.com/bitcoin/bitcoin/blob/2bd9aa5a44b88c866c4d98f8a7bf7154049cba31/src/wallet/coinselection.cpp#L495
-    fn get_change(&self, min_viable_change: u64, change_fee: u64) -> u64 {
-        // change = SUM(inputs) - SUM(outputs) - fees
-        let change = self
-            .get_selected_effective_value()
-            .saturating_sub(self.target_value)
-            .saturating_sub(change_fee);
-
-        if change < min_viable_change {
-            0
-        } else {
-            change
-        }
-    }
-}
+// https://github.com/bitcoin/bitcoin/blob/v24.0/src/wallet/wallet.h
+const DEFAULT_CONSOLIDATE_FEERATE: FeeRate = FeeRate {
+    n_satoshis_per_k: 10000,
+};
 
 // https://github.com/bitcoin/bitcoin/blob/e9262ea32a6e1d364fb7974844fadc36f931f8c6/src/policy/policy.cpp#L26
 fn get_dust_threshold(tx_out: &TxOut, dust_relay_fee_in: &FeeRate) -> u64 {
@@ -203,14 +172,16 @@ fn get_dust_threshold(tx_out: &TxOut, dust_relay_fee_in: &FeeRate) -> u64 {
 
 pub type KeyStore = Arc<RwLock<BTreeMap<Address, PrivateKey>>>;
 
+/// Streams the outputs spent by `tx_inputs` followed by the unspent outputs of `addresses`,
+/// along with the script they pay to.
 pub fn available_coins(
-    electrs: ElectrsClient,
+    electrs: DynElectrsApi,
     tx_inputs: Vec<TxIn>,
     addresses: Vec<Address>,
-) -> impl Stream<Item = Result<Utxo, Error>> + Unpin {
+) -> impl Stream<Item = Result<(Utxo, ScriptBuf), Error>> + Unpin {
     struct StreamState<E> {
         electrs: E,
-        utxos: VecDeque<Utxo>,
+        utxos: VecDeque<(Utxo, ScriptBuf)>,
         tx_inputs: VecDeque<TxIn>,
         addresses: VecDeque<Address>,
     }
@@ -228,12 +199,17 @@ pub fn available_coins(
                 Some(utxo) => Some((Ok(utxo), state)),
                 None => {
                     if let Some(txin) = state.tx_inputs.pop_front() {
-                        match state.electrs.get_prev_value(&txin.previous_output).await {
-                            Ok(value) => Some((
-                                Ok(Utxo {
-                                    outpoint: txin.previous_output,
-                                    value,
-                                }),
+                        match get_prev_output(&state.electrs, &txin.previous_output).await {
+                            Ok(prev_output) => Some((
+                                Ok((
+                                    Utxo {
+                                        outpoint: txin.previous_output,
+                                        value: prev_output.value,
+                                        // not needed for coin selection
+                                        height: None,
+                                    },
+                                    prev_output.script_pubkey,
+                                )),
                                 state,
                             )),
                             Err(e) => Some((Err(Error::ElectrsError(e)), state)),
@@ -241,7 +217,8 @@ pub fn available_coins(
                     } else if let Some(address) = state.addresses.pop_front() {
                         match state.electrs.get_utxos_for_address(&address).await {
                             Ok(utxos) => {
-                                state.utxos = VecDeque::from(utxos);
+                                let script_pubkey = address.script_pubkey();
+                                state.utxos = utxos.into_iter().map(|utxo| (utxo, script_pubkey.clone())).collect();
                                 state.utxos.pop_front().map(|utxo| (Ok(utxo), state))
                             }
                             Err(e) => Some((Err(Error::ElectrsError(e)), state)),
@@ -256,24 +233,73 @@ pub fn available_coins(
     )
 }
 
+// the (possibly cached) raw transaction has both the value and the script of the output
+async fn get_prev_output(electrs: &DynElectrsApi, outpoint: &OutPoint) -> Result<TxOut, ElectrsError> {
+    let prev_tx: Transaction = deserialize(&electrs.get_raw_tx(&outpoint.txid).await?)?;
+    prev_tx
+        .output
+        .into_iter()
+        .nth(usize::try_from(outpoint.vout)?)
+        .ok_or(ElectrsError::NoPrevOut)
+}
+
 #[derive(Clone)]
 pub struct Wallet {
     secp: Secp256k1<All>,
     network: Network,
-    electrs: ElectrsClient,
+    electrs: DynElectrsApi,
     pub(crate) key_store: KeyStore,
+    /// locked while persisting so that concurrent writes are serialized
+    key_store_file: Option<Arc<Mutex<KeyStoreFile>>>,
+    coin_selection: CoinSelectionAlgorithm,
+    /// fee rate at which the wallet expects to spend its coins later, inputs
+    /// are wasted if they are spent at a higher fee rate than this
+    long_term_feerate: FeeRate,
 }
 
 impl Wallet {
-    pub fn new(network: Network, electrs: ElectrsClient) -> Self {
+    pub fn new(
+        network: Network,
+        electrs: DynElectrsApi,
+        key_store_file: Option<KeyStoreFile>,
+        coin_selection: CoinSelectionAlgorithm,
+    ) -> Self {
         Self {
             secp: Secp256k1::new(),
             network,
             electrs,
             key_store: Arc::new(RwLock::new(Default::default())),
+            key_store_file: key_store_file.map(|key_store_file| Arc::new(Mutex::new(key_store_file))),
+            coin_selection,
+            long_term_feerate: DEFAULT_CONSOLIDATE_FEERATE,
         }
     }
 
+    pub fn set_long_term_fee_rate(&mut self, n_satoshis_per_k: u64) {
+        self.long_term_feerate = FeeRate { n_satoshis_per_k };
+    }
+
+    /// Load all previously persisted keys into the in-memory key store.
+    pub fn load_keys(&self) -> Result<(), Error> {
+        let key_store_file = match self.key_store_file {
+            Some(ref key_store_file) => key_store_file.lock()?,
+            None => return Ok(()),
+        };
+
+        let private_keys = key_store_file.load(self.network)?;
+        log::info!(
+            "Loaded {} keys from {}",
+            private_keys.len(),
+            key_store_file.path().display()
+        );
+        let mut key_store = self.key_store.write()?;
+        for private_key in private_keys {
+            let address = Address::p2wpkh(&private_key.public_key(&self.secp), self.network)?;
+            key_store.insert(address, private_key);
+        }
+        Ok(())
+    }
+
     pub fn get_priv_key(&self, script_pubkey: &Script) -> Result<PrivateKey, Error> {
         let address = Address::from_script(script_pubkey, self.network)?;
         let key_store = self.key_store.read()?;
@@ -311,7 +337,8 @@ impl Wallet {
                 txid: Txid::all_zeros(),
                 vout: 0,
             },
-            self.get_pub_key(&change_address.script_pubkey())
+            &change_prototype_txout.script_pubkey,
+            self.get_pub_key(&change_prototype_txout.script_pubkey)
                 .expect("wallet has key"),
         );
         let change_spend_fee = m_discard_feerate.get_fee(change_spend_size);
@@ -324,11 +351,23 @@ impl Wallet {
 
         // https://github.com/bitcoin/bitcoin/blob/01e1627e25bc5477c40f51da03c3c31b609a85c9/src/wallet/spend.cpp#L896
         let selection_target = recipients_sum + not_input_fees;
-        let mut value_to_select = selection_target;
         let mut select_coins = SelectCoins::new(selection_target);
 
-        // not empty if we are fee bumping, include these inputs first
+        let m_long_term_feerate = self.long_term_feerate;
+        let mut rng = StdRng::from_entropy();
+        let coin_selection_params = CoinSelectionParams {
+            change_fee,
+            cost_of_change: change_spend_fee + change_fee,
+            min_viable_change,
+            min_change_target: generate_change_target(recipients_sum, &mut rng),
+        };
+
+        // not empty if we are fee bumping, these inputs are always included
         let prev_inputs = tx.input.clone();
+        let prev_outpoints = prev_inputs
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect::<HashSet<_>>();
         let mut tx_noinputs = tx.clone();
         tx_noinputs.input.clear();
         let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx_noinputs)?;
@@ -336,7 +375,11 @@ impl Wallet {
         // get available coins
         let addresses = self.key_store.read()?.keys().cloned().collect::<Vec<_>>();
         let mut utxo_stream = available_coins(self.electrs.clone(), prev_inputs, addresses);
-        while let Some(Ok(utxo)) = utxo_stream.next().await {
+        let mut preset_coins = vec![];
+        let mut candidate_coins = vec![];
+        let mut seen_outpoints = HashSet::new();
+        while let Some(utxo) = utxo_stream.next().await {
+            let (utxo, script_pubkey) = utxo?;
             log::info!("Found utxo: {}", utxo.outpoint.txid);
 
 //            if prev_txid.contains(&utxo.outpoint.txid) {
@@ -344,19 +387,54 @@ impl Wallet {
                 // skip if trying to spend from the tx we are replacing
                 continue;
             }
+            if !seen_outpoints.insert(utxo.outpoint) {
+                // the inputs we are fee bumping may still be listed as unspent
+                continue;
+            }
 
-            let script_pubkey = self.electrs.get_script_pubkey(utxo.outpoint).await?;
             let public_key = self.get_pub_key(&script_pubkey).expect("wallet has key");
-            let input_bytes = calculate_maximum_signed_input_size(utxo.outpoint, public_key);
+            let input_bytes = calculate_maximum_signed_input_size(utxo.outpoint, &script_pubkey, public_key);
             let coin_output = CoinOutput {
                 value: utxo.value,
                 fee: m_effective_feerate.get_fee(input_bytes),
+                long_term_fee: m_long_term_feerate.get_fee(input_bytes),
             };
 
-            let effective_value = coin_output.get_effective_value();
-            select_coins.add(coin_output);
-            value_to_select = value_to_select.saturating_sub(effective_value);
+            if prev_outpoints.contains(&utxo.outpoint) {
+                preset_coins.push((utxo, script_pubkey, coin_output));
+            } else {
+                candidate_coins.push((utxo, script_pubkey, coin_output));
+            }
+        }
 
+        // the inputs of the replaced transaction are always spent
+        for (_, _, coin_output) in &preset_coins {
+            select_coins.add(*coin_output);
+        }
+        let value_to_select = selection_target.saturating_sub(select_coins.get_selected_effective_value());
+        let selection = if value_to_select > 0 {
+            let utxo_pool = candidate_coins
+                .iter()
+                .map(|(_, _, coin_output)| *coin_output)
+                .collect::<Vec<_>>();
+            self.coin_selection
+                .select_coins(&utxo_pool, value_to_select, &coin_selection_params, &mut rng)
+                .ok_or(Error::NotEnoughInputs)?
+        } else {
+            vec![]
+        };
+        let selection = selection.into_iter().collect::<HashSet<_>>();
+        let selected_coins = candidate_coins
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| selection.contains(index))
+            .map(|(_, selected_coin)| selected_coin)
+            .collect::<Vec<_>>();
+        for (_, _, coin_output) in &selected_coins {
+            select_coins.add(*coin_output);
+        }
+
+        for (utxo, script_pubkey, _) in preset_coins.into_iter().chain(selected_coins) {
             psbt.unsigned_tx.input.push(TxIn {
                 previous_output: utxo.outpoint,
                 sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
@@ -370,45 +448,123 @@ impl Wallet {
                 }),
                 ..Default::default()
             });
+        }
 
-            if value_to_select == 0 {
-                // add change output before computing maximum size
-                let change_amount = select_coins.get_change(min_viable_change, change_fee);
-                let mut n_change_pos_in_out = None;
-                if change_amount > 0 {
-                    n_change_pos_in_out = Some(psbt.unsigned_tx.output.len());
-                    // add change output
-                    psbt.unsigned_tx.output.push(TxOut {
-                        value: change_amount,
-                        script_pubkey: change_address.script_pubkey(),
-                    });
-                }
+        // add change output before computing maximum size
+        let change_amount = select_coins.get_change(min_viable_change, change_fee);
+        let mut n_change_pos_in_out = None;
+        if change_amount > 0 {
+            n_change_pos_in_out = Some(psbt.unsigned_tx.output.len());
+            // add change output
+            psbt.unsigned_tx.output.push(TxOut {
+                value: change_amount,
+                script_pubkey: change_address.script_pubkey(),
+            });
+        }
 
-                // https://github.com/bitcoin/bitcoin/blob/01e1627e25bc5477c40f51da03c3c31b609a85c9/src/wallet/spend.cpp#L945
-                let n_bytes = calculate_maximum_signed_tx_size(&psbt, self);
-                let fee_needed = m_effective_feerate.get_fee(n_bytes);
-                let n_fee_ret = select_coins.get_selected_value() - recipients_sum - change_amount;
+        // https://github.com/bitcoin/bitcoin/blob/01e1627e25bc5477c40f51da03c3c31b609a85c9/src/wallet/spend.cpp#L945
+        let n_bytes = calculate_maximum_signed_tx_size(&psbt, self);
+        let fee_needed = m_effective_feerate.get_fee(n_bytes);
+        let n_fee_ret = select_coins.get_selected_value() - recipients_sum - change_amount;
 
-                if let Some(change_pos) = n_change_pos_in_out {
-                    if fee_needed < n_fee_ret {
-                        log::info!("Fee needed is less than expected");
-                        let change_output = &mut psbt.unsigned_tx.output[change_pos];
-                        change_output.value += n_fee_ret - fee_needed;
-                    }
-                }
+        if let Some(change_pos) = n_change_pos_in_out {
+            if fee_needed < n_fee_ret {
+                log::info!("Fee needed is less than expected");
+                let change_output = &mut psbt.unsigned_tx.output[change_pos];
+                change_output.value += n_fee_ret - fee_needed;
+            }
+        }
 
-                return Ok(psbt);
+        Ok(psbt)
+    }
+
+    /// Spend `utxos`, along with the script they pay to, to a single output paying `change_address`,
+    /// the fee is deducted from that output. Utxos that are worth less than the fee to spend them are skipped.
+    pub fn create_consolidation_transaction(
+        &self,
+        utxos: Vec<(Utxo, ScriptBuf)>,
+        change_address: Address,
+        n_satoshis_per_k: u64,
+    ) -> Result<PartiallySignedTransaction, Error> {
+        let m_effective_feerate = FeeRate { n_satoshis_per_k };
+
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: change_address.script_pubkey(),
+            }],
+        })?;
+
+        let mut input_sum = 0;
+        for (utxo, script_pubkey) in utxos {
+            let public_key = self.get_pub_key(&script_pubkey).expect("wallet has key");
+            let input_bytes = calculate_maximum_signed_input_size(utxo.outpoint, &script_pubkey, public_key);
+            if utxo.value <= m_effective_feerate.get_fee(input_bytes) {
+                continue;
             }
+
+            input_sum += utxo.value;
+            psbt.unsigned_tx.input.push(TxIn {
+                previous_output: utxo.outpoint,
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            });
+            psbt.inputs.push(psbt::Input {
+                witness_utxo: Some(TxOut {
+                    value: utxo.value,
+                    script_pubkey,
+                }),
+                ..Default::default()
+            });
         }
 
-        Err(Error::NotEnoughInputs)
+        let n_bytes = calculate_maximum_signed_tx_size(&psbt, self);
+        let fee_needed = m_effective_feerate.get_fee(n_bytes);
+        let change_output = &mut psbt.unsigned_tx.output[0];
+        change_output.value = input_sum.saturating_sub(fee_needed);
+        if change_output.value < get_dust_threshold(change_output, &DUST_RELAY_TX_FEE) {
+            return Err(Error::NotEnoughInputs);
+        }
+
+        Ok(psbt)
     }
 
     pub fn put_p2wpkh_key(&self, secret_key: SecretKey) -> Result<(), Error> {
         let private_key = PrivateKey::new(secret_key, self.network);
         let public_key = private_key.public_key(&self.secp);
         let address = Address::p2wpkh(&public_key, self.network)?;
+        self.put_key(address, private_key)
+    }
+
+    /// Watch the key path only taproot output of `secret_key`. The key store
+    /// file does not record the address type, so keys loaded from it are
+    /// only watched as P2WPKH and callers need to add this again on start.
+    pub fn put_p2tr_key(&self, secret_key: SecretKey) -> Result<(), Error> {
+        let private_key = PrivateKey::new(secret_key, self.network);
+        let (internal_key, _parity) = secret_key.x_only_public_key(&self.secp);
+        let address = Address::p2tr(&self.secp, internal_key, None, self.network);
+        self.put_key(address, private_key)
+    }
+
+    fn put_key(&self, address: Address, private_key: PrivateKey) -> Result<(), Error> {
         log::info!("Added key for address {}", address);
+        // hold the file lock until the key is inserted, so that a concurrent write can't
+        // persist a snapshot without it, but don't block readers of the key store on i/o
+        let key_store_file = match self.key_store_file {
+            Some(ref key_store_file) => Some(key_store_file.lock()?),
+            None => None,
+        };
+        if let Some(ref key_store_file) = key_store_file {
+            let mut private_keys = self.key_store.read()?.values().copied().collect::<Vec<_>>();
+            if !private_keys.contains(&private_key) {
+                private_keys.push(private_key);
+                // persist first so that we never hand out an address we can't recover
+                key_store_file.store(private_keys.iter())?;
+            }
+        }
         self.key_store.write()?.insert(address, private_key);
         Ok(())
     }
@@ -437,13 +593,46 @@ impl Wallet {
     }
 
     pub fn sign_transaction(&self, psbt: &mut PartiallySignedTransaction) -> Result<(), Error> {
+        let prev_outs = psbt
+            .inputs
+            .iter()
+            .map(|psbt_input| {
+                psbt_input
+                    .witness_utxo
+                    .clone()
+                    .expect("utxo is always set in fund_transaction; qed")
+            })
+            .collect::<Vec<_>>();
+        let mut sig_hasher = SighashCache::new(&psbt.unsigned_tx);
+
         for (index, psbt_input) in psbt.inputs.iter_mut().enumerate() {
-            let prev_out = psbt_input
-                .witness_utxo
-                .clone()
-                .expect("utxo is always set in fund_transaction; qed");
+            let prev_out = &prev_outs[index];
+            let private_key = self.get_priv_key(&prev_out.script_pubkey)?;
+
+            if prev_out.script_pubkey.is_v1_p2tr() {
+                // NOTE: we only support key path spends without a script tree
+                let sighash_ty = psbt_input.taproot_hash_ty()?;
+                let sig_hash =
+                    sig_hasher.taproot_key_spend_signature_hash(index, &Prevouts::All(&prev_outs), sighash_ty)?;
+
+                let key_pair = KeyPair::from_secret_key(&self.secp, &private_key.inner)
+                    .tap_tweak(&self.secp, None)
+                    .to_inner();
+                let sig = self.secp.sign_schnorr_with_aux_rand(
+                    &Message::from_slice(&sig_hash.to_byte_array()[..])?,
+                    &key_pair,
+                    &rand::random(),
+                );
+
+                let final_signature = taproot::Signature {
+                    sig,
+                    hash_ty: sighash_ty,
+                };
+                // https://github.com/bitcoin/bips/blob/master/bip-0341.mediawiki#script-validation-rules
+                psbt_input.final_script_witness = Some(Witness::from_slice(&[final_signature.to_vec()]));
+                continue;
+            }
 
-            // NOTE: we don't support SchnorrSighashType
             let sighash_ty = psbt_input
                 .sighash_type
                 .unwrap_or_else(|| EcdsaSighashType::All.into())
@@ -457,10 +646,8 @@ impl Wallet {
                 .p2wpkh_script_code()
                 .ok_or(Error::InvalidPrevOut)?;
 
-            let mut sig_hasher = SighashCache::new(&psbt.unsigned_tx);
             let sig_hash = sig_hasher.segwit_signature_hash(index, &script_code, prev_out.value, sighash_ty)?;
 
-            let private_key = self.get_priv_key(&prev_out.script_pubkey)?;
             let sig = self
                 .secp
                 .sign_ecdsa(&Message::from_slice(&sig_hash.to_byte_array()[..])?, &private_key.inner);
@@ -484,14 +671,20 @@ impl Wallet {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{deserialize, serialize};
-    use bitcoincore_rpc::bitcoin::{consensus::Encodable, hashes::hex::FromHex, ScriptBuf, Sequence, Txid};
+    use crate::{electrs::MockElectrs, light::coin_selection::CHANGE_LOWER, serialize, ElectrsClient};
+    use bitcoincore_rpc::bitcoin::{
+        consensus::Encodable, hashes::hex::FromHex, sighash::TapSighashType, ScriptBuf, Sequence, Txid,
+    };
     use std::str::FromStr;
 
     #[test]
     fn should_select_coins() -> Result<(), Box<dyn std::error::Error>> {
         let mut select_coins = SelectCoins::new(50);
-        let coin_output = CoinOutput { value: 100, fee: 10 };
+        let coin_output = CoinOutput {
+            value: 100,
+            fee: 10,
+            long_term_fee: 5,
+        };
         assert_eq!(coin_output.get_effective_value(), 90);
 
         select_coins.add(coin_output);
@@ -499,7 +692,11 @@ mod tests {
         assert_eq!(select_coins.get_selected_effective_value(), 90);
         assert_eq!(select_coins.get_change(0, 5), 35);
 
-        select_coins.add(CoinOutput { value: 10, fee: 1 });
+        select_coins.add(CoinOutput {
+            value: 10,
+            fee: 1,
+            long_term_fee: 1,
+        });
         assert_eq!(select_coins.get_selected_value(), 110);
         assert_eq!(select_coins.get_selected_effective_value(), 99);
         assert_eq!(select_coins.get_change(0, 5), 44);
@@ -507,6 +704,124 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn should_select_coins_with_lowest_waste() {
+        let params = CoinSelectionParams {
+            change_fee: 1_000,
+            cost_of_change: 2_000,
+            min_viable_change: 1_000,
+            min_change_target: CHANGE_LOWER,
+        };
+        let utxo_pool = [1, 2, 5, 10, 20].map(|value| CoinOutput {
+            value: value * 100_000,
+            fee: 0,
+            long_term_fee: 0,
+        });
+        let selected_values = |selection: Option<Vec<usize>>| {
+            selection.map(|selection| {
+                let mut values = selection
+                    .into_iter()
+                    .map(|index| utxo_pool[index].value)
+                    .collect::<Vec<_>>();
+                values.sort_unstable();
+                values
+            })
+        };
+
+        for algorithm in [
+            CoinSelectionAlgorithm::LowestWaste,
+            CoinSelectionAlgorithm::BranchAndBound,
+        ] {
+            let mut rng = StdRng::seed_from_u64(0);
+            // changeless solution exists
+            assert_eq!(
+                selected_values(algorithm.select_coins(&utxo_pool, 1_200_000, &params, &mut rng)),
+                Some(vec![200_000, 1_000_000])
+            );
+        }
+
+        for algorithm in [
+            CoinSelectionAlgorithm::LowestWaste,
+            CoinSelectionAlgorithm::BranchAndBound,
+            CoinSelectionAlgorithm::Knapsack,
+            CoinSelectionAlgorithm::SingleRandomDraw,
+        ] {
+            let mut rng = StdRng::seed_from_u64(0);
+            // no changeless solution, but enough funds to create change
+            let selection = algorithm
+                .select_coins(&utxo_pool, 3_000_000, &params, &mut rng)
+                .expect("funds are sufficient");
+            assert!(selection.iter().map(|&index| utxo_pool[index].value).sum::<u64>() >= 3_000_000);
+
+            assert_eq!(algorithm.select_coins(&utxo_pool, 4_000_000, &params, &mut rng), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_list_available_coins_with_scripts() {
+        let address = Address::from_str("bcrt1qxu0en0v9dsywqchvpr6g9aa5vh9wyeupys2ka8")
+            .unwrap()
+            .require_network(Network::Regtest)
+            .unwrap();
+        let prev_tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: 20_000,
+                script_pubkey: ScriptBuf::from_hex("0014810b092d165f424556b1c33fd343871a0cf4d36b").unwrap(),
+            }],
+        };
+        let prev_outpoint = OutPoint {
+            txid: prev_tx.txid(),
+            vout: 0,
+        };
+        let utxo_outpoint = OutPoint {
+            txid: Txid::all_zeros(),
+            vout: 1,
+        };
+
+        // the scripts are not looked up per utxo
+        let mut electrs = MockElectrs::default();
+        electrs
+            .expect_get_raw_tx()
+            .times(1)
+            .returning(move |_| Ok(serialize(&prev_tx)));
+        electrs.expect_get_utxos_for_address().times(1).returning(move |_| {
+            Ok(vec![Utxo {
+                outpoint: utxo_outpoint,
+                value: 10_000,
+                height: Some(1),
+            }])
+        });
+
+        let coins = available_coins(
+            Arc::new(electrs),
+            vec![TxIn {
+                previous_output: prev_outpoint,
+                ..Default::default()
+            }],
+            vec![address.clone()],
+        )
+        .map(|coin| coin.map(|(utxo, script_pubkey)| (utxo.outpoint, utxo.value, script_pubkey)))
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        assert_eq!(
+            coins,
+            vec![
+                (
+                    prev_outpoint,
+                    20_000,
+                    ScriptBuf::from_hex("0014810b092d165f424556b1c33fd343871a0cf4d36b").unwrap()
+                ),
+                (utxo_outpoint, 10_000, address.script_pubkey()),
+            ]
+        );
+    }
+
     #[test]
     fn should_get_serialize_size_segwit() -> Result<(), Box<dyn std::error::Error>> {
         // 8bbe885f5e49d31b0a3b17fb5f8677aa6a8e94fb45b572a2e21903c6376df204
@@ -613,6 +928,7 @@ mod tests {
                 txid: Txid::from_str("0243dee566c0bf1b887416caa0e625b447c793786f1e6a5fc9c24f0d583f4c07")?,
                 vout: 0,
             },
+            &ScriptBuf::from_hex("0014810b092d165f424556b1c33fd343871a0cf4d36b")?,
             PublicKey::from_str("0251bc49a18fc5af7662d04faa1929d44b7155ec723cc7f590efbf4e0fe18b14c6")?,
         );
 
@@ -663,8 +979,11 @@ mod tests {
         let wallet = Wallet {
             secp,
             network: Network::Regtest,
-            electrs: ElectrsClient::new(None, Network::Regtest).unwrap(),
+            electrs: Arc::new(ElectrsClient::new(None, Network::Regtest).unwrap()),
             key_store: Arc::new(RwLock::new(key_store)),
+            key_store_file: None,
+            coin_selection: Default::default(),
+            long_term_feerate: DEFAULT_CONSOLIDATE_FEERATE,
         };
 
         // 020000000001018971609cf35253baa5164e95f79effd9ed466a2a58e6a723b38327b81e5cd2dc0000000000fdffffff02a086010000000000160014998fced992b90c49c2295c5724edf0daf4748dca5c60042a01000000160014709467f945841c6bb638f9e107de2933e214f1c502473044022057aeb22db1f8656513b7f44df3a30d8405ba040cb250d731379307f1799f9cad02201582f355d461fd0c8ced789eb02053995663c66fc63a80341da9354ce3b23e580121028d16c10d62693f938deb171ad0a8323e389e79685da23795bb6e6503cb5db1c000000000
@@ -720,4 +1039,85 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn should_sign_and_estimate_mixed_inputs() -> Result<(), Box<dyn std::error::Error>> {
+        let secp = Secp256k1::new();
+        let wallet = Wallet::new(
+            Network::Regtest,
+            Arc::new(ElectrsClient::new(None, Network::Regtest)?),
+            None,
+            Default::default(),
+        );
+        let p2wpkh_key = SecretKey::from_slice(&[1; 32])?;
+        let p2tr_key = SecretKey::from_slice(&[2; 32])?;
+        wallet.put_p2wpkh_key(p2wpkh_key)?;
+        wallet.put_p2tr_key(p2tr_key)?;
+
+        let p2wpkh_address = Address::p2wpkh(
+            &PrivateKey::new(p2wpkh_key, Network::Regtest).public_key(&secp),
+            Network::Regtest,
+        )?;
+        let (internal_key, _) = p2tr_key.x_only_public_key(&secp);
+        let p2tr_address = Address::p2tr(&secp, internal_key, None, Network::Regtest);
+        let prev_outs = vec![
+            TxOut {
+                value: 100000,
+                script_pubkey: p2wpkh_address.script_pubkey(),
+            },
+            TxOut {
+                value: 200000,
+                script_pubkey: p2tr_address.script_pubkey(),
+            },
+        ];
+
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: (0..prev_outs.len())
+                .map(|vout| TxIn {
+                    previous_output: OutPoint {
+                        txid: Txid::from_str("dcd25c1eb82783b323a7e6582a6a46edd9ff9ef7954e16a5ba5352f39c607189")
+                            .unwrap(),
+                        vout: vout as u32,
+                    },
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 290000,
+                script_pubkey: p2tr_address.script_pubkey(),
+            }],
+        })?;
+        for (psbt_input, prev_out) in psbt.inputs.iter_mut().zip(prev_outs.iter()) {
+            psbt_input.witness_utxo = Some(prev_out.clone());
+        }
+
+        let estimated_size = calculate_maximum_signed_tx_size(&psbt, &wallet);
+        wallet.sign_transaction(&mut psbt)?;
+        let signed_tx = psbt.extract_tx();
+
+        // the ecdsa signature may be one byte shorter than the dummy signature
+        let actual_size = signed_tx.weight().to_vbytes_ceil();
+        assert!(estimated_size >= actual_size && estimated_size <= actual_size + 1);
+
+        // key path spends commit to all prevouts
+        let sig_hash = SighashCache::new(&signed_tx).taproot_key_spend_signature_hash(
+            1,
+            &Prevouts::All(&prev_outs),
+            TapSighashType::Default,
+        )?;
+        let witness = signed_tx.input[1].witness.to_vec();
+        assert_eq!(witness.len(), 1);
+        let signature = taproot::Signature::from_slice(&witness[0])?;
+        let (output_key, _) = internal_key.tap_tweak(&secp, None);
+        secp.verify_schnorr(
+            &signature.sig,
+            &Message::from_slice(&sig_hash.to_byte_array()[..])?,
+            &output_key.to_inner(),
+        )?;
+
+        Ok(())
+    }
 }
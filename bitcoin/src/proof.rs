@@ -0,0 +1,163 @@
+use crate::{deserialize, error::ProofError, BlockHash, BlockHeader, Error, RawTransactionProof, Transaction};
+use bitcoincore_rpc::bitcoin::merkle_tree::MerkleBlock;
+
+/// Checks that the merkle proof matches the header it commits to and includes the transaction,
+/// returns the header and the index of the transaction in the block.
+fn verify_inclusion(raw_proof: &[u8], raw_tx: &[u8]) -> Result<(BlockHeader, Transaction, u32), Error> {
+    let merkle_block: MerkleBlock = deserialize(raw_proof)?;
+    let transaction: Transaction = deserialize(raw_tx)?;
+
+    let (mut matches, mut indexes) = (vec![], vec![]);
+    let merkle_root = merkle_block
+        .txn
+        .extract_matches(&mut matches, &mut indexes)
+        .map_err(ProofError::from)?;
+    if merkle_root != merkle_block.header.merkle_root {
+        return Err(ProofError::MerkleRootMismatch.into());
+    }
+
+    let txid = transaction.txid();
+    let index = matches
+        .iter()
+        .zip(indexes)
+        .find_map(|(matched, index)| (*matched == txid).then_some(index))
+        .ok_or(ProofError::TransactionNotIncluded)?;
+    Ok((merkle_block.header, transaction, index))
+}
+
+/// Verifies a proof built by `wait_for_transaction_metadata` the same way the parachain
+/// does, so that a malformed proof or a lying backend is noticed before submitting it.
+/// Returns the hash of the block the transaction is included in - the caller still needs
+/// to check that this block is part of the relay's main chain.
+///
+/// # Arguments
+/// * `proof` - merkle proofs of the user and coinbase transactions
+pub fn verify_transaction_proof(proof: &RawTransactionProof) -> Result<BlockHash, Error> {
+    let (header, _, _) = verify_inclusion(&proof.user_tx_proof, &proof.raw_user_tx)?;
+    let (coinbase_header, coinbase_tx, coinbase_index) =
+        verify_inclusion(&proof.coinbase_tx_proof, &proof.raw_coinbase_tx)?;
+
+    if coinbase_header != header {
+        return Err(ProofError::BlockMismatch.into());
+    }
+    // the coinbase proof shows the depth of the merkle tree, which prevents a
+    // 64 byte transaction from posing as an inner node
+    if coinbase_index != 0 || !coinbase_tx.is_coin_base() {
+        return Err(ProofError::InvalidCoinbase.into());
+    }
+
+    Ok(header
+        .validate_pow(header.target())
+        .map_err(|_| ProofError::InvalidProofOfWork)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serialize, Block, OutPoint, TxIn, TxMerkleNode, TxOut, Txid};
+    use bitcoincore_rpc::bitcoin::{
+        absolute::LockTime, block::Version, hashes::Hash, CompactTarget, ScriptBuf, Sequence, Witness,
+    };
+
+    fn dummy_tx(previous_output: OutPoint, value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    fn dummy_block() -> Block {
+        let mut block = Block {
+            header: BlockHeader {
+                version: Version::from_consensus(4),
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 1,
+                // regtest difficulty
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: (0..5)
+                .map(|i| dummy_tx(OutPoint::new(Txid::from_byte_array([i; 32]), 0), i as u64))
+                .collect(),
+        };
+        block.txdata[0] = dummy_tx(OutPoint::null(), 50);
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        while block.header.validate_pow(block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    fn build_proof(block: &Block, user_tx: &Transaction) -> RawTransactionProof {
+        let coinbase_tx = &block.txdata[0];
+        RawTransactionProof {
+            user_tx_proof: serialize(&MerkleBlock::from_block_with_predicate(block, |txid| {
+                *txid == user_tx.txid()
+            })),
+            raw_user_tx: serialize(user_tx),
+            coinbase_tx_proof: serialize(&MerkleBlock::from_block_with_predicate(block, |txid| {
+                *txid == coinbase_tx.txid()
+            })),
+            raw_coinbase_tx: serialize(coinbase_tx),
+        }
+    }
+
+    #[test]
+    fn should_verify_valid_proof() {
+        let block = dummy_block();
+        let proof = build_proof(&block, &block.txdata[3]);
+        assert_eq!(verify_transaction_proof(&proof).unwrap(), block.block_hash());
+    }
+
+    #[test]
+    fn should_reject_transaction_not_in_proof() {
+        let block = dummy_block();
+        let mut proof = build_proof(&block, &block.txdata[3]);
+        proof.raw_user_tx = serialize(&block.txdata[2]);
+        assert!(matches!(
+            verify_transaction_proof(&proof),
+            Err(Error::ProofError(ProofError::TransactionNotIncluded))
+        ));
+    }
+
+    #[test]
+    fn should_reject_wrong_merkle_root() {
+        let mut block = dummy_block();
+        let proof = build_proof(&block, &block.txdata[3]);
+        // proof for the same transactions, but committing to another root
+        block.header.merkle_root = TxMerkleNode::all_zeros();
+        let tampered = build_proof(&block, &block.txdata[3]);
+        let proof = RawTransactionProof {
+            user_tx_proof: tampered.user_tx_proof,
+            ..proof
+        };
+        assert!(matches!(
+            verify_transaction_proof(&proof),
+            Err(Error::ProofError(ProofError::MerkleRootMismatch))
+        ));
+    }
+
+    #[test]
+    fn should_reject_non_coinbase_as_coinbase() {
+        let block = dummy_block();
+        let mut proof = build_proof(&block, &block.txdata[3]);
+        let other = build_proof(&block, &block.txdata[1]);
+        proof.coinbase_tx_proof = other.user_tx_proof;
+        proof.raw_coinbase_tx = other.raw_user_tx;
+        assert!(matches!(
+            verify_transaction_proof(&proof),
+            Err(Error::ProofError(ProofError::InvalidCoinbase))
+        ));
+    }
+}
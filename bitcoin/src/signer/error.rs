@@ -0,0 +1,36 @@
+use crate::psbt::Error as PsbtError;
+use base64::DecodeError as Base64DecodeError;
+use reqwest::Error as ReqwestError;
+use serde_json::Error as SerdeJsonError;
+use std::io::Error as IoError;
+use thiserror::Error;
+use url::ParseError;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid signer url: {0}")]
+    InvalidSignerUrl(String),
+    #[error("Signer rejected the transaction: {0}")]
+    Rejected(String),
+    #[error("Signer did not respond in time")]
+    Timeout,
+    #[error("Signer modified the transaction")]
+    TransactionModified,
+    #[error("Signed transaction is missing an output of the payment")]
+    MissingOutput,
+    #[error("Signer did not finalize input {0}")]
+    InputNotFinalized(usize),
+
+    #[error("PsbtError: {0}")]
+    PsbtError(#[from] PsbtError),
+    #[error("Base64DecodeError: {0}")]
+    Base64DecodeError(#[from] Base64DecodeError),
+    #[error("ReqwestError: {0}")]
+    ReqwestError(#[from] ReqwestError),
+    #[error("ParseError: {0}")]
+    ParseError(#[from] ParseError),
+    #[error("SerdeJsonError: {0}")]
+    SerdeJsonError(#[from] SerdeJsonError),
+    #[error("IoError: {0}")]
+    IoError(#[from] IoError),
+}
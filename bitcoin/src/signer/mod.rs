@@ -0,0 +1,277 @@
+mod error;
+
+pub use error::Error;
+
+use crate::{psbt::PartiallySignedTransaction, Transaction, TxOut};
+use async_trait::async_trait;
+use reqwest::{header::CONTENT_TYPE, Client, Url};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+// signing may require manual approval on the signer
+const SIGNER_TIMEOUT: Duration = Duration::from_secs(300);
+
+const UNIX_SOCKET_SCHEME: &str = "unix://";
+
+pub fn encode_psbt(psbt: &PartiallySignedTransaction) -> String {
+    base64::encode(psbt.serialize())
+}
+
+pub fn decode_psbt(psbt: &str) -> Result<PartiallySignedTransaction, Error> {
+    Ok(PartiallySignedTransaction::deserialize(&base64::decode(psbt.trim())?)?)
+}
+
+#[derive(Serialize)]
+struct SignRequest {
+    psbt: String,
+}
+
+#[derive(Deserialize)]
+struct SignResponse {
+    psbt: Option<String>,
+    error: Option<String>,
+}
+
+impl SignResponse {
+    fn into_psbt(self) -> Result<PartiallySignedTransaction, Error> {
+        match (self.psbt, self.error) {
+            (_, Some(error)) => Err(Error::Rejected(error)),
+            (Some(psbt), None) => decode_psbt(&psbt),
+            (None, None) => Err(Error::Rejected("empty response".to_string())),
+        }
+    }
+}
+
+/// Signs the PSBTs of vault payments so that the keys don't need to be
+/// held by the vault process.
+///
+/// Requests are sent as `{"psbt": "<base64>"}`, the signer answers with
+/// the finalized PSBT in the same format or with `{"error": "<reason>"}`.
+#[async_trait]
+pub trait ExternalSigner {
+    async fn sign_psbt(&self, psbt: &PartiallySignedTransaction) -> Result<PartiallySignedTransaction, Error>;
+}
+
+pub type DynExternalSigner = Arc<dyn ExternalSigner + Send + Sync>;
+
+/// Signer that accepts requests as HTTP `POST`s.
+pub struct HttpSigner {
+    url: Url,
+    cli: Client,
+}
+
+impl HttpSigner {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        Ok(Self {
+            url: url.parse()?,
+            cli: Client::builder().timeout(SIGNER_TIMEOUT).build()?,
+        })
+    }
+}
+
+#[async_trait]
+impl ExternalSigner for HttpSigner {
+    async fn sign_psbt(&self, psbt: &PartiallySignedTransaction) -> Result<PartiallySignedTransaction, Error> {
+        let request = SignRequest {
+            psbt: encode_psbt(psbt),
+        };
+        let response = self
+            .cli
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&request)?)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        serde_json::from_str::<SignResponse>(&response)?.into_psbt()
+    }
+}
+
+/// Signer listening on a local unix socket, each request and
+/// response is a single line of json.
+#[cfg(unix)]
+pub struct UnixSocketSigner {
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocketSigner {
+    pub fn new(path: std::path::PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl ExternalSigner for UnixSocketSigner {
+    async fn sign_psbt(&self, psbt: &PartiallySignedTransaction) -> Result<PartiallySignedTransaction, Error> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let mut request = serde_json::to_vec(&SignRequest {
+            psbt: encode_psbt(psbt),
+        })?;
+        request.push(b'\n');
+
+        let response = tokio::time::timeout(SIGNER_TIMEOUT, async {
+            let mut stream = tokio::net::UnixStream::connect(&self.path).await?;
+            stream.write_all(&request).await?;
+            let mut response = String::new();
+            BufReader::new(stream).read_line(&mut response).await?;
+            Ok::<_, Error>(response)
+        })
+        .await
+        .map_err(|_| Error::Timeout)??;
+        serde_json::from_str::<SignResponse>(&response)?.into_psbt()
+    }
+}
+
+/// Connect to the signer at `signer_url`, either `http(s)://` or `unix:///path/to/socket`.
+pub fn new_external_signer(signer_url: &str) -> Result<DynExternalSigner, Error> {
+    if signer_url.starts_with("http://") || signer_url.starts_with("https://") {
+        return Ok(Arc::new(HttpSigner::new(signer_url)?));
+    }
+    #[cfg(unix)]
+    if let Some(path) = signer_url.strip_prefix(UNIX_SOCKET_SCHEME) {
+        return Ok(Arc::new(UnixSocketSigner::new(path.into())));
+    }
+    Err(Error::InvalidSignerUrl(signer_url.to_string()))
+}
+
+/// The signer must only add signatures: the transaction needs to be unchanged,
+/// still contain all `expected_outputs` of the payment and be fully signed.
+fn validate_signed_psbt(
+    psbt: &PartiallySignedTransaction,
+    signed_psbt: PartiallySignedTransaction,
+    expected_outputs: &[TxOut],
+) -> Result<Transaction, Error> {
+    if signed_psbt.unsigned_tx != psbt.unsigned_tx {
+        return Err(Error::TransactionModified);
+    }
+    if !expected_outputs
+        .iter()
+        .all(|expected_output| signed_psbt.unsigned_tx.output.contains(expected_output))
+    {
+        return Err(Error::MissingOutput);
+    }
+    if let Some(index) = signed_psbt
+        .inputs
+        .iter()
+        .position(|input| input.final_script_witness.is_none() && input.final_script_sig.is_none())
+    {
+        return Err(Error::InputNotFinalized(index));
+    }
+    Ok(signed_psbt.extract_tx())
+}
+
+/// Have the `external_signer` sign the funded `psbt` of a payment with the given outputs.
+pub async fn sign_with_external_signer(
+    external_signer: &DynExternalSigner,
+    psbt: PartiallySignedTransaction,
+    expected_outputs: &[TxOut],
+) -> Result<Transaction, Error> {
+    log::info!("Requesting signature from external signer");
+    let signed_psbt = external_signer.sign_psbt(&psbt).await?;
+    validate_signed_psbt(&psbt, signed_psbt, expected_outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LockTime, OutPoint, TxIn, Txid};
+    use bitcoincore_rpc::bitcoin::{ScriptBuf, Sequence, Witness};
+    use std::str::FromStr;
+
+    fn new_psbt() -> PartiallySignedTransaction {
+        PartiallySignedTransaction::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Txid::from_str("dcd25c1eb82783b323a7e6582a6a46edd9ff9ef7954e16a5ba5352f39c607189").unwrap(),
+                    vout: 0,
+                },
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 100000,
+                script_pubkey: ScriptBuf::from_hex("0014998fced992b90c49c2295c5724edf0daf4748dca").unwrap(),
+            }],
+        })
+        .unwrap()
+    }
+
+    fn sign(mut psbt: PartiallySignedTransaction) -> PartiallySignedTransaction {
+        psbt.inputs[0].final_script_witness = Some(Witness::from_slice(&[vec![1; 64]]));
+        psbt
+    }
+
+    #[test]
+    fn should_accept_signed_psbt() -> Result<(), Box<dyn std::error::Error>> {
+        let psbt = new_psbt();
+        let expected_outputs = psbt.unsigned_tx.output.clone();
+        let transaction = validate_signed_psbt(&psbt, sign(psbt.clone()), &expected_outputs)?;
+        assert_eq!(transaction.txid(), psbt.unsigned_tx.txid());
+        assert_eq!(transaction.input[0].witness.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_modified_psbt() {
+        let psbt = new_psbt();
+        let expected_outputs = psbt.unsigned_tx.output.clone();
+
+        let mut signed_psbt = sign(psbt.clone());
+        signed_psbt.unsigned_tx.output[0].value -= 1;
+        assert!(matches!(
+            validate_signed_psbt(&psbt, signed_psbt, &expected_outputs),
+            Err(Error::TransactionModified)
+        ));
+
+        let mut other_outputs = expected_outputs;
+        other_outputs[0].value += 1;
+        assert!(matches!(
+            validate_signed_psbt(&psbt, sign(psbt.clone()), &other_outputs),
+            Err(Error::MissingOutput)
+        ));
+
+        assert!(matches!(
+            validate_signed_psbt(&psbt, psbt.clone(), &[]),
+            Err(Error::InputNotFinalized(0))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn should_sign_over_unix_socket() -> Result<(), Box<dyn std::error::Error>> {
+        use tokio::{
+            io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+            net::UnixListener,
+        };
+
+        let path = std::env::temp_dir().join(format!("signer-{}.sock", rand::random::<u64>()));
+        let listener = UnixListener::bind(&path)?;
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut request = String::new();
+            stream.read_line(&mut request).await.unwrap();
+            let request: serde_json::Value = serde_json::from_str(&request).unwrap();
+            let psbt = sign(decode_psbt(request["psbt"].as_str().unwrap()).unwrap());
+            let response = serde_json::json!({ "psbt": encode_psbt(&psbt) });
+            stream.write_all(format!("{}\n", response).as_bytes()).await.unwrap();
+        });
+
+        let external_signer = new_external_signer(&format!("unix://{}", path.display()))?;
+        let psbt = new_psbt();
+        let expected_outputs = psbt.unsigned_tx.output.clone();
+        let transaction = sign_with_external_signer(&external_signer, psbt, &expected_outputs).await?;
+        assert_eq!(transaction.input[0].witness.len(), 1);
+
+        server.await?;
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
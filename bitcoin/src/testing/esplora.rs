@@ -0,0 +1,369 @@
+//! Esplora REST api backed by an [`InMemoryChain`], serving the endpoints that the
+//! [`ElectrsClient`](crate::ElectrsClient) uses.
+//!
+//! https://github.com/Blockstream/esplora/blob/master/API.md
+
+use super::{InMemoryChain, State};
+use crate::{
+    deserialize,
+    electrs::{TransactionStatus, TransactionValue, UtxoValue},
+    serialize, sha256, Address, BitcoinError, Block, BlockHash, Error, Hash, JsonRpcError, Network, Script,
+    Transaction, Txid,
+};
+use bitcoincore_rpc::bitcoin::{address::NetworkUnchecked, merkle_tree::MerkleBlock};
+use hyper::{
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Serialize;
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, str::FromStr};
+use tokio::sync::oneshot;
+
+// https://github.com/Blockstream/electrs/blob/adedee15f1fe460398a7045b292604df2161adc0/src/rest.rs#L42-L44
+const CHAIN_TXS_PER_PAGE: usize = 25;
+const MAX_MEMPOOL_TXS: usize = 50;
+
+// confirmation targets that esplora estimates
+const FEE_ESTIMATE_TARGETS: [u16; 5] = [1, 3, 6, 144, 1008];
+
+type Reply = Result<String, (StatusCode, String)>;
+
+fn not_found(message: &str) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, message.to_string())
+}
+
+fn bad_request(message: String) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, message)
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, (StatusCode, String)> {
+    value
+        .parse()
+        .map_err(|_| bad_request(format!("Invalid value: {value}")))
+}
+
+fn json<T: Serialize>(value: &T) -> Reply {
+    Ok(serde_json::to_string(value).expect("esplora types are serializable"))
+}
+
+/// The error esplora returns if bitcoind rejects a transaction.
+fn rpc_rejection(code: i32, message: &str) -> (StatusCode, String) {
+    bad_request(format!(
+        "sendrawtransaction RPC error: {}",
+        serde_json::json!({ "code": code, "message": message })
+    ))
+}
+
+impl State {
+    fn tx_status(&self, height: Option<u32>) -> TransactionStatus {
+        match height {
+            Some(height) => TransactionStatus::confirmed(height, &self.blocks[height as usize].header)
+                .expect("height fits into usize"),
+            None => TransactionStatus::unconfirmed(),
+        }
+    }
+
+    fn tx_value(&self, transaction: &Transaction, height: Option<u32>) -> TransactionValue {
+        let prevouts = transaction
+            .input
+            .iter()
+            .map(|input| {
+                if transaction.is_coin_base() {
+                    None
+                } else {
+                    self.prevout(&input.previous_output).cloned()
+                }
+            })
+            .collect();
+        TransactionValue::new(transaction, prevouts, self.tx_status(height), Network::Regtest)
+            .expect("transaction sizes fit into u32")
+    }
+
+    fn get_transaction(&self, txid: &str) -> Result<(Option<u32>, &Transaction), (StatusCode, String)> {
+        self.find_transaction(&parse(txid)?)
+            .ok_or_else(|| not_found("Transaction not found"))
+    }
+
+    fn get_block(&self, hash: &str) -> Result<&Block, (StatusCode, String)> {
+        self.find_block(&parse::<BlockHash>(hash)?)
+            .ok_or_else(|| not_found("Block not found"))
+    }
+
+    /// Transactions that pay to or spend from a matching script, newest first.
+    fn script_history(&self, is_match: &dyn Fn(&Script) -> bool) -> Vec<(Option<u32>, &Transaction)> {
+        let unconfirmed = self.mempool.iter().rev().map(|tx| (None, tx));
+        let confirmed = self
+            .blocks
+            .iter()
+            .enumerate()
+            .rev()
+            .flat_map(|(height, block)| block.txdata.iter().rev().map(move |tx| (Some(height as u32), tx)));
+        unconfirmed
+            .chain(confirmed)
+            .filter(|(_, transaction)| {
+                transaction.output.iter().any(|output| is_match(&output.script_pubkey))
+                    || (!transaction.is_coin_base()
+                        && transaction.input.iter().any(|input| {
+                            self.prevout(&input.previous_output)
+                                .map_or(false, |prevout| is_match(&prevout.script_pubkey))
+                        }))
+            })
+            .collect()
+    }
+
+    /// Serves `/address/:address/*` and `/scripthash/:hash/*`.
+    fn script_route(&self, path: &[&str], is_match: &dyn Fn(&Script) -> bool) -> Reply {
+        let history = self.script_history(is_match);
+        let (unconfirmed, confirmed): (Vec<_>, Vec<_>) = history.into_iter().partition(|(height, _)| height.is_none());
+        let tx_values = |txs: &[(Option<u32>, &Transaction)]| {
+            txs.iter()
+                .map(|(height, transaction)| self.tx_value(transaction, *height))
+                .collect::<Vec<_>>()
+        };
+
+        match path {
+            ["txs"] => {
+                let mut txs = tx_values(&unconfirmed[..unconfirmed.len().min(MAX_MEMPOOL_TXS)]);
+                txs.extend(tx_values(&confirmed[..confirmed.len().min(CHAIN_TXS_PER_PAGE)]));
+                json(&txs)
+            }
+            ["txs", "mempool"] => json(&tx_values(&unconfirmed[..unconfirmed.len().min(MAX_MEMPOOL_TXS)])),
+            ["txs", "chain"] | ["txs", "chain", _] => {
+                // pages continue after the last transaction seen
+                let start = match path.get(2) {
+                    Some(last_seen) => {
+                        let last_seen: Txid = parse(last_seen)?;
+                        confirmed
+                            .iter()
+                            .position(|(_, transaction)| transaction.txid() == last_seen)
+                            .map_or(confirmed.len(), |index| index + 1)
+                    }
+                    None => 0,
+                };
+                let end = confirmed.len().min(start + CHAIN_TXS_PER_PAGE);
+                json(&tx_values(&confirmed[start..end]))
+            }
+            ["utxo"] => {
+                let utxos = self
+                    .utxos()
+                    .into_iter()
+                    .filter(|(_, (output, _))| is_match(&output.script_pubkey))
+                    .map(|(outpoint, (output, height))| UtxoValue {
+                        txid: outpoint.txid,
+                        vout: outpoint.vout,
+                        status: Some(self.tx_status(height)),
+                        value: output.value,
+                    })
+                    .collect::<Vec<_>>();
+                json(&utxos)
+            }
+            _ => Err(not_found("Not found")),
+        }
+    }
+
+    fn get_route(&self, path: &[&str]) -> Reply {
+        match path {
+            ["blocks", "tip", "height"] => Ok(self.tip_height().to_string()),
+            ["blocks", "tip", "hash"] => Ok(self.blocks[self.tip_height() as usize].block_hash().to_string()),
+            ["block-height", height] => self
+                .blocks
+                .get(parse::<usize>(height)?)
+                .map(|block| block.block_hash().to_string())
+                .ok_or_else(|| not_found("Block not found")),
+            ["block", hash, "header"] => Ok(hex::encode(serialize(&self.get_block(hash)?.header))),
+            ["block", hash, "txids"] => json(
+                &self
+                    .get_block(hash)?
+                    .txdata
+                    .iter()
+                    .map(Transaction::txid)
+                    .collect::<Vec<_>>(),
+            ),
+            ["mempool", "txids"] => json(&self.mempool.iter().map(Transaction::txid).collect::<Vec<_>>()),
+            ["tx", txid] => {
+                let (height, transaction) = self.get_transaction(txid)?;
+                json(&self.tx_value(transaction, height))
+            }
+            ["tx", txid, "hex"] => Ok(hex::encode(serialize(self.get_transaction(txid)?.1))),
+            ["tx", txid, "status"] => json(&self.tx_status(self.get_transaction(txid)?.0)),
+            ["tx", txid, "merkleblock-proof"] => match self.get_transaction(txid)? {
+                (Some(height), transaction) => {
+                    let txid = transaction.txid();
+                    let merkle_block =
+                        MerkleBlock::from_block_with_predicate(&self.blocks[height as usize], |x| *x == txid);
+                    Ok(hex::encode(serialize(&merkle_block)))
+                }
+                (None, _) => Err(not_found("Transaction not found or is unconfirmed")),
+            },
+            ["address", address, path @ ..] => {
+                let script = parse::<Address<NetworkUnchecked>>(address)?
+                    .require_network(Network::Regtest)
+                    .map_err(|err| bad_request(err.to_string()))?
+                    .script_pubkey();
+                self.script_route(path, &|x| x == script.as_script())
+            }
+            ["scripthash", script_hash, path @ ..] => {
+                let script_hash = hex::decode(script_hash).map_err(|err| bad_request(err.to_string()))?;
+                self.script_route(path, &|x| {
+                    sha256::Hash::hash(x.as_bytes()).as_byte_array()[..] == script_hash[..]
+                })
+            }
+            ["fee-estimates"] => json(
+                &FEE_ESTIMATE_TARGETS
+                    .iter()
+                    .map(|target| (target.to_string(), self.fee_estimate.0 as f64))
+                    .collect::<HashMap<_, _>>(),
+            ),
+            _ => Err(not_found("Not found")),
+        }
+    }
+
+    /// Serves `POST /tx`, the body is the hex encoded transaction.
+    fn broadcast(&mut self, body: &[u8]) -> Reply {
+        let decode_failed = || rpc_rejection(-22, "TX decode failed");
+        let raw_tx = std::str::from_utf8(body)
+            .ok()
+            .and_then(|body| hex::decode(body.trim()).ok())
+            .ok_or_else(decode_failed)?;
+        let transaction: Transaction = deserialize(&raw_tx).map_err(|_| decode_failed())?;
+        match self.accept_to_mempool(transaction) {
+            Ok(txid) => Ok(txid.to_string()),
+            Err(Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(err)))) => {
+                Err(rpc_rejection(err.code, &err.message))
+            }
+            Err(err) => Err(bad_request(err.to_string())),
+        }
+    }
+}
+
+async fn handle(chain: InMemoryChain, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+    // ignore empty segments, e.g. of the trailing slash in `/txs/chain/`
+    let path = parts
+        .uri
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    let reply = match (&parts.method, path.as_slice()) {
+        (&Method::GET, path) => chain.state().get_route(path),
+        (&Method::POST, ["tx"]) => chain.state().broadcast(&body),
+        _ => Err(not_found("Not found")),
+    };
+    let (status, body) = match reply {
+        Ok(body) => (StatusCode::OK, body),
+        Err(err) => err,
+    };
+    Ok(Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .expect("response is valid"))
+}
+
+/// Serves an [`InMemoryChain`] over the Esplora REST api on a local port, until it is dropped.
+pub struct EsploraServer {
+    url: String,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl EsploraServer {
+    /// Start serving on a free port, this must be called from within a tokio runtime.
+    pub fn start(chain: InMemoryChain) -> Result<Self, hyper::Error> {
+        let make_service = make_service_fn(move |_: &AddrStream| {
+            let chain = chain.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |request| handle(chain.clone(), request))) }
+        });
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+
+        let (shutdown, signal) = oneshot::channel();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            // the sender is dropped with the server
+            let _ = signal.await;
+        }));
+        Ok(Self {
+            url,
+            _shutdown: shutdown,
+        })
+    }
+
+    /// The base url, to pass to the [`ElectrsClient`](crate::ElectrsClient).
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ElectrsApi, ElectrsClient, H256};
+
+    fn external_address() -> Address {
+        Address::p2wsh(Script::empty(), Network::Regtest)
+    }
+
+    #[tokio::test]
+    async fn should_page_scripthash_history() {
+        let chain = InMemoryChain::new();
+        let server = EsploraServer::start(chain.clone()).unwrap();
+        let electrs = ElectrsClient::new(Some(server.url()), Network::Regtest).unwrap();
+
+        let request_id = H256::repeat_byte(1);
+        let mut txids = (0..30)
+            .map(|_| chain.send_external_payment(&external_address(), 10_000, Some(request_id)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        chain.mine_blocks(1);
+
+        let script_hash = sha256::Hash::hash(crate::testing::op_return_script(request_id).as_bytes());
+        let txs = electrs
+            .get_txs_by_scripthash(script_hash.to_byte_array().to_vec())
+            .await
+            .unwrap();
+        // newest first
+        txids.reverse();
+        assert_eq!(txs.iter().map(|tx| tx.txid).collect::<Vec<_>>(), txids);
+        assert!(txs.iter().all(|tx| tx.fee > 0));
+
+        assert_eq!(
+            electrs
+                .get_tx_for_op_return(external_address(), 10_000, request_id)
+                .await
+                .unwrap(),
+            Some(txids[0])
+        );
+        assert_eq!(
+            electrs
+                .get_tx_for_op_return(external_address(), 10_001, request_id)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_transactions() {
+        let chain = InMemoryChain::new();
+        let server = EsploraServer::start(chain.clone()).unwrap();
+        let electrs = ElectrsClient::new(Some(server.url()), Network::Regtest).unwrap();
+
+        let txid = chain.send_external_payment(&external_address(), 10_000, None).unwrap();
+        let mut transaction: Transaction = deserialize(&electrs.get_raw_tx(&txid).await.unwrap()).unwrap();
+        // accepting the same transaction again is a no-op
+        assert_eq!(electrs.send_transaction(transaction.clone()).await.unwrap(), txid);
+
+        // spends more than its inputs
+        transaction.output[0].value = u64::MAX / 2;
+        let err = electrs.send_transaction(transaction).await.unwrap_err();
+        assert!(matches!(
+            err,
+            crate::electrs::Error::ReqwestError(ref err) if err.status() == Some(StatusCode::BAD_REQUEST)
+        ));
+
+        assert!(electrs.get_raw_tx_merkle_proof(&txid).await.unwrap_err().is_not_found());
+        assert!(electrs.get_block_hash(1).await.unwrap_err().is_not_found());
+    }
+}
@@ -0,0 +1,1297 @@
+//! An in-memory bitcoin chain with a wallet, which implements [`BitcoinCoreApi`] without a
+//! node so that the clients can be tested offline.
+//!
+//! Blocks have a valid merkle root and proof of work, and transactions must spend existing
+//! outputs and pay for themselves. Scripts are never executed though: wallet transactions
+//! are not signed, their inputs only carry a dummy witness of realistic size so that fees
+//! match those of bitcoind. Failures that callers handle are reported as the same rpc
+//! errors bitcoind would return. The chain can also be served over the Esplora REST api by
+//! an [`EsploraServer`], to test the light client.
+
+mod esplora;
+
+pub use esplora::EsploraServer;
+
+use crate::{
+    address::NetworkUnchecked, calculate_deposit_secret_key, get_child_fee, json, secp256k1, serialize, sha256,
+    Address, Amount, BitcoinCoreApi, BitcoinError, BitcoinRpcError, Block, BlockHash, BlockHeader, Builder, Error,
+    GetTransactionResultDetailCategory, Hash, JsonRpcError, Network, OutPoint, Payload, PrivateKey, PublicKey,
+    RawTransactionProof, RpcError, SatPerVbyte, SecretKey, SignedAmount, Transaction, TransactionExt,
+    TransactionMetadata, TxIn, TxMerkleNode, TxOut, Txid, H256,
+};
+use async_trait::async_trait;
+use bitcoincore_rpc::bitcoin::{
+    absolute::LockTime, block::Version, merkle_tree::MerkleBlock, CompactTarget, ScriptBuf, Sequence, Witness,
+};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::{sync::watch, time::timeout};
+
+// regtest difficulty, so that a block is found within a few attempts
+const REGTEST_BITS: u32 = 0x207fffff;
+// timestamp of the regtest genesis block
+const GENESIS_TIME: u32 = 1_296_688_602;
+const BLOCK_REWARD: u64 = 50 * 100_000_000;
+// the default minimum relay fee and incremental relay fee of bitcoind
+const MIN_RELAY_FEE_RATE: u64 = 1;
+
+/// Funds outside of the wallet, used to make payments to the wallet.
+fn external_script() -> ScriptBuf {
+    // OP_TRUE
+    ScriptBuf::from(vec![0x51])
+}
+
+fn op_return_script(data: H256) -> ScriptBuf {
+    let mut script = vec![0x6a, 32];
+    script.extend_from_slice(data.as_bytes());
+    ScriptBuf::from(script)
+}
+
+/// Input spending a wallet utxo, with a witness of the size of a p2wpkh signature.
+fn wallet_input(previous_output: OutPoint) -> TxIn {
+    TxIn {
+        previous_output,
+        script_sig: ScriptBuf::new(),
+        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+        witness: Witness::from_slice(&[vec![0u8; 72], vec![0u8; 33]]),
+    }
+}
+
+fn rpc_error(code: BitcoinRpcError, message: &str) -> Error {
+    Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(RpcError {
+        code: code as i32,
+        message: message.to_string(),
+        data: None,
+    })))
+}
+
+fn transaction_not_found() -> Error {
+    rpc_error(
+        BitcoinRpcError::RpcInvalidAddressOrKey,
+        "No such mempool or blockchain transaction",
+    )
+}
+
+fn vsize(transaction: &Transaction) -> u64 {
+    transaction.weight().to_vbytes_ceil()
+}
+
+struct State {
+    secp: secp256k1::Secp256k1<secp256k1::All>,
+    /// the main chain, indexed by height
+    blocks: Vec<Block>,
+    /// blocks that were disconnected by a reorg
+    stale_blocks: HashMap<BlockHash, Block>,
+    /// unconfirmed transactions, in the order they were accepted
+    mempool: Vec<Transaction>,
+    /// fees of all accepted transactions
+    fees: HashMap<Txid, u64>,
+    /// private keys of the wallet by public key
+    keys: HashMap<PublicKey, PrivateKey>,
+    /// scripts spendable by the wallet
+    scripts: HashSet<ScriptBuf>,
+    /// number of keys generated, to derive the next one deterministically
+    key_index: u32,
+    /// number of blocks mined, so that the blocks of competing forks differ
+    block_index: u32,
+    fee_estimate: SatPerVbyte,
+}
+
+impl State {
+    fn tip_height(&self) -> u32 {
+        self.blocks.len() as u32 - 1
+    }
+
+    fn insert_key(&mut self, private_key: PrivateKey) -> PublicKey {
+        let public_key = private_key.public_key(&self.secp);
+        let address = Address::p2wpkh(&public_key, Network::Regtest).expect("keys are compressed");
+        self.scripts.insert(address.script_pubkey());
+        self.keys.insert(public_key, private_key);
+        public_key
+    }
+
+    fn new_key(&mut self) -> PublicKey {
+        self.key_index += 1;
+        let secret = sha256::Hash::hash(&self.key_index.to_le_bytes());
+        let secret_key = SecretKey::from_slice(secret.as_byte_array()).expect("hash is a valid secret key");
+        self.insert_key(PrivateKey::new(secret_key, Network::Regtest))
+    }
+
+    fn new_address(&mut self) -> Address {
+        let public_key = self.new_key();
+        Address::p2wpkh(&public_key, Network::Regtest).expect("keys are compressed")
+    }
+
+    fn is_mine(&self, script: &ScriptBuf) -> bool {
+        self.scripts.contains(script)
+    }
+
+    /// Height of the block the transaction is included in, if any, and the transaction.
+    fn find_transaction(&self, txid: &Txid) -> Option<(Option<u32>, &Transaction)> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .find_map(|(height, block)| {
+                let transaction = block.txdata.iter().find(|tx| tx.txid() == *txid)?;
+                Some((Some(height as u32), transaction))
+            })
+            .or_else(|| self.mempool.iter().find(|tx| tx.txid() == *txid).map(|tx| (None, tx)))
+    }
+
+    fn find_block(&self, hash: &BlockHash) -> Option<&Block> {
+        self.blocks
+            .iter()
+            .find(|block| block.block_hash() == *hash)
+            .or_else(|| self.stale_blocks.get(hash))
+    }
+
+    fn prevout(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+        let (_, transaction) = self.find_transaction(&outpoint.txid)?;
+        transaction.output.get(outpoint.vout as usize)
+    }
+
+    fn confirmations(&self, height: Option<u32>) -> u32 {
+        height.map_or(0, |height| self.tip_height() - height + 1)
+    }
+
+    /// All unspent outputs of the main chain and mempool, with the height they were
+    /// confirmed at.
+    fn utxos(&self) -> HashMap<OutPoint, (TxOut, Option<u32>)> {
+        let confirmed = self
+            .blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(height, block)| block.txdata.iter().map(move |tx| (tx, Some(height as u32))));
+        let unconfirmed = self.mempool.iter().map(|tx| (tx, None));
+
+        let mut utxos = HashMap::new();
+        for (transaction, height) in confirmed.chain(unconfirmed) {
+            if !transaction.is_coin_base() {
+                for input in &transaction.input {
+                    utxos.remove(&input.previous_output);
+                }
+            }
+            let txid = transaction.txid();
+            for (vout, output) in transaction.output.iter().enumerate() {
+                utxos.insert(OutPoint::new(txid, vout as u32), (output.clone(), height));
+            }
+        }
+        utxos
+    }
+
+    fn is_from_me(&self, transaction: &Transaction) -> bool {
+        transaction.input.iter().any(|input| {
+            self.prevout(&input.previous_output)
+                .map_or(false, |prevout| self.is_mine(&prevout.script_pubkey))
+        })
+    }
+
+    /// Wallet utxos with at least `min_confirmations`.
+    fn wallet_utxos(&self, min_confirmations: u32) -> Vec<(OutPoint, TxOut)> {
+        self.utxos()
+            .into_iter()
+            .filter(|(_, (output, height))| {
+                self.is_mine(&output.script_pubkey) && self.confirmations(*height) >= min_confirmations
+            })
+            .map(|(outpoint, (output, _))| (outpoint, output))
+            .collect()
+    }
+
+    /// Confirmed wallet utxos, and the unconfirmed change of the wallet's own transactions.
+    fn spendable_utxos(&self) -> Vec<(OutPoint, TxOut)> {
+        self.wallet_utxos(0)
+            .into_iter()
+            .filter(|(outpoint, _)| match self.find_transaction(&outpoint.txid) {
+                Some((None, transaction)) => self.is_from_me(transaction),
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// The given mempool transactions and all transactions spending their outputs.
+    fn with_descendants(&self, txids: HashSet<Txid>) -> HashSet<Txid> {
+        let mut descendants = txids;
+        loop {
+            let children: Vec<_> = self
+                .mempool
+                .iter()
+                .filter(|tx| {
+                    !descendants.contains(&tx.txid())
+                        && tx
+                            .input
+                            .iter()
+                            .any(|input| descendants.contains(&input.previous_output.txid))
+                })
+                .map(Transaction::txid)
+                .collect();
+            if children.is_empty() {
+                return descendants;
+            }
+            descendants.extend(children);
+        }
+    }
+
+    fn remove_from_mempool(&mut self, txids: &HashSet<Txid>) -> Vec<Transaction> {
+        let (removed, kept) = std::mem::take(&mut self.mempool)
+            .into_iter()
+            .partition(|tx| txids.contains(&tx.txid()));
+        self.mempool = kept;
+        removed
+    }
+
+    /// Accept the transaction into the mempool, replacing conflicting transactions if it
+    /// pays enough more than they do, as in BIP-125.
+    fn accept_to_mempool(&mut self, transaction: Transaction) -> Result<Txid, Error> {
+        let txid = transaction.txid();
+        match self.find_transaction(&txid) {
+            Some((Some(_), _)) => {
+                return Err(rpc_error(
+                    BitcoinRpcError::RpcVerifyAlreadyInChain,
+                    "Transaction already in block chain",
+                ))
+            }
+            Some((None, _)) => return Ok(txid),
+            None => {}
+        }
+        if transaction.is_coin_base() {
+            return Err(rpc_error(BitcoinRpcError::RpcVerifyRejected, "coinbase"));
+        }
+
+        let utxos = self.utxos();
+        let mut conflicts = HashSet::new();
+        let mut input_value = 0u64;
+        for input in &transaction.input {
+            let missing = || rpc_error(BitcoinRpcError::RpcVerifyError, "bad-txns-inputs-missingorspent");
+            if !utxos.contains_key(&input.previous_output) {
+                let conflict = self
+                    .mempool
+                    .iter()
+                    .find(|tx| tx.input.iter().any(|x| x.previous_output == input.previous_output))
+                    .ok_or_else(missing)?;
+                conflicts.insert(conflict.txid());
+            }
+            let prevout = self.prevout(&input.previous_output).ok_or_else(missing)?;
+            input_value = input_value.saturating_add(prevout.value);
+        }
+
+        let output_value = transaction.output.iter().map(|output| output.value).sum::<u64>();
+        let fee = input_value
+            .checked_sub(output_value)
+            .ok_or_else(|| rpc_error(BitcoinRpcError::RpcVerifyRejected, "bad-txns-in-belowout"))?;
+        let transaction_vsize = vsize(&transaction);
+        if fee < MIN_RELAY_FEE_RATE * transaction_vsize {
+            return Err(rpc_error(BitcoinRpcError::RpcVerifyRejected, "min relay fee not met"));
+        }
+
+        if !conflicts.is_empty() {
+            let replaced = self.with_descendants(conflicts.clone());
+            if transaction
+                .input
+                .iter()
+                .any(|input| replaced.contains(&input.previous_output.txid))
+            {
+                return Err(rpc_error(
+                    BitcoinRpcError::RpcVerifyRejected,
+                    "bad-txns-spends-conflicting-tx",
+                ));
+            }
+            for conflict in self.mempool.iter().filter(|tx| conflicts.contains(&tx.txid())) {
+                if !conflict.is_explicitly_rbf() {
+                    return Err(rpc_error(BitcoinRpcError::RpcVerifyRejected, "txn-mempool-conflict"));
+                }
+                // the replacement needs a higher fee rate than each transaction it replaces
+                if fee * vsize(conflict) <= self.fees[&conflict.txid()] * transaction_vsize {
+                    return Err(rpc_error(BitcoinRpcError::RpcVerifyRejected, "insufficient fee"));
+                }
+            }
+            // and pays for the relay of itself on top of the fees of all replaced transactions
+            let replaced_fees = replaced.iter().map(|txid| self.fees[txid]).sum::<u64>();
+            if fee < replaced_fees + MIN_RELAY_FEE_RATE * transaction_vsize {
+                return Err(rpc_error(BitcoinRpcError::RpcVerifyRejected, "insufficient fee"));
+            }
+            self.remove_from_mempool(&replaced);
+        }
+
+        self.fees.insert(txid, fee);
+        self.mempool.push(transaction);
+        Ok(txid)
+    }
+
+    /// Add wallet inputs, and change if it is worth it, until the transaction pays `fee_rate`.
+    /// Outputs of the `excluded` transactions are not spent.
+    fn fund_transaction(
+        &mut self,
+        mut transaction: Transaction,
+        fee_rate: SatPerVbyte,
+        excluded: &HashSet<Txid>,
+    ) -> Result<Transaction, Error> {
+        let mut candidates: Vec<_> = self
+            .spendable_utxos()
+            .into_iter()
+            .filter(|(outpoint, _)| !excluded.contains(&outpoint.txid))
+            .collect();
+        // largest first, to use few inputs
+        candidates.sort_by_key(|(_, output)| Reverse(output.value));
+        let mut candidates = candidates.into_iter().map(|(outpoint, _)| outpoint);
+
+        let change_script = self.new_address().script_pubkey();
+        let output_value = transaction.output.iter().map(|output| output.value).sum::<u64>();
+        loop {
+            let input_value = transaction
+                .input
+                .iter()
+                .map(|input| self.prevout(&input.previous_output).map(|prevout| prevout.value))
+                .sum::<Option<u64>>()
+                .ok_or_else(transaction_not_found)?;
+
+            let mut with_change = transaction.clone();
+            with_change.output.push(TxOut {
+                value: 0,
+                script_pubkey: change_script.clone(),
+            });
+            let change = input_value
+                .checked_sub(output_value + fee_rate.0 * vsize(&with_change))
+                .filter(|change| *change >= change_script.dust_value().to_sat());
+            if let Some(change) = change {
+                with_change.output.last_mut().expect("change was added").value = change;
+                return Ok(with_change);
+            }
+            if input_value >= output_value + fee_rate.0 * vsize(&transaction) {
+                // the remainder is too small for change, so it goes to the fee
+                return Ok(transaction);
+            }
+
+            match candidates.next() {
+                Some(outpoint) => transaction.input.push(wallet_input(outpoint)),
+                None => {
+                    return Err(rpc_error(
+                        BitcoinRpcError::RpcWalletInsufficientFunds,
+                        "Insufficient funds",
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Mine a block with all mempool transactions.
+    fn mine_block(&mut self) -> BlockHash {
+        let height = self.blocks.len() as u32;
+        let transactions = std::mem::take(&mut self.mempool);
+        let fees = transactions.iter().map(|tx| self.fees[&tx.txid()]).sum::<u64>();
+        // the genesis block holds all external funds
+        let reward = if height == 0 {
+            Amount::MAX_MONEY.to_sat()
+        } else {
+            BLOCK_REWARD + fees
+        };
+
+        let coinbase = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                // BIP-34 height, and the block index to make the coinbase unique
+                script_sig: Builder::new()
+                    .push_int(height.into())
+                    .push_int(self.block_index.into())
+                    .into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: reward,
+                script_pubkey: external_script(),
+            }],
+        };
+        self.block_index += 1;
+
+        let mut block = Block {
+            header: BlockHeader {
+                version: Version::from_consensus(4),
+                prev_blockhash: self.blocks.last().map_or(BlockHash::all_zeros(), Block::block_hash),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: GENESIS_TIME + height * 600,
+                bits: CompactTarget::from_consensus(REGTEST_BITS),
+                nonce: 0,
+            },
+            txdata: std::iter::once(coinbase).chain(transactions).collect(),
+        };
+        block.header.merkle_root = block.compute_merkle_root().expect("block has transactions");
+        while block.header.validate_pow(block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+
+        let block_hash = block.block_hash();
+        self.blocks.push(block);
+        block_hash
+    }
+
+    fn transaction_metadata(&self, txid: Txid, height: u32, is_wallet: bool) -> TransactionMetadata {
+        let block = &self.blocks[height as usize];
+        let proof = |txid: Txid| serialize(&MerkleBlock::from_block_with_predicate(block, |x| *x == txid));
+        let coinbase = &block.txdata[0];
+        let (_, transaction) = self.find_transaction(&txid).expect("transaction is in the block");
+
+        TransactionMetadata {
+            txid,
+            proof: RawTransactionProof {
+                user_tx_proof: proof(txid),
+                raw_user_tx: serialize(transaction),
+                coinbase_tx_proof: proof(coinbase.txid()),
+                raw_coinbase_tx: serialize(coinbase),
+            },
+            block_hash: block.block_hash(),
+            // bitcoind reports the fee of outgoing wallet transactions as a negative amount
+            fee: (is_wallet && self.is_from_me(transaction))
+                .then(|| SignedAmount::from_sat(-(self.fees[&txid] as i64))),
+        }
+    }
+
+    fn list_transaction_result(
+        &self,
+        transaction: &Transaction,
+        height: Option<u32>,
+        vout: usize,
+        category: GetTransactionResultDetailCategory,
+    ) -> json::ListTransactionResult {
+        let txid = transaction.txid();
+        let output = &transaction.output[vout];
+        let block = height.map(|height| &self.blocks[height as usize]);
+        let is_send = category == GetTransactionResultDetailCategory::Send;
+        let amount = output.value as i64;
+        json::ListTransactionResult {
+            info: json::WalletTxInfo {
+                confirmations: self.confirmations(height) as i32,
+                blockhash: block.map(Block::block_hash),
+                blockindex: block.and_then(|block| block.txdata.iter().position(|tx| tx.txid() == txid)),
+                blocktime: block.map(|block| block.header.time.into()),
+                blockheight: height,
+                txid,
+                time: block.map_or(0, |block| block.header.time.into()),
+                timereceived: 0,
+                bip125_replaceable: if height.is_none() && transaction.is_explicitly_rbf() {
+                    json::Bip125Replaceable::Yes
+                } else {
+                    json::Bip125Replaceable::No
+                },
+                wallet_conflicts: vec![],
+            },
+            detail: json::GetTransactionResultDetail {
+                address: Payload::from_script(&output.script_pubkey)
+                    .ok()
+                    .map(|payload| Address::<NetworkUnchecked>::new(Network::Regtest, payload)),
+                category,
+                amount: SignedAmount::from_sat(if is_send { -amount } else { amount }),
+                label: None,
+                vout: vout as u32,
+                fee: is_send.then(|| SignedAmount::from_sat(-(self.fees[&txid] as i64))),
+                abandoned: None,
+            },
+            trusted: None,
+            comment: None,
+        }
+    }
+}
+
+/// See the [module documentation](self). Clones share the same chain, so a test can keep a
+/// handle to mine blocks while the client under test owns another.
+#[derive(Clone)]
+pub struct InMemoryChain {
+    state: Arc<Mutex<State>>,
+    /// notifies about changes of the main chain
+    tip: Arc<watch::Sender<u32>>,
+}
+
+impl Default for InMemoryChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryChain {
+    /// Regtest chain with only the genesis block and an empty wallet.
+    pub fn new() -> Self {
+        let mut state = State {
+            secp: secp256k1::Secp256k1::new(),
+            blocks: vec![],
+            stale_blocks: HashMap::new(),
+            mempool: vec![],
+            fees: HashMap::new(),
+            keys: HashMap::new(),
+            scripts: HashSet::new(),
+            key_index: 0,
+            block_index: 0,
+            fee_estimate: SatPerVbyte(MIN_RELAY_FEE_RATE),
+        };
+        state.mine_block();
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            tip: Arc::new(watch::channel(0).0),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("lock is not poisoned")
+    }
+
+    fn notify_tip(&self, state: &State) {
+        self.tip.send_replace(state.tip_height());
+    }
+
+    /// Mine `count` blocks, the first of which includes the mempool. Returns the hash of
+    /// the last block.
+    pub fn mine_blocks(&self, count: u32) -> BlockHash {
+        let mut state = self.state();
+        let mut block_hash = state.blocks[state.blocks.len() - 1].block_hash();
+        for _ in 0..count {
+            block_hash = state.mine_block();
+        }
+        self.notify_tip(&state);
+        block_hash
+    }
+
+    /// Disconnect the `count` most recent blocks. Their transactions return to the mempool,
+    /// unless they are no longer valid. Returns the disconnected blocks, oldest first.
+    pub fn disconnect_blocks(&self, count: u32) -> Vec<Block> {
+        let mut state = self.state();
+        let fork_height = state.blocks.len().saturating_sub(count as usize).max(1);
+        let disconnected = state.blocks.split_off(fork_height);
+
+        let mempool = std::mem::take(&mut state.mempool);
+        let transactions = disconnected
+            .iter()
+            .flat_map(|block| block.txdata.iter().skip(1).cloned())
+            .chain(mempool)
+            .collect::<Vec<_>>();
+        for transaction in transactions {
+            // e.g. spends of the disconnected coinbases are dropped
+            let _ = state.accept_to_mempool(transaction);
+        }
+        for block in &disconnected {
+            state.stale_blocks.insert(block.block_hash(), block.clone());
+        }
+
+        self.notify_tip(&state);
+        disconnected
+    }
+
+    /// Replace the `depth` most recent blocks by a longer fork that includes the same
+    /// transactions, at different heights. Returns the hash of the new tip.
+    pub fn reorg(&self, depth: u32) -> BlockHash {
+        self.disconnect_blocks(depth);
+        self.mine_blocks(depth + 1)
+    }
+
+    /// Evict a transaction and its descendants from the mempool, e.g. to make a disconnected
+    /// transaction disappear from the chain.
+    pub fn remove_from_mempool(&self, txid: Txid) -> Vec<Transaction> {
+        let mut state = self.state();
+        let txids = state.with_descendants(HashSet::from([txid]));
+        state.remove_from_mempool(&txids)
+    }
+
+    /// Payment from outside of the wallet, e.g. from a user to a deposit address.
+    pub fn send_external_payment(&self, address: &Address, sat: u64, request_id: Option<H256>) -> Result<Txid, Error> {
+        self.send_external_transaction(
+            std::iter::once(TxOut {
+                value: sat,
+                script_pubkey: address.script_pubkey(),
+            })
+            .chain(request_id.map(|request_id| TxOut {
+                value: 0,
+                script_pubkey: op_return_script(request_id),
+            }))
+            .collect(),
+        )
+    }
+
+    /// Transaction from outside of the wallet paying to arbitrary `outputs`, the
+    /// fee and change are added at the current fee estimate.
+    pub fn send_external_transaction(&self, outputs: Vec<TxOut>) -> Result<Txid, Error> {
+        let mut state = self.state();
+        let fee_rate = state.fee_estimate;
+        let (outpoint, (funds, _)) = state
+            .utxos()
+            .into_iter()
+            .filter(|(_, (output, _))| output.script_pubkey == external_script())
+            .max_by_key(|(_, (output, _))| output.value)
+            .ok_or_else(|| rpc_error(BitcoinRpcError::RpcWalletInsufficientFunds, "Insufficient funds"))?;
+
+        let amount: u64 = outputs.iter().map(|output| output.value).sum();
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            }],
+            output: outputs
+                .into_iter()
+                .chain(std::iter::once(TxOut {
+                    value: 0,
+                    script_pubkey: external_script(),
+                }))
+                .collect(),
+        };
+        let fee = fee_rate.0 * vsize(&transaction);
+        transaction.output.last_mut().expect("change was added").value = funds
+            .value
+            .checked_sub(amount + fee)
+            .ok_or_else(|| rpc_error(BitcoinRpcError::RpcWalletInsufficientFunds, "Insufficient funds"))?;
+
+        state.accept_to_mempool(transaction)
+    }
+
+    /// Send `sat` to a new wallet address and confirm it.
+    pub fn fund_wallet(&self, sat: u64) -> Result<Txid, Error> {
+        let address = self.state().new_address();
+        let txid = self.send_external_payment(&address, sat, None)?;
+        self.mine_blocks(1);
+        Ok(txid)
+    }
+
+    /// Set the fee rate returned by `estimate_fee_rate` and paid by external payments.
+    pub fn set_fee_estimate(&self, fee_rate: SatPerVbyte) {
+        self.state().fee_estimate = fee_rate;
+    }
+
+    /// Fee paid by an accepted transaction.
+    pub fn fee(&self, txid: &Txid) -> Option<u64> {
+        self.state().fees.get(txid).copied()
+    }
+}
+
+#[async_trait]
+impl BitcoinCoreApi for InMemoryChain {
+    fn network(&self) -> Network {
+        Network::Regtest
+    }
+
+    async fn wait_for_new_block(&self, height: u32, max_wait: Duration) {
+        let mut receiver = self.tip.subscribe();
+        let _ = timeout(max_wait, async {
+            while self.state().tip_height() < height {
+                if receiver.changed().await.is_err() {
+                    return;
+                }
+            }
+        })
+        .await;
+    }
+
+    async fn wait_for_block(&self, height: u32, num_confirmations: u32) -> Result<Block, Error> {
+        let mut receiver = self.tip.subscribe();
+        loop {
+            {
+                let state = self.state();
+                if let Some(block) = state.blocks.get(height as usize) {
+                    if state.confirmations(Some(height)) >= num_confirmations {
+                        return Ok(block.clone());
+                    }
+                }
+            }
+            let _ = receiver.changed().await;
+        }
+    }
+
+    async fn get_block_count(&self) -> Result<u64, Error> {
+        Ok(self.state().tip_height().into())
+    }
+
+    async fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, Error> {
+        let state = self.state();
+        let balance = state
+            .wallet_utxos(min_confirmations.unwrap_or(0))
+            .iter()
+            .map(|(_, output)| output.value)
+            .sum();
+        Ok(Amount::from_sat(balance))
+    }
+
+    async fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, Error> {
+        let state = self.state();
+        let confirmed = state
+            .blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(height, block)| block.txdata.iter().map(move |tx| (tx, Some(height as u32))));
+        let unconfirmed = state.mempool.iter().map(|tx| (tx, None));
+
+        let mut results = vec![];
+        for (transaction, height) in confirmed.chain(unconfirmed) {
+            let is_from_me = state.is_from_me(transaction);
+            for (vout, output) in transaction.output.iter().enumerate() {
+                let category = match (is_from_me, state.is_mine(&output.script_pubkey)) {
+                    (true, false) => GetTransactionResultDetailCategory::Send,
+                    (false, true) => GetTransactionResultDetailCategory::Receive,
+                    // change, or not a wallet transaction at all
+                    _ => continue,
+                };
+                results.push(state.list_transaction_result(transaction, height, vout, category));
+            }
+        }
+        // the most recent transactions, oldest first
+        let skip = results.len().saturating_sub(max_count.unwrap_or(usize::MAX));
+        Ok(results.split_off(skip))
+    }
+
+    async fn get_raw_tx(&self, txid: &Txid, block_hash: &BlockHash) -> Result<Vec<u8>, Error> {
+        Ok(serialize(&self.get_transaction(txid, Some(*block_hash)).await?))
+    }
+
+    async fn get_transaction(&self, txid: &Txid, block_hash: Option<BlockHash>) -> Result<Transaction, Error> {
+        let state = self.state();
+        match block_hash {
+            Some(block_hash) => state
+                .find_block(&block_hash)
+                .ok_or_else(|| rpc_error(BitcoinRpcError::RpcInvalidAddressOrKey, "Block hash not found"))?
+                .txdata
+                .iter()
+                .find(|tx| tx.txid() == *txid)
+                .cloned()
+                .ok_or_else(transaction_not_found),
+            None => state
+                .find_transaction(txid)
+                .map(|(_, tx)| tx.clone())
+                .ok_or_else(transaction_not_found),
+        }
+    }
+
+    async fn get_proof(&self, txid: Txid, block_hash: &BlockHash) -> Result<Vec<u8>, Error> {
+        let state = self.state();
+        let block = state
+            .find_block(block_hash)
+            .ok_or_else(|| rpc_error(BitcoinRpcError::RpcInvalidAddressOrKey, "Block not found"))?;
+        if !block.txdata.iter().any(|tx| tx.txid() == txid) {
+            return Err(rpc_error(
+                BitcoinRpcError::RpcInvalidAddressOrKey,
+                "Not all transactions found in specified or retrieved block",
+            ));
+        }
+        Ok(serialize(&MerkleBlock::from_block_with_predicate(block, |x| {
+            *x == txid
+        })))
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error> {
+        self.state()
+            .blocks
+            .get(height as usize)
+            .map(Block::block_hash)
+            .ok_or_else(|| rpc_error(BitcoinRpcError::RpcInvalidParameter, "Block height out of range"))
+    }
+
+    async fn get_new_address(&self) -> Result<Address, Error> {
+        Ok(self.state().new_address())
+    }
+
+    async fn get_new_public_key(&self) -> Result<PublicKey, Error> {
+        Ok(self.state().new_key())
+    }
+
+    fn dump_derivation_key(&self, public_key: &PublicKey) -> Result<PrivateKey, Error> {
+        self.state()
+            .keys
+            .get(public_key)
+            .copied()
+            .ok_or_else(|| rpc_error(BitcoinRpcError::RpcWalletError, "Private key for address is not known"))
+    }
+
+    fn import_derivation_key(&self, private_key: &PrivateKey) -> Result<(), Error> {
+        self.state().insert_key(*private_key);
+        Ok(())
+    }
+
+    async fn add_new_deposit_key(&self, public_key: PublicKey, secret_key: Vec<u8>) -> Result<(), Error> {
+        let vault_key = self.dump_derivation_key(&public_key)?;
+        let deposit_secret_key = calculate_deposit_secret_key(vault_key.inner, SecretKey::from_slice(&secret_key)?)?;
+        self.state().insert_key(PrivateKey {
+            inner: deposit_secret_key,
+            ..vault_key
+        });
+        Ok(())
+    }
+
+    async fn get_best_block_hash(&self) -> Result<BlockHash, Error> {
+        let state = self.state();
+        Ok(state.blocks[state.blocks.len() - 1].block_hash())
+    }
+
+    async fn get_pruned_height(&self) -> Result<u64, Error> {
+        Ok(0)
+    }
+
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        self.state()
+            .find_block(hash)
+            .cloned()
+            .ok_or_else(|| rpc_error(BitcoinRpcError::RpcInvalidAddressOrKey, "Block not found"))
+    }
+
+    async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error> {
+        Ok(self.get_block(hash).await?.header)
+    }
+
+    async fn get_mempool_transactions<'a>(
+        &'a self,
+    ) -> Result<Box<dyn Iterator<Item = Result<Transaction, Error>> + Send + 'a>, Error> {
+        let transactions = self.state().mempool.clone();
+        Ok(Box::new(transactions.into_iter().map(Ok)))
+    }
+
+    async fn wait_for_transaction_metadata(
+        &self,
+        txid: Txid,
+        num_confirmations: u32,
+        _block_hash: Option<BlockHash>,
+        is_wallet: bool,
+    ) -> Result<TransactionMetadata, Error> {
+        let mut receiver = self.tip.subscribe();
+        loop {
+            {
+                let state = self.state();
+                if let Some((Some(height), _)) = state.find_transaction(&txid) {
+                    if state.confirmations(Some(height)) >= num_confirmations {
+                        return Ok(state.transaction_metadata(txid, height, is_wallet));
+                    }
+                }
+            }
+            let _ = receiver.changed().await;
+        }
+    }
+
+    async fn bump_fee(&self, txid: &Txid, address: Address, fee_rate: SatPerVbyte) -> Result<Txid, Error> {
+        let mut state = self.state();
+        let (height, transaction) = state.find_transaction(txid).ok_or_else(transaction_not_found)?;
+        if height.is_some() {
+            return Err(rpc_error(
+                BitcoinRpcError::RpcVerifyAlreadyInChain,
+                "Transaction already in block chain",
+            ));
+        }
+
+        if !transaction.input.iter().all(|input| {
+            state
+                .prevout(&input.previous_output)
+                .map_or(false, |prevout| state.is_mine(&prevout.script_pubkey))
+        }) {
+            return Err(rpc_error(
+                BitcoinRpcError::RpcWalletError,
+                "Transaction contains inputs that don't belong to this wallet",
+            ));
+        }
+
+        // re-fund the transaction without its return-to-self output
+        let mut transaction = transaction.clone();
+        if let Some((idx, _)) = transaction.extract_return_to_self_address(&address.payload)? {
+            transaction.output.remove(idx);
+        }
+        let replaced = state.with_descendants(HashSet::from([*txid]));
+        let transaction = state.fund_transaction(transaction, fee_rate, &replaced)?;
+        state.accept_to_mempool(transaction)
+    }
+
+    async fn bump_fee_with_child(&self, txid: &Txid, address: Address, fee_rate: SatPerVbyte) -> Result<Txid, Error> {
+        let mut state = self.state();
+        let parent = match state.find_transaction(txid) {
+            Some((None, transaction)) => transaction.clone(),
+            _ => {
+                return Err(rpc_error(
+                    BitcoinRpcError::RpcInvalidAddressOrKey,
+                    "Transaction not in mempool",
+                ))
+            }
+        };
+        let (vout, _) = parent
+            .extract_return_to_self_address(&address.payload)?
+            .ok_or(Error::NoReturnToSelfOutput)?;
+        let return_to_self = parent.output[vout].clone();
+        if !state.is_mine(&return_to_self.script_pubkey) {
+            return Err(Error::TransactionSigningError);
+        }
+
+        let mut child = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![wallet_input(OutPoint::new(*txid, vout as u32))],
+            output: vec![return_to_self.clone()],
+        };
+        let child_fee = get_child_fee(state.fees[txid], vsize(&parent), vsize(&child), fee_rate);
+        child.output[0].value = return_to_self
+            .value
+            .checked_sub(child_fee)
+            .filter(|value| *value >= return_to_self.script_pubkey.dust_value().to_sat())
+            .ok_or(Error::CannotPayForParent)?;
+        state.accept_to_mempool(child)
+    }
+
+    async fn create_and_send_transaction(
+        &self,
+        address: Address,
+        sat: u64,
+        fee_rate: SatPerVbyte,
+        request_id: Option<H256>,
+    ) -> Result<Txid, Error> {
+        let transaction = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: std::iter::once(TxOut {
+                value: sat,
+                script_pubkey: address.script_pubkey(),
+            })
+            .chain(request_id.map(|request_id| TxOut {
+                value: 0,
+                script_pubkey: op_return_script(request_id),
+            }))
+            .collect(),
+        };
+
+        let mut state = self.state();
+        let transaction = state.fund_transaction(transaction, fee_rate, &HashSet::new())?;
+        state.accept_to_mempool(transaction)
+    }
+
+    async fn send_to_address(
+        &self,
+        address: Address,
+        sat: u64,
+        request_id: Option<H256>,
+        fee_rate: SatPerVbyte,
+        num_confirmations: u32,
+    ) -> Result<TransactionMetadata, Error> {
+        let txid = self
+            .create_and_send_transaction(address, sat, fee_rate, request_id)
+            .await?;
+        self.wait_for_transaction_metadata(txid, num_confirmations, None, true)
+            .await
+    }
+
+    async fn create_or_load_wallet(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn rescan_blockchain(&self, _start_height: usize, _end_height: usize) -> Result<(), Error> {
+        // balances are always computed from the whole chain
+        Ok(())
+    }
+
+    async fn rescan_electrs_for_addresses(&self, _addresses: Vec<Address>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn get_utxo_count(&self) -> Result<usize, Error> {
+        Ok(self.state().wallet_utxos(1).len())
+    }
+
+    async fn consolidate_utxos(
+        &self,
+        max_inputs: usize,
+        max_utxo_value: u64,
+        fee_rate: SatPerVbyte,
+    ) -> Result<Option<Txid>, Error> {
+        let mut state = self.state();
+        let mut utxos = state.wallet_utxos(1);
+        utxos.retain(|(_, output)| output.value <= max_utxo_value);
+        utxos.sort_by_key(|(_, output)| output.value);
+        utxos.truncate(max_inputs);
+        if utxos.len() < 2 {
+            return Ok(None);
+        }
+
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: utxos.iter().map(|(outpoint, _)| wallet_input(*outpoint)).collect(),
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: state.new_address().script_pubkey(),
+            }],
+        };
+        // the fee is paid from the output
+        let total = utxos.iter().map(|(_, output)| output.value).sum::<u64>();
+        transaction.output[0].value = total.checked_sub(fee_rate.0 * vsize(&transaction)).ok_or_else(|| {
+            rpc_error(
+                BitcoinRpcError::RpcWalletError,
+                "The transaction amount is too small to pay the fee",
+            )
+        })?;
+        state.accept_to_mempool(transaction).map(Some)
+    }
+
+    async fn is_in_mempool(&self, txid: Txid) -> Result<bool, Error> {
+        match self.state().find_transaction(&txid) {
+            Some((height, _)) => Ok(height.is_none()),
+            None => Err(rpc_error(
+                BitcoinRpcError::RpcInvalidAddressOrKey,
+                "Invalid or non-wallet transaction id",
+            )),
+        }
+    }
+
+    async fn fee_rate(&self, txid: Txid) -> Result<SatPerVbyte, Error> {
+        let state = self.state();
+        let (_, transaction) = state.find_transaction(&txid).ok_or_else(transaction_not_found)?;
+        let fee = state.fees.get(&txid).ok_or(Error::MissingBitcoinFeeInfo)?;
+        Ok(SatPerVbyte(fee / vsize(transaction)))
+    }
+
+    async fn estimate_fee_rate(&self, _target_blocks: u16) -> Result<SatPerVbyte, Error> {
+        Ok(self.state().fee_estimate)
+    }
+
+    async fn get_tx_for_op_return(&self, _address: Address, _amount: u128, _data: H256) -> Result<Option<Txid>, Error> {
+        // direct lookup not supported by bitcoin core either
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify_transaction_proof;
+
+    const FEE_RATE: SatPerVbyte = SatPerVbyte(10);
+
+    fn external_address() -> Address {
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let public_key = PublicKey::new(secret_key.public_key(&secp256k1::Secp256k1::new()));
+        Address::p2wpkh(&public_key, Network::Regtest).unwrap()
+    }
+
+    fn assert_rpc_error(err: Error, expected: BitcoinRpcError) {
+        match err {
+            Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(err))) => {
+                assert_eq!(BitcoinRpcError::from(err), expected)
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_pay_and_prove_inclusion() {
+        let chain = InMemoryChain::new();
+        chain.fund_wallet(100_000).unwrap();
+        let request_id = H256::repeat_byte(1);
+        let address = Address::p2wpkh(&chain.get_new_public_key().await.unwrap(), Network::Regtest).unwrap();
+
+        let txid = chain
+            .create_and_send_transaction(address.clone(), 40_000, FEE_RATE, Some(request_id))
+            .await
+            .unwrap();
+        assert!(chain.is_in_mempool(txid).await.unwrap());
+        assert_eq!(chain.fee_rate(txid).await.unwrap(), FEE_RATE);
+        chain.mine_blocks(1);
+
+        let metadata = chain.wait_for_transaction_metadata(txid, 1, None, true).await.unwrap();
+        assert_eq!(verify_transaction_proof(&metadata.proof).unwrap(), metadata.block_hash);
+        assert_eq!(
+            metadata.fee,
+            Some(SignedAmount::from_sat(-(chain.fee(&txid).unwrap() as i64)))
+        );
+
+        let transaction = chain.get_transaction(&txid, Some(metadata.block_hash)).await.unwrap();
+        assert_eq!(transaction.get_op_return(), Some(request_id));
+        assert_eq!(transaction.get_payment_amount_to(address.payload), Some(40_000));
+        // both the payment and the change are ours
+        assert_eq!(
+            chain.get_balance(None).await.unwrap().to_sat(),
+            100_000 - chain.fee(&txid).unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_wait_for_new_block_at_height() {
+        let chain = InMemoryChain::new();
+        let height = chain.get_block_count().await.unwrap() as u32;
+        let max_wait = Duration::from_secs(10);
+
+        // the block already exists
+        let start = tokio::time::Instant::now();
+        chain.wait_for_new_block(height, max_wait).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // no new block
+        chain.wait_for_new_block(height + 1, max_wait).await;
+        assert_eq!(start.elapsed(), max_wait);
+
+        let start = tokio::time::Instant::now();
+        let miner = chain.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            miner.mine_blocks(1);
+        });
+        chain.wait_for_new_block(height + 1, max_wait).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn should_reject_payment_without_funds() {
+        let chain = InMemoryChain::new();
+        chain.fund_wallet(10_000).unwrap();
+        let address = chain.get_new_address().await.unwrap();
+
+        let err = chain
+            .create_and_send_transaction(address, 10_000, FEE_RATE, None)
+            .await
+            .unwrap_err();
+        assert!(err.could_be_insufficient_funds());
+    }
+
+    #[tokio::test]
+    async fn should_replace_by_fee() {
+        let chain = InMemoryChain::new();
+        chain.fund_wallet(100_000).unwrap();
+        let address = external_address();
+
+        let txid = chain
+            .create_and_send_transaction(address.clone(), 40_000, FEE_RATE, None)
+            .await
+            .unwrap();
+        let replacement = chain.bump_fee(&txid, address.clone(), SatPerVbyte(20)).await.unwrap();
+
+        assert_rpc_error(
+            chain.is_in_mempool(txid).await.unwrap_err(),
+            BitcoinRpcError::RpcInvalidAddressOrKey,
+        );
+        assert_eq!(chain.fee_rate(replacement).await.unwrap(), SatPerVbyte(20));
+
+        // another replacement must pay a higher fee rate
+        let err = chain
+            .bump_fee(&replacement, address, SatPerVbyte(20))
+            .await
+            .unwrap_err();
+        assert!(err.rejected_by_network_rules());
+    }
+
+    #[tokio::test]
+    async fn should_pay_for_parent() {
+        let chain = InMemoryChain::new();
+        chain.fund_wallet(100_000).unwrap();
+        let address = external_address();
+
+        let parent = chain
+            .create_and_send_transaction(address.clone(), 40_000, SatPerVbyte(1), None)
+            .await
+            .unwrap();
+        let child = chain
+            .bump_fee_with_child(&parent, address.clone(), FEE_RATE)
+            .await
+            .unwrap();
+
+        {
+            let state = chain.state();
+            let (_, parent_tx) = state.find_transaction(&parent).unwrap();
+            let (_, child_tx) = state.find_transaction(&child).unwrap();
+            let package_fee = state.fees[&parent] + state.fees[&child];
+            assert!(package_fee >= FEE_RATE.0 * (vsize(parent_tx) + vsize(child_tx)));
+        }
+
+        // a second child replaces the first
+        let replacement = chain
+            .bump_fee_with_child(&parent, address, SatPerVbyte(2 * FEE_RATE.0))
+            .await
+            .unwrap();
+        assert_rpc_error(
+            chain.is_in_mempool(child).await.unwrap_err(),
+            BitcoinRpcError::RpcInvalidAddressOrKey,
+        );
+        assert!(chain.is_in_mempool(replacement).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn should_not_bump_fee_of_external_transaction() {
+        let chain = InMemoryChain::new();
+        let address = chain.get_new_address().await.unwrap();
+        let txid = chain.send_external_payment(&address, 40_000, None).unwrap();
+
+        let err = chain.bump_fee(&txid, external_address(), FEE_RATE).await.unwrap_err();
+        assert_rpc_error(err, BitcoinRpcError::RpcWalletError);
+    }
+
+    #[tokio::test]
+    async fn should_consolidate_utxos() {
+        let chain = InMemoryChain::new();
+        for _ in 0..3 {
+            chain.fund_wallet(10_000).unwrap();
+        }
+        assert_eq!(chain.get_utxo_count().await.unwrap(), 3);
+
+        let txid = chain.consolidate_utxos(2, u64::MAX, FEE_RATE).await.unwrap().unwrap();
+        chain.mine_blocks(1);
+        assert_eq!(chain.get_utxo_count().await.unwrap(), 2);
+        assert_eq!(
+            chain.get_balance(None).await.unwrap().to_sat(),
+            30_000 - chain.fee(&txid).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_consolidate_utxos_above_max_value() {
+        let chain = InMemoryChain::new();
+        chain.fund_wallet(10_000).unwrap();
+        chain.fund_wallet(20_000).unwrap();
+
+        assert_eq!(chain.consolidate_utxos(2, 10_000, FEE_RATE).await.unwrap(), None);
+        assert_eq!(chain.get_utxo_count().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn should_reorg() {
+        let chain = InMemoryChain::new();
+        let txid = chain.fund_wallet(10_000).unwrap();
+        chain.mine_blocks(2);
+        let old_block_hash = chain.get_block_hash(1).await.unwrap();
+
+        // the transaction is confirmed again, in another block
+        chain.reorg(3);
+        assert_eq!(chain.get_block_count().await.unwrap(), 4);
+        let metadata = chain.wait_for_transaction_metadata(txid, 1, None, false).await.unwrap();
+        assert_ne!(metadata.block_hash, old_block_hash);
+        assert_eq!(chain.get_block_hash(1).await.unwrap(), metadata.block_hash);
+        // stale blocks can still be queried
+        assert!(chain.get_block(&old_block_hash).await.is_ok());
+
+        // unless it is evicted from the mempool in between
+        chain.disconnect_blocks(4);
+        assert_eq!(chain.remove_from_mempool(txid).len(), 1);
+        chain.mine_blocks(5);
+        assert_eq!(chain.get_balance(None).await.unwrap(), Amount::ZERO);
+        assert_rpc_error(
+            chain.get_transaction(&txid, None).await.unwrap_err(),
+            BitcoinRpcError::RpcInvalidAddressOrKey,
+        );
+    }
+
+    #[tokio::test]
+    async fn should_derive_deposit_keys() {
+        let chain = InMemoryChain::new();
+        let vault_key = chain.get_new_public_key().await.unwrap();
+        let secret_key = vec![7; 32];
+        chain.add_new_deposit_key(vault_key, secret_key.clone()).await.unwrap();
+
+        let deposit_key = calculate_deposit_secret_key(
+            chain.dump_derivation_key(&vault_key).unwrap().inner,
+            SecretKey::from_slice(&secret_key).unwrap(),
+        )
+        .unwrap();
+        let deposit_public_key = PublicKey::new(deposit_key.public_key(&secp256k1::Secp256k1::new()));
+        let address = Address::p2wpkh(&deposit_public_key, Network::Regtest).unwrap();
+
+        chain.send_external_payment(&address, 5_000, None).unwrap();
+        assert_eq!(chain.get_balance(Some(1)).await.unwrap(), Amount::ZERO);
+        chain.mine_blocks(1);
+        assert_eq!(chain.get_balance(Some(1)).await.unwrap().to_sat(), 5_000);
+        let transactions = chain.list_transactions(None).await.unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(
+            transactions[0].detail.category,
+            GetTransactionResultDetailCategory::Receive
+        );
+    }
+}
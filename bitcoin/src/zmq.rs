@@ -0,0 +1,207 @@
+//! Notifications published by bitcoind on its `-zmqpubhashblock` endpoint.
+//!
+//! ZeroMQ subscriptions are lossy, so consumers should treat notifications as a hint to
+//! query the rpc rather than as a complete record - polling remains the fallback.
+
+use crate::{BlockHash, Error, Hash};
+use log::{trace, warn};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use zeromq::{Socket, SocketRecv, SubSocket};
+
+const HASH_BLOCK_TOPIC: &str = "hashblock";
+
+// Number of notifications buffered for slow subscribers.
+const CHANNEL_CAPACITY: usize = 1024;
+
+// Time to sleep before reconnecting a failed subscription.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Maximum time to wait for a block notification before polling the rpc anyway,
+/// in case a notification was dropped.
+pub(crate) const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Subscription(JoinHandle<()>);
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ZmqNotifier {
+    blocks: broadcast::Sender<BlockHash>,
+    _subscription: Arc<Subscription>,
+}
+
+impl ZmqNotifier {
+    /// Subscribe to the given endpoint in the background, returns `None` if it is not set.
+    /// Must be called from within a tokio runtime.
+    pub(crate) fn new(block_url: Option<String>) -> Option<Self> {
+        let (blocks, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let subscription = tokio::spawn(subscribe(
+            block_url?,
+            HASH_BLOCK_TOPIC,
+            blocks.clone(),
+            parse_block_hash,
+        ));
+        Some(Self {
+            blocks,
+            _subscription: Arc::new(Subscription(subscription)),
+        })
+    }
+
+    pub(crate) fn subscribe_blocks(&self) -> broadcast::Receiver<BlockHash> {
+        self.blocks.subscribe()
+    }
+}
+
+/// Waits for new blocks, either by awaiting block notifications or by sleeping
+/// if they are not available. Subscribe before querying the chain so that no
+/// block is missed in between.
+pub(crate) struct BlockWaiter {
+    receiver: Option<broadcast::Receiver<BlockHash>>,
+}
+
+impl BlockWaiter {
+    pub(crate) fn new(notifier: Option<&ZmqNotifier>) -> Self {
+        Self {
+            receiver: notifier.map(ZmqNotifier::subscribe_blocks),
+        }
+    }
+
+    /// Resolves on the next block notification, but after `FALLBACK_POLL_INTERVAL` at the
+    /// latest. Without notifications this sleeps for `poll_interval`.
+    pub(crate) async fn wait(&mut self, poll_interval: Duration) {
+        let receiver = match self.receiver {
+            Some(ref mut receiver) => receiver,
+            None => return sleep(poll_interval).await,
+        };
+        // a lagging receiver has missed blocks, so it is time to query the rpc anyway
+        if let Ok(Err(RecvError::Closed)) = timeout(FALLBACK_POLL_INTERVAL, receiver.recv()).await {
+            self.receiver = None;
+        }
+    }
+}
+
+/// bitcoind publishes block hashes in rpc (reversed) byte order.
+fn parse_block_hash(body: &[u8]) -> Result<BlockHash, Error> {
+    let mut bytes = body.to_vec();
+    bytes.reverse();
+    BlockHash::from_slice(&bytes).map_err(|_| Error::InvalidZmqMessage)
+}
+
+async fn subscribe<T, F>(url: String, topic: &'static str, sender: broadcast::Sender<T>, parse: F)
+where
+    F: Fn(&[u8]) -> Result<T, Error>,
+{
+    loop {
+        if let Err(err) = forward_notifications(&url, topic, &sender, &parse).await {
+            warn!("Zmq subscription to {} on {} failed: {}", topic, url, err);
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn forward_notifications<T, F>(
+    url: &str,
+    topic: &str,
+    sender: &broadcast::Sender<T>,
+    parse: &F,
+) -> Result<(), Error>
+where
+    F: Fn(&[u8]) -> Result<T, Error>,
+{
+    let mut socket = SubSocket::new();
+    socket.connect(url).await?;
+    socket.subscribe(topic).await?;
+    loop {
+        // multipart message of topic, body and sequence number
+        let message = socket.recv().await?;
+        match message
+            .get(1)
+            .ok_or(Error::InvalidZmqMessage)
+            .and_then(|body| parse(body))
+        {
+            Ok(item) => {
+                trace!("Received {} notification", topic);
+                // there may be no subscribers at the moment
+                let _ = sender.send(item);
+            }
+            Err(err) => warn!("Ignoring {} notification: {}", topic, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn should_parse_block_hash_in_rpc_byte_order() {
+        let block_hash =
+            BlockHash::from_str("00000000000000000003a337a676b4b5f7a5a6a2d6b7a55e2e0a3c8d2d3f5b1c").unwrap();
+        let mut body = block_hash.to_byte_array().to_vec();
+        body.reverse();
+        assert_eq!(parse_block_hash(&body).unwrap(), block_hash);
+        assert!(parse_block_hash(&body[1..]).is_err());
+    }
+
+    fn block_waiter() -> (broadcast::Sender<BlockHash>, BlockWaiter) {
+        let (sender, receiver) = broadcast::channel(CHANNEL_CAPACITY);
+        let block_waiter = BlockWaiter {
+            receiver: Some(receiver),
+        };
+        (sender, block_waiter)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_sleep_without_notifications() {
+        let mut block_waiter = BlockWaiter::new(None);
+        let start = tokio::time::Instant::now();
+        block_waiter.wait(Duration::from_secs(5)).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_wake_on_block_notification() {
+        let (sender, mut block_waiter) = block_waiter();
+        let start = tokio::time::Instant::now();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(1)).await;
+            sender.send(BlockHash::all_zeros()).unwrap();
+            // keep the channel open
+            sleep(FALLBACK_POLL_INTERVAL).await;
+        });
+        block_waiter.wait(Duration::from_secs(5)).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert!(block_waiter.receiver.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_time_out_without_block_notification() {
+        let (_sender, mut block_waiter) = block_waiter();
+        let start = tokio::time::Instant::now();
+        block_waiter.wait(Duration::from_secs(5)).await;
+        assert_eq!(start.elapsed(), FALLBACK_POLL_INTERVAL);
+        assert!(block_waiter.receiver.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_fall_back_to_polling_when_closed() {
+        let (sender, mut block_waiter) = block_waiter();
+        drop(sender);
+        block_waiter.wait(Duration::from_secs(5)).await;
+        assert!(block_waiter.receiver.is_none());
+
+        let start = tokio::time::Instant::now();
+        block_waiter.wait(Duration::from_secs(5)).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,140 @@
+#![cfg(feature = "uses-bitcoind")]
+
+#[cfg(feature = "light-client")]
+use bitcoin::{light::CoinSelectionAlgorithm, BitcoinCompactFilter, BitcoinCoreApi};
+use bitcoin::{
+    secp256k1::{constants::SECRET_KEY_SIZE, Secp256k1},
+    Address, Amount, Auth, Client, CompactFilterClient, ElectrsApi, Network, PrivateKey, PublicKey, RpcApi, SecretKey,
+};
+use rand::{thread_rng, Rng};
+use serial_test::serial;
+use std::{convert::TryFrom, env::var};
+
+const DEFAULT_NETWORK: Network = Network::Regtest;
+
+fn new_random_private_key() -> PrivateKey {
+    let raw_secret_key: [u8; SECRET_KEY_SIZE] = thread_rng().gen();
+    let secret_key = SecretKey::from_slice(&raw_secret_key).unwrap();
+    PrivateKey::new(secret_key, DEFAULT_NETWORK)
+}
+
+fn new_random_address() -> Address {
+    let private_key = new_random_private_key();
+    let public_key = PublicKey::from_private_key(&Secp256k1::new(), &private_key);
+    Address::p2wpkh(&public_key, DEFAULT_NETWORK).unwrap()
+}
+
+fn new_bitcoin_client() -> Client {
+    Client::new(
+        &var("BITCOIN_RPC_URL").expect("BITCOIN_RPC_URL not set"),
+        Auth::UserPass(
+            var("BITCOIN_RPC_USER").expect("BITCOIN_RPC_USER not set"),
+            var("BITCOIN_RPC_PASS").expect("BITCOIN_RPC_PASS not set"),
+        ),
+    )
+    .unwrap()
+}
+
+fn new_compact_filter_client() -> CompactFilterClient {
+    CompactFilterClient::new(
+        &var("BITCOIN_P2P_URL").expect("BITCOIN_P2P_URL not set"),
+        DEFAULT_NETWORK,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+#[serial]
+async fn should_sync_headers_and_find_utxos() -> Result<(), Box<dyn std::error::Error>> {
+    let bitcoin_client = new_bitcoin_client();
+    let compact_filter_client = new_compact_filter_client();
+
+    let address = new_random_address();
+    let block_hashes = bitcoin_client.generate_to_address(101, &address)?;
+    let tip_height = u32::try_from(bitcoin_client.get_block_count()?)?;
+
+    assert_eq!(compact_filter_client.get_blocks_tip_height().await?, tip_height);
+    assert_eq!(
+        compact_filter_client.get_blocks_tip_hash().await?,
+        *block_hashes.last().unwrap()
+    );
+
+    // each block pays its coinbase to `address`
+    let utxos = compact_filter_client.get_utxos_for_address(&address).await?;
+    assert_eq!(utxos.len(), block_hashes.len());
+
+    let first_coinbase = compact_filter_client.get_coinbase_txid(&block_hashes[0]).await?;
+    let proof = compact_filter_client.get_raw_tx_merkle_proof(&first_coinbase).await?;
+    let expected = bitcoin_client.get_tx_out_proof(&[first_coinbase], Some(&block_hashes[0]))?;
+    assert_eq!(proof, expected);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn should_track_spends() -> Result<(), Box<dyn std::error::Error>> {
+    let bitcoin_client = new_bitcoin_client();
+    let compact_filter_client = new_compact_filter_client();
+
+    // make sure the node's wallet has mature coins
+    let change_address = bitcoin_client
+        .get_new_address(None, None)?
+        .require_network(DEFAULT_NETWORK)?;
+    bitcoin_client.generate_to_address(101, &change_address)?;
+
+    let address = new_random_address();
+    let txid =
+        bitcoin_client.send_to_address(&address, Amount::from_sat(100000), None, None, None, None, None, None)?;
+    bitcoin_client.generate_to_address(1, &change_address)?;
+
+    let utxos = compact_filter_client.get_utxos_for_address(&address).await?;
+    assert_eq!(utxos.len(), 1);
+    assert_eq!(utxos[0].outpoint.txid, txid);
+    assert_eq!(utxos[0].value, 100000);
+
+    let tx_info = compact_filter_client.get_tx_info(&txid).await?;
+    assert_eq!(tx_info.confirmations, 0);
+
+    Ok(())
+}
+
+#[cfg(feature = "light-client")]
+#[tokio::test]
+#[serial]
+async fn should_prove_wallet_payment_from_block() -> Result<(), Box<dyn std::error::Error>> {
+    let bitcoin_client = new_bitcoin_client();
+    let light_client = BitcoinCompactFilter::new(
+        &var("BITCOIN_P2P_URL").expect("BITCOIN_P2P_URL not set"),
+        new_random_private_key(),
+        None,
+        CoinSelectionAlgorithm::default(),
+    )?;
+
+    let change_address = bitcoin_client
+        .get_new_address(None, None)?
+        .require_network(DEFAULT_NETWORK)?;
+    bitcoin_client.generate_to_address(101, &change_address)?;
+
+    let address = light_client.get_new_address().await?;
+    let txid =
+        bitcoin_client.send_to_address(&address, Amount::from_sat(100000), None, None, None, None, None, None)?;
+    let block_hash = bitcoin_client.generate_to_address(2, &change_address)?[0];
+
+    let metadata = light_client.wait_for_transaction_metadata(txid, 1, None, true).await?;
+    assert_eq!(metadata.block_hash, block_hash);
+
+    // the coinbase does not touch the wallet, so it is taken from the block
+    let block = bitcoin_client.get_block(&block_hash)?;
+    let coinbase_txid = block.txdata[0].txid();
+    assert_eq!(
+        metadata.proof.coinbase_tx_proof,
+        bitcoin_client.get_tx_out_proof(&[coinbase_txid], Some(&block_hash))?
+    );
+    assert_eq!(
+        metadata.proof.user_tx_proof,
+        bitcoin_client.get_tx_out_proof(&[txid], Some(&block_hash))?
+    );
+
+    Ok(())
+}
@@ -1,9 +1,12 @@
 #![cfg(feature = "uses-bitcoind")]
 
+//! Needs the bitcoind of `scripts/integration_test.sh` and an electrs indexing it
+//! at `ELECTRS_URL`, the esplora client itself is covered offline by `testing::EsploraServer`.
+
 use bitcoin::{
     secp256k1::{constants::SECRET_KEY_SIZE, Secp256k1},
-    Address, AddressType, Amount, Auth, BitcoinCoreApi, BitcoinLight, BlockHash, Client, ElectrsClient, Error, Hash,
-    Network, PrivateKey, PublicKey, RpcApi, SatPerVbyte, SecretKey, H256,
+    Address, AddressType, Amount, Auth, BitcoinCoreApi, BitcoinLight, BlockHash, Client, ElectrsApi, ElectrsClient,
+    Error, Hash, Network, PrivateKey, PublicKey, RpcApi, SatPerVbyte, SecretKey, H256,
 };
 use futures::{future::join, Future};
 use rand::{thread_rng, Rng};
@@ -35,8 +38,13 @@ fn new_bitcoin_client() -> Client {
 
 fn new_bitcoin_light() -> BitcoinLight {
     BitcoinLight::new(
-        Some(var("ELECTRS_URL").expect("ELECTRS_URL not set")),
+        vec![var("ELECTRS_URL").expect("ELECTRS_URL not set")],
+        1,
+        0,
+        None,
         new_random_key_pair().0,
+        None,
+        Default::default(),
     )
     .unwrap()
 }
@@ -1,6 +1,9 @@
 #![cfg(feature = "uses-bitcoind")]
 
-use bitcoin::{Auth, BitcoinCore, BitcoinCoreApi, BitcoinCoreBuilder, Error, Network, PrivateKey, PublicKey};
+use bitcoin::{
+    secp256k1::{Secp256k1, SecretKey},
+    Auth, BitcoinCore, BitcoinCoreApi, BitcoinCoreBuilder, Error, Network, PrivateKey, PublicKey,
+};
 use regex::Regex;
 use std::env::var;
 
@@ -14,6 +17,17 @@ fn new_bitcoin_core(wallet: Option<String>) -> Result<BitcoinCore, Error> {
         .build_with_network(Network::Regtest)
 }
 
+fn new_descriptor_bitcoin_core(wallet: Option<String>) -> Result<BitcoinCore, Error> {
+    BitcoinCoreBuilder::new(var("BITCOIN_RPC_URL").expect("BITCOIN_RPC_URL not set"))
+        .set_auth(Auth::UserPass(
+            var("BITCOIN_RPC_USER").expect("BITCOIN_RPC_USER not set"),
+            var("BITCOIN_RPC_PASS").expect("BITCOIN_RPC_PASS not set"),
+        ))
+        .set_wallet_name(wallet)
+        .set_descriptor_wallet(true)
+        .build_with_network(Network::Regtest)
+}
+
 #[tokio::test]
 async fn should_get_new_address() -> Result<(), Error> {
     let btc_rpc = new_bitcoin_core(Some("Alice".to_string()))?;
@@ -73,3 +87,46 @@ async fn should_add_new_deposit_key() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn should_add_new_deposit_key_to_descriptor_wallet() -> Result<(), Error> {
+    let btc_rpc = new_descriptor_bitcoin_core(Some("Dave".to_string()))?;
+    btc_rpc.create_or_load_wallet().await?;
+    assert!(btc_rpc.is_descriptor_wallet()?);
+
+    let public_key = btc_rpc.get_new_public_key().await?;
+    assert!(btc_rpc.wallet_has_public_key(public_key).await?);
+
+    let secret_key = vec![
+        137, 16, 46, 159, 212, 158, 232, 178, 197, 253, 105, 137, 102, 159, 70, 217, 110, 211, 254, 82, 216, 4, 105,
+        171, 102, 252, 54, 190, 114, 91, 11, 69,
+    ];
+    btc_rpc.add_new_deposit_key(public_key, secret_key.clone()).await?;
+
+    let deposit_key = bitcoin::calculate_deposit_secret_key(
+        btc_rpc.dump_derivation_key(&public_key)?.inner,
+        SecretKey::from_slice(&secret_key)?,
+    )?;
+    let deposit_public_key = PublicKey::new(deposit_key.public_key(&Secp256k1::new()));
+    assert!(btc_rpc.wallet_has_public_key(deposit_public_key).await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn should_migrate_to_descriptor_wallet() -> Result<(), Error> {
+    let legacy = new_bitcoin_core(Some("Erin".to_string()))?;
+    legacy.create_or_load_wallet().await?;
+    let public_key = legacy.get_new_public_key().await?;
+
+    let descriptors = new_descriptor_bitcoin_core(Some("Erin-descriptors".to_string()))?;
+    descriptors.create_or_load_wallet().await?;
+
+    assert!(legacy.migrate_to_descriptor_wallet(&descriptors).await? > 0);
+    assert_eq!(
+        descriptors.dump_derivation_key(&public_key)?.inner,
+        legacy.dump_derivation_key(&public_key)?.inner
+    );
+
+    Ok(())
+}
@@ -9,7 +9,9 @@ use jsonrpsee::{
 use prometheus::Error as PrometheusError;
 use serde_json::Error as SerdeJsonError;
 use sp_core::crypto::SecretStringError;
-use std::{array::TryFromSliceError, fmt::Debug, io::Error as IoError, num::TryFromIntError, str::Utf8Error};
+use std::{
+    array::TryFromSliceError, convert::TryInto, fmt::Debug, io::Error as IoError, num::TryFromIntError, str::Utf8Error,
+};
 use subxt::error::{DispatchError, TransactionError};
 pub use subxt::{error::RpcError, Error as SubxtError};
 use thiserror::Error;
@@ -179,6 +181,19 @@ impl Error {
         })
     }
 
+    pub fn is_no_tokens_issued(&self) -> bool {
+        self.is_module_err(
+            VAULT_REGISTRY_MODULE,
+            &format!("{:?}", VaultRegistryPalletError::NoTokensIssued),
+        )
+    }
+
+    /// The vault registry rpc returns the dispatch error as the message of a custom error,
+    /// returns the pallet index and error of such a module error.
+    pub(crate) fn module_error_in_message(&self) -> Option<(u8, [u8; 4])> {
+        self.map_custom_error(|custom_error| parse_module_error(custom_error.message()))
+    }
+
     pub fn is_pool_too_low_priority(&self) -> Option<()> {
         self.map_custom_error(|custom_error| {
             if custom_error.code() == POOL_TOO_LOW_PRIORITY {
@@ -240,6 +255,19 @@ impl Error {
     }
 }
 
+/// Parses the debug representation of a module error, e.g.
+/// `Module(ModuleError { index: 21, error: [22, 0, 0, 0], message: Some("NoTokensIssued") })`.
+fn parse_module_error(message: &str) -> Option<(u8, [u8; 4])> {
+    let (_, module_error) = message.split_once("ModuleError { index: ")?;
+    let (index, module_error) = module_error.split_once(", error: [")?;
+    let (error, _) = module_error.split_once(']')?;
+    let error = error
+        .split(", ")
+        .map(|byte| byte.parse().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some((index.parse().ok()?, error.try_into().ok()?))
+}
+
 #[derive(Error, Debug)]
 pub enum KeyLoadingError {
     #[error("Key not found in file")]
@@ -256,3 +284,15 @@ pub enum KeyLoadingError {
 const BASE_ERROR: i32 = 1000;
 const POOL_INVALID_TX: i32 = BASE_ERROR + 10;
 const POOL_TOO_LOW_PRIORITY: i32 = POOL_INVALID_TX + 4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_module_error() {
+        let message = r#"Execution error: "Unable to get collateralization": Module(ModuleError { index: 21, error: [22, 0, 0, 0], message: Some("NoTokensIssued") })"#;
+        assert_eq!(parse_module_error(message), Some((21, [22, 0, 0, 0])));
+        assert_eq!(parse_module_error("Execution error: Other(\"NoTokensIssued\")"), None);
+    }
+}
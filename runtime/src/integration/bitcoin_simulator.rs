@@ -144,11 +144,15 @@ impl MockBitcoinCore {
         block
     }
 
+    fn return_to_self_script() -> ScriptBuf {
+        let return_to_self_address = BtcAddress::P2PKH(H160::from_slice(&[20; 20]));
+        ScriptBuf::from(return_to_self_address.to_script_pub_key().as_bytes().to_vec())
+    }
+
     fn generate_normal_transaction(address: &Address, reward: u64) -> Transaction {
         let address = ScriptBuf::from(address.payload.script_pubkey().as_bytes().to_vec());
 
-        let return_to_self_address = BtcAddress::P2PKH(H160::from_slice(&[20; 20]));
-        let return_to_self_address = ScriptBuf::from(return_to_self_address.to_script_pub_key().as_bytes().to_vec());
+        let return_to_self_address = MockBitcoinCore::return_to_self_script();
 
         Transaction {
             input: vec![TxIn {
@@ -335,10 +339,13 @@ impl BitcoinCoreApi for MockBitcoinCore {
             sleep(Duration::from_secs(1)).await;
         }
     }
-    fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, BitcoinError> {
+    async fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, BitcoinError> {
         Ok(Amount::ZERO)
     }
-    fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, BitcoinError> {
+    async fn list_transactions(
+        &self,
+        max_count: Option<usize>,
+    ) -> Result<Vec<json::ListTransactionResult>, BitcoinError> {
         Ok(vec![])
     }
     async fn get_block_count(&self) -> Result<u64, BitcoinError> {
@@ -521,14 +528,45 @@ impl BitcoinCoreApi for MockBitcoinCore {
     async fn rescan_electrs_for_addresses(&self, addresses: Vec<Address>) -> Result<(), BitcoinError> {
         Ok(())
     }
-    fn get_utxo_count(&self) -> Result<usize, BitcoinError> {
+    async fn get_utxo_count(&self) -> Result<usize, BitcoinError> {
         Ok(0)
     }
 
+    async fn consolidate_utxos(
+        &self,
+        max_inputs: usize,
+        max_utxo_value: u64,
+        fee_rate: SatPerVbyte,
+    ) -> Result<Option<Txid>, BitcoinError> {
+        // the wallet doesn't track any utxos, so there is nothing to consolidate
+        Ok(None)
+    }
+
     async fn bump_fee(&self, txid: &Txid, address: Address, fee_rate: SatPerVbyte) -> Result<Txid, BitcoinError> {
         unimplemented!()
     }
 
+    async fn bump_fee_with_child(
+        &self,
+        txid: &Txid,
+        address: Address,
+        fee_rate: SatPerVbyte,
+    ) -> Result<Txid, BitcoinError> {
+        // spend the change output of the parent, fees are ignored in this impl
+        let parent = self.get_transaction(txid, None).await?;
+        let vout = parent
+            .output
+            .iter()
+            .position(|output| output.script_pubkey == MockBitcoinCore::return_to_self_script())
+            .ok_or(BitcoinError::NoReturnToSelfOutput)?;
+        let mut child = MockBitcoinCore::generate_normal_transaction(&address, 0);
+        child.input[0].previous_output = OutPoint {
+            txid: *txid,
+            vout: vout as u32,
+        };
+        self.send_transaction(&child).await
+    }
+
     async fn is_in_mempool(&self, txid: Txid) -> Result<bool, BitcoinError> {
         unimplemented!()
     }
@@ -537,6 +575,11 @@ impl BitcoinCoreApi for MockBitcoinCore {
         unimplemented!()
     }
 
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<SatPerVbyte, BitcoinError> {
+        // fees are ignored in this impl
+        Ok(SatPerVbyte(1))
+    }
+
     async fn get_tx_for_op_return(
         &self,
         _address: Address,
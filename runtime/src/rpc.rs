@@ -1747,15 +1747,28 @@ impl VaultRegistryPallet for InterBtcParachain {
 
     async fn get_collateralization_from_vault(&self, vault_id: VaultId, only_issued: bool) -> Result<u128, Error> {
         let head = Some(self.get_finalized_block_hash().await?);
-        let result: UnsignedFixedPoint = self
+        let result: Result<UnsignedFixedPoint, Error> = self
             .api
             .rpc()
             .request(
                 "vaultRegistry_getCollateralizationFromVault",
                 rpc_params![vault_id, only_issued, head],
             )
-            .await?;
-        Ok(result.into_inner())
+            .await
+            .map_err(Into::into);
+
+        result.map(|result| result.into_inner()).map_err(|err| {
+            // decode the dispatch error, so that callers can match on the module error
+            let (index, error) = match err.module_error_in_message() {
+                Some(module_error) => module_error,
+                None => return err,
+            };
+            let module_error =
+                metadata::DispatchError::Module(metadata::runtime_types::sp_runtime::ModuleError { index, error });
+            let dispatch_error = subxt::error::DispatchError::decode_from(module_error.encode(), self.api.metadata())
+                .unwrap_or(subxt::error::DispatchError::Other);
+            Error::SubxtRuntimeError(SubxtError::Runtime(dispatch_error))
+        })
     }
 
     /// For testing purposes only. Sets the current vault client release.
@@ -0,0 +1,576 @@
+use crate::{
+    api::{calculate_deadline, is_payment_in_flight, serialize_amount, VaultIdInfo},
+    error::Error,
+    execution::RequestType,
+    service::DynBitcoinCoreApi,
+    system::VaultIdManager,
+};
+use futures::try_join;
+use governor::{Quota, RateLimiter};
+use hyper::{client::HttpConnector, Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use lazy_static::lazy_static;
+use runtime::{
+    BtcRelayPallet, CollateralBalancesPallet, FixedU128, InterBtcParachain, PrettyPrint, RedeemPallet,
+    RedeemRequestStatus, SecurityPallet, UtilFuncs, VaultId, VaultRegistryPallet, H256,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    num::NonZeroU32,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{sleep, timeout},
+};
+
+const ALERT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref ALERT_SENDER: Mutex<Option<UnboundedSender<Alert>>> = Mutex::new(None);
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "snake_case", tag = "event")]
+pub enum Alert {
+    LowCollateralization {
+        vault_id: VaultIdInfo,
+        collateralization: f64,
+        threshold: f64,
+    },
+    RedeemDeadline {
+        request_id: H256,
+        vault_id: VaultIdInfo,
+        #[serde(serialize_with = "serialize_amount")]
+        amount: u128,
+        /// parachain blocks until the redeem can be cancelled
+        blocks_remaining: u32,
+    },
+    ExecuteFailed {
+        request_id: H256,
+        request_type: RequestType,
+        vault_id: VaultIdInfo,
+        error: String,
+    },
+    InsufficientBtcBalance {
+        request_id: H256,
+        vault_id: VaultIdInfo,
+        #[serde(serialize_with = "serialize_amount")]
+        amount: u128,
+    },
+    LowNativeBalance {
+        #[serde(serialize_with = "serialize_amount")]
+        balance: u128,
+        #[serde(serialize_with = "serialize_amount")]
+        threshold: u128,
+    },
+    RelayBehind {
+        bitcoin_height: u64,
+        relay_height: u32,
+    },
+    ServiceRestart,
+}
+
+impl Alert {
+    /// Alerts with the same key are duplicates of each other
+    fn key(&self) -> String {
+        let vault_key = |vault_id: &VaultIdInfo| {
+            format!(
+                "{}/{}/{}",
+                vault_id.account_id, vault_id.collateral_currency, vault_id.wrapped_currency
+            )
+        };
+        match self {
+            Self::LowCollateralization { vault_id, .. } => format!("low_collateralization/{}", vault_key(vault_id)),
+            Self::RedeemDeadline { request_id, .. } => format!("redeem_deadline/{request_id:?}"),
+            Self::ExecuteFailed { request_id, .. } => format!("execute_failed/{request_id:?}"),
+            Self::InsufficientBtcBalance { vault_id, .. } => {
+                format!("insufficient_btc_balance/{}", vault_key(vault_id))
+            }
+            Self::LowNativeBalance { .. } => "low_native_balance".to_string(),
+            Self::RelayBehind { .. } => "relay_behind".to_string(),
+            Self::ServiceRestart => "service_restart".to_string(),
+        }
+    }
+}
+
+/// Queues the alert to be sent to the webhooks, if the notifier is running
+pub fn notify(alert: Alert) {
+    if let Ok(sender) = ALERT_SENDER.lock() {
+        if let Some(sender) = sender.as_ref() {
+            let _ = sender.send(alert);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    account_id: &'a str,
+    /// unix timestamp in seconds
+    timestamp: u64,
+    #[serde(flatten)]
+    alert: &'a Alert,
+}
+
+#[derive(Clone, Debug)]
+pub struct NotifierConfig {
+    pub webhooks: Vec<String>,
+    /// minimum time before an alert is sent again
+    pub repeat_interval: Duration,
+    pub max_alerts_per_hour: NonZeroU32,
+}
+
+/// Suppresses alerts that were already sent within the repeat interval
+struct Deduplicator {
+    repeat_interval: Duration,
+    last_sent: HashMap<String, Instant>,
+}
+
+impl Deduplicator {
+    fn new(repeat_interval: Duration) -> Self {
+        Self {
+            repeat_interval,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    fn is_duplicate(&self, key: &str, now: Instant) -> bool {
+        self.last_sent.get(key).map_or(false, |last| {
+            now.saturating_duration_since(*last) < self.repeat_interval
+        })
+    }
+
+    /// Records that `key` was sent at `now`, forgetting alerts whose repeat interval has passed
+    /// so the map doesn't grow with every request id seen over the vault's lifetime
+    fn record(&mut self, key: String, now: Instant) {
+        let repeat_interval = self.repeat_interval;
+        self.last_sent
+            .retain(|_, last| now.saturating_duration_since(*last) < repeat_interval);
+        self.last_sent.insert(key, now);
+    }
+}
+
+async fn post_json(client: &Client<HttpsConnector<HttpConnector>>, url: &str, body: Vec<u8>) -> Result<(), Error> {
+    let request = Request::post(url)
+        .header("content-type", "application/json")
+        .body(Body::from(body))?;
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        tracing::warn!("Webhook {} responded with status {}", url, response.status());
+    }
+    Ok(())
+}
+
+async fn run_notifier(account_id: String, config: NotifierConfig, mut alerts: UnboundedReceiver<Alert>) {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let rate_limiter = RateLimiter::direct(Quota::per_hour(config.max_alerts_per_hour));
+    let mut deduplicator = Deduplicator::new(config.repeat_interval);
+
+    while let Some(alert) = alerts.recv().await {
+        let key = alert.key();
+        let now = Instant::now();
+        if deduplicator.is_duplicate(&key, now) {
+            tracing::debug!("Suppressing duplicate alert {}", key);
+            continue;
+        }
+        if rate_limiter.check().is_err() {
+            tracing::warn!("Dropping alert {} since the rate limit was reached", key);
+            continue;
+        }
+        deduplicator.record(key.clone(), now);
+
+        let notification = Notification {
+            account_id: &account_id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or_default(),
+            alert: &alert,
+        };
+        let body = match serde_json::to_vec(&notification) {
+            Ok(body) => body,
+            Err(err) => {
+                tracing::error!("Failed to serialize alert {}: {}", key, err);
+                continue;
+            }
+        };
+        tracing::info!("Sending alert {}", key);
+        for url in config.webhooks.iter() {
+            match timeout(WEBHOOK_TIMEOUT, post_json(&client, url, body.clone())).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => tracing::warn!("Failed to send alert {} to {}: {}", key, url, err),
+                Err(_) => tracing::warn!("Timed out sending alert {} to {}", key, url),
+            }
+        }
+    }
+}
+
+/// Starts sending alerts to the webhooks. Since the notifier outlives restarts of the
+/// vault service, this is called once at startup.
+pub fn start_notifier(account_id: String, config: NotifierConfig) {
+    let (sender, receiver) = unbounded_channel();
+    if let Ok(mut alert_sender) = ALERT_SENDER.lock() {
+        *alert_sender = Some(sender);
+    }
+    tokio::spawn(run_notifier(account_id, config, receiver));
+}
+
+#[derive(Clone, Debug)]
+pub struct AlertThresholds {
+    /// e.g. 2.0 for 200%
+    pub min_collateralization: FixedU128,
+    /// alert when the free balance of the native currency falls below this amount
+    pub min_native_balance: Option<u128>,
+    /// maximum number of bitcoin blocks the relay may fall behind
+    pub max_relay_lag: u32,
+    /// alert when a redeem is not paid this many parachain blocks before its deadline
+    pub redeem_deadline_margin: u32,
+}
+
+/// Periodically checks the vaults for conditions that need attention of the operator
+pub async fn monitor_alerts(
+    parachain_rpc: InterBtcParachain,
+    vault_id_manager: VaultIdManager,
+    btc_rpc: DynBitcoinCoreApi,
+    thresholds: AlertThresholds,
+) -> Result<(), Error> {
+    loop {
+        let vault_ids = vault_id_manager.get_vault_ids().await;
+        if let Err(err) = check_alerts(&parachain_rpc, vault_ids, &btc_rpc, &thresholds).await {
+            tracing::warn!("Failed to check for alerts: {}", err);
+        }
+        sleep(ALERT_CHECK_INTERVAL).await;
+    }
+}
+
+async fn check_alerts<
+    P: VaultRegistryPallet + CollateralBalancesPallet + BtcRelayPallet + RedeemPallet + SecurityPallet + UtilFuncs,
+>(
+    parachain_rpc: &P,
+    vault_ids: Vec<VaultId>,
+    btc_rpc: &DynBitcoinCoreApi,
+    thresholds: &AlertThresholds,
+) -> Result<(), Error> {
+    for vault_id in vault_ids {
+        let collateralization = match parachain_rpc
+            .get_collateralization_from_vault(vault_id.clone(), false)
+            .await
+        {
+            Ok(collateralization) => FixedU128::from_inner(collateralization),
+            // the collateralization is undefined if nothing has been issued
+            Err(err) if err.is_no_tokens_issued() => continue,
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to get the collateralization of vault {}: {}",
+                    vault_id.pretty_print(),
+                    err
+                );
+                continue;
+            }
+        };
+        if collateralization < thresholds.min_collateralization {
+            notify(Alert::LowCollateralization {
+                vault_id: (&vault_id).into(),
+                collateralization: collateralization.to_float(),
+                threshold: thresholds.min_collateralization.to_float(),
+            });
+        }
+    }
+
+    if let Some(threshold) = thresholds.min_native_balance {
+        let balance = parachain_rpc
+            .get_free_balance(parachain_rpc.get_native_currency_id())
+            .await?;
+        if balance < threshold {
+            notify(Alert::LowNativeBalance { balance, threshold });
+        }
+    }
+
+    let bitcoin_height = btc_rpc.get_block_count().await?;
+    let relay_height = parachain_rpc.get_best_block_height().await?;
+    if bitcoin_height.saturating_sub(relay_height as u64) > thresholds.max_relay_lag as u64 {
+        notify(Alert::RelayBehind {
+            bitcoin_height,
+            relay_height,
+        });
+    }
+
+    let (redeems, redeem_period, current_height) = try_join!(
+        parachain_rpc.get_vault_redeem_requests(parachain_rpc.get_account_id().clone()),
+        parachain_rpc.get_redeem_period(),
+        parachain_rpc.get_current_active_block_number(),
+    )?;
+    for (request_id, request) in redeems {
+        if request.status != RedeemRequestStatus::Pending || is_payment_in_flight(&request_id) {
+            continue;
+        }
+        let deadline = calculate_deadline(request.opentime, request.btc_height, request.period, redeem_period)?;
+        let blocks_remaining = deadline.parachain_block.saturating_sub(current_height);
+        if blocks_remaining < thresholds.redeem_deadline_margin {
+            notify(Alert::RedeemDeadline {
+                request_id,
+                vault_id: (&request.vault).into(),
+                amount: request.amount_btc,
+                blocks_remaining,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        body::Bytes,
+        service::{make_service_fn, service_fn},
+        Method, Response, Server,
+    };
+    use std::convert::Infallible;
+
+    #[test]
+    fn should_suppress_duplicates_within_interval() {
+        let mut deduplicator = Deduplicator::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(!deduplicator.is_duplicate("a", start));
+        deduplicator.record("a".to_string(), start);
+
+        assert!(deduplicator.is_duplicate("a", start + Duration::from_secs(59)));
+        assert!(!deduplicator.is_duplicate("b", start + Duration::from_secs(59)));
+        assert!(!deduplicator.is_duplicate("a", start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn should_forget_expired_alerts() {
+        let mut deduplicator = Deduplicator::new(Duration::from_secs(60));
+        let start = Instant::now();
+        deduplicator.record("a".to_string(), start);
+        deduplicator.record("b".to_string(), start + Duration::from_secs(30));
+        assert_eq!(deduplicator.last_sent.len(), 2);
+
+        deduplicator.record("c".to_string(), start + Duration::from_secs(61));
+        assert!(!deduplicator.last_sent.contains_key("a"));
+        assert!(deduplicator.last_sent.contains_key("b"));
+        assert!(deduplicator.last_sent.contains_key("c"));
+    }
+
+    #[test]
+    fn should_serialize_notification() {
+        let alert = Alert::LowNativeBalance {
+            balance: 1,
+            threshold: 2,
+        };
+        let notification = Notification {
+            account_id: "account",
+            timestamp: 3,
+            alert: &alert,
+        };
+        assert_eq!(
+            serde_json::to_value(&notification).unwrap(),
+            serde_json::json!({
+                "account_id": "account",
+                "timestamp": 3,
+                "event": "low_native_balance",
+                "balance": "1",
+                "threshold": "2",
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(&Alert::ServiceRestart).unwrap(),
+            serde_json::json!({ "event": "service_restart" })
+        );
+    }
+
+    /// Waits for the next notification posted to the webhook and returns its event
+    async fn next_event(bodies: &mut UnboundedReceiver<Bytes>) -> serde_json::Value {
+        let body = timeout(Duration::from_secs(5), bodies.recv()).await.unwrap().unwrap();
+        let notification: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(notification["account_id"], "account");
+        notification["event"].clone()
+    }
+
+    #[tokio::test]
+    async fn should_post_alerts_to_webhook() {
+        let (body_sender, mut bodies) = unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let body_sender = body_sender.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let body_sender = body_sender.clone();
+                    async move {
+                        assert_eq!(request.method(), Method::POST);
+                        assert_eq!(request.headers()["content-type"], "application/json");
+                        let body = hyper::body::to_bytes(request.into_body()).await?;
+                        body_sender.send(body).unwrap();
+                        Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let (alert_sender, alerts) = unbounded_channel();
+        tokio::spawn(run_notifier(
+            "account".to_string(),
+            NotifierConfig {
+                webhooks: vec![url],
+                repeat_interval: Duration::from_secs(60),
+                max_alerts_per_hour: NonZeroU32::new(10).unwrap(),
+            },
+            alerts,
+        ));
+        alert_sender.send(Alert::ServiceRestart).unwrap();
+        // the duplicate is not sent
+        alert_sender.send(Alert::ServiceRestart).unwrap();
+        alert_sender
+            .send(Alert::LowNativeBalance {
+                balance: 1,
+                threshold: 2,
+            })
+            .unwrap();
+
+        assert_eq!(next_event(&mut bodies).await, "service_restart");
+        assert_eq!(next_event(&mut bodies).await, "low_native_balance");
+    }
+
+    #[cfg(feature = "parachain-metadata-kintsugi")]
+    mod check_alerts_tests {
+        use super::*;
+        use crate::mock::MockProvider;
+        use bitcoin::testing::InMemoryChain;
+        use runtime::{
+            subxt::utils::Static, AccountId, Error as RuntimeError, FixedPointNumber, InterBtcRedeemRequest, Token,
+            DOT, IBTC, INTR,
+        };
+        use serial_test::serial;
+        use std::sync::Arc;
+
+        fn dummy_vault_id() -> VaultId {
+            VaultId::new(AccountId::new([1u8; 32]), Token(DOT), Token(IBTC))
+        }
+
+        fn thresholds() -> AlertThresholds {
+            AlertThresholds {
+                min_collateralization: FixedU128::from(2),
+                min_native_balance: Some(100),
+                max_relay_lag: 6,
+                redeem_deadline_margin: 10,
+            }
+        }
+
+        /// Parachain of a vault with a redeem that can be cancelled at block 150
+        fn parachain_rpc(
+            collateralization: Option<FixedU128>,
+            native_balance: u128,
+            relay_height: u32,
+            current_height: u32,
+        ) -> MockProvider {
+            let mut parachain_rpc = MockProvider::default();
+            parachain_rpc
+                .expect_get_collateralization_from_vault()
+                .returning(move |_, _| {
+                    collateralization
+                        .map(|x| x.into_inner())
+                        .ok_or(RuntimeError::VaultNotFound)
+                });
+            parachain_rpc
+                .expect_get_native_currency_id()
+                .returning(move || Token(INTR));
+            parachain_rpc
+                .expect_get_free_balance()
+                .returning(move |_| Ok(native_balance));
+            parachain_rpc
+                .expect_get_best_block_height()
+                .returning(move || Ok(relay_height));
+            parachain_rpc
+                .expect_get_account_id()
+                .return_const(AccountId::new([1u8; 32]));
+            parachain_rpc.expect_get_vault_redeem_requests().returning(|_| {
+                Ok(vec![(
+                    H256::repeat_byte(7),
+                    InterBtcRedeemRequest {
+                        amount_btc: 10_000,
+                        btc_address: Static(Default::default()),
+                        btc_height: Default::default(),
+                        fee: Default::default(),
+                        transfer_fee_btc: Default::default(),
+                        premium: Default::default(),
+                        opentime: 100,
+                        period: 0,
+                        redeemer: AccountId::new([1u8; 32]),
+                        status: RedeemRequestStatus::Pending,
+                        vault: dummy_vault_id(),
+                    },
+                )])
+            });
+            parachain_rpc.expect_get_redeem_period().returning(|| Ok(50));
+            parachain_rpc
+                .expect_get_current_active_block_number()
+                .returning(move || Ok(current_height));
+            parachain_rpc
+        }
+
+        /// Bitcoin chain at height 10
+        fn btc_rpc() -> DynBitcoinCoreApi {
+            let chain = InMemoryChain::new();
+            chain.mine_blocks(10);
+            Arc::new(chain)
+        }
+
+        /// Runs `check_alerts` and returns the events of the alerts it sent
+        async fn check_alert_events(parachain_rpc: MockProvider) -> Vec<String> {
+            let (sender, mut receiver) = unbounded_channel();
+            *ALERT_SENDER.lock().unwrap() = Some(sender);
+            check_alerts(&parachain_rpc, vec![dummy_vault_id()], &btc_rpc(), &thresholds())
+                .await
+                .unwrap();
+            *ALERT_SENDER.lock().unwrap() = None;
+
+            std::iter::from_fn(|| receiver.try_recv().ok())
+                .map(|alert| {
+                    serde_json::to_value(alert).unwrap()["event"]
+                        .as_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect()
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn should_alert_when_thresholds_are_crossed() {
+            let parachain_rpc = parachain_rpc(Some(FixedU128::saturating_from_rational(3u32, 2u32)), 99, 3, 141);
+            assert_eq!(
+                check_alert_events(parachain_rpc).await,
+                vec![
+                    "low_collateralization",
+                    "low_native_balance",
+                    "relay_behind",
+                    "redeem_deadline"
+                ]
+            );
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn should_not_alert_within_thresholds() {
+            let parachain_rpc = parachain_rpc(Some(FixedU128::from(2)), 100, 4, 140);
+            assert_eq!(check_alert_events(parachain_rpc).await, Vec::<String>::new());
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn should_check_other_alerts_if_collateralization_is_unavailable() {
+            let parachain_rpc = parachain_rpc(None, 99, 3, 141);
+            assert_eq!(
+                check_alert_events(parachain_rpc).await,
+                vec!["low_native_balance", "relay_behind", "redeem_deadline"]
+            );
+        }
+    }
+}
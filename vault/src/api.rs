@@ -0,0 +1,520 @@
+use crate::{
+    error::Error,
+    execution::{parachain_blocks_to_bitcoin_blocks_rounded_up, RequestType},
+    service::{
+        warp::{self, Filter, Rejection, Reply},
+        DynBitcoinCoreApi,
+    },
+    system::VaultIdManager,
+};
+use bitcoin::Txid;
+use futures::{try_join, Future};
+use jsonrpc_core::{serde_json::Value, Error as JsonRpcError, ErrorCode as JsonRpcErrorCode, IoHandler};
+use lazy_static::lazy_static;
+use runtime::{
+    BtcRelayPallet, FixedU128, InterBtcParachain, IssuePallet, IssueRequestStatus, PrettyPrint, RedeemPallet,
+    RedeemRequestStatus, ReplacePallet, ReplaceRequestStatus, RuntimeCurrencyInfo, SecurityPallet, UtilFuncs, VaultId,
+    VaultRegistryPallet, H256,
+};
+use serde::{Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+const MAX_REQUEST_SIZE_BYTES: u64 = 64 * 1024;
+
+lazy_static! {
+    static ref TASK_STATUS: Mutex<BTreeMap<String, TaskStatus>> = Mutex::new(BTreeMap::new());
+    static ref IN_FLIGHT_PAYMENTS: Mutex<HashMap<H256, InFlightPayment>> = Mutex::new(HashMap::new());
+}
+
+// amounts are serialized as strings since json consumers commonly parse numbers as f64
+pub(crate) fn serialize_amount<S: Serializer>(amount: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&amount.to_string())
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct VaultIdInfo {
+    pub account_id: String,
+    pub collateral_currency: String,
+    pub wrapped_currency: String,
+}
+
+impl From<&VaultId> for VaultIdInfo {
+    fn from(vault_id: &VaultId) -> Self {
+        Self {
+            account_id: vault_id.account_id.pretty_print(),
+            collateral_currency: vault_id.collateral_currency().symbol().unwrap_or_default(),
+            wrapped_currency: vault_id.wrapped_currency().symbol().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "status", content = "error")]
+pub enum TaskStatus {
+    Disabled,
+    Running,
+    Stopped,
+    Failed(String),
+}
+
+/// Forgets the task statuses and in-flight payments, e.g. when the service restarts
+pub(crate) fn reset_state() {
+    if let Ok(mut tasks) = TASK_STATUS.lock() {
+        tasks.clear();
+    }
+    if let Ok(mut payments) = IN_FLIGHT_PAYMENTS.lock() {
+        payments.clear();
+    }
+}
+
+pub(crate) fn set_task_status(name: &str, status: TaskStatus) {
+    if let Ok(mut tasks) = TASK_STATUS.lock() {
+        tasks.insert(name.to_string(), status);
+    }
+}
+
+/// Runs the task, recording whether it is still running or has stopped
+pub(crate) async fn run_with_status<F>(name: String, task: F) -> Result<(), Error>
+where
+    F: Future<Output = Result<(), Error>>,
+{
+    set_task_status(&name, TaskStatus::Running);
+    let result = task.await;
+    let status = match result {
+        Ok(_) => TaskStatus::Stopped,
+        Err(ref err) => TaskStatus::Failed(err.to_string()),
+    };
+    set_task_status(&name, status);
+    result
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct InFlightPayment {
+    pub request_id: H256,
+    pub request_type: RequestType,
+    pub vault_id: VaultIdInfo,
+    pub txid: Txid,
+}
+
+/// Records the (latest) bitcoin transaction paying the given request
+pub(crate) fn track_payment(request_id: H256, request_type: RequestType, vault_id: &VaultId, txid: Txid) {
+    if let Ok(mut payments) = IN_FLIGHT_PAYMENTS.lock() {
+        payments.insert(
+            request_id,
+            InFlightPayment {
+                request_id,
+                request_type,
+                vault_id: vault_id.into(),
+                txid,
+            },
+        );
+    }
+}
+
+pub(crate) fn is_payment_in_flight(request_id: &H256) -> bool {
+    IN_FLIGHT_PAYMENTS
+        .lock()
+        .map_or(false, |payments| payments.contains_key(request_id))
+}
+
+/// Removes the payment of the request once it is dropped, i.e. when the
+/// request is executed, has failed or the task is cancelled.
+pub(crate) struct PaymentGuard(H256);
+
+impl PaymentGuard {
+    pub(crate) fn new(request_id: H256) -> Self {
+        Self(request_id)
+    }
+}
+
+impl Drop for PaymentGuard {
+    fn drop(&mut self) {
+        if let Ok(mut payments) = IN_FLIGHT_PAYMENTS.lock() {
+            payments.remove(&self.0);
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Deadline {
+    pub parachain_block: u32,
+    pub bitcoin_height: u32,
+}
+
+/// Calculates the deadline after which the request can be cancelled
+pub(crate) fn calculate_deadline(
+    opentime: u32,
+    btc_height: u32,
+    period: u32,
+    global_period: u32,
+) -> Result<Deadline, Error> {
+    let period = period.max(global_period);
+    Ok(Deadline {
+        parachain_block: opentime.checked_add(period).ok_or(Error::ArithmeticOverflow)?,
+        bitcoin_height: btc_height
+            .checked_add(parachain_blocks_to_bitcoin_blocks_rounded_up(period)?)
+            .ok_or(Error::ArithmeticOverflow)?,
+    })
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct VaultInfo {
+    pub vault_id: VaultIdInfo,
+    pub status: String,
+    #[serde(serialize_with = "serialize_amount")]
+    pub collateral: u128,
+    /// `None` if the vault has no issued tokens
+    pub collateralization: Option<f64>,
+    #[serde(serialize_with = "serialize_amount")]
+    pub issued_tokens: u128,
+    #[serde(serialize_with = "serialize_amount")]
+    pub to_be_issued_tokens: u128,
+    #[serde(serialize_with = "serialize_amount")]
+    pub to_be_redeemed_tokens: u128,
+    #[serde(serialize_with = "serialize_amount")]
+    pub to_be_replaced_tokens: u128,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct OpenRequest {
+    pub request_id: H256,
+    pub vault_id: VaultIdInfo,
+    #[serde(serialize_with = "serialize_amount")]
+    pub amount: u128,
+    pub deadline: Deadline,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct OpenRequests {
+    pub issue: Vec<OpenRequest>,
+    pub redeem: Vec<OpenRequest>,
+    /// replace requests where this client pays as the old vault
+    pub replace_outgoing: Vec<OpenRequest>,
+    /// replace requests where this client receives as the new vault
+    pub replace_incoming: Vec<OpenRequest>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RelayStatus {
+    pub enabled: bool,
+    pub bitcoin_height: u64,
+    pub relay_height: u32,
+    pub blocks_behind: u64,
+}
+
+#[derive(Clone)]
+pub struct ApiContext {
+    pub parachain_rpc: InterBtcParachain,
+    pub vault_id_manager: VaultIdManager,
+    pub btc_rpc: DynBitcoinCoreApi,
+    pub relay_enabled: bool,
+}
+
+impl ApiContext {
+    async fn vault_ids(&self) -> Result<Vec<VaultIdInfo>, Error> {
+        Ok(self
+            .vault_id_manager
+            .get_vault_ids()
+            .await
+            .iter()
+            .map(Into::into)
+            .collect())
+    }
+
+    async fn vault_status(&self) -> Result<Vec<VaultInfo>, Error> {
+        let mut ret = Vec::new();
+        for vault_id in self.vault_id_manager.get_vault_ids().await {
+            let (vault, collateral) = try_join!(
+                self.parachain_rpc.get_vault(&vault_id),
+                self.parachain_rpc.get_vault_total_collateral(vault_id.clone()),
+            )?;
+            // the collateralization is undefined if nothing has been issued
+            let collateralization = self
+                .parachain_rpc
+                .get_collateralization_from_vault(vault_id.clone(), false)
+                .await
+                .ok()
+                .map(|x| FixedU128::from_inner(x).to_float());
+            ret.push(VaultInfo {
+                vault_id: (&vault_id).into(),
+                status: format!("{:?}", vault.status),
+                collateral,
+                collateralization,
+                issued_tokens: vault.issued_tokens,
+                to_be_issued_tokens: vault.to_be_issued_tokens,
+                to_be_redeemed_tokens: vault.to_be_redeemed_tokens,
+                to_be_replaced_tokens: vault.to_be_replaced_tokens,
+            });
+        }
+        Ok(ret)
+    }
+
+    async fn open_requests(&self) -> Result<OpenRequests, Error> {
+        let account_id = self.parachain_rpc.get_account_id();
+        let (issues, redeems, old_replaces, new_replaces) = try_join!(
+            self.parachain_rpc.get_vault_issue_requests(account_id.clone()),
+            self.parachain_rpc.get_vault_redeem_requests(account_id.clone()),
+            self.parachain_rpc.get_old_vault_replace_requests(account_id.clone()),
+            self.parachain_rpc.get_new_vault_replace_requests(account_id.clone()),
+        )?;
+        let (issue_period, redeem_period, replace_period) = try_join!(
+            self.parachain_rpc.get_issue_period(),
+            self.parachain_rpc.get_redeem_period(),
+            self.parachain_rpc.get_replace_period(),
+        )?;
+
+        let replace_requests = |requests: Vec<(H256, runtime::InterBtcReplaceRequest)>, incoming: bool| {
+            requests
+                .into_iter()
+                .filter(|(_, request)| request.status == ReplaceRequestStatus::Pending)
+                .map(|(request_id, request)| {
+                    Ok(OpenRequest {
+                        request_id,
+                        vault_id: if incoming {
+                            (&request.new_vault).into()
+                        } else {
+                            (&request.old_vault).into()
+                        },
+                        amount: request.amount,
+                        deadline: calculate_deadline(
+                            request.accept_time,
+                            request.btc_height,
+                            request.period,
+                            replace_period,
+                        )?,
+                    })
+                })
+                .collect::<Result<Vec<_>, Error>>()
+        };
+
+        Ok(OpenRequests {
+            issue: issues
+                .into_iter()
+                .filter(|(_, request)| request.status == IssueRequestStatus::Pending)
+                .map(|(request_id, request)| {
+                    Ok(OpenRequest {
+                        request_id,
+                        vault_id: (&request.vault).into(),
+                        amount: request.amount.saturating_add(request.fee),
+                        deadline: calculate_deadline(
+                            request.opentime,
+                            request.btc_height,
+                            request.period,
+                            issue_period,
+                        )?,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+            redeem: redeems
+                .into_iter()
+                .filter(|(_, request)| request.status == RedeemRequestStatus::Pending)
+                .map(|(request_id, request)| {
+                    Ok(OpenRequest {
+                        request_id,
+                        vault_id: (&request.vault).into(),
+                        amount: request.amount_btc,
+                        deadline: calculate_deadline(
+                            request.opentime,
+                            request.btc_height,
+                            request.period,
+                            redeem_period,
+                        )?,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+            replace_outgoing: replace_requests(old_replaces, false)?,
+            replace_incoming: replace_requests(new_replaces, true)?,
+        })
+    }
+
+    async fn in_flight_payments(&self) -> Result<Vec<InFlightPayment>, Error> {
+        Ok(IN_FLIGHT_PAYMENTS
+            .lock()
+            .map(|payments| payments.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn relay_status(&self) -> Result<RelayStatus, Error> {
+        let bitcoin_height = self.btc_rpc.get_block_count().await?;
+        let relay_height = self.parachain_rpc.get_best_block_height().await?;
+        Ok(RelayStatus {
+            enabled: self.relay_enabled,
+            bitcoin_height,
+            relay_height,
+            blocks_behind: bitcoin_height.saturating_sub(relay_height as u64),
+        })
+    }
+
+    async fn task_health(&self) -> Result<BTreeMap<String, TaskStatus>, Error> {
+        Ok(TASK_STATUS.lock().map(|tasks| tasks.clone()).unwrap_or_default())
+    }
+
+    async fn system_health(&self) -> Result<u32, Error> {
+        Ok(self.parachain_rpc.get_current_active_block_number().await?)
+    }
+}
+
+fn handle_resp<T: Serialize>(resp: Result<T, Error>) -> Result<Value, JsonRpcError> {
+    resp.and_then(|data| Ok(serde_json::to_value(data)?))
+        .map_err(|err| JsonRpcError {
+            code: JsonRpcErrorCode::InternalError,
+            message: err.to_string(),
+            data: None,
+        })
+}
+
+fn add_method<F, R, T>(io: &mut IoHandler, name: &str, ctx: &Arc<ApiContext>, method: F)
+where
+    F: Fn(Arc<ApiContext>) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<T, Error>> + Send + 'static,
+    T: Serialize + 'static,
+{
+    let ctx = ctx.clone();
+    io.add_method(name, move |_| {
+        let result = method(ctx.clone());
+        async move { handle_resp(result.await) }
+    });
+}
+
+fn rpc_handler(ctx: ApiContext) -> IoHandler {
+    let ctx = Arc::new(ctx);
+    let mut io = IoHandler::default();
+    add_method(&mut io, "system_health", &ctx, |ctx| async move {
+        ctx.system_health().await
+    });
+    add_method(&mut io, "vault_ids", &ctx, |ctx| async move { ctx.vault_ids().await });
+    add_method(
+        &mut io,
+        "vault_status",
+        &ctx,
+        |ctx| async move { ctx.vault_status().await },
+    );
+    add_method(&mut io, "open_requests", &ctx, |ctx| async move {
+        ctx.open_requests().await
+    });
+    add_method(&mut io, "in_flight_payments", &ctx, |ctx| async move {
+        ctx.in_flight_payments().await
+    });
+    add_method(
+        &mut io,
+        "relay_status",
+        &ctx,
+        |ctx| async move { ctx.relay_status().await },
+    );
+    add_method(
+        &mut io,
+        "task_health",
+        &ctx,
+        |ctx| async move { ctx.task_health().await },
+    );
+    io
+}
+
+async fn handle_request(io: Arc<IoHandler>, body: warp::hyper::body::Bytes) -> Result<impl Reply, Rejection> {
+    // invalid utf-8 results in a json-rpc parse error
+    let response = io
+        .handle_request(&String::from_utf8_lossy(&body))
+        .await
+        .unwrap_or_default();
+    Ok(warp::reply::with_header(response, "content-type", "application/json"))
+}
+
+/// Serves the JSON-RPC API on localhost until the service shuts down.
+pub async fn serve_api(ctx: ApiContext, port: u16) -> Result<(), Error> {
+    let io = Arc::new(rpc_handler(ctx));
+    let route = warp::post()
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(MAX_REQUEST_SIZE_BYTES))
+        .and(warp::any().map(move || io.clone()))
+        .and(warp::body::bytes())
+        .and_then(handle_request);
+
+    // the api is not essential, so don't restart the vault if the port is taken
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+    let (addr, server) = match warp::serve(route).try_bind_ephemeral(addr) {
+        Ok(bound) => bound,
+        Err(err) => {
+            tracing::error!("Failed to start the vault API on port {}: {}", port, err);
+            return Ok(());
+        }
+    };
+    tracing::info!("Starting the vault API at http://{}", addr);
+    server.await;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "parachain-metadata-kintsugi"))]
+mod tests {
+    use super::*;
+    use bitcoin::Hash;
+    use runtime::{AccountId, Token, DOT, IBTC};
+    use serial_test::serial;
+
+    fn dummy_vault_id() -> VaultId {
+        VaultId::new(AccountId::new([1u8; 32]), Token(DOT), Token(IBTC))
+    }
+
+    #[test]
+    fn should_calculate_deadline_with_longest_period() {
+        // 1 bitcoin block per 50 parachain blocks
+        assert_eq!(
+            calculate_deadline(100, 10, 100, 50).unwrap(),
+            Deadline {
+                parachain_block: 200,
+                bitcoin_height: 12,
+            }
+        );
+        assert_eq!(
+            calculate_deadline(100, 10, 50, 100).unwrap(),
+            Deadline {
+                parachain_block: 200,
+                bitcoin_height: 12,
+            }
+        );
+        assert!(calculate_deadline(u32::MAX, 10, 1, 1).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn should_remove_payment_when_guard_is_dropped() {
+        let request_id = H256::from_low_u64_be(1);
+        let guard = PaymentGuard::new(request_id);
+        track_payment(request_id, RequestType::Redeem, &dummy_vault_id(), Txid::all_zeros());
+        assert!(IN_FLIGHT_PAYMENTS.lock().unwrap().contains_key(&request_id));
+
+        drop(guard);
+        assert!(!IN_FLIGHT_PAYMENTS.lock().unwrap().contains_key(&request_id));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn should_record_task_status() {
+        run_with_status("ok task".to_string(), async { Ok(()) }).await.unwrap();
+        run_with_status("failing task".to_string(), async { Err(Error::ClientShutdown) })
+            .await
+            .unwrap_err();
+
+        let tasks = TASK_STATUS.lock().unwrap().clone();
+        assert_eq!(tasks.get("ok task"), Some(&TaskStatus::Stopped));
+        assert_eq!(
+            tasks.get("failing task"),
+            Some(&TaskStatus::Failed(Error::ClientShutdown.to_string()))
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn should_reset_state() {
+        let request_id = H256::from_low_u64_be(2);
+        set_task_status("stale task", TaskStatus::Running);
+        track_payment(request_id, RequestType::Replace, &dummy_vault_id(), Txid::all_zeros());
+
+        reset_state();
+        assert!(TASK_STATUS.lock().unwrap().is_empty());
+        assert!(!is_payment_in_flight(&request_id));
+    }
+}
@@ -19,6 +19,33 @@ impl FromStr for RestartPolicy {
     }
 }
 
+/// How the fee rate of payments is derived from the parachain oracle
+/// and the estimate of the local bitcoin node or electrs server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeeRatePolicy {
+    /// Only use the oracle.
+    Oracle,
+    /// Use the higher of both estimates.
+    Max,
+    /// Use the lower of both estimates.
+    Min,
+    /// Use the oracle, but within bounds of the local estimate.
+    Bounded,
+}
+
+impl FromStr for FeeRatePolicy {
+    type Err = String;
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        match code {
+            "oracle" => Ok(FeeRatePolicy::Oracle),
+            "max" => Ok(FeeRatePolicy::Max),
+            "min" => Ok(FeeRatePolicy::Min),
+            "bounded" => Ok(FeeRatePolicy::Bounded),
+            _ => Err("Could not parse input as FeeRatePolicy".to_string()),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum LoggingFormat {
     Full,
@@ -0,0 +1,315 @@
+use crate::{error::Error, metrics::increment_collateral_adjustment_counter, system::VaultIdManager};
+use runtime::{
+    CollateralBalancesPallet, CurrencyId, FixedPointNumber, FixedU128, PrettyPrint, VaultId, VaultRegistryPallet,
+};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+const COLLATERAL_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// part of the free balance that is never deposited, so that the vault can still pay transaction fees
+const FREE_BALANCE_RESERVE_PERCENT: u128 = 10;
+
+/// The collateralization band (e.g. 2.6 for 260%) a vault should stay within
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CollateralBand {
+    pub floor: FixedU128,
+    pub ceiling: FixedU128,
+}
+
+impl CollateralBand {
+    /// adjustments aim for the middle of the band so that the next adjustment is not immediately triggered again
+    fn target(&self) -> FixedU128 {
+        FixedU128::from_inner(self.floor.into_inner() / 2 + self.ceiling.into_inner() / 2)
+    }
+}
+
+/// Parses `LOWER:UPPER`, in percent, where lower is positive and below upper.
+pub(crate) fn parse_percent_range(
+    input: &str,
+    lower_name: &str,
+    upper_name: &str,
+) -> Result<(FixedU128, FixedU128), String> {
+    let (lower, upper) = input
+        .split_once(':')
+        .ok_or_else(|| format!("invalid range: no `:` found in `{input}`"))?;
+    let lower: u128 = lower
+        .parse()
+        .map_err(|err| format!("invalid {lower_name} `{lower}`: {err}"))?;
+    let upper: u128 = upper
+        .parse()
+        .map_err(|err| format!("invalid {upper_name} `{upper}`: {err}"))?;
+    if lower == 0 || lower >= upper {
+        return Err(format!(
+            "{lower_name} ({lower}) must be positive and below the {upper_name} ({upper})"
+        ));
+    }
+    Ok((
+        FixedU128::saturating_from_rational(lower, 100u128),
+        FixedU128::saturating_from_rational(upper, 100u128),
+    ))
+}
+
+impl FromStr for CollateralBand {
+    type Err = String;
+
+    /// Parses `FLOOR:CEILING`, in percent.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (floor, ceiling) = parse_percent_range(input, "floor", "ceiling")?;
+        Ok(Self { floor, ceiling })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CollateralConfig {
+    /// the band of each vault, by its collateral currency
+    pub bands: Vec<(CurrencyId, CollateralBand)>,
+    /// minimum time between two adjustments of the same vault
+    pub min_interval: Duration,
+    /// maximum adjustment as a fraction of the vault's collateral
+    pub max_adjustment: FixedU128,
+    /// only log the adjustments that would be made
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Adjustment {
+    Deposit(u128),
+    Withdraw(u128),
+}
+
+/// Calculates the deposit or withdrawal that brings the collateralization back to the
+/// middle of the band. Since the collateralization is proportional to the collateral,
+/// the target collateral is `collateral * target / collateralization`.
+fn calculate_adjustment(
+    band: &CollateralBand,
+    max_adjustment: FixedU128,
+    collateral: u128,
+    collateralization: FixedU128,
+    required_collateral: u128,
+    free_balance: u128,
+) -> Option<Adjustment> {
+    if collateralization >= band.floor && collateralization <= band.ceiling {
+        return None;
+    }
+    let target_collateral =
+        FixedU128::checked_from_rational(band.target().into_inner(), collateralization.into_inner())?
+            .checked_mul_int(collateral)?;
+    let max_amount = max_adjustment.saturating_mul_int(collateral);
+
+    let adjustment = if collateralization < band.floor {
+        let available = free_balance.saturating_sub(free_balance.saturating_mul(FREE_BALANCE_RESERVE_PERCENT) / 100);
+        Adjustment::Deposit(
+            target_collateral
+                .saturating_sub(collateral)
+                .min(max_amount)
+                .min(available),
+        )
+    } else {
+        // never withdraw below the secure threshold, regardless of the configured band
+        Adjustment::Withdraw(
+            collateral
+                .saturating_sub(target_collateral.max(required_collateral))
+                .min(max_amount),
+        )
+    };
+    match adjustment {
+        Adjustment::Deposit(0) | Adjustment::Withdraw(0) => None,
+        adjustment => Some(adjustment),
+    }
+}
+
+async fn check_collateral<P: VaultRegistryPallet + CollateralBalancesPallet>(
+    parachain_rpc: &P,
+    vault_id: &VaultId,
+    band: &CollateralBand,
+    config: &CollateralConfig,
+) -> Result<Option<Adjustment>, Error> {
+    let collateralization = match parachain_rpc
+        .get_collateralization_from_vault(vault_id.clone(), false)
+        .await
+    {
+        Ok(collateralization) => FixedU128::from_inner(collateralization),
+        // the collateralization is undefined if nothing has been issued, keep the collateral as is
+        Err(err) if err.is_no_tokens_issued() => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let (collateral, required_collateral, free_balance) = futures::try_join!(
+        parachain_rpc.get_vault_total_collateral(vault_id.clone()),
+        parachain_rpc.get_required_collateral_for_vault(vault_id.clone()),
+        parachain_rpc.get_free_balance(vault_id.collateral_currency()),
+    )?;
+
+    let adjustment = match calculate_adjustment(
+        band,
+        config.max_adjustment,
+        collateral,
+        collateralization,
+        required_collateral,
+        free_balance,
+    ) {
+        Some(adjustment) => adjustment,
+        None => {
+            if collateralization < band.floor {
+                tracing::warn!(
+                    "[{}] Collateralization {} is below the floor but no free balance is available",
+                    vault_id.pretty_print(),
+                    collateralization.to_float()
+                );
+            }
+            return Ok(None);
+        }
+    };
+
+    tracing::info!(
+        "[{}] Collateralization {} is outside of [{}, {}]: {}{:?}",
+        vault_id.pretty_print(),
+        collateralization.to_float(),
+        band.floor.to_float(),
+        band.ceiling.to_float(),
+        if config.dry_run { "(dry run) " } else { "" },
+        adjustment
+    );
+    if !config.dry_run {
+        match adjustment {
+            Adjustment::Deposit(amount) => parachain_rpc.deposit_collateral(vault_id, amount).await?,
+            Adjustment::Withdraw(amount) => parachain_rpc.withdraw_collateral(vault_id, amount).await?,
+        }
+    }
+    Ok(Some(adjustment))
+}
+
+/// Periodically deposits collateral from the free balance if the collateralization of a vault
+/// falls below the floor of its band, and withdraws the excess above the ceiling.
+pub async fn manage_collateral<P: VaultRegistryPallet + CollateralBalancesPallet>(
+    parachain_rpc: P,
+    vault_id_manager: VaultIdManager,
+    config: CollateralConfig,
+) -> Result<(), Error> {
+    let mut last_adjustments: HashMap<VaultId, Instant> = HashMap::new();
+    loop {
+        for vault_id in vault_id_manager.get_vault_ids().await {
+            let band = match config
+                .bands
+                .iter()
+                .find(|(currency_id, _)| *currency_id == vault_id.collateral_currency())
+            {
+                Some((_, band)) => band,
+                None => continue,
+            };
+            if last_adjustments
+                .get(&vault_id)
+                .map_or(false, |last| last.elapsed() < config.min_interval)
+            {
+                continue;
+            }
+            match check_collateral(&parachain_rpc, &vault_id, band, &config).await {
+                Ok(Some(adjustment)) => {
+                    let action = match adjustment {
+                        Adjustment::Deposit(_) => "deposit",
+                        Adjustment::Withdraw(_) => "withdraw",
+                    };
+                    increment_collateral_adjustment_counter(&vault_id, action, config.dry_run);
+                    last_adjustments.insert(vault_id, Instant::now());
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    increment_collateral_adjustment_counter(&vault_id, "failed", config.dry_run);
+                    tracing::error!("[{}] Failed to adjust collateral: {}", vault_id.pretty_print(), err);
+                    // don't retry immediately, the failure is likely to persist
+                    last_adjustments.insert(vault_id, Instant::now());
+                }
+            }
+        }
+        sleep(COLLATERAL_CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn percent(x: u128) -> FixedU128 {
+        FixedU128::saturating_from_rational(x, 100u128)
+    }
+
+    fn band() -> CollateralBand {
+        CollateralBand {
+            floor: percent(200),
+            ceiling: percent(400),
+        }
+    }
+
+    #[test]
+    fn should_parse_band() {
+        assert_eq!(CollateralBand::from_str("200:400"), Ok(band()));
+        assert!(CollateralBand::from_str("200").is_err());
+        assert!(CollateralBand::from_str("400:200").is_err());
+        assert!(CollateralBand::from_str("0:200").is_err());
+        assert!(CollateralBand::from_str("a:200").is_err());
+    }
+
+    #[test]
+    fn should_not_adjust_within_band() {
+        assert_eq!(
+            calculate_adjustment(&band(), percent(100), 1000, percent(250), 0, 1000),
+            None
+        );
+    }
+
+    #[test]
+    fn should_deposit_up_to_target() {
+        // 1000 at 150% -> 2000 at 300%
+        assert_eq!(
+            calculate_adjustment(&band(), percent(100), 1000, percent(150), 0, 10_000),
+            Some(Adjustment::Deposit(1000))
+        );
+    }
+
+    #[test]
+    fn should_limit_deposit() {
+        // at most 50% of the collateral
+        assert_eq!(
+            calculate_adjustment(&band(), percent(50), 1000, percent(150), 0, 10_000),
+            Some(Adjustment::Deposit(500))
+        );
+        // keep a reserve of the free balance
+        assert_eq!(
+            calculate_adjustment(&band(), percent(100), 1000, percent(150), 0, 100),
+            Some(Adjustment::Deposit(90))
+        );
+        assert_eq!(
+            calculate_adjustment(&band(), percent(100), 1000, percent(150), 0, 0),
+            None
+        );
+    }
+
+    #[test]
+    fn should_withdraw_down_to_target() {
+        // 1000 at 600% -> 500 at 300%
+        assert_eq!(
+            calculate_adjustment(&band(), percent(100), 1000, percent(600), 0, 0),
+            Some(Adjustment::Withdraw(500))
+        );
+        // at most 20% of the collateral
+        assert_eq!(
+            calculate_adjustment(&band(), percent(20), 1000, percent(600), 0, 0),
+            Some(Adjustment::Withdraw(200))
+        );
+    }
+
+    #[test]
+    fn should_not_withdraw_below_required_collateral() {
+        assert_eq!(
+            calculate_adjustment(&band(), percent(100), 1000, percent(600), 800, 0),
+            Some(Adjustment::Withdraw(200))
+        );
+        assert_eq!(
+            calculate_adjustment(&band(), percent(100), 1000, percent(600), 1000, 0),
+            None
+        );
+    }
+}
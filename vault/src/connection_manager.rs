@@ -19,6 +19,22 @@ pub use warp;
 
 pub type DynBitcoinCoreApi = Arc<dyn BitcoinCoreApi + Send + Sync>;
 
+/// Name of the bitcoin-core wallet of `vault_id`, the master wallet is named `{prefix}-master`.
+pub fn vault_wallet_name(prefix: &str, vault_id: &VaultId) -> Result<String, BitcoinError> {
+    let collateral_currency: CurrencyId = vault_id.collateral_currency();
+    let wrapped_currency: CurrencyId = vault_id.wrapped_currency();
+    Ok(format!(
+        "{}-{}-{}",
+        prefix,
+        collateral_currency
+            .symbol()
+            .map_err(|_| BitcoinError::FailedToConstructWalletName)?,
+        wrapped_currency
+            .symbol()
+            .map_err(|_| BitcoinError::FailedToConstructWalletName)?,
+    ))
+}
+
 #[async_trait]
 pub trait Service<Config> {
     const NAME: &'static str;
@@ -100,19 +116,7 @@ impl<Config: Clone + Send + 'static, F: Fn()> ConnectionManager<Config, F> {
             let config_copy = self.bitcoin_config.clone();
             let network_copy = bitcoin_core.network();
             let constructor = move |vault_id: VaultId| {
-                let collateral_currency: CurrencyId = vault_id.collateral_currency();
-                let wrapped_currency: CurrencyId = vault_id.wrapped_currency();
-                let wallet_name = format!(
-                    "{}-{}-{}",
-                    prefix,
-                    collateral_currency
-                        .symbol()
-                        .map_err(|_| BitcoinError::FailedToConstructWalletName)?,
-                    wrapped_currency
-                        .symbol()
-                        .map_err(|_| BitcoinError::FailedToConstructWalletName)?,
-                );
-                config_copy.new_client_with_network(Some(wallet_name), network_copy)
+                config_copy.new_client_with_network(Some(vault_wallet_name(&prefix, &vault_id)?), network_copy)
             };
 
             let service = S::new_service(
@@ -0,0 +1,144 @@
+use crate::{
+    cli::FeeRatePolicy, error::Error, execution::get_fee_rate, service::DynBitcoinCoreApi, system::VaultIdManager,
+};
+use bitcoin::{SatPerVbyte, Txid};
+use runtime::{OraclePallet, PrettyPrint};
+use std::time::Duration;
+use tokio::time::sleep;
+
+const CONSOLIDATION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConsolidationConfig {
+    /// only consolidate if the bitcoin fee estimate is at or below this rate
+    pub max_fee_rate: SatPerVbyte,
+    /// only consolidate if the wallet has more utxos than this
+    pub min_utxos: usize,
+    /// spend at most this many utxos per consolidation
+    pub max_inputs: usize,
+    /// only spend utxos worth at most this many satoshis
+    pub max_utxo_value: u64,
+    /// how to combine the oracle and local fee estimates
+    pub fee_rate_policy: FeeRatePolicy,
+}
+
+async fn maybe_consolidate(
+    btc_rpc: &DynBitcoinCoreApi,
+    config: &ConsolidationConfig,
+    fee_rate: SatPerVbyte,
+) -> Result<Option<Txid>, Error> {
+    if fee_rate > config.max_fee_rate || btc_rpc.get_utxo_count().await? <= config.min_utxos {
+        return Ok(None);
+    }
+    Ok(btc_rpc
+        .consolidate_utxos(config.max_inputs, config.max_utxo_value, fee_rate)
+        .await?)
+}
+
+/// Periodically sweep the small utxos of each vault into a single output while fees are low,
+/// so that payments made during fee spikes need fewer inputs.
+pub async fn consolidate_utxos<P: OraclePallet + Send + Sync>(
+    parachain_rpc: P,
+    vault_id_manager: VaultIdManager,
+    config: ConsolidationConfig,
+) -> Result<(), Error> {
+    loop {
+        for vault in vault_id_manager.get_entries().await {
+            let fee_rate = match get_fee_rate(&parachain_rpc, &vault.btc_rpc, config.fee_rate_policy).await {
+                Ok(fee_rate) => fee_rate,
+                Err(err) => {
+                    tracing::warn!("Failed to get the bitcoin fee estimate: {}", err);
+                    continue;
+                }
+            };
+            match maybe_consolidate(&vault.btc_rpc, &config, fee_rate).await {
+                Ok(Some(txid)) => tracing::info!(
+                    "[{}] Consolidated utxos in {txid} at {} sat/vByte",
+                    vault.vault_id.pretty_print(),
+                    fee_rate.0
+                ),
+                Ok(None) => {}
+                Err(err) => tracing::warn!(
+                    "[{}] Failed to consolidate utxos: {}",
+                    vault.vault_id.pretty_print(),
+                    err
+                ),
+            }
+        }
+        sleep(CONSOLIDATION_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{testing::InMemoryChain, BitcoinCoreApi};
+    use std::sync::Arc;
+
+    const CONFIG: ConsolidationConfig = ConsolidationConfig {
+        max_fee_rate: SatPerVbyte(5),
+        min_utxos: 50,
+        max_inputs: 100,
+        max_utxo_value: 10_000,
+        fee_rate_policy: FeeRatePolicy::Oracle,
+    };
+
+    /// Wallet with `count` confirmed utxos.
+    fn wallet_with_utxos(count: usize) -> (InMemoryChain, DynBitcoinCoreApi) {
+        let chain = InMemoryChain::new();
+        for _ in 0..count {
+            chain.fund_wallet(10_000).unwrap();
+        }
+        (chain.clone(), Arc::new(chain))
+    }
+
+    #[tokio::test]
+    async fn should_consolidate_when_fees_are_low() {
+        let (chain, btc_rpc) = wallet_with_utxos(51);
+
+        let txid = maybe_consolidate(&btc_rpc, &CONFIG, SatPerVbyte(5))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(btc_rpc.fee_rate(txid).await.unwrap(), SatPerVbyte(5));
+
+        chain.mine_blocks(1);
+        assert_eq!(btc_rpc.get_utxo_count().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_not_consolidate_when_fees_are_high() {
+        let (_chain, btc_rpc) = wallet_with_utxos(51);
+
+        assert_eq!(
+            maybe_consolidate(&btc_rpc, &CONFIG, SatPerVbyte(6)).await.unwrap(),
+            None
+        );
+        assert_eq!(btc_rpc.get_utxo_count().await.unwrap(), 51);
+    }
+
+    #[tokio::test]
+    async fn should_not_consolidate_large_utxos() {
+        let (chain, btc_rpc) = wallet_with_utxos(51);
+        chain.fund_wallet(20_000).unwrap();
+
+        maybe_consolidate(&btc_rpc, &CONFIG, SatPerVbyte(5))
+            .await
+            .unwrap()
+            .unwrap();
+        chain.mine_blocks(1);
+        // the large utxo is left alone
+        assert_eq!(btc_rpc.get_utxo_count().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn should_not_consolidate_few_utxos() {
+        let (_chain, btc_rpc) = wallet_with_utxos(50);
+
+        assert_eq!(
+            maybe_consolidate(&btc_rpc, &CONFIG, SatPerVbyte(1)).await.unwrap(),
+            None
+        );
+        assert_eq!(btc_rpc.get_utxo_count().await.unwrap(), 50);
+    }
+}
@@ -0,0 +1,331 @@
+use crate::{
+    collateral::parse_percent_range, error::Error, metrics::publish_outstanding_replace_amount, system::VaultIdManager,
+};
+use runtime::{CurrencyId, FixedPointNumber, FixedU128, PrettyPrint, ReplacePallet, VaultId, VaultRegistryPallet};
+use std::{collections::HashMap, str::FromStr, time::Duration};
+use tokio::time::sleep;
+
+const DELEVERAGE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Once the collateralization falls below the threshold, request the replacement of enough
+/// tokens to get back to the target (e.g. 2.6 for 260%). The request is only withdrawn once
+/// the target is reached, so that a vault hovering around the threshold doesn't keep
+/// requesting and withdrawing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeleveragePolicy {
+    pub threshold: FixedU128,
+    pub target: FixedU128,
+}
+
+impl FromStr for DeleveragePolicy {
+    type Err = String;
+
+    /// Parses `THRESHOLD:TARGET`, in percent.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (threshold, target) = parse_percent_range(input, "threshold", "target")?;
+        Ok(Self { threshold, target })
+    }
+}
+
+/// Calculates the additional amount to replace, on top of the outstanding replace requests.
+/// Since the collateralization is inversely proportional to the tokens backed by the vault,
+/// replacing all but `tokens * collateralization / target` gets the vault to the target.
+fn calculate_replace_amount(
+    policy: &DeleveragePolicy,
+    collateralization: FixedU128,
+    tokens: u128,
+    outstanding: u128,
+) -> Option<u128> {
+    let remaining_tokens =
+        FixedU128::checked_from_rational(collateralization.into_inner(), policy.target.into_inner())?
+            .checked_mul_int(tokens)?;
+    match tokens.saturating_sub(remaining_tokens).saturating_sub(outstanding) {
+        0 => None,
+        amount => Some(amount),
+    }
+}
+
+async fn deleverage_vault<P: VaultRegistryPallet + ReplacePallet>(
+    parachain_rpc: &P,
+    vault_id: &VaultId,
+    policy: &DeleveragePolicy,
+) -> Result<u128, Error> {
+    let vault = parachain_rpc.get_vault(vault_id).await?;
+    let outstanding = vault.to_be_replaced_tokens;
+
+    let collateralization = match parachain_rpc
+        .get_collateralization_from_vault(vault_id.clone(), false)
+        .await
+    {
+        Ok(collateralization) => FixedU128::from_inner(collateralization),
+        // the collateralization is undefined if nothing has been issued
+        Err(err) if err.is_no_tokens_issued() => return Ok(outstanding),
+        Err(err) => return Err(err.into()),
+    };
+
+    if collateralization >= policy.target && outstanding > 0 {
+        tracing::info!(
+            "[{}] Collateralization {} recovered, withdrawing the replace request of {}",
+            vault_id.pretty_print(),
+            collateralization.to_float(),
+            outstanding
+        );
+        parachain_rpc.withdraw_replace(vault_id, outstanding).await?;
+        return Ok(0);
+    }
+    if collateralization >= policy.threshold {
+        // keep any outstanding request until the target is reached
+        return Ok(outstanding);
+    }
+
+    // tokens that are being redeemed or replaced already can not be replaced again
+    let replaceable = vault
+        .issued_tokens
+        .saturating_sub(vault.to_be_redeemed_tokens)
+        .saturating_sub(outstanding);
+    let amount = match calculate_replace_amount(
+        policy,
+        collateralization,
+        vault.issued_tokens.saturating_add(vault.to_be_issued_tokens),
+        outstanding,
+    ) {
+        Some(amount) => amount.min(replaceable),
+        None => return Ok(outstanding),
+    };
+    if amount < parachain_rpc.get_replace_dust_amount().await? {
+        tracing::debug!(
+            "[{}] Not requesting the replacement of {} since it is below the dust amount",
+            vault_id.pretty_print(),
+            amount
+        );
+        return Ok(outstanding);
+    }
+
+    tracing::info!(
+        "[{}] Collateralization {} is below {}, requesting the replacement of {}",
+        vault_id.pretty_print(),
+        collateralization.to_float(),
+        policy.threshold.to_float(),
+        amount
+    );
+    parachain_rpc.request_replace(vault_id, amount).await?;
+    Ok(outstanding.saturating_add(amount))
+}
+
+/// Periodically requests the replacement of part of the tokens backed by a vault whose
+/// collateralization fell below the threshold of its policy, and withdraws the request once
+/// the target is reached.
+pub async fn deleverage_vaults<P: VaultRegistryPallet + ReplacePallet>(
+    parachain_rpc: P,
+    vault_id_manager: VaultIdManager,
+    policies: Vec<(CurrencyId, DeleveragePolicy)>,
+) -> Result<(), Error> {
+    // the amount of our replace requests that have not been accepted yet
+    let mut outstanding_replaces: HashMap<VaultId, u128> = HashMap::new();
+    loop {
+        for vault_id in vault_id_manager.get_vault_ids().await {
+            let policy = match policies
+                .iter()
+                .find(|(currency_id, _)| *currency_id == vault_id.collateral_currency())
+            {
+                Some((_, policy)) => policy,
+                None => continue,
+            };
+            match deleverage_vault(&parachain_rpc, &vault_id, policy).await {
+                Ok(outstanding) => {
+                    if outstanding_replaces.get(&vault_id) != Some(&outstanding) {
+                        tracing::info!(
+                            "[{}] Outstanding replace amount: {}",
+                            vault_id.pretty_print(),
+                            outstanding
+                        );
+                    }
+                    publish_outstanding_replace_amount(&vault_id, outstanding);
+                    outstanding_replaces.insert(vault_id, outstanding);
+                }
+                Err(err) => tracing::error!("[{}] Failed to deleverage: {}", vault_id.pretty_print(), err),
+            }
+        }
+        sleep(DELEVERAGE_CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(all(test, feature = "parachain-metadata-kintsugi"))]
+mod tests {
+    use super::*;
+    use crate::mock::MockProvider;
+    use runtime::{AccountId, Error as RuntimeError, InterBtcVault, Token, VaultStatus, DOT, IBTC};
+
+    fn dummy_vault_id() -> VaultId {
+        VaultId::new(AccountId::new([1u8; 32]), Token(DOT), Token(IBTC))
+    }
+
+    /// a vault backing `issued_tokens`, with a dust amount of 10
+    fn mock_provider_with_vault(
+        issued_tokens: u128,
+        to_be_redeemed_tokens: u128,
+        to_be_replaced_tokens: u128,
+    ) -> MockProvider {
+        let mut parachain_rpc = MockProvider::default();
+        parachain_rpc.expect_get_vault().returning(move |vault_id| {
+            Ok(InterBtcVault {
+                id: vault_id.clone(),
+                status: VaultStatus::Active(true),
+                banned_until: None,
+                secure_collateral_threshold: None,
+                to_be_issued_tokens: 0,
+                issued_tokens,
+                to_be_redeemed_tokens,
+                to_be_replaced_tokens,
+                replace_collateral: 0,
+                liquidated_collateral: 0,
+                active_replace_collateral: 0,
+            })
+        });
+        parachain_rpc.expect_get_replace_dust_amount().returning(|| Ok(10));
+        parachain_rpc
+    }
+
+    fn mock_collateralization(parachain_rpc: &mut MockProvider, collateralization: FixedU128) {
+        parachain_rpc
+            .expect_get_collateralization_from_vault()
+            .returning(move |_, _| Ok(collateralization.into_inner()));
+    }
+
+    fn percent(x: u128) -> FixedU128 {
+        FixedU128::saturating_from_rational(x, 100u128)
+    }
+
+    fn policy() -> DeleveragePolicy {
+        DeleveragePolicy {
+            threshold: percent(200),
+            target: percent(300),
+        }
+    }
+
+    #[test]
+    fn should_parse_policy() {
+        assert_eq!(DeleveragePolicy::from_str("200:300"), Ok(policy()));
+        assert!(DeleveragePolicy::from_str("300:200").is_err());
+        assert!(DeleveragePolicy::from_str("200").is_err());
+    }
+
+    #[test]
+    fn should_replace_down_to_target() {
+        // 1000 tokens at 150% -> 500 tokens at 300%
+        assert_eq!(calculate_replace_amount(&policy(), percent(150), 1000, 0), Some(500));
+    }
+
+    #[test]
+    fn should_subtract_outstanding_replace_amount() {
+        assert_eq!(calculate_replace_amount(&policy(), percent(150), 1000, 200), Some(300));
+        assert_eq!(calculate_replace_amount(&policy(), percent(150), 1000, 500), None);
+        assert_eq!(calculate_replace_amount(&policy(), percent(150), 1000, 800), None);
+    }
+
+    #[test]
+    fn should_not_replace_above_target() {
+        assert_eq!(calculate_replace_amount(&policy(), percent(300), 1000, 0), None);
+        assert_eq!(calculate_replace_amount(&policy(), percent(150), 0, 0), None);
+    }
+
+    #[tokio::test]
+    async fn should_request_replace_below_threshold() {
+        let mut parachain_rpc = mock_provider_with_vault(1000, 0, 0);
+        mock_collateralization(&mut parachain_rpc, percent(150));
+        parachain_rpc
+            .expect_request_replace()
+            .withf(|vault_id, amount| *vault_id == dummy_vault_id() && *amount == 500)
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        assert_eq!(
+            deleverage_vault(&parachain_rpc, &dummy_vault_id(), &policy())
+                .await
+                .unwrap(),
+            500
+        );
+    }
+
+    #[tokio::test]
+    async fn should_withdraw_replace_once_recovered() {
+        let mut parachain_rpc = mock_provider_with_vault(1000, 0, 300);
+        mock_collateralization(&mut parachain_rpc, percent(300));
+        parachain_rpc
+            .expect_withdraw_replace()
+            .withf(|vault_id, amount| *vault_id == dummy_vault_id() && *amount == 300)
+            .times(1)
+            .returning(|_, _| Ok(()));
+        parachain_rpc.expect_request_replace().never();
+
+        assert_eq!(
+            deleverage_vault(&parachain_rpc, &dummy_vault_id(), &policy())
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn should_keep_replace_request_between_threshold_and_target() {
+        let mut parachain_rpc = mock_provider_with_vault(1000, 0, 300);
+        mock_collateralization(&mut parachain_rpc, percent(250));
+        parachain_rpc.expect_withdraw_replace().never();
+        parachain_rpc.expect_request_replace().never();
+
+        assert_eq!(
+            deleverage_vault(&parachain_rpc, &dummy_vault_id(), &policy())
+                .await
+                .unwrap(),
+            300
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_request_replace_below_dust_amount() {
+        // 20 tokens at 150% -> 10 tokens to replace, 5 of which are being replaced already
+        let mut parachain_rpc = mock_provider_with_vault(20, 0, 5);
+        mock_collateralization(&mut parachain_rpc, percent(150));
+        parachain_rpc.expect_request_replace().never();
+
+        assert_eq!(
+            deleverage_vault(&parachain_rpc, &dummy_vault_id(), &policy())
+                .await
+                .unwrap(),
+            5
+        );
+    }
+
+    #[tokio::test]
+    async fn should_cap_replace_amount_at_replaceable_tokens() {
+        // 500 tokens should be replaced, but 800 of the 1000 tokens are being redeemed
+        let mut parachain_rpc = mock_provider_with_vault(1000, 800, 0);
+        mock_collateralization(&mut parachain_rpc, percent(150));
+        parachain_rpc
+            .expect_request_replace()
+            .withf(|_, amount| *amount == 200)
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        assert_eq!(
+            deleverage_vault(&parachain_rpc, &dummy_vault_id(), &policy())
+                .await
+                .unwrap(),
+            200
+        );
+    }
+
+    #[tokio::test]
+    async fn should_propagate_collateralization_errors() {
+        let mut parachain_rpc = mock_provider_with_vault(1000, 0, 0);
+        parachain_rpc
+            .expect_get_collateralization_from_vault()
+            .returning(|_, _| Err(RuntimeError::BlockNotFound));
+        parachain_rpc.expect_request_replace().never();
+
+        assert!(matches!(
+            deleverage_vault(&parachain_rpc, &dummy_vault_id(), &policy()).await,
+            Err(Error::RuntimeError(RuntimeError::BlockNotFound))
+        ));
+    }
+}
@@ -1,13 +1,16 @@
+use crate::replace_policy::Rule;
 use bitcoin::Error as BitcoinError;
+use hyper::{http::Error as HttpError, Error as HyperError};
 use jsonrpc_core_client::RpcError;
 use parity_scale_codec::Error as CodecError;
 use rocksdb::Error as RocksDbError;
-use runtime::Error as RuntimeError;
+use runtime::{Error as RuntimeError, H256};
 use serde_json::Error as SerdeJsonError;
 use std::{io::Error as IoError, num::ParseIntError, string::FromUtf8Error};
 use thiserror::Error;
 use tokio::task::JoinError as TokioJoinError;
 use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use warp::Error as WarpError;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -27,6 +30,8 @@ pub enum Error {
     FaucetUrlNotSet,
     #[error("Faucet allowance for `{0}` not set")]
     FaucetAllowanceNotSet(String),
+    #[error("Rejected by replace policy: {0}")]
+    ReplaceRejected(#[from] Rule),
 
     #[error("RPC error: {0}")]
     RpcError(#[from] RpcError),
@@ -50,6 +55,10 @@ pub enum Error {
     OsStringError,
     #[error("File already exists")]
     FileAlreadyExists,
+    #[error("The target wallet prefix must differ from the current wallet prefix")]
+    InvalidWalletPrefix,
+    #[error("The deposit key of issue {0:?} doesn't pay to its address")]
+    DepositAddressMismatch(H256),
     #[error("There is a services already running on the system, with pid {0}")]
     ServiceAlreadyRunning(u32),
     #[error("Process with pid {0} not found")]
@@ -60,6 +69,12 @@ pub enum Error {
     TokioError(#[from] TokioJoinError),
     #[error("System I/O error: {0}")]
     IoError(#[from] IoError),
+    #[error("WarpError: {0}")]
+    WarpError(#[from] WarpError),
+    #[error("HyperError: {0}")]
+    HyperError(#[from] HyperError),
+    #[error("HttpError: {0}")]
+    HttpError(#[from] HttpError),
 }
 
 impl Error {
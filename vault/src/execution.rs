@@ -1,4 +1,7 @@
 use crate::{
+    alerts::{notify, Alert},
+    api::{track_payment, PaymentGuard},
+    cli::FeeRatePolicy,
     error::Error,
     metrics::update_bitcoin_metrics,
     service::{spawn_cancelable, DynBitcoinCoreApi, ShutdownSender},
@@ -6,8 +9,8 @@ use crate::{
     VaultIdManager, YIELD_RATE,
 };
 use bitcoin::{
-    Error as BitcoinError, Hash, SatPerVbyte, Transaction, TransactionExt, TransactionMetadata, Txid,
-    BLOCK_INTERVAL as BITCOIN_BLOCK_INTERVAL,
+    Error as BitcoinError, Hash, RawTransactionProof, SatPerVbyte, Transaction, TransactionExt, TransactionMetadata,
+    Txid, BLOCK_INTERVAL as BITCOIN_BLOCK_INTERVAL,
 };
 use futures::{future::Either, stream::StreamExt, try_join, TryStreamExt};
 use governor::RateLimiter;
@@ -22,6 +25,10 @@ use tokio::time::sleep;
 use tokio_stream::wrappers::BroadcastStream;
 
 const ON_FORK_RETRY_DELAY: Duration = Duration::from_secs(10);
+// confirmation target of the local fee estimate
+const LOCAL_FEE_ESTIMATE_TARGET_BLOCKS: u16 = 2;
+// with `FeeRatePolicy::Bounded` the oracle may be at most this factor above or below the local estimate
+const MAX_FEE_RATE_DEVIATION: u64 = 2;
 
 #[derive(Debug, Clone, PartialEq)]
 struct Deadline {
@@ -61,25 +68,96 @@ pub fn parachain_blocks_to_bitcoin_blocks_rounded_up(parachain_blocks: u32) -> R
     Ok(num_bitcoin_blocks.try_into()?)
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum RequestType {
     Redeem,
     Replace,
 }
 
-impl Request {
-    fn duration_to_parachain_blocks(duration: Duration) -> Result<u32, Error> {
-        let num_blocks = duration.as_millis() / (runtime::MILLISECS_PER_BLOCK as u128);
-        Ok(num_blocks.try_into()?)
+/// returns the oracle fee rate in sat/vByte
+async fn get_oracle_fee_rate<P: OraclePallet + Send + Sync>(parachain_rpc: &P) -> Result<SatPerVbyte, Error> {
+    let fee_rate: FixedU128 = parachain_rpc.get_bitcoin_fees().await?;
+    let rate = fee_rate
+        .into_inner()
+        .checked_div(FixedU128::accuracy())
+        .ok_or(Error::ArithmeticUnderflow)?
+        .try_into()?;
+    Ok(SatPerVbyte(rate))
+}
+
+fn combine_fee_rates(fee_rate_policy: FeeRatePolicy, oracle: SatPerVbyte, local: SatPerVbyte) -> SatPerVbyte {
+    if local.0 == 0 {
+        // the node has no estimate (e.g. on regtest), treat it as a failed estimate
+        return oracle;
+    }
+    match fee_rate_policy {
+        FeeRatePolicy::Oracle => oracle,
+        FeeRatePolicy::Max => SatPerVbyte(oracle.0.max(local.0)),
+        FeeRatePolicy::Min => SatPerVbyte(oracle.0.min(local.0)),
+        FeeRatePolicy::Bounded => SatPerVbyte(oracle.0.clamp(
+            local.0 / MAX_FEE_RATE_DEVIATION,
+            local.0.saturating_mul(MAX_FEE_RATE_DEVIATION),
+        )),
+    }
+}
+
+/// returns the fee rate in sat/vByte, combining the oracle with the local estimate
+/// according to the given policy. Falls back to the oracle if the local estimate fails.
+pub(crate) async fn get_fee_rate<P: OraclePallet + Send + Sync>(
+    parachain_rpc: &P,
+    btc_rpc: &DynBitcoinCoreApi,
+    fee_rate_policy: FeeRatePolicy,
+) -> Result<SatPerVbyte, Error> {
+    let oracle_fee_rate = get_oracle_fee_rate(parachain_rpc).await?;
+    Ok(apply_fee_rate_policy(btc_rpc, fee_rate_policy, oracle_fee_rate).await)
+}
+
+/// combines the given oracle fee rate with the local estimate according to the policy
+async fn apply_fee_rate_policy(
+    btc_rpc: &DynBitcoinCoreApi,
+    fee_rate_policy: FeeRatePolicy,
+    oracle_fee_rate: SatPerVbyte,
+) -> SatPerVbyte {
+    if fee_rate_policy == FeeRatePolicy::Oracle {
+        return oracle_fee_rate;
+    }
+    match btc_rpc.estimate_fee_rate(LOCAL_FEE_ESTIMATE_TARGET_BLOCKS).await {
+        Ok(local_fee_rate) => combine_fee_rates(fee_rate_policy, oracle_fee_rate, local_fee_rate),
+        Err(err) => {
+            tracing::warn!("Failed to estimate fee rate locally, using the oracle: {}", err);
+            oracle_fee_rate
+        }
     }
+}
+
+/// Verifies the proof locally and checks that its block is in the main chain of the relay,
+/// so that an invalid proof is noticed before paying for a failing extrinsic.
+pub(crate) async fn verify_transaction_proof<P: BtcRelayPallet>(
+    parachain_rpc: &P,
+    proof: &RawTransactionProof,
+) -> Result<(), Error> {
+    let block_hash = bitcoin::verify_transaction_proof(proof)?;
+    parachain_rpc
+        .verify_block_header_inclusion(H256Le::from_bytes_le(block_hash.as_byte_array()))
+        .await?;
+    Ok(())
+}
+
+/// Number of parachain blocks produced within `duration`, rounded down
+pub(crate) fn duration_to_parachain_blocks(duration: Duration) -> Result<u32, Error> {
+    let num_blocks = duration.as_millis() / (runtime::MILLISECS_PER_BLOCK as u128);
+    Ok(num_blocks.try_into()?)
+}
 
+impl Request {
     fn calculate_deadline(
         opentime: u32,
         btc_start_height: u32,
         period: u32,
         payment_margin: Duration,
     ) -> Result<Deadline, Error> {
-        let margin_parachain_blocks = Self::duration_to_parachain_blocks(payment_margin)?;
+        let margin_parachain_blocks = duration_to_parachain_blocks(payment_margin)?;
         // if margin > period, we allow deadline to be before opentime. The rest of the code
         // can deal with the expired deadline as normal.
         let parachain_deadline = opentime
@@ -146,17 +224,6 @@ impl Request {
         })
     }
 
-    /// returns the fee rate in sat/vByte
-    async fn get_fee_rate<P: OraclePallet + Send + Sync>(&self, parachain_rpc: &P) -> Result<SatPerVbyte, Error> {
-        let fee_rate: FixedU128 = parachain_rpc.get_bitcoin_fees().await?;
-        let rate = fee_rate
-            .into_inner()
-            .checked_div(FixedU128::accuracy())
-            .ok_or(Error::ArithmeticUnderflow)?
-            .try_into()?;
-        Ok(SatPerVbyte(rate))
-    }
-
     /// Makes the bitcoin transfer and executes the request
     pub async fn pay_and_execute<
         P: ReplacePallet
@@ -175,7 +242,10 @@ impl Request {
         vault: VaultData,
         num_confirmations: u32,
         auto_rbf: bool,
+        fee_rate_policy: FeeRatePolicy,
     ) -> Result<(), Error> {
+        let _payment = PaymentGuard::new(self.hash);
+
         // ensure the deadline has not expired yet
         if let Some(ref deadline) = self.deadline {
             if parachain_rpc.get_current_active_block_number().await? >= deadline.parachain
@@ -185,15 +255,28 @@ impl Request {
             }
         }
 
-        let tx_metadata = self
+        let tx_metadata = match self
             .transfer_btc(
                 &parachain_rpc,
                 &vault.btc_rpc,
                 num_confirmations,
                 self.vault_id.clone(),
                 auto_rbf,
+                fee_rate_policy,
             )
-            .await?;
+            .await
+        {
+            Ok(tx_metadata) => tx_metadata,
+            Err(Error::BitcoinError(err)) if err.could_be_insufficient_funds() => {
+                notify(Alert::InsufficientBtcBalance {
+                    request_id: self.hash,
+                    vault_id: (&self.vault_id).into(),
+                    amount: self.amount,
+                });
+                return Err(err.into());
+            }
+            Err(err) => return Err(err),
+        };
 
         let _ = update_bitcoin_metrics(&vault, tx_metadata.fee, self.fee_budget).await;
         self.execute(parachain_rpc, tx_metadata).await
@@ -215,8 +298,9 @@ impl Request {
         num_confirmations: u32,
         vault_id: VaultId,
         auto_rbf: bool,
+        fee_rate_policy: FeeRatePolicy,
     ) -> Result<TransactionMetadata, Error> {
-        let fee_rate = self.get_fee_rate(parachain_rpc).await?;
+        let fee_rate = get_fee_rate(parachain_rpc, btc_rpc, fee_rate_policy).await?;
 
         tracing::debug!("Using fee_rate = {} sat/vByte", fee_rate.0);
 
@@ -231,8 +315,15 @@ impl Request {
             )
             .await?;
 
-        self.wait_for_inclusion(parachain_rpc, btc_rpc, num_confirmations, txid, auto_rbf)
-            .await
+        self.wait_for_inclusion(
+            parachain_rpc,
+            btc_rpc,
+            num_confirmations,
+            txid,
+            auto_rbf,
+            fee_rate_policy,
+        )
+        .await
     }
 
     #[tracing::instrument(
@@ -252,9 +343,11 @@ impl Request {
         num_confirmations: u32,
         mut txid: Txid,
         auto_rbf: bool,
+        fee_rate_policy: FeeRatePolicy,
     ) -> Result<TransactionMetadata, Error> {
         'outer: loop {
             tracing::info!("Awaiting bitcoin confirmations for {txid}");
+            track_payment(self.hash, self.request_type, &self.vault_id, txid);
 
             let txid_copy = txid; // we get borrow check error if we don't use a copy
 
@@ -262,15 +355,16 @@ impl Request {
             let fee_rate_subscription = BroadcastStream::new(fee_rate_subscription);
             let subscription = fee_rate_subscription
                 .map_err(Into::<Error>::into)
-                .and_then(|x| {
+                .and_then(|x| async move {
                     tracing::debug!("Received new inclusion fee estimate {}...", x);
 
-                    let ret: Result<SatPerVbyte, _> = x
+                    let oracle_fee_rate = x
                         .into_inner()
                         .checked_div(FixedU128::accuracy())
-                        .ok_or(Error::ArithmeticUnderflow)
-                        .and_then(|x| x.try_into().map(SatPerVbyte).map_err(Into::<Error>::into));
-                    futures::future::ready(ret)
+                        .ok_or(Error::ArithmeticUnderflow)?
+                        .try_into()
+                        .map(SatPerVbyte)?;
+                    Ok::<_, Error>(apply_fee_rate_policy(btc_rpc, fee_rate_policy, oracle_fee_rate).await)
                 })
                 .filter(|_| futures::future::ready(auto_rbf)) // if auto-rbf is disabled, don't propagate the events
                 .try_filter_map(|x| async move {
@@ -311,6 +405,8 @@ impl Request {
             futures::pin_mut!(subscription);
 
             let mut metadata_fut = wait_for_transaction_metadata;
+            // child paying for the tx, and the fee rate of both together, if we did cpfp
+            let mut child: Option<(Txid, SatPerVbyte)> = None;
 
             // The code below looks a little bit complicated but the idea is simple:
             // we keep waiting for inclusion until it's either included in the bitcoin chain,
@@ -325,37 +421,58 @@ impl Request {
                         metadata_fut = continuation;
                     }
                     Either::Right((Some(Ok((old_fee, new_fee))), continuation)) => {
-                        tracing::debug!("Attempting to bump fee rate from {} to {}...", old_fee.0, new_fee.0);
-                        match btc_rpc
-                            .bump_fee(
-                                &txid,
-                                self.btc_address
-                                    .to_address(btc_rpc.network())
-                                    .map_err(BitcoinError::ConversionError)?,
-                                new_fee,
-                            )
-                            .await
-                        {
-                            Ok(new_txid) => {
-                                tracing::info!("Bumped fee rate. Old txid = {txid}, new txid = {new_txid}");
-                                txid = new_txid;
-                                continue 'outer;
-                            }
-                            Err(x) if x.rejected_by_network_rules() => {
-                                // bump not big enough. This is not unexpected, so only debug print
-                                tracing::debug!("Failed to bump fees: {:?}", x);
-                            }
-                            Err(x) if x.could_be_insufficient_funds() => {
-                                // Unexpected: likely (but no certainly) there are insufficient
-                                // funds in the wallet to pay the increased fee.
-                                tracing::warn!("Failed to bump fees - likely due to insufficient funds: {:?}", x);
+                        metadata_fut = continuation;
+                        if child.map_or(false, |(_, package_fee_rate)| package_fee_rate >= new_fee) {
+                            // a child transaction already pays for this tx
+                            continue;
+                        }
+                        let address = self
+                            .btc_address
+                            .to_address(btc_rpc.network())
+                            .map_err(BitcoinError::ConversionError)?;
+
+                        // the wallet cannot replace a tx that has a child, so bump the child instead
+                        if child.is_none() {
+                            tracing::debug!("Attempting to bump fee rate from {} to {}...", old_fee.0, new_fee.0);
+                            match btc_rpc.bump_fee(&txid, address.clone(), new_fee).await {
+                                Ok(new_txid) => {
+                                    tracing::info!("Bumped fee rate. Old txid = {txid}, new txid = {new_txid}");
+                                    txid = new_txid;
+                                    continue 'outer;
+                                }
+                                Err(x) if x.rejected_by_network_rules() => {
+                                    // bump not big enough. This is not unexpected, so only debug print
+                                    tracing::debug!("Failed to bump fees: {:?}", x);
+                                }
+                                Err(x) if x.could_be_insufficient_funds() => {
+                                    // Unexpected: likely (but no certainly) there are insufficient
+                                    // funds in the wallet to pay the increased fee.
+                                    tracing::warn!("Failed to bump fees - likely due to insufficient funds: {:?}", x);
+                                }
+                                Err(x) => {
+                                    // unexpected error. Just continue waiting for the original tx
+                                    tracing::warn!("Failed to bump fees due to unexpected reasons: {:?}", x);
+                                }
+                            };
+                        }
+
+                        // pay for the tx with a child instead. A new child spends the same output
+                        // so it replaces the previous one. This does not change the txid so we
+                        // keep waiting for the original tx
+                        match btc_rpc.bump_fee_with_child(&txid, address, new_fee).await {
+                            Ok(child_txid) => {
+                                match child {
+                                    Some((old_child_txid, _)) => tracing::info!(
+                                        "Bumped fee rate of {txid} by replacing child {old_child_txid} with {child_txid}"
+                                    ),
+                                    None => tracing::info!("Bumped fee rate of {txid} with child {child_txid}"),
+                                }
+                                child = Some((child_txid, new_fee));
                             }
                             Err(x) => {
-                                // unexpected error. Just continue waiting for the original tx
-                                tracing::warn!("Failed to bump fees due to unexpected reasons: {:?}", x);
+                                tracing::warn!("Failed to bump fees with a child transaction: {:?}", x);
                             }
-                        };
-                        metadata_fut = continuation;
+                        }
                     }
                 }
             };
@@ -385,7 +502,7 @@ impl Request {
     }
 
     /// Executes the request. Upon failure it will retry
-    async fn execute<P: ReplacePallet + RedeemPallet>(
+    async fn execute<P: ReplacePallet + RedeemPallet + BtcRelayPallet>(
         &self,
         parachain_rpc: P,
         tx_metadata: TransactionMetadata,
@@ -407,20 +524,35 @@ impl Request {
             _ => {}
         }
 
-        // Retry until success or timeout, explicitly handle the cases
-        // where the redeem has expired or the rpc has disconnected
-        runtime::notify_retry(
-            || (execute)(&parachain_rpc, self.hash, &tx_metadata.proof),
-            |result| async {
-                match result {
-                    Ok(ok) => Ok(ok),
-                    Err(err) if err.is_rpc_disconnect_error() => Err(runtime::RetryPolicy::Throw(err)),
-                    Err(err) if err.is_invalid_chain_id() => Err(runtime::RetryPolicy::Throw(err)),
-                    Err(err) => Err(runtime::RetryPolicy::Skip(err)),
-                }
-            },
-        )
-        .await?;
+        let result = async {
+            verify_transaction_proof(&parachain_rpc, &tx_metadata.proof).await?;
+
+            // Retry until success or timeout, explicitly handle the cases
+            // where the redeem has expired or the rpc has disconnected
+            runtime::notify_retry(
+                || (execute)(&parachain_rpc, self.hash, &tx_metadata.proof),
+                |result| async {
+                    match result {
+                        Ok(ok) => Ok(ok),
+                        Err(err) if err.is_rpc_disconnect_error() => Err(runtime::RetryPolicy::Throw(err)),
+                        Err(err) if err.is_invalid_chain_id() => Err(runtime::RetryPolicy::Throw(err)),
+                        Err(err) => Err(runtime::RetryPolicy::Skip(err)),
+                    }
+                },
+            )
+            .await?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(ref err) = result {
+            notify(Alert::ExecuteFailed {
+                request_id: self.hash,
+                request_type: self.request_type,
+                vault_id: (&self.vault_id).into(),
+                error: err.to_string(),
+            });
+        }
+        result?;
 
         tracing::info!("Executed request #{:?}", self.hash);
 
@@ -428,6 +560,7 @@ impl Request {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn create_payment_worker(
     shutdown_tx: ShutdownSender,
     parachain_rpc: InterBtcParachain,
@@ -436,6 +569,7 @@ fn create_payment_worker(
     txid: Txid,
     num_confirmations: u32,
     auto_rbf: bool,
+    fee_rate_policy: FeeRatePolicy,
 ) {
     tracing::info!(
         "{:?} request #{:?} has valid bitcoin payment - processing...",
@@ -454,8 +588,16 @@ fn create_payment_worker(
             }
         };
 
+        let _payment = PaymentGuard::new(request.hash);
         match request
-            .wait_for_inclusion(&parachain_rpc, &btc_rpc, num_confirmations, txid, auto_rbf)
+            .wait_for_inclusion(
+                &parachain_rpc,
+                &btc_rpc,
+                num_confirmations,
+                txid,
+                auto_rbf,
+                fee_rate_policy,
+            )
             .await
         {
             Ok(tx_metadata) => {
@@ -481,6 +623,7 @@ pub async fn execute_open_requests(
     num_confirmations: u32,
     payment_margin: Duration,
     auto_rbf: bool,
+    fee_rate_policy: FeeRatePolicy,
 ) -> Result<(), Error> {
     let parachain_rpc = &parachain_rpc;
     let vault_id = parachain_rpc.get_account_id().clone();
@@ -563,6 +706,7 @@ pub async fn execute_open_requests(
                     tx.txid(),
                     num_confirmations,
                     auto_rbf,
+                    fee_rate_policy,
                 );
             }
         }
@@ -593,6 +737,7 @@ pub async fn execute_open_requests(
                     txid,
                     num_confirmations,
                     auto_rbf,
+                    fee_rate_policy,
                 );
                 // task will handling execution
                 continue;
@@ -630,7 +775,7 @@ pub async fn execute_open_requests(
             );
 
             match request
-                .pay_and_execute(parachain_rpc, vault, num_confirmations, auto_rbf)
+                .pay_and_execute(parachain_rpc, vault, num_confirmations, auto_rbf, fee_rate_policy)
                 .await
             {
                 Ok(_) => tracing::info!(
@@ -670,8 +815,13 @@ mod tests {
     use crate::metrics::PerCurrencyMetrics;
     use async_trait::async_trait;
     use bitcoin::{
-        json, Address, Amount, BitcoinCoreApi, Block, BlockHash, BlockHeader, Error as BitcoinError, Hash, Network,
-        PrivateKey, PublicKey, RawTransactionProof, Transaction, TransactionMetadata, Txid,
+        bitcoin_primitives::{
+            absolute::LockTime, block::Version, merkle_tree::MerkleBlock, CompactTarget, ScriptBuf, Sequence, Witness,
+        },
+        json, serialize,
+        testing::InMemoryChain,
+        Address, Amount, BitcoinCoreApi, Block, BlockHash, BlockHeader, Error as BitcoinError, Hash, Network, OutPoint,
+        PrivateKey, PublicKey, RawTransactionProof, Transaction, TransactionMetadata, TxIn, TxMerkleNode, TxOut, Txid,
     };
     use jsonrpc_core::serde_json::{Map, Value};
     use runtime::{
@@ -694,6 +844,57 @@ mod tests {
         };
     }
 
+    // payment in a block with a valid proof of work, as the proof is verified before execution
+    fn dummy_transaction_metadata() -> TransactionMetadata {
+        let dummy_tx = |previous_output| Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 100,
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let mut block = Block {
+            header: BlockHeader {
+                version: Version::from_consensus(4),
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 1,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![
+                dummy_tx(OutPoint::null()),
+                dummy_tx(OutPoint::new(Txid::all_zeros(), 0)),
+            ],
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        while block.header.validate_pow(block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+        let proof_for = |index: usize| {
+            let txid = block.txdata[index].txid();
+            serialize(&MerkleBlock::from_block_with_predicate(&block, |x| *x == txid))
+        };
+        TransactionMetadata {
+            txid: block.txdata[1].txid(),
+            proof: RawTransactionProof {
+                coinbase_tx_proof: proof_for(0),
+                raw_coinbase_tx: serialize(&block.txdata[0]),
+                raw_user_tx: serialize(&block.txdata[1]),
+                user_tx_proof: proof_for(1),
+            },
+            block_hash: block.block_hash(),
+            fee: None,
+        }
+    }
+
     mockall::mock! {
         Provider {}
 
@@ -798,8 +999,8 @@ mod tests {
             fn is_full_node(&self) -> bool;
             fn network(&self) -> Network;
             async fn wait_for_block(&self, height: u32, num_confirmations: u32) -> Result<Block, BitcoinError>;
-            fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, BitcoinError>;
-            fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, BitcoinError>;
+            async fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, BitcoinError>;
+            async fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, BitcoinError>;
             async fn get_block_count(&self) -> Result<u64, BitcoinError>;
             async fn get_raw_tx(&self, txid: &Txid, block_hash: &BlockHash) -> Result<Vec<u8>, BitcoinError>;
             async fn get_transaction(&self, txid: &Txid, block_hash: Option<BlockHash>) -> Result<Transaction, BitcoinError>;
@@ -821,15 +1022,23 @@ mod tests {
             async fn create_or_load_wallet(&self) -> Result<(), BitcoinError>;
             async fn rescan_blockchain(&self, start_height: usize, end_height: usize) -> Result<(), BitcoinError>;
             async fn rescan_electrs_for_addresses(&self, addresses: Vec<Address>) -> Result<(), BitcoinError>;
-            fn get_utxo_count(&self) -> Result<usize, BitcoinError>;
+            async fn get_utxo_count(&self) -> Result<usize, BitcoinError>;
+            async fn consolidate_utxos(&self, max_inputs: usize, max_utxo_value: u64, fee_rate: SatPerVbyte) -> Result<Option<Txid>, BitcoinError>;
             async fn bump_fee(
                 &self,
                 txid: &Txid,
                 address: Address,
                 fee_rate: SatPerVbyte,
             ) -> Result<Txid, BitcoinError>;
+            async fn bump_fee_with_child(
+                &self,
+                txid: &Txid,
+                address: Address,
+                fee_rate: SatPerVbyte,
+            ) -> Result<Txid, BitcoinError>;
             async fn is_in_mempool(&self, txid: Txid) -> Result<bool, BitcoinError>;
             async fn fee_rate(&self, txid: Txid) -> Result<SatPerVbyte, BitcoinError>;
+            async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<SatPerVbyte, BitcoinError>;
             async fn get_tx_for_op_return(&self, address: Address, amount: u128, data: H256) -> Result<Option<Txid>, BitcoinError>;
         }
     }
@@ -891,6 +1100,20 @@ mod tests {
         assert_err!(Request::calculate_deadline(0, 0, 0, margin), Error::ArithmeticUnderflow);
     }
 
+    #[test]
+    fn should_convert_duration_to_parachain_blocks() {
+        let block_time = Duration::from_millis(runtime::MILLISECS_PER_BLOCK);
+
+        assert_ok!(duration_to_parachain_blocks(block_time * 300), 300);
+        // partial blocks are not counted
+        assert_ok!(
+            duration_to_parachain_blocks(block_time * 3 - Duration::from_millis(1)),
+            2
+        );
+        assert_ok!(duration_to_parachain_blocks(Duration::ZERO), 0);
+        assert_err!(duration_to_parachain_blocks(Duration::MAX), Error::TryIntoIntError(_));
+    }
+
     mod pay_and_execute_redeem_tests {
         use bitcoin::Hash;
 
@@ -914,6 +1137,9 @@ mod tests {
                 .returning(move || Ok(current_parachain_height));
             parachain_rpc.expect_execute_redeem().returning(|_, _| Ok(()));
             parachain_rpc.expect_wait_for_block_in_relay().returning(|_, _| Ok(()));
+            parachain_rpc
+                .expect_verify_block_header_inclusion()
+                .returning(|_| Ok(()));
 
             parachain_rpc
                 .expect_on_fee_rate_change()
@@ -929,19 +1155,7 @@ mod tests {
                 .returning(|_, _, _, _| Ok(Txid::all_zeros()));
             mock_bitcoin
                 .expect_wait_for_transaction_metadata()
-                .returning(|_, _, _, _| {
-                    Ok(TransactionMetadata {
-                        txid: Txid::all_zeros(),
-                        proof: RawTransactionProof {
-                            coinbase_tx_proof: vec![],
-                            raw_coinbase_tx: vec![],
-                            raw_user_tx: vec![],
-                            user_tx_proof: vec![],
-                        },
-                        block_hash: BlockHash::all_zeros(),
-                        fee: None,
-                    })
-                });
+                .returning(|_, _, _, _| Ok(dummy_transaction_metadata()));
             mock_bitcoin.expect_list_transactions().returning(|_| Ok(vec![]));
             mock_bitcoin.expect_get_balance().returning(|_| Ok(Amount::ZERO));
             let btc_rpc: DynBitcoinCoreApi = Arc::new(mock_bitcoin);
@@ -973,21 +1187,33 @@ mod tests {
         async fn should_pay_and_execute_redeem_if_neither_parachain_nor_bitcoin_deadlines_expired() {
             let (request, parachain_rpc, btc_rpc) = should_pay_and_execute_with_deadlines(100, 50, 100, 50);
 
-            assert_ok!(request.pay_and_execute(parachain_rpc, btc_rpc, 6, true).await);
+            assert_ok!(
+                request
+                    .pay_and_execute(parachain_rpc, btc_rpc, 6, true, FeeRatePolicy::Oracle)
+                    .await
+            );
         }
 
         #[tokio::test]
         async fn should_pay_and_execute_redeem_if_only_parachain_deadline_expired() {
             let (request, parachain_rpc, btc_rpc) = should_pay_and_execute_with_deadlines(100, 101, 100, 50);
 
-            assert_ok!(request.pay_and_execute(parachain_rpc, btc_rpc, 6, true).await);
+            assert_ok!(
+                request
+                    .pay_and_execute(parachain_rpc, btc_rpc, 6, true, FeeRatePolicy::Oracle)
+                    .await
+            );
         }
 
         #[tokio::test]
         async fn should_pay_and_execute_redeem_if_only_bitcoin_deadline_expired() {
             let (request, parachain_rpc, btc_rpc) = should_pay_and_execute_with_deadlines(100, 50, 100, 101);
 
-            assert_ok!(request.pay_and_execute(parachain_rpc, btc_rpc, 6, true).await);
+            assert_ok!(
+                request
+                    .pay_and_execute(parachain_rpc, btc_rpc, 6, true, FeeRatePolicy::Oracle)
+                    .await
+            );
         }
 
         #[tokio::test]
@@ -995,7 +1221,9 @@ mod tests {
             let (request, parachain_rpc, btc_rpc) = should_pay_and_execute_with_deadlines(100, 101, 100, 101);
 
             assert_err!(
-                request.pay_and_execute(parachain_rpc, btc_rpc, 6, true).await,
+                request
+                    .pay_and_execute(parachain_rpc, btc_rpc, 6, true, FeeRatePolicy::Oracle)
+                    .await,
                 Error::DeadlineExpired
             );
         }
@@ -1034,7 +1262,9 @@ mod tests {
         };
 
         assert_err!(
-            request.pay_and_execute(parachain_rpc, vault_data, 6, true).await,
+            request
+                .pay_and_execute(parachain_rpc, vault_data, 6, true, FeeRatePolicy::Oracle)
+                .await,
             Error::DeadlineExpired
         );
     }
@@ -1054,6 +1284,10 @@ mod tests {
             .expect_wait_for_block_in_relay()
             .times(1)
             .returning(|_, _| Ok(()));
+        parachain_rpc
+            .expect_verify_block_header_inclusion()
+            .times(1)
+            .returning(|_| Ok(()));
         parachain_rpc
             .expect_on_fee_rate_change()
             .returning(|| tokio::sync::broadcast::channel(2).1);
@@ -1065,19 +1299,7 @@ mod tests {
             .returning(|_, _, _, _| Ok(Txid::all_zeros()));
         mock_bitcoin
             .expect_wait_for_transaction_metadata()
-            .returning(|_, _, _, _| {
-                Ok(TransactionMetadata {
-                    txid: Txid::all_zeros(),
-                    proof: RawTransactionProof {
-                        coinbase_tx_proof: vec![],
-                        raw_coinbase_tx: vec![],
-                        raw_user_tx: vec![],
-                        user_tx_proof: vec![],
-                    },
-                    block_hash: BlockHash::all_zeros(),
-                    fee: None,
-                })
-            });
+            .returning(|_, _, _, _| Ok(dummy_transaction_metadata()));
         mock_bitcoin.expect_get_balance().returning(|_| Ok(Amount::ZERO));
         let btc_rpc: DynBitcoinCoreApi = Arc::new(mock_bitcoin);
 
@@ -1101,6 +1323,122 @@ mod tests {
             metrics: PerCurrencyMetrics::dummy(),
         };
 
-        assert_ok!(request.pay_and_execute(parachain_rpc, vault_data, 6, true).await);
+        assert_ok!(
+            request
+                .pay_and_execute(parachain_rpc, vault_data, 6, true, FeeRatePolicy::Oracle)
+                .await
+        );
+    }
+
+    async fn wait_for_child(chain: &InMemoryChain, parent: Txid, previous: Option<Txid>) -> Txid {
+        let find_child = || async move {
+            chain
+                .get_mempool_transactions()
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .map(|tx| (tx.txid(), tx))
+                .find(|(txid, tx)| {
+                    Some(*txid) != previous && tx.input.iter().any(|input| input.previous_output.txid == parent)
+                })
+                .map(|(txid, _)| txid)
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(child) = find_child().await {
+                    return child;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("child was not created")
+    }
+
+    #[tokio::test]
+    async fn should_pay_for_parent_if_it_cannot_be_replaced() {
+        let chain = InMemoryChain::new();
+        let btc_rpc: DynBitcoinCoreApi = Arc::new(chain.clone());
+        let destination = Address::p2wsh(&ScriptBuf::new(), Network::Regtest);
+
+        // the wallet can't replace a tx with inputs it doesn't own, but it can spend the change
+        let return_to_self = btc_rpc.get_new_address().await.unwrap();
+        let parent = chain
+            .send_external_transaction(vec![
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: destination.script_pubkey(),
+                },
+                TxOut {
+                    value: 100_000,
+                    script_pubkey: return_to_self.script_pubkey(),
+                },
+            ])
+            .unwrap();
+
+        let fee_rate_sender = tokio::sync::broadcast::channel(16).0;
+        let mut parachain_rpc = MockProvider::default();
+        let subscribe = fee_rate_sender.clone();
+        parachain_rpc
+            .expect_on_fee_rate_change()
+            .returning(move || subscribe.subscribe());
+        parachain_rpc
+            .expect_wait_for_block_in_relay()
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let request = Request {
+            amount: 10_000,
+            deadline: None,
+            btc_address: BtcAddress::from_address(destination).unwrap(),
+            hash: H256::from_slice(&[1; 32]),
+            btc_height: None,
+            request_type: RequestType::Redeem,
+            vault_id: dummy_vault_id(),
+            fee_budget: None,
+        };
+        let inclusion = tokio::spawn({
+            let btc_rpc = btc_rpc.clone();
+            async move {
+                request
+                    .wait_for_inclusion(&parachain_rpc, &btc_rpc, 1, parent, true, FeeRatePolicy::Oracle)
+                    .await
+            }
+        });
+        while fee_rate_sender.receiver_count() == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        fee_rate_sender.send(FixedU128::from(10)).unwrap();
+        let child = wait_for_child(&chain, parent, None).await;
+        let child_fee = chain.fee(&child).unwrap();
+
+        // a higher fee rate replaces the child, rather than creating a conflicting one
+        fee_rate_sender.send(FixedU128::from(20)).unwrap();
+        let replacement = wait_for_child(&chain, parent, Some(child)).await;
+        assert!(chain.fee(&replacement).unwrap() > child_fee);
+        assert!(btc_rpc.is_in_mempool(child).await.is_err());
+
+        chain.mine_blocks(1);
+        let tx_metadata = inclusion.await.unwrap().unwrap();
+        assert_eq!(tx_metadata.txid, parent);
+        assert!(!btc_rpc.is_in_mempool(replacement).await.unwrap());
+    }
+
+    #[test]
+    fn should_combine_fee_rates() {
+        let combine = |policy, oracle, local| combine_fee_rates(policy, SatPerVbyte(oracle), SatPerVbyte(local)).0;
+
+        assert_eq!(combine(FeeRatePolicy::Oracle, 10, 50), 10);
+        assert_eq!(combine(FeeRatePolicy::Max, 10, 50), 50);
+        assert_eq!(combine(FeeRatePolicy::Min, 10, 50), 10);
+        // oracle within bounds of the local estimate
+        assert_eq!(combine(FeeRatePolicy::Bounded, 30, 20), 30);
+        // oracle too low or too high
+        assert_eq!(combine(FeeRatePolicy::Bounded, 5, 20), 10);
+        assert_eq!(combine(FeeRatePolicy::Bounded, 100, 20), 40);
+        // no local estimate
+        assert_eq!(combine(FeeRatePolicy::Min, 10, 0), 10);
+        assert_eq!(combine(FeeRatePolicy::Bounded, 10, 0), 10);
     }
 }
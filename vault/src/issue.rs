@@ -1,8 +1,13 @@
 use crate::{
-    delay::RandomDelay, metrics::publish_expected_bitcoin_balance, service::DynBitcoinCoreApi, Error, Event,
-    IssueRequests, VaultIdManager,
+    delay::RandomDelay, execution::verify_transaction_proof, metrics::publish_expected_bitcoin_balance,
+    service::DynBitcoinCoreApi, Error, Event, IssueRequests, VaultIdManager,
+};
+use bitcoin::{
+    calculate_deposit_secret_key,
+    secp256k1::{Secp256k1, SecretKey},
+    Address, BlockHash, ChainEvent, Error as BitcoinError, Hash, ImportDescriptorRequest, PrivateKey, PublicKey,
+    Timestamp, Transaction, TransactionExt,
 };
-use bitcoin::{BlockHash, Error as BitcoinError, Hash, PublicKey, Transaction, TransactionExt};
 use futures::{channel::mpsc::Sender, future, SinkExt, StreamExt, TryFutureExt};
 use runtime::{
     BtcAddress, BtcPublicKey, BtcRelayPallet, CancelIssueEvent, ExecuteIssueEvent, H256Le, InterBtcIssueRequest,
@@ -11,15 +16,17 @@ use runtime::{
 };
 use sha2::{Digest, Sha256};
 use std::{
+    collections::BTreeMap,
     sync::Arc,
     time::{Duration, Instant},
 };
+use tokio::task::AbortHandle;
 
 // initialize `issue_set` with currently open issues, and return the block height
 // from which to start watching the bitcoin chain
-pub(crate) async fn initialize_issue_set(
+pub(crate) async fn initialize_issue_set<P: IssuePallet>(
     bitcoin_core: &DynBitcoinCoreApi,
-    btc_parachain: &InterBtcParachain,
+    btc_parachain: &P,
     issue_set: &Arc<IssueRequests>,
 ) -> Result<u32, Error> {
     let (mut issue_set, requests) = future::join(issue_set.lock(), btc_parachain.get_all_active_issues()).await;
@@ -40,9 +47,9 @@ pub(crate) async fn initialize_issue_set(
 
 /// execute issue requests on best-effort (i.e. don't retry on error),
 /// returns an error if stream ends, otherwise runs forever
-pub async fn process_issue_requests(
+pub async fn process_issue_requests<P: IssuePallet + BtcRelayPallet + Clone + Send + Sync + 'static>(
     bitcoin_core: DynBitcoinCoreApi,
-    btc_parachain: InterBtcParachain,
+    btc_parachain: P,
     issue_set: Arc<IssueRequests>,
     btc_start_height: u32,
     num_confirmations: u32,
@@ -53,22 +60,46 @@ pub async fn process_issue_requests(
     let mut stream =
         bitcoin::stream_in_chain_transactions(bitcoin_core.clone(), btc_start_height, num_confirmations).await;
 
+    // tasks processing the transactions of each block, aborted if the block is orphaned
+    let mut pending_tasks: BTreeMap<u32, Vec<AbortHandle>> = BTreeMap::new();
+
     while let Some(result) = stream.next().await {
         match result {
-            Ok((block_hash, transaction)) => tokio::spawn(
-                process_transaction_and_execute_issue(
-                    bitcoin_core.clone(),
-                    btc_parachain.clone(),
-                    issue_set.clone(),
-                    num_confirmations,
-                    block_hash,
-                    transaction,
-                    random_delay.clone(),
-                )
-                .map_err(|e| {
-                    tracing::warn!("Failed to execute issue request: {}", e.to_human());
-                }),
-            ),
+            Ok(ChainEvent::Connected(height, (block_hash, transaction))) => {
+                if !pending_tasks.contains_key(&height) {
+                    // first transaction of a new block, forget about completed tasks
+                    pending_tasks.retain(|_, tasks| {
+                        tasks.retain(|task| !task.is_finished());
+                        !tasks.is_empty()
+                    });
+                }
+                let task = tokio::spawn(
+                    process_transaction_and_execute_issue(
+                        bitcoin_core.clone(),
+                        btc_parachain.clone(),
+                        issue_set.clone(),
+                        num_confirmations,
+                        block_hash,
+                        transaction,
+                        random_delay.clone(),
+                    )
+                    .map_err(|e| {
+                        tracing::warn!("Failed to execute issue request: {}", e.to_human());
+                    }),
+                );
+                pending_tasks.entry(height).or_default().push(task.abort_handle());
+            }
+            Ok(ChainEvent::Reorg { from_height }) => {
+                tracing::warn!("Bitcoin blocks from height {} were orphaned", from_height);
+                pending_tasks
+                    .split_off(&from_height)
+                    .into_values()
+                    .flatten()
+                    .for_each(|task| task.abort());
+                // payments in the orphaned blocks removed their issues from the set,
+                // restore them so that they are found in the new branch
+                initialize_issue_set(&bitcoin_core, &btc_parachain, &issue_set).await?;
+            }
             Err(err) => return Err(err.into()),
         };
     }
@@ -224,9 +255,9 @@ pub async fn add_keys_from_past_issue_request(
 }
 
 /// execute issue requests with a matching Bitcoin payment
-async fn process_transaction_and_execute_issue(
+async fn process_transaction_and_execute_issue<P: IssuePallet + BtcRelayPallet>(
     bitcoin_core: DynBitcoinCoreApi,
-    btc_parachain: InterBtcParachain,
+    btc_parachain: P,
     issue_set: Arc<IssueRequests>,
     num_confirmations: u32,
     block_hash: BlockHash,
@@ -301,6 +332,7 @@ async fn process_transaction_and_execute_issue(
                 let tx_metadata = bitcoin_core
                     .wait_for_transaction_metadata(transaction.txid(), num_confirmations, Some(block_hash), false)
                     .await?;
+                verify_transaction_proof(&btc_parachain, &tx_metadata.proof).await?;
 
                 tracing::info!(
                     "Executing issue #{:?} on behalf of user {:?} with vault {:?}",
@@ -323,27 +355,89 @@ async fn process_transaction_and_execute_issue(
     Ok(())
 }
 
-/// Import the deposit key using the on-chain key derivation scheme
-async fn add_new_deposit_key(
-    bitcoin_core: &DynBitcoinCoreApi,
-    secure_id: H256,
-    public_key: BtcPublicKey,
-) -> Result<(), Error> {
+/// The secret the vault key is multiplied with in the on-chain key derivation scheme
+fn deposit_secret(public_key: &BtcPublicKey, secure_id: H256) -> Vec<u8> {
     let mut hasher = Sha256::default();
     // input compressed public key
     hasher.input(public_key.0);
     // input issue id
     hasher.input(secure_id.as_bytes());
+    hasher.result().as_slice().to_vec()
+}
 
+/// Import the deposit key using the on-chain key derivation scheme
+async fn add_new_deposit_key(
+    bitcoin_core: &DynBitcoinCoreApi,
+    secure_id: H256,
+    public_key: BtcPublicKey,
+) -> Result<(), Error> {
     bitcoin_core
         .add_new_deposit_key(
             PublicKey::from_slice(&public_key.0).map_err(BitcoinError::KeyError)?,
-            hasher.result().as_slice().to_vec(),
+            deposit_secret(&public_key, secure_id),
         )
         .await?;
     Ok(())
 }
 
+/// Derive the private key of the deposit address of the issue from the vault's derivation key,
+/// failing if it doesn't pay to the address of the request, e.g. since the wrong key was dumped.
+fn derive_deposit_key(
+    vault_key: PrivateKey,
+    issue_id: H256,
+    request: &InterBtcIssueRequest,
+) -> Result<PrivateKey, Error> {
+    let issue_key =
+        SecretKey::from_slice(&deposit_secret(&request.btc_public_key, issue_id)).map_err(BitcoinError::from)?;
+    let deposit_key = PrivateKey {
+        inner: calculate_deposit_secret_key(vault_key.inner, issue_key)?,
+        ..vault_key
+    };
+
+    let deposit_public_key = deposit_key.public_key(&Secp256k1::signing_only());
+    let address = Address::p2wpkh(&deposit_public_key, deposit_key.network)
+        .map_err(|err| BitcoinError::ConversionError(err.into()))?;
+    if BtcAddress::from_address(address).map_err(BitcoinError::ConversionError)? != *request.btc_address {
+        return Err(Error::DepositAddressMismatch(issue_id));
+    }
+    Ok(deposit_key)
+}
+
+/// Derive the private keys of the deposit addresses of all past issue requests of this
+/// account, as descriptors that any descriptor wallet can import to recover the funds.
+///
+/// # Arguments
+///
+/// * `bitcoin_core` - the bitcoin core RPC handle of the wallet holding the derivation keys
+/// * `btc_parachain` - the parachain RPC handle
+pub async fn export_deposit_key_descriptors<P: IssuePallet + UtilFuncs>(
+    bitcoin_core: &DynBitcoinCoreApi,
+    btc_parachain: &P,
+) -> Result<Vec<ImportDescriptorRequest>, Error> {
+    let mut issue_requests = btc_parachain
+        .get_vault_issue_requests(btc_parachain.get_account_id().clone())
+        .await?;
+    issue_requests.sort_by_key(|(_, request)| request.opentime);
+
+    let mut descriptors = Vec::with_capacity(issue_requests.len());
+    for (issue_id, request) in issue_requests {
+        let public_key = PublicKey::from_slice(&request.btc_public_key.0).map_err(BitcoinError::KeyError)?;
+        let vault_key = bitcoin_core.dump_derivation_key(&public_key)?;
+        let deposit_key = derive_deposit_key(vault_key, issue_id, &request)?;
+
+        // the deposit can't be older than the bitcoin block at the time of the request
+        let block_hash = bitcoin_core.get_block_hash(request.btc_height).await?;
+        let timestamp = bitcoin_core.get_block_header(&block_hash).await?.time;
+
+        descriptors.push(ImportDescriptorRequest::wpkh(
+            &deposit_key,
+            Timestamp::Time(timestamp.into()),
+            format!("issue-{issue_id:?}"),
+        ));
+    }
+    Ok(descriptors)
+}
+
 /// Listen for RequestIssueEvent directed at this vault. Schedules a cancellation of
 /// the received issue
 ///
@@ -463,6 +557,7 @@ pub async fn listen_for_issue_cancels(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bitcoin::secp256k1::Scalar;
     use runtime::{
         subxt::utils::Static,
         AccountId,
@@ -490,6 +585,41 @@ mod tests {
             .collect()
     }
 
+    #[test]
+    fn should_derive_deposit_key_of_issue() {
+        let secp = Secp256k1::new();
+        let vault_key = PrivateKey::new(SecretKey::from_slice(&[1; 32]).unwrap(), bitcoin::Network::Regtest);
+        let vault_public_key = BtcPublicKey {
+            0: vault_key.public_key(&secp).inner.serialize(),
+        };
+        let issue_id = H256::repeat_byte(2);
+
+        // the parachain derives the deposit address from the public key only
+        let issue_key = SecretKey::from_slice(&deposit_secret(&vault_public_key, issue_id)).unwrap();
+        let deposit_public_key = PublicKey::new(
+            vault_key
+                .public_key(&secp)
+                .inner
+                .mul_tweak(&secp, &Scalar::from(issue_key))
+                .unwrap(),
+        );
+        let deposit_address = Address::p2wpkh(&deposit_public_key, bitcoin::Network::Regtest).unwrap();
+        let request = InterBtcIssueRequest {
+            btc_public_key: vault_public_key,
+            btc_address: Static(BtcAddress::from_address(deposit_address).unwrap()),
+            ..dummy_issues(vec![(0, 0)]).remove(0)
+        };
+
+        let deposit_key = derive_deposit_key(vault_key, issue_id, &request).unwrap();
+        assert_eq!(deposit_key.public_key(&secp), deposit_public_key);
+
+        // the key derived for another issue doesn't pay to the address
+        assert!(matches!(
+            derive_deposit_key(vault_key, H256::repeat_byte(3), &request),
+            Err(Error::DepositAddressMismatch(id)) if id == H256::repeat_byte(3)
+        ));
+    }
+
     #[test]
     fn test_rescan_status_update() {
         let mut status = RescanStatus::default();
@@ -630,4 +760,117 @@ mod tests {
 
         assert_eq!(status.process_blocks(15), None);
     }
+
+    #[cfg(feature = "parachain-metadata-kintsugi")]
+    mod process_issue_requests_tests {
+        use super::*;
+        use crate::{delay::ZeroDelay, mock::MockProvider};
+        use bitcoin::{bitcoin_primitives::ScriptBuf, testing::InMemoryChain, Network};
+        use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+        /// Parachain with a single pending issue, whose executions are sent to `executed`.
+        /// Clones keep the expectations, since every processed transaction gets a clone.
+        fn parachain_with_issue(
+            issue_id: H256,
+            request: InterBtcIssueRequest,
+            executed: UnboundedSender<H256>,
+        ) -> MockProvider {
+            let mut parachain_rpc = MockProvider::default();
+            let active_issue = request.clone();
+            parachain_rpc
+                .expect_get_all_active_issues()
+                .returning(move || Ok(vec![(issue_id, active_issue.clone())]));
+            let issue = request.clone();
+            parachain_rpc
+                .expect_get_issue_request()
+                .returning(move |_| Ok(issue.clone()));
+            parachain_rpc.expect_wait_for_block_in_relay().returning(|_, _| Ok(()));
+            parachain_rpc
+                .expect_verify_block_header_inclusion()
+                .returning(|_| Ok(()));
+            let sender = executed.clone();
+            parachain_rpc.expect_execute_issue().returning(move |issue_id, _| {
+                sender.send(issue_id).unwrap();
+                Ok(())
+            });
+            parachain_rpc
+                .expect_clone()
+                .returning(move || parachain_with_issue(issue_id, request.clone(), executed.clone()));
+            parachain_rpc
+        }
+
+        /// Starts processing the transactions of `chain` with 1 confirmation, returns the
+        /// deposit address of the issue and the receiver of its executions.
+        async fn process_issue(chain: &InMemoryChain, issue_id: H256) -> (Address, UnboundedReceiver<H256>) {
+            let btc_rpc: DynBitcoinCoreApi = Arc::new(chain.clone());
+            let deposit_address = Address::p2wsh(&ScriptBuf::new(), Network::Regtest);
+            let request = InterBtcIssueRequest {
+                amount: 10_000,
+                btc_address: Static(BtcAddress::from_address(deposit_address.clone()).unwrap()),
+                ..dummy_issues(vec![(0, 0)]).remove(0)
+            };
+            let (executed, receiver) = unbounded_channel();
+            let parachain_rpc = parachain_with_issue(issue_id, request, executed);
+
+            let issue_set = Arc::new(IssueRequests::default());
+            let btc_start_height = initialize_issue_set(&btc_rpc, &parachain_rpc, &issue_set)
+                .await
+                .unwrap();
+            tokio::spawn(process_issue_requests(
+                btc_rpc,
+                parachain_rpc,
+                issue_set,
+                btc_start_height,
+                1,
+                Arc::new(Box::new(ZeroDelay)),
+            ));
+            (deposit_address, receiver)
+        }
+
+        async fn next_execution(executed: &mut UnboundedReceiver<H256>) -> Option<H256> {
+            tokio::time::timeout(Duration::from_secs(5), executed.recv())
+                .await
+                .ok()
+                .flatten()
+        }
+
+        #[tokio::test]
+        async fn should_execute_paid_issue() {
+            let chain = InMemoryChain::new();
+            let issue_id = H256::repeat_byte(1);
+            let (deposit_address, mut executed) = process_issue(&chain, issue_id).await;
+
+            chain.send_external_payment(&deposit_address, 10_000, None).unwrap();
+            chain.mine_blocks(1);
+
+            assert_eq!(next_execution(&mut executed).await, Some(issue_id));
+        }
+
+        #[tokio::test]
+        async fn should_execute_issue_again_if_its_payment_is_reorged() {
+            let chain = InMemoryChain::new();
+            let issue_id = H256::repeat_byte(1);
+            let (deposit_address, mut executed) = process_issue(&chain, issue_id).await;
+
+            chain.send_external_payment(&deposit_address, 10_000, None).unwrap();
+            chain.mine_blocks(1);
+            assert_eq!(next_execution(&mut executed).await, Some(issue_id));
+
+            // the payment is orphaned and included in a block of the new branch, which
+            // must not be skipped even though the issue was removed from the set
+            chain.reorg(1);
+            assert_eq!(next_execution(&mut executed).await, Some(issue_id));
+        }
+
+        #[tokio::test]
+        async fn should_not_execute_underpaid_issue() {
+            let chain = InMemoryChain::new();
+            let (deposit_address, mut executed) = process_issue(&chain, H256::repeat_byte(1)).await;
+
+            chain.send_external_payment(&deposit_address, 9_999, None).unwrap();
+            chain.mine_blocks(1);
+
+            assert_eq!(next_execution(&mut executed).await, None);
+        }
+    }
 }
@@ -1,18 +1,26 @@
 #![recursion_limit = "256"]
 
+pub mod alerts;
+mod api;
 mod cancellation;
 mod cli;
+mod collateral;
 mod connection_manager;
+mod consolidation;
 pub mod delay;
+mod deleverage;
 mod error;
 mod execution;
 mod faucet;
 mod issue;
 pub mod metrics;
+#[cfg(all(test, feature = "parachain-metadata-kintsugi"))]
+mod mock;
 pub mod process;
 mod redeem;
 pub mod relay;
 mod replace;
+mod replace_policy;
 mod system;
 mod trace;
 mod types;
@@ -20,18 +28,21 @@ mod types;
 pub mod service {
     pub use crate::{
         cancellation::{CancellationScheduler, IssueCanceller, ReplaceCanceller},
+        cli::FeeRatePolicy,
         connection_manager::{
-            init_subscriber, spawn_cancelable, wait_or_shutdown, warp, warp::Filter, ConnectionManager,
-            DynBitcoinCoreApi, MonitoringConfig, Service, ServiceConfig, ShutdownSender,
+            init_subscriber, spawn_cancelable, vault_wallet_name, wait_or_shutdown, warp, warp::Filter,
+            ConnectionManager, DynBitcoinCoreApi, MonitoringConfig, Service, ServiceConfig, ShutdownSender,
         },
         execution::execute_open_requests,
         issue::{
-            listen_for_issue_cancels, listen_for_issue_executes, listen_for_issue_requests, process_issue_requests,
+            export_deposit_key_descriptors, listen_for_issue_cancels, listen_for_issue_executes,
+            listen_for_issue_requests, process_issue_requests,
         },
         metrics::monitor_bridge_metrics,
         redeem::listen_for_redeem_requests,
         relay::{Config, Runner},
         replace::{listen_for_accept_replace, listen_for_execute_replace, listen_for_replace_requests},
+        replace_policy::{ReplacePolicy, Rule},
     };
 }
 use governor::Quota;
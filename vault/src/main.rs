@@ -1,9 +1,9 @@
-use bitcoin::{Network, PrivateKey};
+use bitcoin::{BitcoinCoreApi, Network, PrivateKey};
 use clap::Parser;
 use futures::Future;
 use runtime::{
     sp_core::crypto::{Pair, Ss58Codec},
-    InterBtcSigner, KeyPair, DEFAULT_SPEC_NAME, SS58_PREFIX,
+    InterBtcSigner, KeyPair, PrettyPrint, VaultRegistryPallet, DEFAULT_SPEC_NAME, SS58_PREFIX,
 };
 use secp256k1::{rand::thread_rng, SecretKey};
 use signal_hook::consts::*;
@@ -12,13 +12,18 @@ use std::{
     io::Write,
     net::{Ipv4Addr, SocketAddr},
     path::PathBuf,
+    time::Duration,
 };
 use sysinfo::{System, SystemExt};
 use tokio_stream::StreamExt;
 use vault::{
+    alerts::{self, Alert, NotifierConfig},
     metrics::{self, increment_restart_counter},
     process::PidFile,
-    service::{warp, warp::Filter, ConnectionManager, MonitoringConfig, ServiceConfig},
+    service::{
+        export_deposit_key_descriptors, vault_wallet_name, warp, warp::Filter, ConnectionManager, MonitoringConfig,
+        ServiceConfig, ShutdownSender,
+    },
     Error, VaultService, VaultServiceConfig, ABOUT, AUTHORS, NAME, VERSION,
 };
 
@@ -38,6 +43,10 @@ enum Commands {
     GenerateBitcoinKey(GenerateBitcoinKeyOpts),
     /// Generate the sr25519 parachain key pair.
     GenerateParachainKey(GenerateParachainKeyOpts),
+    /// Export the private keys of all issue deposit addresses as `importdescriptors` requests.
+    ExportDepositKeys(Box<ExportDepositKeysOpts>),
+    /// Copy the keys of the legacy bitcoin-core wallets of this account into new descriptor wallets.
+    MigrateBitcoinWallets(Box<MigrateBitcoinWalletsOpts>),
     /// Run the Vault client (default).
     #[clap(name = "run")]
     RunVault(Box<RunVaultOpts>),
@@ -102,6 +111,108 @@ impl GenerateParachainKeyOpts {
     }
 }
 
+#[derive(Parser, Debug, Clone)]
+struct ExportDepositKeysOpts {
+    /// Output file name or stdout if unspecified. The file contains private keys!
+    #[clap(long, value_parser)]
+    output: Option<PathBuf>,
+
+    /// Keyring / keyfile options.
+    #[clap(flatten)]
+    account_info: runtime::cli::ProviderUserOpts,
+
+    /// Connection settings for the BTC Parachain.
+    #[clap(flatten)]
+    parachain: runtime::cli::ConnectionOpts,
+
+    /// Connection settings for Bitcoin Core.
+    #[clap(flatten)]
+    bitcoin: bitcoin::cli::BitcoinOpts,
+}
+
+impl ExportDepositKeysOpts {
+    async fn export_and_write(&self) -> Result<(), Error> {
+        let (pair, wallet_name) = self.account_info.get_key_pair()?;
+        // the master wallet holds the derivation keys of all vaults of this account
+        let bitcoin_core = self.bitcoin.new_client(Some(format!("{wallet_name}-master"))).await?;
+        let btc_parachain = self
+            .parachain
+            .try_connect(InterBtcSigner::new(pair), ShutdownSender::new())
+            .await?;
+
+        let descriptors = export_deposit_key_descriptors(&bitcoin_core, &btc_parachain).await?;
+        tracing::info!("Exporting {} deposit keys", descriptors.len());
+        let data = serde_json::to_vec_pretty(&descriptors)?;
+
+        try_write_file(&self.output, data)
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+struct MigrateBitcoinWalletsOpts {
+    /// Prefix of the descriptor wallets that are created for the migrated keys. Run
+    /// the vault with this `--bitcoin-wallet-prefix` afterwards.
+    #[clap(long)]
+    target_wallet_prefix: String,
+
+    /// Keyring / keyfile options.
+    #[clap(flatten)]
+    account_info: runtime::cli::ProviderUserOpts,
+
+    /// Connection settings for the BTC Parachain.
+    #[clap(flatten)]
+    parachain: runtime::cli::ConnectionOpts,
+
+    /// Connection settings for Bitcoin Core.
+    #[clap(flatten)]
+    bitcoin: bitcoin::cli::BitcoinOpts,
+}
+
+impl MigrateBitcoinWalletsOpts {
+    async fn migrate(&self) -> Result<(), Error> {
+        let (pair, wallet_name) = self.account_info.get_key_pair()?;
+        if wallet_name == self.target_wallet_prefix {
+            return Err(Error::InvalidWalletPrefix);
+        }
+        let signer = InterBtcSigner::new(pair);
+        let account_id = signer.account_id().clone();
+        let btc_parachain = self.parachain.try_connect(signer, ShutdownSender::new()).await?;
+
+        // the master wallet and the wallet of every vault of this account
+        let mut wallet_names = vec![(
+            format!("{wallet_name}-master"),
+            format!("{}-master", self.target_wallet_prefix),
+        )];
+        for vault_id in btc_parachain.get_vaults_by_account_id(&account_id).await? {
+            wallet_names.push((
+                vault_wallet_name(&wallet_name, &vault_id)?,
+                vault_wallet_name(&self.target_wallet_prefix, &vault_id)?,
+            ));
+        }
+
+        let connection_timeout = Duration::from_millis(self.bitcoin.bitcoin_connection_timeout_ms);
+        for (source_name, target_name) in wallet_names {
+            let source = self
+                .bitcoin
+                .new_client_builder(Some(source_name.clone()))
+                .build_and_connect(connection_timeout)
+                .await?;
+            source.create_or_load_wallet().await?;
+            let target = self
+                .bitcoin
+                .new_client_builder(Some(target_name.clone()))
+                .set_descriptor_wallet(true)
+                .build_and_connect(connection_timeout)
+                .await?;
+            target.create_or_load_wallet().await?;
+
+            let migrated = source.migrate_to_descriptor_wallet(&target).await?;
+            tracing::info!("Migrated {} keys from {} to {}", migrated, source_name, target_name);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Parser, Debug, Clone)]
 #[clap(name = NAME, version = VERSION, author = AUTHORS, about = ABOUT)]
 pub struct RunVaultOpts {
@@ -161,6 +272,12 @@ async fn start() -> Result<(), Error> {
         Some(Commands::GenerateParachainKey(opts)) => {
             return opts.generate_and_write();
         }
+        Some(Commands::ExportDepositKeys(opts)) => {
+            return opts.export_and_write().await;
+        }
+        Some(Commands::MigrateBitcoinWallets(opts)) => {
+            return opts.migrate().await;
+        }
         _ => (),
     }
 
@@ -173,15 +290,34 @@ async fn start() -> Result<(), Error> {
         .clone()
         .unwrap_or(format!("{}.db", wallet_name.clone()));
 
+    if !opts.vault.alert_webhook.is_empty() {
+        alerts::start_notifier(
+            signer.account_id().pretty_print(),
+            NotifierConfig {
+                webhooks: opts.vault.alert_webhook.clone(),
+                repeat_interval: opts.vault.alert_repeat_interval_minutes,
+                max_alerts_per_hour: opts.vault.alert_max_per_hour,
+            },
+        );
+    }
+
     let vault_connection_manager = ConnectionManager::new(
         signer.clone(),
-        Some(wallet_name.to_string()),
+        Some(
+            opts.vault
+                .bitcoin_wallet_prefix
+                .clone()
+                .unwrap_or_else(|| wallet_name.to_string()),
+        ),
         opts.bitcoin,
         opts.parachain,
         opts.service,
         opts.monitoring.clone(),
         opts.vault,
-        increment_restart_counter,
+        || {
+            increment_restart_counter();
+            alerts::notify(Alert::ServiceRestart);
+        },
         db_path,
     );
 
@@ -12,8 +12,10 @@ use futures::{try_join, StreamExt, TryFutureExt};
 use lazy_static::lazy_static;
 use runtime::{
     prometheus::{
-        gather, proto::MetricFamily, Encoder, Gauge, GaugeVec, IntCounter, IntGauge, IntGaugeVec, Opts, Registry,
-        TextEncoder,
+        core::{Collector, Desc},
+        gather,
+        proto::MetricFamily,
+        Encoder, Gauge, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
     },
     CollateralBalancesPallet, CurrencyId, CurrencyIdExt, Error as RuntimeError, FeedValuesEvent, FixedU128,
     InterBtcParachain, InterBtcRedeemRequest, IssuePallet, IssueRequestStatus, OracleKey, RedeemPallet,
@@ -31,8 +33,19 @@ const CURRENCY_LABEL: &str = "currency";
 const BTC_BALANCE_TYPE_LABEL: &str = "type";
 const REQUEST_STATUS_LABEL: &str = "status";
 const TASK_NAME: &str = "task";
+const COLLATERAL_ACTION_LABEL: &str = "action";
+const DRY_RUN_LABEL: &str = "dry_run";
 const TOKIO_POLLING_INTERVAL_MS: u64 = 10000;
 
+const ELECTRS_CACHE_HITS: (&str, &str) = (
+    "electrs_cache_hits",
+    "Number of electrs requests answered from the cache",
+);
+const ELECTRS_CACHE_MISSES: (&str, &str) = (
+    "electrs_cache_misses",
+    "Number of electrs requests not answered from the cache",
+);
+
 // Metrics are stored under the [`CURRENCY_LABEL`] key so that multiple vaults can be easily
 // monitored at the same time.
 lazy_static! {
@@ -94,6 +107,19 @@ lazy_static! {
             .expect("Failed to create prometheus metric");
     pub static ref RESTART_COUNT: IntCounter =
         IntCounter::new("restart_count", "Number of service restarts").expect("Failed to create prometheus metric");
+    pub static ref OUTSTANDING_REPLACE_AMOUNT: GaugeVec = GaugeVec::new(
+        Opts::new(
+            "outstanding_replace_amount",
+            "Amount of own replace requests not accepted yet"
+        ),
+        &[CURRENCY_LABEL]
+    )
+    .expect("Failed to create prometheus metric");
+    pub static ref COLLATERAL_ADJUSTMENTS: IntCounterVec = IntCounterVec::new(
+        Opts::new("collateral_adjustments", "Number of automatic collateral adjustments"),
+        &[CURRENCY_LABEL, COLLATERAL_ACTION_LABEL, DRY_RUN_LABEL]
+    )
+    .expect("Failed to create prometheus metric");
 }
 
 #[derive(Clone, Debug)]
@@ -252,7 +278,7 @@ impl PerCurrencyMetrics {
     }
 
     pub async fn initialize_values(parachain_rpc: InterBtcParachain, vault: &VaultData) {
-        let bitcoin_transactions = match vault.btc_rpc.list_transactions(None) {
+        let bitcoin_transactions = match vault.btc_rpc.list_transactions(None).await {
             Ok(x) => x
                 .into_iter()
                 .filter(|x| x.detail.category == GetTransactionResultDetailCategory::Send)
@@ -267,8 +293,8 @@ impl PerCurrencyMetrics {
             .fold((0, 0), |(total, count), x| (total + x, count + 1));
         *vault.metrics.average_btc_fee.data.write().await = AverageTracker { total, count };
 
-        publish_utxo_count(vault);
-        publish_bitcoin_balance(vault);
+        publish_utxo_count(vault).await;
+        publish_bitcoin_balance(vault).await;
 
         let _ = tokio::join!(
             Self::initialize_fee_budget_surplus(vault, parachain_rpc.clone(), bitcoin_transactions),
@@ -297,6 +323,9 @@ pub fn register_custom_metrics() -> Result<(), RuntimeError> {
     REGISTRY.register(Box::new(MEAN_SCHEDULED_DURATION.clone()))?;
     REGISTRY.register(Box::new(REMAINING_TIME_TO_REDEEM_HOURS.clone()))?;
     REGISTRY.register(Box::new(RESTART_COUNT.clone()))?;
+    REGISTRY.register(Box::new(ElectrsCacheCollector::new()?))?;
+    REGISTRY.register(Box::new(COLLATERAL_ADJUSTMENTS.clone()))?;
+    REGISTRY.register(Box::new(OUTSTANDING_REPLACE_AMOUNT.clone()))?;
 
     Ok(())
 }
@@ -318,6 +347,39 @@ fn serialize(metrics: &[MetricFamily]) -> String {
     res
 }
 
+/// Reads the electrs cache hits and misses counted by the bitcoin crate on every scrape.
+struct ElectrsCacheCollector {
+    descs: Vec<Desc>,
+}
+
+impl ElectrsCacheCollector {
+    fn new() -> Result<Self, RuntimeError> {
+        let descs = [ELECTRS_CACHE_HITS, ELECTRS_CACHE_MISSES]
+            .iter()
+            .map(|(name, help)| Desc::new(name.to_string(), help.to_string(), vec![], HashMap::new()))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { descs })
+    }
+}
+
+impl Collector for ElectrsCacheCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let stats = bitcoin::electrs_cache_stats();
+        [(ELECTRS_CACHE_HITS, stats.hits), (ELECTRS_CACHE_MISSES, stats.misses)]
+            .iter()
+            .flat_map(|((name, help), value)| {
+                let counter = IntCounter::new(*name, *help).expect("Failed to create prometheus metric");
+                counter.inc_by(*value);
+                counter.collect()
+            })
+            .collect()
+    }
+}
+
 pub async fn metrics_handler() -> Result<impl Reply, Rejection> {
     let mut metrics = serialize(&REGISTRY.gather());
     let custom_metrics = serialize(&gather());
@@ -341,6 +403,14 @@ pub async fn publish_locked_collateral<P: VaultRegistryPallet>(
     Ok(())
 }
 
+pub fn publish_outstanding_replace_amount(vault_id: &VaultId, amount: u128) {
+    if let Ok(amount) = raw_value_as_currency(amount, vault_id.wrapped_currency()) {
+        OUTSTANDING_REPLACE_AMOUNT
+            .with_label_values(&[PerCurrencyMetrics::label(vault_id).as_str()])
+            .set(amount);
+    }
+}
+
 pub async fn publish_required_collateral<P: VaultRegistryPallet>(
     vault: &VaultData,
     parachain_rpc: P,
@@ -390,7 +460,7 @@ pub async fn update_bitcoin_metrics(
         publish_fee_budget_surplus(vault).await?;
     }
 
-    publish_bitcoin_balance(vault);
+    publish_bitcoin_balance(vault).await;
     Ok(())
 }
 
@@ -412,8 +482,8 @@ async fn publish_average_bitcoin_fee(vault: &VaultData) {
     vault.metrics.average_btc_fee.gauge.set(average);
 }
 
-fn publish_bitcoin_balance(vault: &VaultData) {
-    match vault.btc_rpc.get_balance(None) {
+async fn publish_bitcoin_balance(vault: &VaultData) {
+    match vault.btc_rpc.get_balance(None).await {
         Ok(bitcoin_balance) => vault.metrics.btc_balance.actual.set(bitcoin_balance.to_btc()),
         Err(e) => {
             // unexpected error, but not critical so just continue
@@ -433,8 +503,8 @@ async fn publish_native_currency_balance<P: CollateralBalancesPallet + UtilFuncs
     Ok(())
 }
 
-fn publish_utxo_count(vault: &VaultData) {
-    if let Ok(count) = vault.btc_rpc.get_utxo_count() {
+async fn publish_utxo_count(vault: &VaultData) {
+    if let Ok(count) = vault.btc_rpc.get_utxo_count().await {
         if let Ok(count_i64) = count.try_into() {
             vault.metrics.utxo_count.set(count_i64);
         }
@@ -445,6 +515,18 @@ pub fn increment_restart_counter() {
     RESTART_COUNT.inc();
 }
 
+pub fn increment_collateral_adjustment_counter(vault_id: &VaultId, action: &str, dry_run: bool) {
+    let label = PerCurrencyMetrics::label(vault_id);
+    let dry_run = dry_run.to_string();
+    COLLATERAL_ADJUSTMENTS
+        .with(&HashMap::from([
+            (CURRENCY_LABEL, label.as_str()),
+            (COLLATERAL_ACTION_LABEL, action),
+            (DRY_RUN_LABEL, dry_run.as_str()),
+        ]))
+        .inc();
+}
+
 async fn publish_issue_count<V: VaultDataReader, P: IssuePallet + UtilFuncs>(parachain_rpc: &P, vault_id_manager: &V) {
     if let Ok(issues) = parachain_rpc
         .get_vault_issue_requests(parachain_rpc.get_account_id().clone())
@@ -613,7 +695,7 @@ pub async fn poll_metrics<P: CollateralBalancesPallet + RedeemPallet + IssuePall
         }
 
         for vault in vault_id_manager.get_entries().await {
-            publish_utxo_count(&vault);
+            publish_utxo_count(&vault).await;
         }
 
         sleep(SLEEP_DURATION).await;
@@ -783,8 +865,8 @@ mod tests {
             fn is_full_node(&self) -> bool;
             fn network(&self) -> Network;
             async fn wait_for_block(&self, height: u32, num_confirmations: u32) -> Result<Block, BitcoinError>;
-            fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, BitcoinError>;
-            fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, BitcoinError>;
+            async fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, BitcoinError>;
+            async fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, BitcoinError>;
             async fn get_block_count(&self) -> Result<u64, BitcoinError>;
             async fn get_raw_tx(&self, txid: &Txid, block_hash: &BlockHash) -> Result<Vec<u8>, BitcoinError>;
             async fn get_transaction(&self, txid: &Txid, block_hash: Option<BlockHash>) -> Result<Transaction, BitcoinError>;
@@ -806,15 +888,23 @@ mod tests {
             async fn create_or_load_wallet(&self) -> Result<(), BitcoinError>;
             async fn rescan_blockchain(&self, start_height: usize, end_height: usize) -> Result<(), BitcoinError>;
             async fn rescan_electrs_for_addresses(&self, addresses: Vec<Address>) -> Result<(), BitcoinError>;
-            fn get_utxo_count(&self) -> Result<usize, BitcoinError>;
+            async fn get_utxo_count(&self) -> Result<usize, BitcoinError>;
+            async fn consolidate_utxos(&self, max_inputs: usize, max_utxo_value: u64, fee_rate: SatPerVbyte) -> Result<Option<Txid>, BitcoinError>;
             async fn bump_fee(
                 &self,
                 txid: &Txid,
                 address: Address,
                 fee_rate: SatPerVbyte,
             ) -> Result<Txid, BitcoinError>;
+            async fn bump_fee_with_child(
+                &self,
+                txid: &Txid,
+                address: Address,
+                fee_rate: SatPerVbyte,
+            ) -> Result<Txid, BitcoinError>;
             async fn is_in_mempool(&self, txid: Txid) -> Result<bool, BitcoinError>;
             async fn fee_rate(&self, txid: Txid) -> Result<SatPerVbyte, BitcoinError>;
+            async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<SatPerVbyte, BitcoinError>;
             async fn get_tx_for_op_return(&self, address: Address, amount: u128, data: H256) -> Result<Option<Txid>, BitcoinError>;
         }
     }
@@ -1048,7 +1138,7 @@ mod tests {
             btc_rpc,
             metrics: PerCurrencyMetrics::dummy(),
         };
-        publish_utxo_count(&vault_data);
+        publish_utxo_count(&vault_data).await;
 
         let utxo_count = vault_data.metrics.utxo_count.get();
         assert_eq!(utxo_count, 102);
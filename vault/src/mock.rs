@@ -0,0 +1,113 @@
+//! Mock of the parachain, shared by the unit tests that need more than a few of its pallets.
+
+use async_trait::async_trait;
+use bitcoin::RawTransactionProof;
+use jsonrpc_core::serde_json::{Map, Value};
+use runtime::{
+    AccountId, AssetMetadata, Balance, BitcoinBlockHeight, BlockNumber, BtcAddress, BtcPublicKey, BtcRelayPallet,
+    CollateralBalancesPallet, CurrencyId, Error as RuntimeError, H256Le, InterBtcIssueRequest, InterBtcRedeemRequest,
+    InterBtcReplaceRequest, InterBtcRichBlockHeader, InterBtcVault, IssuePallet, RawBlockHeader, RedeemPallet,
+    ReplacePallet, RequestIssueEvent, SecurityPallet, UtilFuncs, VaultId, VaultRegistryPallet, H256,
+};
+
+mockall::mock! {
+    pub Provider {}
+
+    #[async_trait]
+    pub trait UtilFuncs {
+        async fn get_current_chain_height(&self) -> Result<u32, RuntimeError>;
+        async fn get_rpc_properties(&self) -> Result<Map<String, Value>, RuntimeError>;
+        fn get_native_currency_id(&self) -> CurrencyId;
+        fn get_account_id(&self) -> &AccountId;
+        fn is_this_vault(&self, vault_id: &VaultId) -> bool;
+        async fn get_foreign_assets_metadata(&self) -> Result<Vec<(u32, AssetMetadata)>, RuntimeError>;
+        async fn get_foreign_asset_metadata(&self, id: u32) -> Result<AssetMetadata, RuntimeError>;
+        async fn get_lend_tokens(&self) -> Result<Vec<(CurrencyId, CurrencyId)>, RuntimeError>;
+    }
+
+    #[async_trait]
+    pub trait VaultRegistryPallet {
+        async fn get_vault(&self, vault_id: &VaultId) -> Result<InterBtcVault, RuntimeError>;
+        async fn get_vaults_by_account_id(&self, account_id: &AccountId) -> Result<Vec<VaultId>, RuntimeError>;
+        async fn get_all_vaults(&self) -> Result<Vec<InterBtcVault>, RuntimeError>;
+        async fn register_vault(&self, vault_id: &VaultId, collateral: u128) -> Result<(), RuntimeError>;
+        async fn deposit_collateral(&self, vault_id: &VaultId, amount: u128) -> Result<(), RuntimeError>;
+        async fn withdraw_collateral(&self, vault_id: &VaultId, amount: u128) -> Result<(), RuntimeError>;
+        async fn get_public_key(&self) -> Result<Option<BtcPublicKey>, RuntimeError>;
+        async fn register_public_key(&self, public_key: BtcPublicKey) -> Result<(), RuntimeError>;
+        async fn get_required_collateral_for_wrapped(&self, amount_btc: u128, collateral_currency: CurrencyId) -> Result<u128, RuntimeError>;
+        async fn get_required_collateral_for_vault(&self, vault_id: VaultId) -> Result<u128, RuntimeError>;
+        async fn get_vault_total_collateral(&self, vault_id: VaultId) -> Result<u128, RuntimeError>;
+        async fn get_collateralization_from_vault(&self, vault_id: VaultId, only_issued: bool) -> Result<u128, RuntimeError>;
+        async fn set_current_client_release(&self, uri: &[u8], code_hash: &H256) -> Result<(), RuntimeError>;
+        async fn set_pending_client_release(&self, uri: &[u8], code_hash: &H256) -> Result<(), RuntimeError>;
+    }
+
+    #[async_trait]
+    pub trait CollateralBalancesPallet {
+        async fn get_free_balance(&self, currency_id: CurrencyId) -> Result<Balance, RuntimeError>;
+        async fn get_free_balance_for_id(&self, id: AccountId, currency_id: CurrencyId) -> Result<Balance, RuntimeError>;
+        async fn get_reserved_balance(&self, currency_id: CurrencyId) -> Result<Balance, RuntimeError>;
+        async fn get_reserved_balance_for_id(&self, id: AccountId, currency_id: CurrencyId) -> Result<Balance, RuntimeError>;
+        async fn transfer_to(&self, recipient: &AccountId, amounts: Vec<(u128, CurrencyId)>) -> Result<(), RuntimeError>;
+    }
+
+    #[async_trait]
+    pub trait BtcRelayPallet {
+        async fn get_best_block(&self) -> Result<H256Le, RuntimeError>;
+        async fn get_best_block_height(&self) -> Result<u32, RuntimeError>;
+        async fn get_block_hash(&self, height: u32) -> Result<H256Le, RuntimeError>;
+        async fn get_block_header(&self, hash: H256Le) -> Result<InterBtcRichBlockHeader, RuntimeError>;
+        async fn get_bitcoin_confirmations(&self) -> Result<u32, RuntimeError>;
+        async fn get_parachain_confirmations(&self) -> Result<BlockNumber, RuntimeError>;
+        async fn wait_for_block_in_relay(&self, block_hash: H256Le, btc_confirmations: Option<BlockNumber>) -> Result<(), RuntimeError>;
+        async fn verify_block_header_inclusion(&self, block_hash: H256Le) -> Result<(), RuntimeError>;
+        async fn initialize_btc_relay(&self, header: RawBlockHeader, height: BitcoinBlockHeight) -> Result<(), RuntimeError>;
+        async fn store_block_header(&self, header: RawBlockHeader) -> Result<(), RuntimeError>;
+        async fn store_block_headers(&self, headers: Vec<RawBlockHeader>) -> Result<(), RuntimeError>;
+    }
+
+    #[async_trait]
+    pub trait IssuePallet {
+        async fn request_issue(&self, amount: u128, vault_id: &VaultId) -> Result<RequestIssueEvent, RuntimeError>;
+        async fn execute_issue(&self, issue_id: H256, raw_proof: &RawTransactionProof,) -> Result<(), RuntimeError>;
+        async fn cancel_issue(&self, issue_id: H256) -> Result<(), RuntimeError>;
+        async fn get_issue_request(&self, issue_id: H256) -> Result<InterBtcIssueRequest, RuntimeError>;
+        async fn get_vault_issue_requests(&self, account_id: AccountId) -> Result<Vec<(H256, InterBtcIssueRequest)>, RuntimeError>;
+        async fn get_issue_period(&self) -> Result<u32, RuntimeError>;
+        async fn get_all_active_issues(&self) -> Result<Vec<(H256, InterBtcIssueRequest)>, RuntimeError>;
+    }
+
+    #[async_trait]
+    pub trait RedeemPallet {
+        async fn request_redeem(&self, amount: u128, btc_address: BtcAddress, vault_id: &VaultId) -> Result<H256, RuntimeError>;
+        async fn execute_redeem(&self, redeem_id: H256, raw_proof: &RawTransactionProof) -> Result<(), RuntimeError>;
+        async fn cancel_redeem(&self, redeem_id: H256, reimburse: bool) -> Result<(), RuntimeError>;
+        async fn get_redeem_request(&self, redeem_id: H256) -> Result<InterBtcRedeemRequest, RuntimeError>;
+        async fn get_vault_redeem_requests(&self, account_id: AccountId) -> Result<Vec<(H256, InterBtcRedeemRequest)>, RuntimeError>;
+        async fn get_redeem_period(&self) -> Result<BlockNumber, RuntimeError>;
+    }
+
+    #[async_trait]
+    pub trait ReplacePallet {
+        async fn request_replace(&self, vault_id: &VaultId, amount: u128) -> Result<(), RuntimeError>;
+        async fn withdraw_replace(&self, vault_id: &VaultId, amount: u128) -> Result<(), RuntimeError>;
+        async fn accept_replace(&self, new_vault: &VaultId, old_vault: &VaultId, amount_btc: u128, collateral: u128, btc_address: BtcAddress) -> Result<(), RuntimeError>;
+        async fn execute_replace(&self, replace_id: H256, raw_proof: &RawTransactionProof) -> Result<(), RuntimeError>;
+        async fn cancel_replace(&self, replace_id: H256) -> Result<(), RuntimeError>;
+        async fn get_new_vault_replace_requests(&self, account_id: AccountId) -> Result<Vec<(H256, InterBtcReplaceRequest)>, RuntimeError>;
+        async fn get_old_vault_replace_requests(&self, account_id: AccountId) -> Result<Vec<(H256, InterBtcReplaceRequest)>, RuntimeError>;
+        async fn get_replace_period(&self) -> Result<u32, RuntimeError>;
+        async fn get_replace_request(&self, replace_id: H256) -> Result<InterBtcReplaceRequest, RuntimeError>;
+        async fn get_replace_dust_amount(&self) -> Result<u128, RuntimeError>;
+    }
+
+    #[async_trait]
+    pub trait SecurityPallet {
+        async fn get_current_active_block_number(&self) -> Result<u32, RuntimeError>;
+    }
+
+    impl Clone for Provider {
+        fn clone(&self) -> Self;
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{
+    cli::FeeRatePolicy,
     execution::*,
     metrics::publish_expected_bitcoin_balance,
     service::{spawn_cancelable, ShutdownSender},
@@ -23,6 +24,7 @@ pub async fn listen_for_redeem_requests(
     num_confirmations: u32,
     payment_margin: Duration,
     auto_rbf: bool,
+    fee_rate_policy: FeeRatePolicy,
 ) -> Result<(), Error> {
     parachain_rpc
         .on_event::<RequestRedeemEvent, _, _, _>(
@@ -50,7 +52,7 @@ pub async fn listen_for_redeem_requests(
                             payment_margin,
                         )?;
                         request
-                            .pay_and_execute(parachain_rpc, vault, num_confirmations, auto_rbf)
+                            .pay_and_execute(parachain_rpc, vault, num_confirmations, auto_rbf, fee_rate_policy)
                             .await
                     }
                     .await;
@@ -2,6 +2,8 @@ use super::Error;
 use crate::service::DynBitcoinCoreApi;
 use async_trait::async_trait;
 use bitcoin::{serialize, BitcoinCoreApi, Error as BitcoinError};
+use std::{ops::Range, time::Duration};
+use tokio::time::sleep;
 
 #[async_trait]
 pub trait Backing {
@@ -15,12 +17,35 @@ pub trait Backing {
     /// * `height` - The height of the block to fetch
     async fn get_block_header(&self, height: u32) -> Result<Option<Vec<u8>>, Error>;
 
+    /// Returns the raw headers of consecutive blocks in storage
+    ///
+    /// # Arguments
+    ///
+    /// * `heights` - The heights of the blocks to fetch
+    async fn get_block_headers(&self, heights: Range<u32>) -> Result<Vec<Vec<u8>>, Error> {
+        let mut headers = Vec::new();
+        for height in heights {
+            headers.push(self.get_block_header(height).await?.ok_or(Error::BlockHeaderNotFound)?);
+        }
+        Ok(headers)
+    }
+
     /// Returns the (little endian) hash of a block
     ///
     /// # Arguments
     ///
     /// * `height` - The height of the block to fetch
     async fn get_block_hash(&self, height: u32) -> Result<Vec<u8>, Error>;
+
+    /// Waits until a block is available at `height`, but at most `max_wait`
+    ///
+    /// # Arguments
+    ///
+    /// * `height` - The height of the block to wait for
+    /// * `max_wait` - The maximum time to wait
+    async fn wait_for_new_block(&self, _height: u32, max_wait: Duration) {
+        sleep(max_wait).await
+    }
 }
 
 #[async_trait]
@@ -42,10 +67,22 @@ impl Backing for DynBitcoinCoreApi {
         Ok(Some(serialize(&block_header)))
     }
 
+    async fn get_block_headers(&self, heights: Range<u32>) -> Result<Vec<Vec<u8>>, Error> {
+        match BitcoinCoreApi::get_block_headers(&**self, heights).await {
+            Ok(headers) => Ok(headers.iter().map(serialize).collect()),
+            Err(BitcoinError::InvalidBitcoinHeight) => Err(Error::BlockHeaderNotFound),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     async fn get_block_hash(&self, height: u32) -> Result<Vec<u8>, Error> {
         let block_hash = BitcoinCoreApi::get_block_hash(&**self, height)
             .await
             .map(|hash| serialize(&hash))?;
         Ok(block_hash)
     }
+
+    async fn wait_for_new_block(&self, height: u32, max_wait: Duration) {
+        BitcoinCoreApi::wait_for_new_block(&**self, height, max_wait).await
+    }
 }
@@ -1,7 +1,6 @@
 use crate::{service::DynBitcoinCoreApi, Error as VaultError};
 use runtime::InterBtcParachain;
 use std::{sync::Arc, time::Duration};
-use tokio::time::sleep;
 
 use crate::delay::RandomDelay;
 
@@ -18,15 +17,7 @@ const SLEEP_TIME: Duration = Duration::from_secs(600);
 
 /// Retrieves `batch` blocks starting at block `height` from the backing blockchain
 async fn collect_headers(height: u32, batch: u32, cli: &impl Backing) -> Result<Vec<Vec<u8>>, Error> {
-    let mut headers = Vec::new();
-    for h in height..height + batch {
-        headers.push(
-            cli.get_block_header(h)
-                .await
-                .map(|header| header.ok_or(Error::BlockHeaderNotFound))??,
-        );
-    }
-    Ok(headers)
+    cli.get_block_headers(height..height + batch).await
 }
 
 /// Computes the height at which the relayer should start to submit blocks.
@@ -66,7 +57,7 @@ pub struct Config {
     pub start_height: Option<u32>,
     /// Maximum number of headers to collect on catchup
     pub max_batch_size: u32,
-    /// Thread sleep duration
+    /// Maximum time to wait for the next block
     pub interval: Option<Duration>,
     /// Number of confirmations a block needs to have before it is submitted.
     pub btc_confirmations: u32,
@@ -107,8 +98,8 @@ impl<B: Backing, I: Issuing> Runner<B, I> {
             match self.backing.get_block_header(height).await? {
                 Some(header) => return Ok(header),
                 None => {
-                    tracing::trace!("No block found at height {}, sleeping for {:?}", height, self.interval);
-                    sleep(self.interval).await
+                    tracing::trace!("No block found at height {}, waiting for {:?}", height, self.interval);
+                    self.backing.wait_for_new_block(height, self.interval).await
                 }
             };
         }
@@ -151,7 +142,9 @@ impl<B: Backing, I: Issuing> Runner<B, I> {
             0 => {
                 // nothing to submit right now. Wait a little while
                 tracing::trace!("Waiting for the next Bitcoin block...");
-                sleep(self.interval).await;
+                self.backing
+                    .wait_for_new_block(current_height.saturating_add(self.btc_confirmations), self.interval)
+                    .await;
             }
             1 => {
                 // submit a single block header
@@ -211,10 +204,12 @@ mod tests {
 
     use super::*;
     use async_trait::async_trait;
+    use bitcoin::{deserialize, serialize, testing::InMemoryChain, BlockHeader};
     use std::{
         cell::{Ref, RefCell, RefMut},
-        collections::HashMap,
+        collections::{BTreeMap, HashMap},
         rc::Rc,
+        sync::Mutex,
     };
 
     struct DummyIssuing {
@@ -571,4 +566,123 @@ mod tests {
         assert!(!runner.issuing.is_block_stored(make_hash("d")).await?);
         Ok(())
     }
+
+    /// Relay that, like the parachain, checks that headers extend its chain and
+    /// switches to a fork that reaches the tip of the backing chain.
+    #[derive(Default)]
+    struct ForkingIssuing {
+        headers: Mutex<BTreeMap<u32, BlockHeader>>,
+    }
+
+    #[async_trait]
+    impl Issuing for ForkingIssuing {
+        async fn is_initialized(&self) -> Result<bool, Error> {
+            Ok(!self.headers.lock().unwrap().is_empty())
+        }
+
+        async fn initialize(&self, header: Vec<u8>, height: u32) -> Result<(), Error> {
+            let header = deserialize(&header).map_err(|_| Error::SerializeHeader)?;
+            self.headers.lock().unwrap().insert(height, header);
+            Ok(())
+        }
+
+        async fn submit_block_header(
+            &self,
+            header: Vec<u8>,
+            _random_delay: Arc<Box<dyn RandomDelay + Send + Sync>>,
+        ) -> Result<(), Error> {
+            let header: BlockHeader = deserialize(&header).map_err(|_| Error::SerializeHeader)?;
+            let mut headers = self.headers.lock().unwrap();
+            if headers
+                .values()
+                .any(|stored| stored.block_hash() == header.block_hash())
+            {
+                return Err(Error::BlockExists);
+            }
+            let parent_height = headers
+                .iter()
+                .find_map(|(height, stored)| (stored.block_hash() == header.prev_blockhash).then_some(*height))
+                .ok_or(Error::BlockHashNotFound)?;
+            // drop the headers of the stale branch
+            headers.retain(|height, _| *height <= parent_height);
+            headers.insert(parent_height + 1, header);
+            Ok(())
+        }
+
+        async fn submit_block_header_batch(&self, headers: Vec<Vec<u8>>) -> Result<(), Error> {
+            for header in headers {
+                self.submit_block_header(header, Arc::new(Box::new(ZeroDelay))).await?;
+            }
+            Ok(())
+        }
+
+        async fn get_best_height(&self) -> Result<u32, Error> {
+            self.headers
+                .lock()
+                .unwrap()
+                .keys()
+                .max()
+                .copied()
+                .ok_or(Error::CannotFetchBestHeight)
+        }
+
+        async fn get_block_hash(&self, height: u32) -> Result<Vec<u8>, Error> {
+            self.headers
+                .lock()
+                .unwrap()
+                .get(&height)
+                .map(|header| serialize(&header.block_hash()))
+                .ok_or(Error::BlockHashNotFound)
+        }
+
+        async fn is_block_stored(&self, hash: Vec<u8>) -> Result<bool, Error> {
+            Ok(self
+                .headers
+                .lock()
+                .unwrap()
+                .values()
+                .any(|header| serialize(&header.block_hash()) == hash))
+        }
+    }
+
+    async fn assert_relay_in_sync(runner: &Runner<DynBitcoinCoreApi, ForkingIssuing>) -> Result<(), Error> {
+        let best_height = runner.backing.get_block_count().await?;
+        assert_eq!(runner.issuing.get_best_height().await?, best_height);
+        for height in 0..=best_height {
+            assert_eq!(
+                runner.issuing.get_block_hash(height).await?,
+                runner.backing.get_block_hash(height).await?
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn submit_next_follows_in_memory_chain_through_reorg() -> Result<(), Error> {
+        let chain = InMemoryChain::new();
+        chain.mine_blocks(5);
+        let backing: DynBitcoinCoreApi = Arc::new(chain.clone());
+        let runner = Runner::new(
+            backing,
+            ForkingIssuing::default(),
+            Config {
+                start_height: Some(0),
+                max_batch_size: 16,
+                interval: Some(Duration::from_secs(0)),
+                btc_confirmations: 0,
+            },
+            Arc::new(Box::new(ZeroDelay)),
+        );
+
+        runner.submit_next().await?;
+        assert_relay_in_sync(&runner).await?;
+
+        // the two most recent blocks are replaced by a longer fork
+        let stale_hash = runner.backing.get_block_hash(5).await?;
+        chain.reorg(2);
+        runner.submit_next().await?;
+        assert_relay_in_sync(&runner).await?;
+        assert!(!runner.issuing.is_block_stored(stale_hash).await?);
+        Ok(())
+    }
 }
@@ -1,16 +1,19 @@
 use crate::{
     cancellation::Event,
+    cli::FeeRatePolicy,
     error::Error,
     execution::Request,
     metrics::publish_expected_bitcoin_balance,
+    replace_policy::{ReplacePolicy, Rule},
     service::{spawn_cancelable, DynBitcoinCoreApi, ShutdownSender},
     system::VaultIdManager,
 };
 use bitcoin::Error as BitcoinError;
 use futures::{channel::mpsc::Sender, future::try_join3, SinkExt};
 use runtime::{
-    AcceptReplaceEvent, BtcAddress, CollateralBalancesPallet, ExecuteReplaceEvent, InterBtcParachain, PartialAddress,
-    PrettyPrint, ReplacePallet, RequestReplaceEvent, UtilFuncs, VaultId, VaultRegistryPallet,
+    AcceptReplaceEvent, BtcAddress, CollateralBalancesPallet, ExecuteReplaceEvent, FixedPointNumber, FixedU128,
+    InterBtcParachain, OraclePallet, PartialAddress, PrettyPrint, ReplacePallet, RequestReplaceEvent, UtilFuncs,
+    VaultId, VaultRegistryPallet,
 };
 use std::time::Duration;
 
@@ -29,6 +32,7 @@ pub async fn listen_for_accept_replace(
     num_confirmations: u32,
     payment_margin: Duration,
     auto_rbf: bool,
+    fee_rate_policy: FeeRatePolicy,
 ) -> Result<(), Error> {
     let parachain_rpc = &parachain_rpc;
     let vault_id_manager = &vault_id_manager;
@@ -59,7 +63,7 @@ pub async fn listen_for_accept_replace(
                             payment_margin,
                         )?;
                         request
-                            .pay_and_execute(parachain_rpc, vault, num_confirmations, auto_rbf)
+                            .pay_and_execute(parachain_rpc, vault, num_confirmations, auto_rbf, fee_rate_policy)
                             .await
                     }
                     .await;
@@ -91,15 +95,18 @@ pub async fn listen_for_accept_replace(
 /// * `parachain_rpc` - the parachain RPC handle
 /// * `event_channel` - the channel over which to signal events
 /// * `accept_replace_requests` - if true, we attempt to accept replace requests
+/// * `policy` - the rules deciding which requests to accept, and with which vault
 pub async fn listen_for_replace_requests(
     parachain_rpc: InterBtcParachain,
     btc_rpc: VaultIdManager,
     event_channel: Sender<Event>,
     accept_replace_requests: bool,
+    policy: ReplacePolicy,
 ) -> Result<(), Error> {
     let parachain_rpc = &parachain_rpc;
     let btc_rpc = &btc_rpc;
     let event_channel = &event_channel;
+    let policy = &policy;
     parachain_rpc
         .on_event::<RequestReplaceEvent, _, _, _>(
             |event| async move {
@@ -114,28 +121,55 @@ pub async fn listen_for_replace_requests(
                     event.amount
                 );
 
-                if accept_replace_requests {
-                    for (vault_id, btc_rpc) in btc_rpc.get_vault_btc_rpcs().await {
-                        match handle_replace_request(parachain_rpc.clone(), btc_rpc.clone(), &event, &vault_id).await {
-                            Ok(_) => {
-                                tracing::info!(
-                                    "[{}] Accepted replace request from {}",
-                                    vault_id.pretty_print(),
-                                    event.old_vault_id.pretty_print()
-                                );
-                                // try to send the event, but ignore the returned result since
-                                // the only way it can fail is if the channel is closed
-                                let _ = event_channel.clone().send(Event::Opened).await;
-
-                                return; // no need to iterate over the rest of the vault ids
-                            }
-                            Err(e) => tracing::error!(
-                                "[{}] Failed to accept replace request from {}: {}",
+                if !accept_replace_requests {
+                    return;
+                }
+                if let Err(rule) = policy.check_request(&event) {
+                    tracing::info!(
+                        "Rejected replace request from {}: {}",
+                        event.old_vault_id.pretty_print(),
+                        rule
+                    );
+                    return;
+                }
+
+                let mut vaults = btc_rpc.get_vault_btc_rpcs().await;
+                let vault_ids: Vec<_> = vaults.iter().map(|(vault_id, _)| vault_id.clone()).collect();
+                policy.sort_by_preference(&mut vaults);
+                for (vault_id, btc_rpc) in vaults {
+                    let result = async {
+                        policy
+                            .check_exposure(parachain_rpc, &vault_ids, &vault_id, event.amount)
+                            .await?;
+                        handle_replace_request(parachain_rpc.clone(), btc_rpc.clone(), &event, &vault_id, policy).await
+                    }
+                    .await;
+                    match result {
+                        Ok(collateral) => {
+                            tracing::info!(
+                                "[{}] Accepted replace request from {}, locking {} additional collateral",
                                 vault_id.pretty_print(),
                                 event.old_vault_id.pretty_print(),
-                                e.to_human()
-                            ),
+                                collateral
+                            );
+                            // try to send the event, but ignore the returned result since
+                            // the only way it can fail is if the channel is closed
+                            let _ = event_channel.clone().send(Event::Opened).await;
+
+                            return; // no need to iterate over the rest of the vault ids
                         }
+                        Err(Error::ReplaceRejected(rule)) => tracing::info!(
+                            "[{}] Rejected replace request from {}: {}",
+                            vault_id.pretty_print(),
+                            event.old_vault_id.pretty_print(),
+                            rule
+                        ),
+                        Err(e) => tracing::error!(
+                            "[{}] Failed to accept replace request from {}: {}",
+                            vault_id.pretty_print(),
+                            event.old_vault_id.pretty_print(),
+                            e.to_human()
+                        ),
                     }
                 }
             },
@@ -145,14 +179,18 @@ pub async fn listen_for_replace_requests(
     Ok(())
 }
 
-/// Attempts to accept a replace request. Does not retry RPC calls upon
-/// failure, since nothing is at stake at this point
-pub async fn handle_replace_request<'a, P: CollateralBalancesPallet + ReplacePallet + VaultRegistryPallet>(
+/// Attempts to accept a replace request, returning the additional collateral that
+/// was locked. Does not retry RPC calls upon failure, since nothing is at stake at this point
+pub async fn handle_replace_request<
+    'a,
+    P: CollateralBalancesPallet + ReplacePallet + VaultRegistryPallet + OraclePallet,
+>(
     parachain_rpc: P,
     btc_rpc: DynBitcoinCoreApi,
     event: &'a RequestReplaceEvent,
     vault_id: &'a VaultId,
-) -> Result<(), Error> {
+    policy: &'a ReplacePolicy,
+) -> Result<u128, Error> {
     let collateral_currency = vault_id.collateral_currency();
 
     let (required_replace_collateral, current_collateral, used_collateral) = try_join3(
@@ -163,20 +201,45 @@ pub async fn handle_replace_request<'a, P: CollateralBalancesPallet + ReplacePal
     .await?;
 
     let total_required_collateral = required_replace_collateral.saturating_add(used_collateral);
+    let mut additional_collateral = total_required_collateral.saturating_sub(current_collateral);
+
+    if let Some(min_collateralization) = policy.min_collateralization {
+        let vault = parachain_rpc.get_vault(vault_id).await?;
+        let tokens = vault
+            .issued_tokens
+            .saturating_add(vault.to_be_issued_tokens)
+            .saturating_add(event.amount);
+        let tokens_value = parachain_rpc.wrapped_to_collateral(tokens, collateral_currency).await?;
+        let min_collateral = min_collateralization.saturating_mul_int(tokens_value);
+        if current_collateral < min_collateral && !policy.lock_collateral {
+            return Err(Rule::MinCollateralization {
+                collateralization: FixedU128::checked_from_rational(current_collateral, tokens_value)
+                    .unwrap_or_default()
+                    .to_float(),
+                min: min_collateralization.to_float(),
+            }
+            .into());
+        }
+        additional_collateral = additional_collateral.max(min_collateral.saturating_sub(current_collateral));
+    }
 
-    if current_collateral < total_required_collateral {
-        Err(Error::InsufficientFunds)
-    } else {
-        Ok(parachain_rpc
-            .accept_replace(
-                vault_id,
-                &event.old_vault_id,
-                event.amount,
-                0, // do not lock any additional collateral
-                BtcAddress::from_address(btc_rpc.get_new_address().await?).map_err(BitcoinError::ConversionError)?,
-            )
-            .await?)
+    if additional_collateral > 0
+        && (!policy.lock_collateral
+            || parachain_rpc.get_free_balance(collateral_currency).await? < additional_collateral)
+    {
+        return Err(Error::InsufficientFunds);
     }
+
+    parachain_rpc
+        .accept_replace(
+            vault_id,
+            &event.old_vault_id,
+            event.amount,
+            additional_collateral,
+            BtcAddress::from_address(btc_rpc.get_new_address().await?).map_err(BitcoinError::ConversionError)?,
+        )
+        .await?;
+    Ok(additional_collateral)
 }
 
 /// Listen for ExecuteReplaceEvent directed at this vault and continue the replacement
@@ -216,8 +279,8 @@ mod tests {
         PrivateKey, PublicKey, RawTransactionProof, SatPerVbyte, Transaction, TransactionMetadata, Txid,
     };
     use runtime::{
-        AccountId, Balance, BtcAddress, BtcPublicKey, CurrencyId, Error as RuntimeError, InterBtcReplaceRequest,
-        InterBtcVault, Token, DOT, H256, IBTC,
+        AccountId, Balance, BtcAddress, BtcPublicKey, CurrencyId, Error as RuntimeError, FeeRateUpdateReceiver,
+        InterBtcReplaceRequest, InterBtcVault, OracleKey, Token, VaultStatus, DOT, H256, IBTC,
     };
     use std::{str::FromStr, sync::Arc};
 
@@ -239,8 +302,8 @@ mod tests {
             fn is_full_node(&self) -> bool;
             fn network(&self) -> Network;
             async fn wait_for_block(&self, height: u32, num_confirmations: u32) -> Result<Block, BitcoinError>;
-            fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, BitcoinError>;
-            fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, BitcoinError>;
+            async fn get_balance(&self, min_confirmations: Option<u32>) -> Result<Amount, BitcoinError>;
+            async fn list_transactions(&self, max_count: Option<usize>) -> Result<Vec<json::ListTransactionResult>, BitcoinError>;
             async fn get_block_count(&self) -> Result<u64, BitcoinError>;
             async fn get_raw_tx(&self, txid: &Txid, block_hash: &BlockHash) -> Result<Vec<u8>, BitcoinError>;
             async fn get_transaction(&self, txid: &Txid, block_hash: Option<BlockHash>) -> Result<Transaction, BitcoinError>;
@@ -287,15 +350,23 @@ mod tests {
             async fn create_or_load_wallet(&self) -> Result<(), BitcoinError>;
             async fn rescan_blockchain(&self, start_height: usize, end_height: usize) -> Result<(), BitcoinError>;
             async fn rescan_electrs_for_addresses(&self, addresses: Vec<Address>) -> Result<(), BitcoinError>;
-            fn get_utxo_count(&self) -> Result<usize, BitcoinError>;
+            async fn get_utxo_count(&self) -> Result<usize, BitcoinError>;
+            async fn consolidate_utxos(&self, max_inputs: usize, max_utxo_value: u64, fee_rate: SatPerVbyte) -> Result<Option<Txid>, BitcoinError>;
             async fn bump_fee(
                 &self,
                 txid: &Txid,
                 address: Address,
                 fee_rate: SatPerVbyte,
             ) -> Result<Txid, BitcoinError>;
+            async fn bump_fee_with_child(
+                &self,
+                txid: &Txid,
+                address: Address,
+                fee_rate: SatPerVbyte,
+            ) -> Result<Txid, BitcoinError>;
             async fn is_in_mempool(&self, txid: Txid) -> Result<bool, BitcoinError>;
             async fn fee_rate(&self, txid: Txid) -> Result<SatPerVbyte, BitcoinError>;
+            async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<SatPerVbyte, BitcoinError>;
             async fn get_tx_for_op_return(&self, address: Address, amount: u128, data: H256) -> Result<Option<Txid>, BitcoinError>;
         }
     }
@@ -350,6 +421,18 @@ mod tests {
         async fn get_reserved_balance(&self, currency_id: CurrencyId) -> Result<Balance, RuntimeError>;
         async fn get_reserved_balance_for_id(&self, id: AccountId, currency_id: CurrencyId) -> Result<Balance, RuntimeError>;
         async fn transfer_to(&self, recipient: &AccountId, amounts: Vec<(u128, CurrencyId)>) -> Result<(), RuntimeError>;         }
+
+    #[async_trait]
+    pub trait OraclePallet {
+        async fn get_exchange_rate(&self, currency_id: CurrencyId) -> Result<FixedU128, RuntimeError>;
+        async fn feed_values(&self, values: Vec<(OracleKey, FixedU128)>) -> Result<(), RuntimeError>;
+        async fn set_bitcoin_fees(&self, value: FixedU128) -> Result<(), RuntimeError>;
+        async fn get_bitcoin_fees(&self) -> Result<FixedU128, RuntimeError>;
+        async fn wrapped_to_collateral(&self, amount: u128, currency_id: CurrencyId) -> Result<u128, RuntimeError>;
+        async fn collateral_to_wrapped(&self, amount: u128, currency_id: CurrencyId) -> Result<u128, RuntimeError>;
+        async fn has_updated(&self, key: &OracleKey) -> Result<bool, RuntimeError>;
+        fn on_fee_rate_change(&self) -> FeeRateUpdateReceiver;
+    }
     }
 
     impl Clone for MockProvider {
@@ -363,6 +446,48 @@ mod tests {
         VaultId::new(AccountId::new([1u8; 32]), Token(DOT), Token(IBTC))
     }
 
+    fn dummy_btc_rpc() -> DynBitcoinCoreApi {
+        let mut mock_bitcoin = MockBitcoin::default();
+        mock_bitcoin.expect_get_new_address().returning(|| {
+            Ok(Address::from_str("bcrt1q6v2c7q7uv8vu6xle2k9ryfj3y3fuuy4rqnl50f")
+                .unwrap()
+                .require_network(Network::Regtest)
+                .unwrap())
+        });
+        Arc::new(mock_bitcoin)
+    }
+
+    /// a vault with 100 collateral backing 50 tokens, worth 50 collateral
+    fn mock_provider_with_vault() -> MockProvider {
+        let mut parachain_rpc = MockProvider::default();
+        parachain_rpc
+            .expect_get_required_collateral_for_wrapped()
+            .returning(|amount, _| Ok(amount));
+        parachain_rpc
+            .expect_get_required_collateral_for_vault()
+            .returning(|_| Ok(50));
+        parachain_rpc.expect_get_vault_total_collateral().returning(|_| Ok(100));
+        parachain_rpc.expect_get_vault().returning(|vault_id| {
+            Ok(InterBtcVault {
+                id: vault_id.clone(),
+                status: VaultStatus::Active(true),
+                banned_until: None,
+                secure_collateral_threshold: None,
+                to_be_issued_tokens: 0,
+                issued_tokens: 50,
+                to_be_redeemed_tokens: 0,
+                to_be_replaced_tokens: 0,
+                replace_collateral: 0,
+                liquidated_collateral: 0,
+                active_replace_collateral: 0,
+            })
+        });
+        parachain_rpc
+            .expect_wrapped_to_collateral()
+            .returning(|amount, _| Ok(amount));
+        parachain_rpc
+    }
+
     #[tokio::test]
     async fn test_handle_replace_request_with_insufficient_balance() {
         let mut mock_bitcoin = MockBitcoin::default();
@@ -389,7 +514,14 @@ mod tests {
             griefing_collateral: Default::default(),
         };
         assert_err!(
-            handle_replace_request(parachain_rpc, btc_rpc, &event, &dummy_vault_id()).await,
+            handle_replace_request(
+                parachain_rpc,
+                btc_rpc,
+                &event,
+                &dummy_vault_id(),
+                &ReplacePolicy::default()
+            )
+            .await,
             Error::InsufficientFunds
         );
     }
@@ -420,8 +552,60 @@ mod tests {
             amount: Default::default(),
             griefing_collateral: Default::default(),
         };
-        handle_replace_request(parachain_rpc, btc_rpc, &event, &dummy_vault_id())
-            .await
-            .unwrap();
+        handle_replace_request(
+            parachain_rpc,
+            btc_rpc,
+            &event,
+            &dummy_vault_id(),
+            &ReplacePolicy::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handle_replace_request_below_min_collateralization() {
+        let parachain_rpc = mock_provider_with_vault();
+        let event = RequestReplaceEvent {
+            old_vault_id: dummy_vault_id(),
+            amount: 50,
+            griefing_collateral: Default::default(),
+        };
+        let policy = ReplacePolicy {
+            // 100 collateral for 100 tokens afterwards
+            min_collateralization: Some(FixedU128::saturating_from_rational(150u128, 100u128)),
+            ..Default::default()
+        };
+        assert_err!(
+            handle_replace_request(parachain_rpc, dummy_btc_rpc(), &event, &dummy_vault_id(), &policy).await,
+            Error::ReplaceRejected(Rule::MinCollateralization { .. })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_replace_request_locks_collateral() {
+        let mut parachain_rpc = mock_provider_with_vault();
+        parachain_rpc.expect_get_free_balance().returning(|_| Ok(100));
+        parachain_rpc
+            .expect_accept_replace()
+            .withf(|_, _, _, collateral, _| *collateral == 50)
+            .returning(|_, _, _, _, _| Ok(()));
+
+        let event = RequestReplaceEvent {
+            old_vault_id: dummy_vault_id(),
+            amount: 50,
+            griefing_collateral: Default::default(),
+        };
+        let policy = ReplacePolicy {
+            min_collateralization: Some(FixedU128::saturating_from_rational(150u128, 100u128)),
+            lock_collateral: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            handle_replace_request(parachain_rpc, dummy_btc_rpc(), &event, &dummy_vault_id(), &policy)
+                .await
+                .unwrap(),
+            50
+        );
     }
 }
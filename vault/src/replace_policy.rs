@@ -0,0 +1,251 @@
+use crate::error::Error;
+use runtime::{
+    AccountId, CurrencyId, FixedPointNumber, FixedU128, PrettyPrint, RequestReplaceEvent, RuntimeCurrencyInfo,
+    TryFromSymbol, VaultId, VaultRegistryPallet,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// The rule that caused a replace request to be rejected
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum Rule {
+    #[error("amount {amount} is below min_amount {min}")]
+    MinAmount { amount: u128, min: u128 },
+    #[error("amount {amount} is above max_amount {max}")]
+    MaxAmount { amount: u128, max: u128 },
+    #[error("old vault {0} is on the blocklist")]
+    Blocklist(String),
+    #[error("old vault {0} is not on the allowlist")]
+    Allowlist(String),
+    #[error("exposure of {exposure} in {currency} would exceed max_exposure {max}")]
+    MaxExposure {
+        currency: String,
+        exposure: u128,
+        max: u128,
+    },
+    #[error("collateralization {collateralization} would be below min_collateralization {min}")]
+    MinCollateralization { collateralization: f64, min: f64 },
+}
+
+/// The replace policy as read from the config file. Amounts are in satoshi,
+/// currencies are given by their symbol and accounts in ss58 format.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ReplacePolicyFile {
+    min_amount: Option<u128>,
+    max_amount: Option<u128>,
+    max_exposure: HashMap<String, u128>,
+    preferred_collateral: Vec<String>,
+    blocklist: Vec<AccountId>,
+    allowlist: Vec<AccountId>,
+    /// in percent
+    min_collateralization: Option<u128>,
+    lock_collateral: bool,
+}
+
+/// Rules deciding which replace requests to accept, and with which vault. The default
+/// policy accepts every request with the first vault that has enough collateral.
+#[derive(Clone, Debug, Default)]
+pub struct ReplacePolicy {
+    pub min_amount: Option<u128>,
+    pub max_amount: Option<u128>,
+    /// maximum amount of tokens backed by all vaults with the given collateral currency
+    pub max_exposure: Vec<(CurrencyId, u128)>,
+    /// vaults are tried in this order, vaults with other collateral currencies come last
+    pub preferred_collateral: Vec<CurrencyId>,
+    pub blocklist: Vec<AccountId>,
+    /// if not empty, only requests from these accounts are accepted
+    pub allowlist: Vec<AccountId>,
+    /// minimum collateralization after accepting the request (e.g. 2.6 for 260%)
+    pub min_collateralization: Option<FixedU128>,
+    /// lock additional collateral from the free balance if needed, rather than rejecting the request
+    pub lock_collateral: bool,
+}
+
+impl ReplacePolicy {
+    pub fn from_file(path: &str) -> Result<Self, Error> {
+        let file: ReplacePolicyFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        Ok(Self {
+            min_amount: file.min_amount,
+            max_amount: file.max_amount,
+            max_exposure: file
+                .max_exposure
+                .into_iter()
+                .map(|(symbol, max)| Ok((CurrencyId::try_from_symbol(symbol)?, max)))
+                .collect::<Result<Vec<_>, Error>>()?,
+            preferred_collateral: file
+                .preferred_collateral
+                .into_iter()
+                .map(|symbol| Ok(CurrencyId::try_from_symbol(symbol)?))
+                .collect::<Result<Vec<_>, Error>>()?,
+            blocklist: file.blocklist,
+            allowlist: file.allowlist,
+            min_collateralization: file
+                .min_collateralization
+                .map(|percent| FixedU128::saturating_from_rational(percent, 100u128)),
+            lock_collateral: file.lock_collateral,
+        })
+    }
+
+    /// Checks the rules that do not depend on the vault accepting the request.
+    pub fn check_request(&self, event: &RequestReplaceEvent) -> Result<(), Rule> {
+        let amount = event.amount;
+        if let Some(min) = self.min_amount.filter(|min| amount < *min) {
+            return Err(Rule::MinAmount { amount, min });
+        }
+        if let Some(max) = self.max_amount.filter(|max| amount > *max) {
+            return Err(Rule::MaxAmount { amount, max });
+        }
+        let old_account = &event.old_vault_id.account_id;
+        if self.blocklist.contains(old_account) {
+            return Err(Rule::Blocklist(event.old_vault_id.pretty_print()));
+        }
+        if !self.allowlist.is_empty() && !self.allowlist.contains(old_account) {
+            return Err(Rule::Allowlist(event.old_vault_id.pretty_print()));
+        }
+        Ok(())
+    }
+
+    /// Orders the vaults by the preference of their collateral currency. The order
+    /// of vaults with equal preference is kept.
+    pub fn sort_by_preference<T>(&self, vaults: &mut [(VaultId, T)]) {
+        vaults.sort_by_key(|(vault_id, _)| {
+            self.preferred_collateral
+                .iter()
+                .position(|currency_id| *currency_id == vault_id.collateral_currency())
+                .unwrap_or(usize::MAX)
+        });
+    }
+
+    /// Checks that accepting `amount` with `vault_id` keeps the tokens backed by all of
+    /// `vault_ids` with the same collateral currency below the maximum exposure.
+    pub async fn check_exposure<P: VaultRegistryPallet>(
+        &self,
+        parachain_rpc: &P,
+        vault_ids: &[VaultId],
+        vault_id: &VaultId,
+        amount: u128,
+    ) -> Result<(), Error> {
+        let currency_id = vault_id.collateral_currency();
+        let max = match self.max_exposure.iter().find(|(x, _)| *x == currency_id) {
+            Some((_, max)) => *max,
+            None => return Ok(()),
+        };
+        let mut exposure = amount;
+        for vault_id in vault_ids.iter().filter(|x| x.collateral_currency() == currency_id) {
+            let vault = parachain_rpc.get_vault(vault_id).await?;
+            exposure = exposure
+                .saturating_add(vault.issued_tokens)
+                .saturating_add(vault.to_be_issued_tokens);
+        }
+        if exposure > max {
+            return Err(Rule::MaxExposure {
+                currency: currency_id.symbol()?,
+                exposure,
+                max,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use runtime::{Token, DOT, IBTC, KSM};
+
+    fn vault_id(account: u8, collateral: CurrencyId) -> VaultId {
+        VaultId::new(AccountId::new([account; 32]), collateral, Token(IBTC))
+    }
+
+    fn event(account: u8, amount: u128) -> RequestReplaceEvent {
+        RequestReplaceEvent {
+            old_vault_id: vault_id(account, Token(DOT)),
+            amount,
+            griefing_collateral: Default::default(),
+        }
+    }
+
+    #[test]
+    fn should_accept_everything_by_default() {
+        let policy = ReplacePolicy::default();
+        assert_eq!(policy.check_request(&event(1, 0)), Ok(()));
+        assert_eq!(policy.check_request(&event(1, u128::MAX)), Ok(()));
+    }
+
+    #[test]
+    fn should_check_amount() {
+        let policy = ReplacePolicy {
+            min_amount: Some(100),
+            max_amount: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check_request(&event(1, 99)),
+            Err(Rule::MinAmount { amount: 99, min: 100 })
+        );
+        assert_eq!(policy.check_request(&event(1, 100)), Ok(()));
+        assert_eq!(policy.check_request(&event(1, 1000)), Ok(()));
+        assert_eq!(
+            policy.check_request(&event(1, 1001)),
+            Err(Rule::MaxAmount {
+                amount: 1001,
+                max: 1000
+            })
+        );
+    }
+
+    #[test]
+    fn should_check_old_vault_account() {
+        let policy = ReplacePolicy {
+            blocklist: vec![AccountId::new([1; 32])],
+            ..Default::default()
+        };
+        assert!(matches!(policy.check_request(&event(1, 0)), Err(Rule::Blocklist(_))));
+        assert_eq!(policy.check_request(&event(2, 0)), Ok(()));
+
+        let policy = ReplacePolicy {
+            allowlist: vec![AccountId::new([1; 32])],
+            ..Default::default()
+        };
+        assert_eq!(policy.check_request(&event(1, 0)), Ok(()));
+        assert!(matches!(policy.check_request(&event(2, 0)), Err(Rule::Allowlist(_))));
+    }
+
+    #[test]
+    fn should_sort_by_preference() {
+        let policy = ReplacePolicy {
+            preferred_collateral: vec![Token(KSM), Token(DOT)],
+            ..Default::default()
+        };
+        let mut vaults = vec![
+            (vault_id(1, Token(IBTC)), 0),
+            (vault_id(2, Token(DOT)), 1),
+            (vault_id(3, Token(KSM)), 2),
+            (vault_id(4, Token(DOT)), 3),
+        ];
+        policy.sort_by_preference(&mut vaults);
+        assert_eq!(vaults.into_iter().map(|(_, x)| x).collect::<Vec<_>>(), vec![2, 1, 3, 0]);
+    }
+
+    #[test]
+    fn should_parse_policy_file() {
+        let file: ReplacePolicyFile = serde_json::from_str(
+            r#"{
+                "min_amount": 10000,
+                "max_exposure": { "DOT": 100000000 },
+                "min_collateralization": 300,
+                "lock_collateral": true
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(file.min_amount, Some(10000));
+        assert_eq!(file.max_amount, None);
+        assert_eq!(file.max_exposure.get("DOT"), Some(&100000000));
+        assert_eq!(file.min_collateralization, Some(300));
+        assert!(file.lock_collateral);
+
+        assert!(serde_json::from_str::<ReplacePolicyFile>(r#"{ "max_amonut": 1 }"#).is_err());
+    }
+}
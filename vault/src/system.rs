@@ -1,15 +1,23 @@
 use crate::{
+    alerts::{monitor_alerts, AlertThresholds},
+    api::{self, ApiContext, TaskStatus},
+    cli::FeeRatePolicy,
+    collateral::{manage_collateral, CollateralBand, CollateralConfig},
+    consolidation::{consolidate_utxos, ConsolidationConfig},
     delay::{OrderedVaultsDelay, RandomDelay, ZeroDelay},
+    deleverage::{deleverage_vaults, DeleveragePolicy},
     error::Error,
+    execution::duration_to_parachain_blocks,
     faucet, issue,
     metrics::{poll_metrics, publish_tokio_metrics, PerCurrencyMetrics},
     relay::run_relayer,
+    replace_policy::ReplacePolicy,
     service::{wait_or_shutdown, DynBitcoinCoreApi, MonitoringConfig, Service, ShutdownSender, *},
     Event, IssueRequests, CHAIN_HEIGHT_POLLING_INTERVAL,
 };
 use async_trait::async_trait;
 use backoff::Error as BackoffError;
-use bitcoin::{Error as BitcoinError, Network, PublicKey};
+use bitcoin::{Error as BitcoinError, Network, PublicKey, SatPerVbyte};
 use clap::Parser;
 use futures::{
     channel::{mpsc, mpsc::Sender},
@@ -19,11 +27,11 @@ use futures::{
 use git_version::git_version;
 use runtime::{
     cli::{parse_duration_minutes, parse_duration_ms},
-    BtcRelayPallet, CollateralBalancesPallet, CurrencyId, Error as RuntimeError, InterBtcParachain, PrettyPrint,
-    RegisterVaultEvent, RuntimeCurrencyInfo, StoreMainChainHeaderEvent, TryFromSymbol, UpdateActiveBlockEvent,
-    UtilFuncs, VaultCurrencyPair, VaultId, VaultRegistryPallet,
+    BtcRelayPallet, CollateralBalancesPallet, CurrencyId, Error as RuntimeError, FixedPointNumber, FixedU128,
+    InterBtcParachain, PrettyPrint, RegisterVaultEvent, RuntimeCurrencyInfo, StoreMainChainHeaderEvent, TryFromSymbol,
+    UpdateActiveBlockEvent, UtilFuncs, VaultCurrencyPair, VaultId, VaultRegistryPallet,
 };
-use std::{collections::HashMap, pin::Pin, sync::Arc, time::Duration};
+use std::{collections::HashMap, num::NonZeroU32, pin::Pin, str::FromStr, sync::Arc, time::Duration};
 use tokio::{sync::RwLock, time::sleep};
 
 pub const VERSION: &str = git_version!(args = ["--tags"]);
@@ -51,6 +59,15 @@ fn parse_collateral_and_amount(
     ))
 }
 
+fn parse_collateral_and_policy<T: FromStr<Err = String>>(
+    input: &str,
+) -> Result<(String, T), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let pos = input
+        .find('=')
+        .ok_or_else(|| format!("invalid CurrencyId=LOWER:UPPER: no `=` found in `{input}`"))?;
+    Ok((input[..pos].to_string(), input[pos + 1..].parse()?))
+}
+
 #[derive(Parser, Clone, Debug)]
 pub struct VaultServiceConfig {
     /// Automatically register the vault with the given amount of collateral and a newly generated address.
@@ -65,14 +82,28 @@ pub struct VaultServiceConfig {
     #[clap(long)]
     pub no_auto_replace: bool,
 
+    /// Path of a JSON file with the rules deciding which replace requests to accept: `min_amount`
+    /// and `max_amount` (in satoshi), `max_exposure` per collateral currency (e.g. `{"KSM": 100000000}`),
+    /// `preferred_collateral` currencies in order, a `blocklist` or `allowlist` of old vault accounts,
+    /// `min_collateralization` after accepting (in percent) and `lock_collateral` to lock missing
+    /// collateral from the free balance. By default, every request is accepted if there is enough
+    /// collateral.
+    #[clap(long)]
+    pub replace_policy_file: Option<String>,
+
     /// Don't try to execute issues.
     #[clap(long)]
     pub no_issue_execution: bool,
 
-    /// Don't run the RPC API.
+    /// Deprecated - the RPC API only runs if --api-port is set.
     #[clap(long)]
     pub no_api: bool,
 
+    /// Serve the RPC API on this TCP port, it is only exposed on localhost.
+    /// Disabled if not set.
+    #[clap(long)]
+    pub api_port: Option<u16>,
+
     /// Attempt to execute best-effort transactions immediately, rather than using a random delay.
     #[clap(long)]
     pub no_random_delay: bool,
@@ -90,7 +121,8 @@ pub struct VaultServiceConfig {
     #[clap(long, value_parser = parse_duration_minutes, default_value = "120")]
     pub payment_margin_minutes: Duration,
 
-    /// Timeout in milliseconds to poll Bitcoin.
+    /// Timeout in milliseconds to poll Bitcoin. With --bitcoin-zmq-block-url,
+    /// new blocks are relayed as soon as they are announced.
     #[clap(long, value_parser = parse_duration_ms, default_value = "6000")]
     pub bitcoin_poll_interval_ms: Duration,
 
@@ -99,6 +131,12 @@ pub struct VaultServiceConfig {
     #[clap(long)]
     pub bitcoin_relay_start_height: Option<u32>,
 
+    /// Prefix of the bitcoin-core wallet names, the keyring account name by default.
+    /// Pass the `--target-wallet-prefix` of `migrate-bitcoin-wallets` to use the
+    /// migrated descriptor wallets.
+    #[clap(long)]
+    pub bitcoin_wallet_prefix: Option<String>,
+
     /// Max batch size for combined block header submission.
     #[clap(long, default_value = "16")]
     pub max_batch_size: u32,
@@ -116,10 +154,90 @@ pub struct VaultServiceConfig {
     pub no_auto_refund: bool,
 
     /// Bump bitcoin tx fees whenever the oracle reports a new,
-    /// higher inclusion fee estimate.
+    /// higher inclusion fee estimate. Falls back to child-pays-for-parent
+    /// if the tx can not be replaced.
     #[clap(long)]
     pub auto_rbf: bool,
 
+    /// Sweep small utxos into a single output whenever the bitcoin fee estimate
+    /// is at or below this rate (in sat/vByte). Disabled if not set.
+    #[clap(long)]
+    pub consolidation_fee_rate: Option<u64>,
+
+    /// Only consolidate utxos if the vault has more than this many.
+    #[clap(long, default_value = "50")]
+    pub consolidation_min_utxos: usize,
+
+    /// Maximum number of utxos to spend in a single consolidation transaction.
+    #[clap(long, default_value = "100")]
+    pub consolidation_max_inputs: usize,
+
+    /// Only consolidate utxos worth at most this many satoshis.
+    #[clap(long, default_value = "1000000")]
+    pub consolidation_max_utxo_value: u64,
+
+    /// How to combine the oracle fee estimate with the estimate of the local
+    /// bitcoin node or electrs server: "oracle", "max", "min" or "bounded"
+    /// (oracle, but within a factor of two of the local estimate).
+    #[clap(long, default_value = "oracle")]
+    pub fee_rate_policy: FeeRatePolicy,
+
+    /// Keep the collateralization of the vault with the given collateral currency
+    /// within a band, in percent, e.g. `DOT=260:350`. Below the floor, collateral is
+    /// deposited from the free balance; above the ceiling, the excess is withdrawn.
+    /// Can be passed multiple times.
+    #[clap(long, value_parser = parse_collateral_and_policy::<CollateralBand>)]
+    pub collateral_band: Vec<(String, CollateralBand)>,
+
+    /// Minimum time in minutes between two automatic collateral adjustments of a vault.
+    #[clap(long, value_parser = parse_duration_minutes, default_value = "10")]
+    pub collateral_adjustment_interval_minutes: Duration,
+
+    /// Maximum automatic collateral adjustment, in percent of the vault's collateral.
+    #[clap(long, default_value = "50")]
+    pub collateral_max_adjustment_percent: u32,
+
+    /// Only log the automatic collateral adjustments instead of submitting them.
+    #[clap(long)]
+    pub collateral_dry_run: bool,
+
+    /// Request the replacement of part of the issued tokens of the vault with the given
+    /// collateral currency once its collateralization falls below a threshold, in percent.
+    /// E.g. `DOT=200:260` requests enough to get back to 260% when below 200%. The request
+    /// is withdrawn once the target is reached. Can be passed multiple times.
+    #[clap(long, value_parser = parse_collateral_and_policy::<DeleveragePolicy>)]
+    pub deleverage: Vec<(String, DeleveragePolicy)>,
+
+    /// URL to POST alerts to as JSON, e.g. on a low collateralization, a failed execution
+    /// or a service restart. Can be passed multiple times. Alerts are disabled if not set.
+    #[clap(long)]
+    pub alert_webhook: Vec<String>,
+
+    /// Minimum time in minutes before the same alert is sent again.
+    #[clap(long, value_parser = parse_duration_minutes, default_value = "60")]
+    pub alert_repeat_interval_minutes: Duration,
+
+    /// Maximum number of alerts sent per hour, further alerts are dropped.
+    #[clap(long, default_value = "30")]
+    pub alert_max_per_hour: NonZeroU32,
+
+    /// Alert when the collateralization of a vault falls below this value, in percent.
+    #[clap(long, default_value = "200")]
+    pub alert_min_collateralization: u32,
+
+    /// Alert when the free balance of the native currency, used to pay transaction fees,
+    /// falls below this amount (in planck).
+    #[clap(long)]
+    pub alert_min_native_balance: Option<u128>,
+
+    /// Alert when the relay is more than this many bitcoin blocks behind.
+    #[clap(long, default_value = "6")]
+    pub alert_max_relay_lag: u32,
+
+    /// Alert when a redeem has not been paid this many minutes before it can be cancelled.
+    #[clap(long, value_parser = parse_duration_minutes, default_value = "180")]
+    pub alert_redeem_deadline_minutes: Duration,
+
     /// Path of a caching database. If you want to create a new database, set
     /// this to an unexisting path, e.g. `${pwd}/myvault.db`. If not set, a
     /// the path is generated from the --keyname argument
@@ -452,10 +570,14 @@ async fn run_and_monitor_tasks(shutdown_tx: ShutdownSender, items: Vec<(&str, Se
             let monitor = tokio_metrics::TaskMonitor::new();
             let metrics_iterator = monitor.intervals();
             let task = match task {
-                ServiceTask::Optional(true, t) | ServiceTask::Essential(t) => {
-                    Some(wait_or_shutdown(shutdown_tx.clone(), t))
+                ServiceTask::Optional(true, t) | ServiceTask::Essential(t) => Some(wait_or_shutdown(
+                    shutdown_tx.clone(),
+                    api::run_with_status(name.to_string(), t),
+                )),
+                _ => {
+                    api::set_task_status(name, TaskStatus::Disabled);
+                    None
                 }
-                _ => None,
             }?;
             let task = monitor.instrument(task);
             let task = tokio::spawn(task);
@@ -568,6 +690,8 @@ impl VaultService {
     }
 
     async fn run_service(&self) -> Result<(), BackoffError<Error>> {
+        // don't report the tasks and payments of the previous run
+        api::reset_state();
         self.validate_bitcoin_network()
             .await
             .map_err(|err| BackoffError::Permanent(err))?;
@@ -582,6 +706,32 @@ impl VaultService {
             .into_iter()
             .collect::<Result<Vec<_>, Error>>()?;
 
+        let collateral_bands = self
+            .config
+            .collateral_band
+            .iter()
+            .map(|(symbol, band)| Ok((CurrencyId::try_from_symbol(symbol.clone())?, *band)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let deleverage_policies = self
+            .config
+            .deleverage
+            .iter()
+            .map(|(symbol, policy)| Ok((CurrencyId::try_from_symbol(symbol.clone())?, *policy)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let alert_thresholds = AlertThresholds {
+            min_collateralization: FixedU128::saturating_from_rational(self.config.alert_min_collateralization, 100u32),
+            min_native_balance: self.config.alert_min_native_balance,
+            max_relay_lag: self.config.alert_max_relay_lag,
+            redeem_deadline_margin: duration_to_parachain_blocks(self.config.alert_redeem_deadline_minutes)?,
+        };
+
+        let replace_policy = match &self.config.replace_policy_file {
+            Some(path) => ReplacePolicy::from_file(path)?,
+            None => ReplacePolicy::default(),
+        };
+
         // exit if auto-register uses faucet and faucet url not set
         if parsed_auto_register.iter().any(|(_, o)| o.is_none()) && self.config.faucet_url.is_none() {
             // TODO: validate before bitcoin / parachain connections
@@ -639,6 +789,7 @@ impl VaultService {
             num_confirmations,
             self.config.payment_margin_minutes,
             self.config.auto_rbf,
+            self.config.fee_rate_policy,
         );
 
         let shutdown_clone = self.shutdown.clone();
@@ -736,6 +887,7 @@ impl VaultService {
                     self.vault_id_manager.clone(),
                     replace_event_tx.clone(),
                     !self.config.no_auto_replace,
+                    replace_policy,
                 )),
             ),
             (
@@ -747,6 +899,7 @@ impl VaultService {
                     num_confirmations,
                     self.config.payment_margin_minutes,
                     self.config.auto_rbf,
+                    self.config.fee_rate_policy,
                 )),
             ),
             (
@@ -791,6 +944,7 @@ impl VaultService {
                     num_confirmations,
                     self.config.payment_margin_minutes,
                     self.config.auto_rbf,
+                    self.config.fee_rate_policy,
                 )),
             ),
             (
@@ -842,6 +996,80 @@ impl VaultService {
                     poll_metrics(self.btc_parachain.clone(), self.vault_id_manager.clone()),
                 ),
             ),
+            (
+                "Utxo Consolidation",
+                maybe_run(
+                    self.config.consolidation_fee_rate.is_some(),
+                    consolidate_utxos(
+                        self.btc_parachain.clone(),
+                        self.vault_id_manager.clone(),
+                        ConsolidationConfig {
+                            max_fee_rate: SatPerVbyte(self.config.consolidation_fee_rate.unwrap_or_default()),
+                            min_utxos: self.config.consolidation_min_utxos,
+                            max_inputs: self.config.consolidation_max_inputs,
+                            max_utxo_value: self.config.consolidation_max_utxo_value,
+                            fee_rate_policy: self.config.fee_rate_policy,
+                        },
+                    ),
+                ),
+            ),
+            (
+                "Collateral Manager",
+                maybe_run(
+                    !collateral_bands.is_empty(),
+                    manage_collateral(
+                        self.btc_parachain.clone(),
+                        self.vault_id_manager.clone(),
+                        CollateralConfig {
+                            bands: collateral_bands,
+                            min_interval: self.config.collateral_adjustment_interval_minutes,
+                            max_adjustment: FixedU128::saturating_from_rational(
+                                self.config.collateral_max_adjustment_percent,
+                                100u32,
+                            ),
+                            dry_run: self.config.collateral_dry_run,
+                        },
+                    ),
+                ),
+            ),
+            (
+                "Deleveraging",
+                maybe_run(
+                    !deleverage_policies.is_empty(),
+                    deleverage_vaults(
+                        self.btc_parachain.clone(),
+                        self.vault_id_manager.clone(),
+                        deleverage_policies,
+                    ),
+                ),
+            ),
+            (
+                "API Server",
+                maybe_run(
+                    self.config.api_port.is_some(),
+                    api::serve_api(
+                        ApiContext {
+                            parachain_rpc: self.btc_parachain.clone(),
+                            vault_id_manager: self.vault_id_manager.clone(),
+                            btc_rpc: self.btc_rpc_master_wallet.clone(),
+                            relay_enabled: !self.config.no_bitcoin_block_relay,
+                        },
+                        self.config.api_port.unwrap_or_default(),
+                    ),
+                ),
+            ),
+            (
+                "Alert Monitor",
+                maybe_run(
+                    !self.config.alert_webhook.is_empty(),
+                    monitor_alerts(
+                        self.btc_parachain.clone(),
+                        self.vault_id_manager.clone(),
+                        self.btc_rpc_master_wallet.clone(),
+                        alert_thresholds,
+                    ),
+                ),
+            ),
             (
                 "Restart Timer",
                 run(async move {
@@ -18,7 +18,11 @@ use runtime::{
 use serial_test::serial;
 use sp_keyring::AccountKeyring;
 use std::{process::Child, sync::Arc, time::Duration};
-use vault::{self, service::DynBitcoinCoreApi, Event as CancellationEvent, IssueRequests, VaultIdManager, ZeroDelay};
+use vault::{
+    self,
+    service::{DynBitcoinCoreApi, FeeRatePolicy},
+    Event as CancellationEvent, IssueRequests, VaultIdManager, ZeroDelay,
+};
 
 const TIMEOUT: Duration = Duration::from_secs(90);
 
@@ -129,6 +133,7 @@ async fn test_redeem_succeeds() {
                     0,
                     Duration::from_secs(0),
                     true,
+                    FeeRatePolicy::Oracle,
                 ),
                 periodically_produce_blocks(user_provider.clone()),
             ),
@@ -219,6 +224,7 @@ async fn test_replace_succeeds() {
                     vault_id_manager.clone(),
                     replace_event_tx.clone(),
                     true,
+                    Default::default(),
                 ),
                 vault::service::listen_for_accept_replace(
                     shutdown_tx.clone(),
@@ -227,6 +233,7 @@ async fn test_replace_succeeds() {
                     0,
                     Duration::from_secs(0),
                     true,
+                    FeeRatePolicy::Oracle,
                 ),
                 periodically_produce_blocks(old_vault_provider.clone()),
             ),
@@ -877,6 +884,7 @@ async fn test_execute_open_requests_succeeds() {
                 0,
                 Duration::from_secs(0),
                 true,
+                FeeRatePolicy::Oracle,
             )
             .map(Result::unwrap),
             assert_redeem_event(TIMEOUT, user_provider.clone(), redeem_ids[0]),
@@ -978,7 +986,7 @@ mod test_with_bitcoind {
             bitcoin_rpc_user: Some(var("BITCOIN_RPC_USER").expect("BITCOIN_RPC_USER not set").to_string()),
             bitcoin_rpc_pass: Some(var("BITCOIN_RPC_PASS").expect("BITCOIN_RPC_PASS not set").to_string()),
             bitcoin_connection_timeout_ms: 10000,
-            electrs_url: None,
+            electrs_url: vec![],
             ..Default::default()
         };
         let ret = opts
@@ -1242,6 +1250,7 @@ mod test_with_bitcoind {
                     0,
                     Duration::from_secs(0),
                     true,
+                    FeeRatePolicy::Oracle,
                 ),
                 vault_provider.listen_for_fee_rate_changes(),
             );